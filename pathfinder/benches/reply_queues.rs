@@ -0,0 +1,166 @@
+//! Measures the throughput of requests to a microservice, when responses are
+//! received via the temporary reply queues and via the direct reply-to.
+//!
+//! The benchmark requires a running RabbitMQ broker (e.g. the one from the
+//! `docker-compose.dev.yml` file), and the requests are answered by the echo
+//! consumer started by the benchmark itself:
+//!
+//! ```bash
+//! cargo bench --bench reply_queues
+//! ```
+//!
+//! The broker is taken from the `PATHFINDER_RABBITMQ_HOST` and the
+//! `PATHFINDER_RABBITMQ_PORT` environment variables, the number of requests
+//! and of concurrent requests from the `BENCH_REQUESTS` and the
+//! `BENCH_CONCURRENCY` variables.
+//!
+
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::Future;
+use futures::stream::{self, Stream};
+use futures::sync::mpsc;
+use json::object;
+use lapin_futures_rustls::lapin::channel::{
+    BasicConsumeOptions, BasicProperties, BasicPublishOptions, ExchangeDeclareOptions, QueueBindOptions,
+    QueueDeclareOptions,
+};
+use lapin_futures_rustls::lapin::types::FieldTable;
+use tokio::runtime::Runtime;
+
+use pathfinder::config::ProxyConfig;
+use pathfinder::engine::options::ReplyQueueOptions;
+use pathfinder::engine::utils::with_correlation_id;
+use pathfinder::engine::{rpc_request_future, Endpoint, RpcOptions, REQUEST_EXCHANGE, RESPONSE_EXCHANGE};
+use pathfinder::rabbitmq::{get_tls_options, get_uri, RabbitMQClient, RabbitMQContext, DIRECT_REPLY_TO_QUEUE};
+
+/// The routing key of the echo consumer.
+const ROUTING_KEY: &str = "pathfinder.bench.echo";
+/// The time to wait for each response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn get_env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn get_config() -> ProxyConfig {
+    ProxyConfig {
+        rabbitmq_host: get_env_var("PATHFINDER_RABBITMQ_HOST", String::from("127.0.0.1")),
+        rabbitmq_port: get_env_var("PATHFINDER_RABBITMQ_PORT", 5672),
+        ..ProxyConfig::default()
+    }
+}
+
+fn connect(config: &ProxyConfig, use_direct_reply_to: bool) -> impl Future<Item=Arc<RabbitMQClient>, Error=()> + Send + 'static {
+    let uri = get_uri(config).expect("The RabbitMQ settings are invalid");
+    RabbitMQClient::connect(&uri, &get_tls_options(config))
+        .map(move |client| Arc::new(client.with_direct_reply_to(use_direct_reply_to)))
+        .map_err(|error| panic!("Can't connect to RabbitMQ: {}", error))
+}
+
+/// Declares the exchanges and the queue of the echo consumer, which sends
+/// the body of each request back to the `reply_to` queue.
+fn start_echo_consumer(context: Arc<RabbitMQContext>) -> impl Future<Item=(), Error=()> + Send + 'static {
+    let channel = context.get_consume_channel();
+    let publish_channel = context.get_publish_channel();
+    let exchange_options = ExchangeDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+    let queue_options = QueueDeclareOptions {
+        exclusive: true,
+        auto_delete: true,
+        ..Default::default()
+    };
+    let consume_options = BasicConsumeOptions {
+        no_ack: true,
+        ..Default::default()
+    };
+
+    channel.exchange_declare(REQUEST_EXCHANGE, "direct", exchange_options.clone(), FieldTable::new())
+        .and_then(move |_| {
+            channel.exchange_declare(RESPONSE_EXCHANGE, "direct", exchange_options, FieldTable::new())
+                .map(move |_| channel)
+        })
+        .and_then(move |channel| {
+            channel.queue_declare(ROUTING_KEY, queue_options, FieldTable::new())
+                .map(move |queue| (channel, queue))
+        })
+        .and_then(|(channel, queue)| {
+            channel.queue_bind(ROUTING_KEY, REQUEST_EXCHANGE, ROUTING_KEY, QueueBindOptions::default(), FieldTable::new())
+                .map(move |_| (channel, queue))
+        })
+        .and_then(move |(channel, queue)| channel.basic_consume(&queue, "echo_consumer", consume_options, FieldTable::new()))
+        .map(move |deliveries| {
+            tokio::spawn(
+                deliveries
+                    .for_each(move |delivery| {
+                        let reply_to = delivery.properties.reply_to().clone().unwrap_or_default();
+                        let correlation_id = delivery.properties.correlation_id().clone().unwrap_or_default();
+                        let exchange = match reply_to.starts_with(DIRECT_REPLY_TO_QUEUE) {
+                            true => "",
+                            false => RESPONSE_EXCHANGE
+                        };
+                        let properties = BasicProperties::default()
+                            .with_content_type(String::from("application/json"))
+                            .with_correlation_id(correlation_id);
+                        publish_channel
+                            .basic_publish(exchange, &reply_to, delivery.data, BasicPublishOptions::default(), properties)
+                            .map(|_| ())
+                    })
+                    .map_err(|error| panic!("The echo consumer failed: {}", error))
+            );
+        })
+        .map_err(|error| panic!("Can't start the echo consumer: {}", error))
+}
+
+/// Sends the requests through one context and returns the number of the
+/// requests processed per second.
+fn run_requests(client: Arc<RabbitMQClient>, requests: usize, concurrency: usize) -> impl Future<Item=f64, Error=()> + Send + 'static {
+    let endpoint = Arc::new(Endpoint::new("/api/bench", ROUTING_KEY, REQUEST_EXCHANGE, RESPONSE_EXCHANGE, false));
+    let (transmitter, receiver) = mpsc::unbounded();
+    let transmitter = Arc::new(transmitter);
+    tokio::spawn(receiver.for_each(|_| Ok(())));
+
+    client.get_context()
+        .map_err(|error| panic!("Can't open the channels: {}", error))
+        .and_then(move |context| {
+            let started_at = Instant::now();
+            stream::iter_ok(0..requests)
+                .map(move |index| {
+                    let message = Arc::new(Box::new(object!{"url" => "/api/bench", "content" => object!{"index" => index}}));
+                    let reply_queue = ReplyQueueOptions::default();
+                    let options = RpcOptions::default()
+                        .with_endpoint(endpoint.clone())
+                        .with_routing_key(String::from(ROUTING_KEY))
+                        .with_message(with_correlation_id(message))
+                        .with_queue_name(Arc::new(reply_queue.get_queue_name(ROUTING_KEY)))
+                        .with_reply_queue(reply_queue)
+                        .with_timeout(TIMEOUT);
+                    rpc_request_future(transmitter.clone(), context.clone(), Arc::new(options), Default::default())
+                })
+                .buffer_unordered(concurrency)
+                .for_each(|_| Ok(()))
+                .map(move |_| requests as f64 / started_at.elapsed().as_secs_f64())
+                .map_err(|error| panic!("The request failed: {}", error))
+        })
+}
+
+fn main() {
+    let config = get_config();
+    let requests = get_env_var("BENCH_REQUESTS", 10_000);
+    let concurrency = get_env_var("BENCH_CONCURRENCY", 100);
+    let mut runtime = Runtime::new().unwrap();
+
+    let echo_client = runtime.block_on(connect(&config, false)).unwrap();
+    let echo_context = runtime.block_on(echo_client.get_context().map_err(|_| ())).unwrap();
+    runtime.block_on(start_echo_consumer(echo_context)).unwrap();
+
+    for &(name, use_direct_reply_to) in &[("reply queues", false), ("direct reply-to", true)] {
+        let client = runtime.block_on(connect(&config, use_direct_reply_to)).unwrap();
+        let throughput = runtime.block_on(run_requests(client, requests, concurrency)).unwrap();
+        println!("{}: {} requests, {} concurrent, {:.1} requests/sec", name, requests, concurrency, throughput);
+    }
+}
@@ -3,37 +3,67 @@
 //! For more details about using the structopt crate you can find [here](https://github.com/TeXitoi/structopt).
 //!
 
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
+
 use clap;
 use structopt::StructOpt;
 
+use crate::config::{ProxyConfig, Secret};
+
+/// The first descriptor passed by systemd on the socket activation.
+const SYSTEMD_LISTEN_FDS_START: i32 = 3;
+
 /// A structure that defines available arguments and options for CLI
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "Pathfinder",
     version = "1.1.0",
     about = "WebSocket-over-RabbitMQ reverse proxy",
+    after_help = "Each option can also be set via the environment variable shown next to it. \
+                  Values passed on the command line take precedence over environment variables, \
+                  which take precedence over the default values. Flags are enabled by the \
+                  \"true\" or \"1\" values of their variables.",
     raw(setting = "clap::AppSettings::DeriveDisplayOrder")
 )]
 pub struct CliOptions {
     #[structopt(
         short = "s",
         long = "secured",
-        help = "Enable the SSL/TLS mode for connections with RabbitMQ"
+        help = "Enable the SSL/TLS mode for connections with RabbitMQ [env: PATHFINDER_RABBITMQ_SECURED]"
     )]
     pub rabbitmq_secured: bool,
 
     #[structopt(
         short = "c",
         long = "config",
-        help = "Path to a custom settings file",
+        help = "Path to a custom settings file or a directory with them, or the HTTP(S) URL of the remote settings",
+        env = "PATHFINDER_CONFIG",
         default_value = ""
     )]
     pub config: String,
 
+    #[structopt(
+        long = "config-poll-interval",
+        help = "Interval in seconds of reloading the settings, e.g. to pick up changes of the remote settings (0 disables polling)",
+        env = "PATHFINDER_CONFIG_POLL_INTERVAL",
+        default_value = "0"
+    )]
+    pub config_poll_interval: u64,
+
+    #[structopt(
+        long = "check-config",
+        help = "Validate the configuration file and exit without starting the server [env: PATHFINDER_CHECK_CONFIG]"
+    )]
+    pub check_config: bool,
+
     #[structopt(
         short = "i",
         long = "ip",
         help = "The used IP for a server",
+        env = "PATHFINDER_IP",
         default_value = "127.0.0.1"
     )]
     pub ip: String,
@@ -42,21 +72,89 @@ pub struct CliOptions {
         short = "p",
         long = "port",
         help = "The listened port",
+        env = "PATHFINDER_PORT",
         default_value = "9000"
     )]
     pub port: u16,
 
+    #[structopt(
+        long = "listen",
+        help = "A comma-separated list of additional addresses to listen on, e.g. [::]:9000",
+        env = "PATHFINDER_LISTEN",
+        raw(use_delimiter = "true")
+    )]
+    pub listen: Vec<SocketAddr>,
+
+    #[structopt(
+        long = "listen-fd",
+        help = "An already open listening socket, used instead of binding the IP and the port (e.g. passed by systemd)",
+        env = "PATHFINDER_LISTEN_FD"
+    )]
+    pub listen_fd: Option<i32>,
+
+    #[structopt(
+        long = "ignore-bind-errors",
+        help = "Keep running when some of the listened addresses can't be used [env: PATHFINDER_IGNORE_BIND_ERRORS]"
+    )]
+    pub ignore_bind_errors: bool,
+
     #[structopt(
         short = "l",
         long = "--log-level",
         help = "Verbosity level filter of the logger",
+        env = "PATHFINDER_LOG_LEVEL",
         default_value = "info"
     )]
     pub log_level: String,
 
+    #[structopt(
+        long = "log-format",
+        help = "Format of the log records: text or json",
+        env = "PATHFINDER_LOG_FORMAT",
+        default_value = "text"
+    )]
+    pub log_format: String,
+
+    #[structopt(
+        long = "message-pack",
+        help = "Accept binary messages in the MessagePack format [env: PATHFINDER_MESSAGE_PACK]"
+    )]
+    pub message_pack: bool,
+
+    #[structopt(
+        long = "message-format",
+        help = "Format of binary messages: json, msgpack or cbor",
+        env = "PATHFINDER_MESSAGE_FORMAT",
+        default_value = "json"
+    )]
+    pub message_format: String,
+
+    #[structopt(
+        long = "max-message-size",
+        help = "The maximum size of an incoming message in bytes",
+        env = "PATHFINDER_MAX_MESSAGE_SIZE",
+        default_value = "1048576"
+    )]
+    pub max_message_size: usize,
+
+    #[structopt(
+        long = "max-pending-messages",
+        help = "The maximum number of responses waiting for a slow client before the connection is dropped",
+        env = "PATHFINDER_MAX_PENDING_MESSAGES",
+        default_value = "1024"
+    )]
+    pub max_pending_messages: usize,
+
+    #[structopt(
+        long = "close-on-fatal-errors",
+        help = "Close the connection after decoding and authentication errors [env: PATHFINDER_CLOSE_ON_FATAL_ERRORS]"
+    )]
+    pub close_on_fatal_errors: bool,
+
     #[structopt(
         long = "rabbitmq-host",
         help = "The used host by RabbitMQ broker",
+        env = "PATHFINDER_RABBITMQ_HOST",
         default_value = "127.0.0.1"
     )]
     pub rabbitmq_host: String,
@@ -64,6 +162,7 @@ pub struct CliOptions {
     #[structopt(
         long = "rabbitmq-port",
         help = "The listened port by RabbitMQ broker",
+        env = "PATHFINDER_RABBITMQ_PORT",
         default_value = "5672"
     )]
     pub rabbitmq_port: u16,
@@ -71,6 +170,7 @@ pub struct CliOptions {
     #[structopt(
         long = "rabbitmq-virtual-host",
         help = "The virtual host of a RabbitMQ node",
+        env = "PATHFINDER_RABBITMQ_VIRTUAL_HOST",
         default_value = "vhost"
     )]
     pub rabbitmq_virtual_host: String,
@@ -78,6 +178,7 @@ pub struct CliOptions {
     #[structopt(
         long = "rabbitmq-user",
         help = "A RabbitMQ application username",
+        env = "PATHFINDER_RABBITMQ_USERNAME",
         default_value = "user"
     )]
     pub rabbitmq_username: String,
@@ -85,13 +186,84 @@ pub struct CliOptions {
     #[structopt(
         long = "rabbitmq-password",
         help = "A RabbitMQ application password",
+        env = "PATHFINDER_RABBITMQ_PASSWORD",
+        raw(hide_env_values = "true"),
         default_value = "password"
     )]
-    pub rabbitmq_password: String,
+    pub rabbitmq_password: Secret,
+
+    #[structopt(
+        long = "rabbitmq-password-file",
+        help = "Path to a file with a RabbitMQ application password (takes precedence over --rabbitmq-password)",
+        env = "PATHFINDER_RABBITMQ_PASSWORD_FILE",
+        default_value = ""
+    )]
+    pub rabbitmq_password_file: String,
+
+    #[structopt(
+        long = "rabbitmq-direct-reply-to",
+        help = "Receive responses via the amq.rabbitmq.reply-to pseudo-queue instead of temporary queues [env: PATHFINDER_RABBITMQ_DIRECT_REPLY_TO]"
+    )]
+    pub rabbitmq_direct_reply_to: bool,
+
+    #[structopt(
+        long = "rabbitmq-no-confirms",
+        help = "Publish requests without waiting for the confirmation from RabbitMQ (faster, but lost requests aren't detected) [env: PATHFINDER_RABBITMQ_NO_CONFIRMS]"
+    )]
+    pub rabbitmq_no_confirms: bool,
+
+    #[structopt(
+        long = "rabbitmq-prefetch",
+        help = "The maximum number of unacknowledged responses per consumer (0 means no limit)",
+        env = "PATHFINDER_RABBITMQ_PREFETCH",
+        default_value = "0"
+    )]
+    pub rabbitmq_prefetch: u16,
+
+    #[structopt(
+        long = "rabbitmq-declare-rate",
+        help = "The maximum number of reply queues declared per second by all connections, requests over the limit wait for their turn (0 means no limit)",
+        env = "PATHFINDER_RABBITMQ_DECLARE_RATE",
+        default_value = "0"
+    )]
+    pub rabbitmq_declare_rate: u32,
+
+    #[structopt(
+        long = "rabbitmq-channel-pool-size",
+        help = "The maximum number of idle channel pairs kept for reuse by new connections (0 disables pooling)",
+        env = "PATHFINDER_RABBITMQ_CHANNEL_POOL_SIZE",
+        default_value = "0"
+    )]
+    pub rabbitmq_channel_pool_size: usize,
+
+    #[structopt(
+        long = "rabbitmq-ca-cert",
+        help = "Path to a CA bundle for verifying the RabbitMQ server certificate (the Mozilla roots are used by default)",
+        env = "PATHFINDER_RABBITMQ_CA_CERTIFICATE",
+        default_value = ""
+    )]
+    pub rabbitmq_ca_certificate: String,
+
+    #[structopt(
+        long = "rabbitmq-client-cert",
+        help = "Path to a client certificate for the mutual TLS authentication with RabbitMQ",
+        env = "PATHFINDER_RABBITMQ_CLIENT_CERTIFICATE",
+        default_value = ""
+    )]
+    pub rabbitmq_client_certificate: String,
+
+    #[structopt(
+        long = "rabbitmq-client-key",
+        help = "Path to a private key of the client certificate",
+        env = "PATHFINDER_RABBITMQ_CLIENT_KEY",
+        default_value = ""
+    )]
+    pub rabbitmq_client_key: String,
 
     #[structopt(
         long = "ssl-cert",
         help = "Path to a SSL certificate",
+        env = "PATHFINDER_SSL_CERTIFICATE",
         default_value = ""
     )]
     pub ssl_certificate: String,
@@ -99,7 +271,491 @@ pub struct CliOptions {
     #[structopt(
         long = "ssl-key",
         help = "Path to a SSL public key",
+        env = "PATHFINDER_SSL_PUBLIC_KEY",
         default_value = ""
     )]
     pub ssl_public_key: String,
+
+    #[structopt(
+        long = "token-cache-ttl",
+        help = "Time in seconds during which the verified tokens are cached, but not after their expiration (0 disables the cache)",
+        env = "PATHFINDER_TOKEN_CACHE_TTL",
+        default_value = "0"
+    )]
+    pub token_cache_ttl: u64,
+
+    #[structopt(
+        long = "token-cache-size",
+        help = "The maximum number of the verified tokens stored in the cache",
+        env = "PATHFINDER_TOKEN_CACHE_SIZE",
+        default_value = "1000"
+    )]
+    pub token_cache_size: usize,
+
+    #[structopt(
+        long = "replay-window",
+        help = "The maximum difference in seconds between the timestamp of the request and the current time for endpoints with the `replay_protection` middleware",
+        env = "PATHFINDER_REPLAY_WINDOW",
+        default_value = "300"
+    )]
+    pub replay_window: u64,
+
+    #[structopt(
+        long = "replay-cache-size",
+        help = "The maximum number of the recent nonces stored for the `replay_protection` middleware",
+        env = "PATHFINDER_REPLAY_CACHE_SIZE",
+        default_value = "100000"
+    )]
+    pub replay_cache_size: usize,
+
+    #[structopt(
+        long = "connection-auth",
+        help = "Authenticate each connection once by the first message with the `token` field instead of verifying tokens of every request [env: PATHFINDER_CONNECTION_AUTH]"
+    )]
+    pub connection_auth: bool,
+
+    #[structopt(
+        long = "reauth-interval",
+        help = "Time in seconds after which an authenticated connection must send the token again (0 keeps it until the connection is closed)",
+        env = "PATHFINDER_REAUTH_INTERVAL",
+        default_value = "0"
+    )]
+    pub reauth_interval: u64,
+
+    #[structopt(
+        long = "rpc-timeout",
+        help = "Time in seconds to wait for a response from a microservice",
+        env = "PATHFINDER_RPC_TIMEOUT",
+        default_value = "30"
+    )]
+    pub rpc_timeout: u64,
+
+    #[structopt(
+        long = "slow-request-ms",
+        help = "Log a warning for requests to microservices that take longer than this time in milliseconds (0 disables the logging)",
+        env = "PATHFINDER_SLOW_REQUEST_MS",
+        default_value = "0"
+    )]
+    pub slow_request_ms: u64,
+
+    #[structopt(
+        long = "rabbitmq-confirm-timeout",
+        help = "Time in milliseconds to wait for RabbitMQ to confirm a published request",
+        env = "PATHFINDER_RABBITMQ_CONFIRM_TIMEOUT",
+        default_value = "5000"
+    )]
+    pub rabbitmq_confirm_timeout: u64,
+
+    #[structopt(
+        long = "amqp-prefix",
+        help = "The prefix of exchanges and routing keys, that allows isolated environments to share one broker (empty keeps the names as is)",
+        env = "PATHFINDER_AMQP_PREFIX",
+        default_value = ""
+    )]
+    pub amqp_prefix: String,
+
+    #[structopt(
+        long = "event-name-field",
+        help = "The field of the message with the event name, which is passed to microservices in the `event_name` header",
+        env = "PATHFINDER_EVENT_NAME_FIELD",
+        default_value = "event-name"
+    )]
+    pub event_name_field: String,
+
+    #[structopt(
+        long = "health-check-routing-key",
+        help = "Routing key of the microservice that answers to pings sent on health checks (empty checks only the connection to RabbitMQ)",
+        env = "PATHFINDER_HEALTH_CHECK_ROUTING_KEY",
+        default_value = ""
+    )]
+    pub health_check_routing_key: String,
+
+    #[structopt(
+        long = "health-check-timeout-ms",
+        help = "Time in milliseconds to wait for a response to the ping sent on health checks",
+        env = "PATHFINDER_HEALTH_CHECK_TIMEOUT_MS",
+        default_value = "1000"
+    )]
+    pub health_check_timeout_ms: u64,
+
+    #[structopt(
+        long = "rabbitmq-heartbeat",
+        help = "Interval in seconds of AMQP heartbeats between reverse proxy and RabbitMQ (0 uses the interval suggested by RabbitMQ)",
+        env = "PATHFINDER_RABBITMQ_HEARTBEAT",
+        default_value = "10"
+    )]
+    pub rabbitmq_heartbeat: u64,
+
+    #[structopt(
+        long = "shutdown-grace-period",
+        help = "Time in seconds to wait for active connections on shutdown",
+        env = "PATHFINDER_SHUTDOWN_GRACE_PERIOD",
+        default_value = "10"
+    )]
+    pub shutdown_grace_period: u64,
+
+    #[structopt(
+        long = "shutdown-message",
+        help = "The JSON object sent to connected clients on shutdown, so that they can reconnect to another instance (an empty value disables the notice)",
+        env = "PATHFINDER_SHUTDOWN_MESSAGE",
+        default_value = r#"{"type": "server_shutdown", "reconnect_after_ms": 1000}"#
+    )]
+    pub shutdown_message: String,
+
+    #[structopt(
+        long = "http-fallback-path",
+        help = "The path for POST requests of clients that can't use WebSockets, e.g. /rpc (an empty path disables the HTTP fallback)",
+        env = "PATHFINDER_HTTP_FALLBACK_PATH",
+        default_value = ""
+    )]
+    pub http_fallback_path: String,
+
+    #[structopt(
+        long = "ping-interval",
+        help = "Time in seconds between WebSocket pings sent to clients (0 disables pings)",
+        env = "PATHFINDER_PING_INTERVAL",
+        default_value = "30"
+    )]
+    pub ping_interval: u64,
+
+    #[structopt(
+        long = "max-missed-pongs",
+        help = "The number of unanswered pings after which the connection is closed",
+        env = "PATHFINDER_MAX_MISSED_PONGS",
+        default_value = "3"
+    )]
+    pub max_missed_pongs: usize,
+
+    #[structopt(
+        long = "idle-timeout",
+        help = "Time in seconds without any frames from a client after which the connection is closed (0 disables the timeout)",
+        env = "PATHFINDER_IDLE_TIMEOUT",
+        default_value = "0"
+    )]
+    pub idle_timeout: u64,
+
+    #[structopt(
+        long = "allowed-origins",
+        help = "A comma-separated list of origins allowed for WebSocket handshakes (all origins are allowed by default)",
+        env = "PATHFINDER_ALLOWED_ORIGINS",
+        raw(use_delimiter = "true")
+    )]
+    pub allowed_origins: Vec<String>,
+
+    #[structopt(
+        long = "subprotocols",
+        help = "A comma-separated list of supported WebSocket subprotocols, e.g. json,msgpack (the negotiation is disabled by default)",
+        env = "PATHFINDER_SUBPROTOCOLS",
+        raw(use_delimiter = "true")
+    )]
+    pub subprotocols: Vec<String>,
+
+    #[structopt(
+        long = "rate-limit",
+        help = "The maximum number of requests per second from one WebSocket connection (0 means no limit)",
+        env = "PATHFINDER_RATE_LIMIT",
+        default_value = "0"
+    )]
+    pub rate_limit: u32,
+
+    #[structopt(
+        long = "rate-limit-burst",
+        help = "The number of requests that one WebSocket connection can send at once (0 means the same as --rate-limit)",
+        env = "PATHFINDER_RATE_LIMIT_BURST",
+        default_value = "0"
+    )]
+    pub rate_limit_burst: u32,
+
+    #[structopt(
+        long = "max-in-flight-requests",
+        help = "The maximum number of concurrent requests from one WebSocket connection (0 means no limit)",
+        env = "PATHFINDER_MAX_IN_FLIGHT_REQUESTS",
+        default_value = "0"
+    )]
+    pub max_in_flight_requests: usize,
+
+    #[structopt(
+        long = "max-connections-per-ip",
+        help = "The maximum number of concurrent WebSocket connections from one IP address (0 means no limit)",
+        env = "PATHFINDER_MAX_CONNECTIONS_PER_IP",
+        default_value = "0"
+    )]
+    pub max_connections_per_ip: usize,
+
+    #[structopt(
+        long = "max-connections",
+        help = "The maximum number of concurrent WebSocket connections in total (0 means no limit)",
+        env = "PATHFINDER_MAX_CONNECTIONS",
+        default_value = "0"
+    )]
+    pub max_connections: usize,
+
+    #[structopt(
+        long = "metrics-port",
+        help = "The listened port for exposing metrics in the Prometheus format (0 disables metrics)",
+        env = "PATHFINDER_METRICS_PORT",
+        default_value = "0"
+    )]
+    pub metrics_port: u16,
+
+    #[structopt(
+        long = "metrics-path",
+        help = "The path to metrics in the Prometheus format",
+        env = "PATHFINDER_METRICS_PATH",
+        default_value = "/metrics"
+    )]
+    pub metrics_path: String,
+
+    #[structopt(
+        long = "expose-routes",
+        help = "Expose the list of the loaded routes on the metrics port [env: PATHFINDER_EXPOSE_ROUTES]"
+    )]
+    pub expose_routes: bool,
+
+    #[structopt(
+        long = "routes-token",
+        help = "The bearer token required for getting the list of routes (an empty token disables the check)",
+        env = "PATHFINDER_ROUTES_TOKEN",
+        default_value = ""
+    )]
+    pub routes_token: Secret,
+
+    #[structopt(
+        long = "expose-stats",
+        help = "Answer the messages to the \"/__stats\" URL with the statistics of the proxy [env: PATHFINDER_EXPOSE_STATS]"
+    )]
+    pub expose_stats: bool,
+
+    #[structopt(
+        long = "stats-token",
+        help = "The token required in messages for getting the statistics (an empty token disables the check)",
+        env = "PATHFINDER_STATS_TOKEN",
+        default_value = ""
+    )]
+    pub stats_token: Secret,
+
+    #[structopt(
+        long = "tracing-endpoint",
+        help = "The OTLP/HTTP endpoint of the OpenTelemetry collector (e.g. http://127.0.0.1:4318/v1/traces). An empty value disables tracing",
+        env = "PATHFINDER_TRACING_ENDPOINT",
+        default_value = ""
+    )]
+    pub tracing_endpoint: String,
+
+    #[structopt(
+        long = "tracing-sample-ratio",
+        help = "The share of new traces that are exported, from 0.0 to 1.0",
+        env = "PATHFINDER_TRACING_SAMPLE_RATIO",
+        default_value = "1.0"
+    )]
+    pub tracing_sample_ratio: f64,
+
+    #[structopt(
+        long = "broadcast-exchange",
+        help = "The topic exchange for messages broadcasted to subscribed clients (an empty name disables broadcasting from RabbitMQ)",
+        env = "PATHFINDER_BROADCAST_EXCHANGE",
+        default_value = ""
+    )]
+    pub broadcast_exchange: String,
+}
+
+impl CliOptions {
+    /// Returns options taken from the command line arguments and the
+    /// environment variables.
+    pub fn from_args_and_env() -> CliOptions {
+        CliOptions::from_args()
+            .with_env_flags(|name| env::var(name).ok())
+            .with_systemd_socket(|name| env::var(name).ok(), process::id())
+    }
+
+    /// Returns the settings of the reverse proxy based on the options.
+    pub fn get_proxy_config(&self) -> ProxyConfig {
+        let metrics_address = match self.metrics_port {
+            0 => None,
+            port => Some(format!("{}:{}", self.ip, port).parse().unwrap())
+        };
+
+        ProxyConfig {
+            config: self.config.clone(),
+            config_poll_interval: Duration::from_secs(self.config_poll_interval),
+            message_pack: self.message_pack,
+            message_format: self.message_format.clone(),
+            max_message_size: self.max_message_size,
+            max_pending_messages: self.max_pending_messages,
+            close_on_fatal_errors: self.close_on_fatal_errors,
+            ignore_bind_errors: self.ignore_bind_errors,
+            listen_fd: self.listen_fd,
+            rabbitmq_secured: self.rabbitmq_secured,
+            rabbitmq_host: self.rabbitmq_host.clone(),
+            rabbitmq_port: self.rabbitmq_port,
+            rabbitmq_virtual_host: self.rabbitmq_virtual_host.clone(),
+            rabbitmq_username: self.rabbitmq_username.clone(),
+            rabbitmq_password: self.rabbitmq_password.clone(),
+            rabbitmq_password_file: self.rabbitmq_password_file.clone(),
+            rabbitmq_direct_reply_to: self.rabbitmq_direct_reply_to,
+            rabbitmq_no_confirms: self.rabbitmq_no_confirms,
+            rabbitmq_prefetch: self.rabbitmq_prefetch,
+            rabbitmq_declare_rate: self.rabbitmq_declare_rate,
+            rabbitmq_channel_pool_size: self.rabbitmq_channel_pool_size,
+            rabbitmq_ca_certificate: self.rabbitmq_ca_certificate.clone(),
+            rabbitmq_client_certificate: self.rabbitmq_client_certificate.clone(),
+            rabbitmq_client_key: self.rabbitmq_client_key.clone(),
+            rabbitmq_confirm_timeout: Duration::from_millis(self.rabbitmq_confirm_timeout),
+            amqp_prefix: self.amqp_prefix.clone(),
+            event_name_field: self.event_name_field.clone(),
+            health_check_routing_key: self.health_check_routing_key.clone(),
+            health_check_timeout: Duration::from_millis(self.health_check_timeout_ms),
+            rabbitmq_heartbeat: Duration::from_secs(self.rabbitmq_heartbeat),
+            token_cache_ttl: Duration::from_secs(self.token_cache_ttl),
+            token_cache_size: self.token_cache_size,
+            replay_window: Duration::from_secs(self.replay_window),
+            replay_cache_size: self.replay_cache_size,
+            connection_auth: self.connection_auth,
+            reauth_interval: Duration::from_secs(self.reauth_interval),
+            rpc_timeout: Duration::from_secs(self.rpc_timeout),
+            slow_request_threshold: Duration::from_millis(self.slow_request_ms),
+            shutdown_grace_period: Duration::from_secs(self.shutdown_grace_period),
+            shutdown_message: self.shutdown_message.clone(),
+            http_fallback_path: self.http_fallback_path.clone(),
+            ping_interval: Duration::from_secs(self.ping_interval),
+            max_missed_pongs: self.max_missed_pongs,
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            allowed_origins: self.allowed_origins.clone(),
+            subprotocols: self.subprotocols.clone(),
+            rate_limit: self.rate_limit,
+            rate_limit_burst: self.rate_limit_burst,
+            max_in_flight_requests: self.max_in_flight_requests,
+            max_connections_per_ip: self.max_connections_per_ip,
+            max_connections: self.max_connections,
+            metrics_address,
+            metrics_path: self.metrics_path.clone(),
+            expose_routes: self.expose_routes,
+            routes_token: self.routes_token.clone(),
+            expose_stats: self.expose_stats,
+            stats_token: self.stats_token.clone(),
+            tracing_endpoint: self.tracing_endpoint.clone(),
+            tracing_sample_ratio: self.tracing_sample_ratio,
+            broadcast_exchange: self.broadcast_exchange.clone()
+        }
+    }
+
+    /// Enables flags by the environment variables. Unlike other options,
+    /// flags can't be read by clap from the environment, because they don't
+    /// take any values.
+    fn with_env_flags<F>(mut self, get_variable: F) -> CliOptions
+        where F: Fn(&str) -> Option<String>
+    {
+        let flags = vec![
+            ("PATHFINDER_RABBITMQ_SECURED", &mut self.rabbitmq_secured),
+            ("PATHFINDER_CHECK_CONFIG", &mut self.check_config),
+            ("PATHFINDER_MESSAGE_PACK", &mut self.message_pack),
+            ("PATHFINDER_CLOSE_ON_FATAL_ERRORS", &mut self.close_on_fatal_errors),
+            ("PATHFINDER_RABBITMQ_DIRECT_REPLY_TO", &mut self.rabbitmq_direct_reply_to),
+            ("PATHFINDER_RABBITMQ_NO_CONFIRMS", &mut self.rabbitmq_no_confirms),
+            ("PATHFINDER_EXPOSE_ROUTES", &mut self.expose_routes),
+            ("PATHFINDER_EXPOSE_STATS", &mut self.expose_stats),
+            ("PATHFINDER_IGNORE_BIND_ERRORS", &mut self.ignore_bind_errors),
+            ("PATHFINDER_CONNECTION_AUTH", &mut self.connection_auth),
+        ];
+        for (name, flag) in flags {
+            let is_enabled = get_variable(name).is_some_and(|value| value == "true" || value == "1");
+            *flag = *flag || is_enabled;
+        }
+        self
+    }
+
+    /// Uses the first socket passed by systemd (or another supervisor that
+    /// follows the socket activation protocol), unless the descriptor was
+    /// specified explicitly. The sockets are passed starting from the
+    /// descriptor 3 and only to the process with the `LISTEN_PID` identifier.
+    fn with_systemd_socket<F>(mut self, get_variable: F, pid: u32) -> CliOptions
+        where F: Fn(&str) -> Option<String>
+    {
+        let is_activated = get_variable("LISTEN_PID").and_then(|value| value.parse::<u32>().ok()) == Some(pid) &&
+            get_variable("LISTEN_FDS").and_then(|value| value.parse::<u32>().ok()).unwrap_or(0) > 0;
+        if self.listen_fd.is_none() && is_activated {
+            self.listen_fd = Some(SYSTEMD_LISTEN_FDS_START);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use structopt::StructOpt;
+
+    use crate::cli::CliOptions;
+    use crate::config::ProxyConfig;
+
+    fn get_variable(name: &str) -> Option<String> {
+        match name {
+            "PATHFINDER_MESSAGE_PACK" => Some(String::from("true")),
+            "PATHFINDER_RABBITMQ_DIRECT_REPLY_TO" => Some(String::from("1")),
+            "PATHFINDER_CLOSE_ON_FATAL_ERRORS" => Some(String::from("false")),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn test_with_env_flags_enables_flags_by_variables() {
+        let cli = CliOptions::from_iter(vec!["pathfinder"]).with_env_flags(get_variable);
+        assert!(cli.message_pack);
+        assert!(cli.rabbitmq_direct_reply_to);
+        assert!(!cli.close_on_fatal_errors);
+        assert!(!cli.rabbitmq_secured);
+    }
+
+    #[test]
+    fn test_with_env_flags_keeps_flags_passed_in_arguments() {
+        let cli = CliOptions::from_iter(vec!["pathfinder", "--close-on-fatal-errors"]).with_env_flags(get_variable);
+        assert!(cli.close_on_fatal_errors);
+    }
+
+    #[test]
+    fn test_get_proxy_config_uses_the_same_defaults() {
+        let cli = CliOptions::from_iter(vec!["pathfinder"]);
+        assert_eq!(cli.get_proxy_config(), ProxyConfig::default());
+    }
+
+    #[test]
+    fn test_get_proxy_config_converts_options() {
+        let cli = CliOptions::from_iter(vec![
+            "pathfinder", "--ip", "0.0.0.0", "--metrics-port", "9100", "--rpc-timeout", "5", "--rabbitmq-confirm-timeout", "250",
+            "--rabbitmq-heartbeat", "30"
+        ]);
+        let config = cli.get_proxy_config();
+        assert_eq!(config.metrics_address, Some("0.0.0.0:9100".parse().unwrap()));
+        assert_eq!(config.rpc_timeout, Duration::from_secs(5));
+        assert_eq!(config.slow_request_threshold, Duration::from_millis(0));
+        assert_eq!(config.rabbitmq_confirm_timeout, Duration::from_millis(250));
+        assert_eq!(config.rabbitmq_heartbeat, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_with_systemd_socket_uses_the_passed_socket() {
+        let get_variable = |name: &str| match name {
+            "LISTEN_PID" => Some(String::from("42")),
+            "LISTEN_FDS" => Some(String::from("1")),
+            _ => None
+        };
+
+        let cli = CliOptions::from_iter(vec!["pathfinder"]).with_systemd_socket(get_variable, 42);
+        assert_eq!(cli.listen_fd, Some(3));
+
+        let cli = CliOptions::from_iter(vec!["pathfinder"]).with_systemd_socket(get_variable, 43);
+        assert_eq!(cli.listen_fd, None);
+
+        let cli = CliOptions::from_iter(vec!["pathfinder", "--listen-fd", "5"]).with_systemd_socket(get_variable, 42);
+        assert_eq!(cli.listen_fd, Some(5));
+        assert_eq!(cli.get_proxy_config().listen_fd, Some(5));
+    }
+
+    #[test]
+    fn test_listen_accepts_ipv4_and_ipv6_addresses() {
+        let cli = CliOptions::from_iter(vec!["pathfinder", "--listen", "0.0.0.0:9001,[::]:9000"]);
+        assert_eq!(cli.listen, vec!["0.0.0.0:9001".parse().unwrap(), "[::]:9000".parse().unwrap()]);
+        assert!(CliOptions::from_iter(vec!["pathfinder"]).listen.is_empty());
+    }
 }
@@ -1,33 +1,462 @@
 //! Wrappers for handling an application configuration
 //!
 
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::read_dir;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
 use log::error;
-use config::{Config, File};
+use config::{Config, ConfigError, File, FileFormat, Value};
+
+use crate::engine::options::ReplyQueueOptions;
+use crate::engine::router::parse_endpoints;
+use crate::error::{PathfinderError, Result};
+use crate::remote::{fetch_config, is_remote_config};
+
+/// Supported extensions of configuration files and the formats they imply.
+/// HJSON is a relaxed JSON that allows comments and trailing commas.
+const CONFIG_FORMATS: [(&str, FileFormat); 5] = [
+    ("yaml", FileFormat::Yaml),
+    ("yml", FileFormat::Yaml),
+    ("json", FileFormat::Json),
+    ("hjson", FileFormat::Hjson),
+    ("toml", FileFormat::Toml),
+];
+
+/// Settings of the reverse proxy. The command line options are converted
+/// into this structure, but it can be built directly as well when the proxy
+/// is embedded into another application (e.g. for integration tests).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyConfig {
+    /// Path to the configuration file or a directory with them, or the
+    /// HTTP(S) URL of the remote configuration. An empty path means that
+    /// no endpoints are configured.
+    pub config: String,
+    /// Interval of reloading the configuration (zero disables polling). A
+    /// failed reload keeps the last valid configuration.
+    pub config_poll_interval: Duration,
+    /// Accept binary messages in the MessagePack format.
+    pub message_pack: bool,
+    /// The format of binary messages: `json`, `msgpack` or `cbor`.
+    pub message_format: String,
+    /// The maximum size of an incoming message in bytes.
+    pub max_message_size: usize,
+    /// The maximum number of responses waiting to be written to a slow
+    /// client before the connection is dropped.
+    pub max_pending_messages: usize,
+    /// Close the connection after decoding and authentication errors.
+    pub close_on_fatal_errors: bool,
+    /// Keep running when some of the listened addresses can't be used.
+    pub ignore_bind_errors: bool,
+    /// An already open listening socket, used instead of binding the first
+    /// address (e.g. passed by systemd). Supported on Unix only.
+    pub listen_fd: Option<i32>,
+    /// Use the `amqps` scheme for connections to RabbitMQ.
+    pub rabbitmq_secured: bool,
+    /// The host of the RabbitMQ broker.
+    pub rabbitmq_host: String,
+    /// The port of the RabbitMQ broker.
+    pub rabbitmq_port: u16,
+    /// The virtual host of the RabbitMQ node.
+    pub rabbitmq_virtual_host: String,
+    /// The RabbitMQ application username.
+    pub rabbitmq_username: String,
+    /// The RabbitMQ application password.
+    pub rabbitmq_password: Secret,
+    /// Path to a file with the RabbitMQ password, that takes precedence
+    /// over the `rabbitmq_password` field when it isn't empty.
+    pub rabbitmq_password_file: String,
+    /// Receive responses via the direct reply-to instead of temporary queues.
+    pub rabbitmq_direct_reply_to: bool,
+    /// Publish requests without waiting for the confirmations of RabbitMQ.
+    pub rabbitmq_no_confirms: bool,
+    /// The maximum number of unacknowledged responses per consumer.
+    pub rabbitmq_prefetch: u16,
+    /// The maximum number of reply queues declared per second.
+    pub rabbitmq_declare_rate: u32,
+    /// The maximum number of idle channel pairs kept for reuse.
+    pub rabbitmq_channel_pool_size: usize,
+    /// Path to a CA bundle for verifying the RabbitMQ server certificate.
+    pub rabbitmq_ca_certificate: String,
+    /// Path to a client certificate for mutual TLS with RabbitMQ.
+    pub rabbitmq_client_certificate: String,
+    /// Path to a private key of the client certificate.
+    pub rabbitmq_client_key: String,
+    /// Time to wait for RabbitMQ to confirm a published request.
+    pub rabbitmq_confirm_timeout: Duration,
+    /// The prefix of exchanges and routing keys (empty keeps the names as is).
+    pub amqp_prefix: String,
+    /// The field of the message with the event name.
+    pub event_name_field: String,
+    /// The routing key of the microservice that answers to pings sent on
+    /// health checks (empty checks only the connection to RabbitMQ).
+    pub health_check_routing_key: String,
+    /// Time to wait for a response to the ping sent on health checks.
+    pub health_check_timeout: Duration,
+    /// Interval of AMQP heartbeats (zero uses the interval suggested by RabbitMQ).
+    pub rabbitmq_heartbeat: Duration,
+    /// Time to keep the verified tokens in the cache (zero disables the cache).
+    pub token_cache_ttl: Duration,
+    /// The maximum number of the verified tokens stored in the cache.
+    pub token_cache_size: usize,
+    /// The maximum difference between the timestamp of the request and the
+    /// current time for the `replay_protection` middleware.
+    pub replay_window: Duration,
+    /// The maximum number of the recent nonces stored by the `replay_protection` middleware.
+    pub replay_cache_size: usize,
+    /// Authenticate each connection once by its first message.
+    pub connection_auth: bool,
+    /// Time after which an authenticated connection must send the token
+    /// again (zero keeps it until the connection is closed).
+    pub reauth_interval: Duration,
+    /// Time to wait for a response from a microservice.
+    pub rpc_timeout: Duration,
+    /// Requests that take longer than this time are logged (zero disables the logging).
+    pub slow_request_threshold: Duration,
+    /// Time to wait for active connections on shutdown.
+    pub shutdown_grace_period: Duration,
+    /// The JSON object sent to connected clients on shutdown (empty disables the notice).
+    pub shutdown_message: String,
+    /// The path for POST requests of clients that can't use WebSockets (empty
+    /// disables the HTTP fallback).
+    pub http_fallback_path: String,
+    /// Time between WebSocket pings sent to clients (zero disables pings).
+    pub ping_interval: Duration,
+    /// The number of unanswered pings after which the connection is closed.
+    pub max_missed_pongs: usize,
+    /// Time without any frames from the client after which the connection
+    /// is closed (zero disables the idle timeout).
+    pub idle_timeout: Duration,
+    /// Origins allowed for WebSocket handshakes (empty allows all origins).
+    pub allowed_origins: Vec<String>,
+    /// Supported WebSocket subprotocols (empty disables the negotiation).
+    pub subprotocols: Vec<String>,
+    /// The maximum number of requests per second from one connection.
+    pub rate_limit: u32,
+    /// The number of requests that one connection can send at once.
+    pub rate_limit_burst: u32,
+    /// The maximum number of concurrent requests from one connection.
+    pub max_in_flight_requests: usize,
+    /// The maximum number of connections from one IP address.
+    pub max_connections_per_ip: usize,
+    /// The maximum number of connections in total.
+    pub max_connections: usize,
+    /// The address for exposing metrics (`None` disables metrics).
+    pub metrics_address: Option<SocketAddr>,
+    /// The path to metrics in the Prometheus format.
+    pub metrics_path: String,
+    /// Expose the list of the loaded routes on the metrics address.
+    pub expose_routes: bool,
+    /// The bearer token required for getting the list of routes (empty disables the check).
+    pub routes_token: Secret,
+    /// Answer the messages to the `/__stats` URL with the statistics of the proxy.
+    pub expose_stats: bool,
+    /// The token required in messages for getting the statistics (empty disables the check).
+    pub stats_token: Secret,
+    /// The OTLP/HTTP endpoint of the OpenTelemetry collector (empty disables tracing).
+    pub tracing_endpoint: String,
+    /// The share of new traces that are exported.
+    pub tracing_sample_ratio: f64,
+    /// The topic exchange for broadcasted messages (empty disables broadcasting).
+    pub broadcast_exchange: String
+}
+
+impl Default for ProxyConfig {
+    fn default() -> ProxyConfig {
+        ProxyConfig {
+            config: String::new(),
+            config_poll_interval: Duration::from_secs(0),
+            message_pack: false,
+            message_format: String::from("json"),
+            max_message_size: 1_048_576,
+            max_pending_messages: 1024,
+            close_on_fatal_errors: false,
+            ignore_bind_errors: false,
+            listen_fd: None,
+            rabbitmq_secured: false,
+            rabbitmq_host: String::from("127.0.0.1"),
+            rabbitmq_port: 5672,
+            rabbitmq_virtual_host: String::from("vhost"),
+            rabbitmq_username: String::from("user"),
+            rabbitmq_password: Secret::from("password"),
+            rabbitmq_password_file: String::new(),
+            rabbitmq_direct_reply_to: false,
+            rabbitmq_no_confirms: false,
+            rabbitmq_prefetch: 0,
+            rabbitmq_declare_rate: 0,
+            rabbitmq_channel_pool_size: 0,
+            rabbitmq_ca_certificate: String::new(),
+            rabbitmq_client_certificate: String::new(),
+            rabbitmq_client_key: String::new(),
+            rabbitmq_confirm_timeout: Duration::from_millis(5000),
+            amqp_prefix: String::new(),
+            event_name_field: String::from("event-name"),
+            health_check_routing_key: String::new(),
+            health_check_timeout: Duration::from_millis(1000),
+            rabbitmq_heartbeat: Duration::from_secs(10),
+            token_cache_ttl: Duration::from_secs(0),
+            token_cache_size: 1000,
+            replay_window: Duration::from_secs(300),
+            replay_cache_size: 100000,
+            connection_auth: false,
+            reauth_interval: Duration::from_secs(0),
+            rpc_timeout: Duration::from_secs(30),
+            slow_request_threshold: Duration::from_millis(0),
+            shutdown_grace_period: Duration::from_secs(10),
+            shutdown_message: String::from(r#"{"type": "server_shutdown", "reconnect_after_ms": 1000}"#),
+            http_fallback_path: String::new(),
+            ping_interval: Duration::from_secs(30),
+            max_missed_pongs: 3,
+            idle_timeout: Duration::from_secs(0),
+            allowed_origins: Vec::new(),
+            subprotocols: Vec::new(),
+            rate_limit: 0,
+            rate_limit_burst: 0,
+            max_in_flight_requests: 0,
+            max_connections_per_ip: 0,
+            max_connections: 0,
+            metrics_address: None,
+            metrics_path: String::from("/metrics"),
+            expose_routes: false,
+            routes_token: Secret::default(),
+            expose_stats: false,
+            stats_token: Secret::default(),
+            tracing_endpoint: String::new(),
+            tracing_sample_ratio: 1.0,
+            broadcast_exchange: String::new()
+        }
+    }
+}
+
+/// The summary of the configuration file, that was checked without
+/// starting the reverse proxy.
+pub struct ConfigReport {
+    /// The number of valid endpoints.
+    pub endpoints_count: usize,
+    /// The errors for endpoints that were skipped.
+    pub errors: Vec<PathfinderError>
+}
+
+impl ConfigReport {
+    /// Determines whether the reverse proxy can be started with this configuration.
+    pub fn is_valid(&self) -> bool {
+        self.endpoints_count > 0
+    }
+}
+
+/// A setting that must never appear in logs or error messages, such as
+/// a password or a token. Both `Debug` and `Display` print a mask instead
+/// of the value, which is available only via `get_value`.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The text printed instead of the value.
+    pub const MASK: &'static str = "******";
+
+    /// Returns the wrapped value.
+    pub fn get_value(&self) -> &str {
+        &self.0
+    }
+
+    /// Checks that the value isn't specified.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(String::from(value))
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Secret::from(value))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", Secret::MASK)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Secret::MASK)
+    }
+}
 
 /// Returns a configuration for the application with data that was
-/// read from a file. When specified an empty string, returns a
-/// default configuration.
+/// read from a file or a directory. When specified an empty string,
+/// returns a default configuration.
 pub fn get_config(file_path: &str) -> Box<Config> {
-    let mut conf = Box::new(Config::default());
-
     if file_path != "" {
-        conf.merge(File::with_name(file_path))
-            .map_err(|err| {
-                error!(
-                    "Error during reading file: {}. \
-                     Changes won't applied.",
-                    err
-                )
-            }).and_then(|new_config| Ok(new_config))
-            .is_ok();
+        match read_config(file_path) {
+            Ok(conf) => return Box::new(conf),
+            Err(err) => error!(
+                "Error during reading file: {}. \
+                 Changes won't applied.",
+                err
+            )
+        }
+    }
+
+    Box::new(Config::default())
+}
+
+/// Returns a configuration for the application with data that was
+/// read from a file or a directory. Unlike `get_config`, fails when the
+/// files can't be read or parsed.
+pub fn load_config(file_path: &str) -> Result<Box<Config>> {
+    if file_path.is_empty() {
+        let error = ConfigError::Message(String::from("the configuration file isn't specified"));
+        return Err(error.into());
+    }
+
+    Ok(Box::new(read_config(file_path)?))
+}
+
+/// Reads the configuration file. For a directory, all configuration files
+/// are merged in the sorted order: later files override scalar values of the
+/// earlier ones, whereas the `endpoints` arrays are concatenated. An HTTP(S)
+/// URL is fetched from the remote source instead.
+fn read_config(file_path: &str) -> Result<Config> {
+    if is_remote_config(file_path) {
+        return fetch_config(file_path);
+    }
+
+    let path = Path::new(file_path);
+    if !path.is_dir() {
+        return match path.extension() {
+            Some(_) => read_file(path),
+            // The extension is guessed by the existing file with the same name
+            None => {
+                let mut conf = Config::default();
+                conf.merge(File::with_name(file_path))?;
+                Ok(conf)
+            }
+        };
+    }
+
+    let mut conf = Config::default();
+    let mut endpoints: Vec<Value> = Vec::new();
+    for path in get_config_files(file_path)? {
+        let file_conf = read_file(&path)?;
+        if let Ok(file_endpoints) = file_conf.get_array("endpoints") {
+            endpoints.extend(file_endpoints);
+        }
+        conf.merge(file_conf)?;
+    }
+
+    if !endpoints.is_empty() {
+        conf.set("endpoints", endpoints)?;
     }
+    Ok(conf)
+}
+
+/// Returns the format of the configuration file by its extension.
+pub(crate) fn get_file_format(path: &Path) -> Option<FileFormat> {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    CONFIG_FORMATS
+        .iter()
+        .find(|(format_extension, _)| format_extension.eq_ignore_ascii_case(extension))
+        .map(|(_, format)| *format)
+}
+
+/// Reads a single configuration file in the format defined by its extension.
+fn read_file(path: &Path) -> Result<Config> {
+    let format = match get_file_format(path) {
+        Some(format) => format,
+        None => {
+            let extensions: Vec<&str> = CONFIG_FORMATS.iter().map(|(extension, _)| *extension).collect();
+            let message = format!(
+                "the format of \"{}\" isn't supported, the extension must be one of: {}",
+                path.display(), extensions.join(", ")
+            );
+            return Err(ConfigError::Message(message).into());
+        }
+    };
+
+    let mut conf = Config::default();
+    conf.merge(File::from(path).format(format)).map_err(|err| {
+        let message = format!("the file \"{}\" can't be loaded as {:?}: {}", path.display(), format, err);
+        ConfigError::Message(message)
+    })?;
+    Ok(conf)
+}
+
+/// Returns the sorted paths to the configuration files in the directory.
+/// Files of unsupported formats are skipped.
+fn get_config_files(directory: &str) -> Result<Vec<PathBuf>> {
+    let entries = read_dir(directory).map_err(|err| {
+        let message = format!("the directory \"{}\" can't be read: {}", directory, err);
+        ConfigError::Message(message)
+    })?;
 
-    conf
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && get_file_format(path).is_some())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Loads the configuration file and parses endpoints in the same way as
+/// it's done on start. Fails only when the file can't be read or parsed,
+/// or contains settings that prevent the proxy from starting.
+pub fn check_config(file_path: &str) -> Result<ConfigReport> {
+    let conf = load_config(file_path)?;
+    ReplyQueueOptions::from_config(&conf).validate()?;
+    let (endpoints, errors) = parse_endpoints(&conf);
+    Ok(ConfigReport {
+        endpoints_count: endpoints.len(),
+        errors
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::get_config;
+    use std::collections::HashMap;
+
+    use super::{check_config, get_config, load_config, ProxyConfig, Secret};
+    use crate::engine::router::{extract_endpoints, ReadOnlyEndpoint, Router};
+
+    #[test]
+    fn test_secret_masks_the_value() {
+        let secret = Secret::from("qwerty");
+        assert_eq!(secret.get_value(), "qwerty");
+        assert_eq!(format!("{:?}", secret), "Secret(******)");
+        assert_eq!(format!("{}", secret), "******");
+    }
+
+    #[test]
+    fn test_proxy_config_debug_output_hides_secrets() {
+        let config = ProxyConfig {
+            rabbitmq_password: Secret::from("rabbitmq-password"),
+            routes_token: Secret::from("routes-token"),
+            stats_token: Secret::from("stats-token"),
+            ..ProxyConfig::default()
+        };
+        let output = format!("{:?}", config);
+        assert!(!output.contains("rabbitmq-password"));
+        assert!(!output.contains("routes-token"));
+        assert!(!output.contains("stats-token"));
+    }
 
     #[test]
     fn test_get_config_returns_a_new_config_by_default() {
@@ -49,4 +478,99 @@ mod tests {
         assert_eq!(foo_array.len(), 1);
         assert_eq!(foo_array[0].clone().into_str().unwrap(), "bar");
     }
+
+    #[test]
+    fn test_load_config_returns_values_from_file() {
+        let conf = load_config("./tests/files/valid_file.yaml").unwrap();
+        let table = conf.cache.into_table().unwrap();
+        assert!(table.contains_key("foo"));
+    }
+
+    #[test]
+    fn test_load_config_returns_an_error_for_missing_file() {
+        assert!(load_config("./tests/files/unknown.yaml").is_err());
+        assert!(load_config("").is_err());
+    }
+
+    fn get_endpoints(file_path: &str) -> HashMap<String, String> {
+        extract_endpoints(load_config(file_path).unwrap())
+            .into_iter()
+            .map(|(url, endpoint)| (url, describe_endpoint(&endpoint)))
+            .collect()
+    }
+
+    fn describe_endpoint(endpoint: &ReadOnlyEndpoint) -> String {
+        format!(
+            "{} -> {} (timeout: {:?}, middlewares: {:?})",
+            endpoint.get_url(), endpoint.get_routing_key(), endpoint.get_timeout(), endpoint.get_middlewares()
+        )
+    }
+
+    #[test]
+    fn test_load_config_extracts_the_same_endpoints_from_all_formats() {
+        let expected = get_endpoints("./tests/files/config_formats/endpoints.yaml");
+        assert_eq!(expected.len(), 2);
+
+        for extension in &["toml", "json", "hjson"] {
+            let endpoints = get_endpoints(&format!("./tests/files/config_formats/endpoints.{}", extension));
+            assert_eq!(endpoints, expected, "the endpoints from the {} file differ", extension);
+        }
+    }
+
+    #[test]
+    fn test_load_config_returns_a_clear_error_for_invalid_syntax() {
+        let error = load_config("./tests/files/config_with_syntax_error.json").unwrap_err();
+        let message = format!("{}", error);
+        assert!(message.contains("config_with_syntax_error.json"));
+        assert!(message.contains("can't be loaded as Json"));
+    }
+
+    #[test]
+    fn test_load_config_returns_an_error_for_unsupported_extension() {
+        let error = load_config("./tests/files/config_directory/README.txt").unwrap_err();
+        let message = format!("{}", error);
+        assert!(message.contains("isn't supported"));
+        assert!(message.contains("yaml, yml, json, hjson, toml"));
+    }
+
+    #[test]
+    fn test_load_config_merges_files_from_directory() {
+        let conf = load_config("./tests/files/config_directory").unwrap();
+        assert_eq!(conf.get_array("endpoints").unwrap().len(), 3);
+        assert_eq!(conf.get_int("auth.retry_attempts").unwrap(), 5);
+        assert_eq!(conf.get_int("auth.retry_delay_ms").unwrap(), 250);
+    }
+
+    #[test]
+    fn test_get_config_with_directory_builds_combined_routing_table() {
+        let conf = get_config("./tests/files/config_directory");
+        let router = Router::new(extract_endpoints(conf));
+        assert!(router.match_url("/api/matchmaking/search").is_ok());
+        assert!(router.match_url("/api/matchmaking/leaderboard").is_ok());
+        assert!(router.match_url("/api/profiles/me").is_ok());
+    }
+
+    #[test]
+    fn test_check_config_with_directory() {
+        let report = check_config("./tests/files/config_directory").unwrap();
+        assert_eq!(report.endpoints_count, 3);
+        assert!(report.errors.is_empty());
+    }
+
+    #[test]
+    fn test_check_config_reports_skipped_endpoints() {
+        let report = check_config("./tests/files/config_with_invalid_endpoints.yaml").unwrap();
+        assert_eq!(report.endpoints_count, 1);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.is_valid());
+    }
+
+    #[test]
+    fn test_check_config_without_endpoints_is_invalid() {
+        let report = check_config("./tests/files/config_without_endpoints.yaml").unwrap();
+        assert_eq!(report.endpoints_count, 0);
+        assert!(!report.is_valid());
+
+        assert!(check_config("./tests/files/unknown.yaml").is_err());
+    }
 }
@@ -0,0 +1,281 @@
+//! Broadcasting messages to multiple connected clients
+//!
+//! A client subscribes onto a topic (e.g. a room ID) by sending a request
+//! to the special URL. After that each message published for the topic is
+//! delivered to every subscribed connection in the format of the client.
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use json::object;
+use tungstenite::Message;
+
+use crate::error::{Result, PathfinderError};
+use crate::engine::MessageSender;
+use crate::engine::serializer::{ContentType, JsonMessage};
+use crate::engine::utils::convert_message;
+
+/// The URL for subscribing onto a topic.
+pub const SUBSCRIBE_URL: &str = "/broadcast/subscribe";
+/// The URL for cancelling a subscription onto a topic.
+pub const UNSUBSCRIBE_URL: &str = "/broadcast/unsubscribe";
+
+/// A request of a client for changing its subscriptions.
+#[derive(Debug, PartialEq)]
+pub enum Subscription {
+    Subscribe(String),
+    Unsubscribe(String)
+}
+
+impl Subscription {
+    /// Returns the subscription request when the message was sent to one of
+    /// the special URLs. The topic is taken from the `content.topic` field.
+    pub fn from_message(json: &JsonMessage) -> Option<Result<Subscription>> {
+        let constructor: fn(String) -> Subscription = match json["url"].as_str() {
+            Some(SUBSCRIBE_URL) => Subscription::Subscribe,
+            Some(UNSUBSCRIBE_URL) => Subscription::Unsubscribe,
+            _ => return None
+        };
+
+        match json["content"]["topic"].as_str() {
+            Some(topic) if !topic.is_empty() => Some(Ok(constructor(topic.to_string()))),
+            _ => {
+                let error_message = String::from("The `content.topic` field must be a non-empty string");
+                Some(Err(PathfinderError::DecodingError(error_message)))
+            }
+        }
+    }
+
+    /// Returns the topic of the subscription.
+    pub fn get_topic(&self) -> &str {
+        match self {
+            Subscription::Subscribe(topic) => topic,
+            Subscription::Unsubscribe(topic) => topic
+        }
+    }
+}
+
+/// A connection subscribed onto a topic.
+struct Subscriber {
+    transmitter: MessageSender,
+    content_type: ContentType
+}
+
+/// Storage for connections subscribed onto topics.
+#[derive(Default)]
+pub struct Broadcaster {
+    topics: Mutex<HashMap<String, Vec<Subscriber>>>
+}
+
+impl Broadcaster {
+    /// Returns a new instance of `Broadcaster`.
+    pub fn new() -> Broadcaster {
+        Broadcaster {
+            topics: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Subscribes the connection onto the topic. The messages for the topic
+    /// will be sent in the specified format.
+    pub fn subscribe(&self, topic: &str, transmitter: MessageSender, content_type: ContentType) {
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = topics.entry(topic.to_string()).or_default();
+        if !subscribers.iter().any(|subscriber| Arc::ptr_eq(&subscriber.transmitter, &transmitter)) {
+            subscribers.push(Subscriber { transmitter, content_type });
+        }
+    }
+
+    /// Cancels the subscription of the connection onto the topic.
+    pub fn unsubscribe(&self, topic: &str, transmitter: &MessageSender) {
+        let mut topics = self.topics.lock().unwrap();
+        let is_empty = match topics.get_mut(topic) {
+            Some(subscribers) => {
+                subscribers.retain(|subscriber| !Arc::ptr_eq(&subscriber.transmitter, transmitter));
+                subscribers.is_empty()
+            },
+            None => false
+        };
+        if is_empty {
+            topics.remove(topic);
+        }
+    }
+
+    /// Cancels all subscriptions of the connection, e.g. when it was closed.
+    pub fn remove(&self, transmitter: &MessageSender) {
+        let mut topics = self.topics.lock().unwrap();
+        for subscribers in topics.values_mut() {
+            subscribers.retain(|subscriber| !Arc::ptr_eq(&subscriber.transmitter, transmitter));
+        }
+        topics.retain(|_, subscribers| !subscribers.is_empty());
+    }
+
+    /// Applies the subscription request of the connection and confirms it.
+    pub fn apply(
+        &self,
+        subscription: &Subscription,
+        transmitter: MessageSender,
+        content_type: ContentType,
+        correlation_id: &str
+    ) -> Result<()> {
+        let topic = subscription.get_topic();
+        let is_subscribed = match subscription {
+            Subscription::Subscribe(_) => {
+                self.subscribe(topic, transmitter.clone(), content_type);
+                true
+            },
+            Subscription::Unsubscribe(_) => {
+                self.unsubscribe(topic, &transmitter);
+                false
+            }
+        };
+
+        let response = object!{
+            "topic" => topic,
+            "subscribed" => is_subscribed,
+            "correlation-id" => correlation_id
+        };
+        transmitter
+            .unbounded_send(convert_message(Message::Text(response.dump()), content_type))
+            .map_err(|_| PathfinderError::MessageBrokerError(String::from("The connection was closed.")))
+    }
+
+    /// Sends the message to each connection subscribed onto the topic and
+    /// returns the number of connections that received it.
+    pub fn broadcast(&self, topic: &str, message: Message) -> usize {
+        let topics = self.topics.lock().unwrap();
+        match topics.get(topic) {
+            Some(subscribers) => subscribers
+                .iter()
+                .filter(|subscriber| {
+                    let message = convert_message(message.clone(), subscriber.content_type);
+                    subscriber.transmitter.unbounded_send(message).is_ok()
+                })
+                .count(),
+            None => 0
+        }
+    }
+
+    /// Returns the number of connections subscribed onto the topic.
+    pub fn get_subscribers_count(&self, topic: &str) -> usize {
+        let topics = self.topics.lock().unwrap();
+        topics.get(topic).map(|subscribers| subscribers.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::sync::mpsc;
+    use futures::{Future, Stream};
+    use json::parse as json_parse;
+    use tungstenite::Message;
+
+    use crate::engine::broadcast::{Broadcaster, Subscription};
+    use crate::engine::serializer::ContentType;
+    use crate::engine::MessageSender;
+
+    fn get_connection() -> (MessageSender, mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded();
+        (Arc::new(tx), rx)
+    }
+
+    fn get_subscription(url: &str, content: &str) -> Option<Subscription> {
+        let json = json_parse(&format!(r#"{{"url": "{}", "content": {}}}"#, url, content)).unwrap();
+        Subscription::from_message(&Arc::new(Box::new(json))).map(|result| result.unwrap())
+    }
+
+    fn receive(rx: mpsc::UnboundedReceiver<Message>, count: usize) -> Vec<Message> {
+        rx.take(count as u64).collect().wait().unwrap()
+    }
+
+    #[test]
+    fn test_broadcast_delivers_message_to_every_subscriber() {
+        let broadcaster = Broadcaster::new();
+        let (first_tx, first_rx) = get_connection();
+        let (second_tx, second_rx) = get_connection();
+        let (other_tx, _other_rx) = get_connection();
+        broadcaster.subscribe("room.1", first_tx, ContentType::Json);
+        broadcaster.subscribe("room.1", second_tx, ContentType::Json);
+        broadcaster.subscribe("room.2", other_tx, ContentType::Json);
+
+        let message = Message::Text(String::from(r#"{"event-name": "match-found"}"#));
+        assert_eq!(broadcaster.broadcast("room.1", message.clone()), 2);
+        assert_eq!(receive(first_rx, 1), vec![message.clone()]);
+        assert_eq!(receive(second_rx, 1), vec![message]);
+    }
+
+    #[test]
+    fn test_broadcast_converts_message_for_message_pack_subscribers() {
+        let broadcaster = Broadcaster::new();
+        let (tx, rx) = get_connection();
+        broadcaster.subscribe("room.1", tx, ContentType::MessagePack);
+
+        let message = Message::Text(String::from(r#"{"event-name": "match-found"}"#));
+        assert_eq!(broadcaster.broadcast("room.1", message), 1);
+        assert!(receive(rx, 1)[0].is_binary());
+    }
+
+    #[test]
+    fn test_broadcast_without_subscribers() {
+        let broadcaster = Broadcaster::new();
+        let message = Message::Text(String::from("{}"));
+        assert_eq!(broadcaster.broadcast("room.1", message), 0);
+    }
+
+    #[test]
+    fn test_unsubscribe_and_remove_cancel_subscriptions() {
+        let broadcaster = Broadcaster::new();
+        let (first_tx, _first_rx) = get_connection();
+        let (second_tx, _second_rx) = get_connection();
+        broadcaster.subscribe("room.1", first_tx.clone(), ContentType::Json);
+        broadcaster.subscribe("room.1", first_tx.clone(), ContentType::Json);
+        broadcaster.subscribe("room.1", second_tx.clone(), ContentType::Json);
+        broadcaster.subscribe("room.2", second_tx.clone(), ContentType::Json);
+        assert_eq!(broadcaster.get_subscribers_count("room.1"), 2);
+
+        broadcaster.unsubscribe("room.1", &first_tx);
+        assert_eq!(broadcaster.get_subscribers_count("room.1"), 1);
+
+        broadcaster.remove(&second_tx);
+        assert_eq!(broadcaster.get_subscribers_count("room.1"), 0);
+        assert_eq!(broadcaster.get_subscribers_count("room.2"), 0);
+    }
+
+    #[test]
+    fn test_apply_confirms_the_subscription() {
+        let broadcaster = Broadcaster::new();
+        let (tx, rx) = get_connection();
+        let subscription = Subscription::Subscribe(String::from("room.1"));
+        broadcaster.apply(&subscription, tx, ContentType::Json, "request-id").unwrap();
+        assert_eq!(broadcaster.get_subscribers_count("room.1"), 1);
+
+        let response = match receive(rx, 1).remove(0) {
+            Message::Text(text) => json_parse(&text).unwrap(),
+            _ => panic!("Expected a text message")
+        };
+        assert_eq!(response["topic"], "room.1");
+        assert_eq!(response["subscribed"], true);
+        assert_eq!(response["correlation-id"], "request-id");
+    }
+
+    #[test]
+    fn test_from_message_parses_subscriptions() {
+        let subscription = get_subscription("/broadcast/subscribe", r#"{"topic": "room.1"}"#);
+        assert_eq!(subscription, Some(Subscription::Subscribe(String::from("room.1"))));
+
+        let subscription = get_subscription("/broadcast/unsubscribe", r#"{"topic": "room.1"}"#);
+        assert_eq!(subscription, Some(Subscription::Unsubscribe(String::from("room.1"))));
+
+        let subscription = get_subscription("/api/matchmaking/search", r#"{"topic": "room.1"}"#);
+        assert_eq!(subscription, None);
+    }
+
+    #[test]
+    fn test_from_message_requires_topic() {
+        let json = json_parse(r#"{"url": "/broadcast/subscribe", "content": {}}"#).unwrap();
+        let result = Subscription::from_message(&Arc::new(Box::new(json))).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Compression of the bodies of requests and responses of microservices
+//!
+//! The compression is specified in the `content_encoding` property of AMQP
+//! messages. Requests are compressed only for endpoints that opt in, whereas
+//! compressed responses are decompressed for any endpoint before sending
+//! them to the client.
+//!
+
+use std::io::{self, Read, Write};
+
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+/// The supported values of the `content_encoding` property.
+pub const CONTENT_ENCODINGS: [&str; 2] = ["gzip", "deflate"];
+
+/// The compression of the body of an AMQP message. As in HTTP, the `deflate`
+/// encoding means the zlib format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate
+}
+
+impl ContentEncoding {
+    /// Returns the encoding by the value of the `content_encoding` property.
+    pub fn from_name(name: &str) -> Option<ContentEncoding> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" => Some(ContentEncoding::Gzip),
+            "deflate" => Some(ContentEncoding::Deflate),
+            _ => None
+        }
+    }
+
+    /// Returns the value of the `content_encoding` property.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate"
+        }
+    }
+
+    /// Compresses the data.
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        // Writing into the memory can't fail
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            },
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    /// Decompresses the data. Fails when the data is corrupted, was
+    /// compressed in another format or exceeds `max_size` bytes after
+    /// decompression, so that a small message can't exhaust the memory.
+    // The errors are built via io::Error::new, as in the rest of the crate
+    #[allow(clippy::io_other_error)]
+    pub fn decode(&self, data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+        let limit = max_size as u64 + 1;
+        let mut decoded = Vec::new();
+        match self {
+            ContentEncoding::Gzip => GzDecoder::new(data).take(limit).read_to_end(&mut decoded)?,
+            ContentEncoding::Deflate => ZlibDecoder::new(data).take(limit).read_to_end(&mut decoded)?
+        };
+
+        match decoded.len() > max_size {
+            true => {
+                let message = format!("the decompressed data exceeds {} bytes", max_size);
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            },
+            false => Ok(decoded)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::encoding::ContentEncoding;
+
+    #[test]
+    fn test_from_name_returns_supported_encodings() {
+        assert_eq!(ContentEncoding::from_name("gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(ContentEncoding::from_name("Deflate"), Some(ContentEncoding::Deflate));
+        assert_eq!(ContentEncoding::from_name("br"), None);
+    }
+
+    #[test]
+    fn test_decode_returns_the_encoded_data() {
+        let data = br#"{"players": ["first", "second", "third"]}"#.repeat(10);
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let encoded = encoding.encode(&data);
+            assert!(encoded.len() < data.len());
+            assert_eq!(encoding.decode(&encoded, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_decode_fails_for_data_in_another_format() {
+        let encoded = ContentEncoding::Gzip.encode(b"data");
+        assert!(ContentEncoding::Deflate.decode(&encoded, 1024).is_err());
+        assert!(ContentEncoding::Gzip.decode(b"data", 1024).is_err());
+    }
+
+    #[test]
+    fn test_decode_fails_when_the_decoded_data_exceeds_the_max_size() {
+        let data = vec![0; 1024 * 1024];
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let encoded = encoding.encode(&data);
+            assert!(encoded.len() < 64 * 1024);
+            assert!(encoding.decode(&encoded, 64 * 1024).is_err());
+        }
+    }
+}
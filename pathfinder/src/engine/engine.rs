@@ -6,138 +6,1413 @@
 //!
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use futures::future::{lazy, Future};
+use config::{Config, ConfigError};
+use futures::future::{err, lazy, Either, Future};
+use json::{object, JsonValue};
+use log::{debug, error, info, warn};
 use tungstenite::Message;
-use uuid::Uuid;
 
-use crate::cli::CliOptions;
-use crate::config::get_config;
-use crate::error::{Result, PathfinderError};
+use crate::config::{get_config, load_config, ProxyConfig};
+use crate::error::{Result, PathfinderError, RequestError};
+use crate::http::HEALTH_CHECK_PATH;
+use crate::limits::{RequestLimits, RequestSlot};
+use crate::metrics::{Metrics, UNKNOWN_ROUTING_KEY};
 use crate::rabbitmq::RabbitMQContext;
+use crate::remote::is_remote_config;
+use crate::tracing::{SpanContext, SpanKind, Tracer, TRACEPARENT_HEADER};
 use super::middleware::{
-    CustomUserHeaders, EmptyMiddleware, JwtTokenMiddleware, Middleware,
-    MiddlewareFuture
+    get_user_permissions, has_permission, CustomUserHeaders, EmptyMiddleware, JwtOptions,
+    JwtTokenMiddleware, Middleware, MiddlewareFuture, ReplayProtectionMiddleware, TokenCache
 };
-use super::MessageSender;
+use super::{MessageSender, REQUEST_EXCHANGE, RESPONSE_EXCHANGE};
+use super::broadcast::{Broadcaster, Subscription};
 use super::futures::rpc_request_future;
-use super::router::{extract_endpoints, ReadOnlyEndpoint, Router};
-use super::options::RpcOptions;
-use super::serializer::JsonMessage;
-use super::utils::{deserialize_message};
+use super::router::{
+    expand_routing_key, extract_endpoints, parse_endpoints, Endpoint, ReadOnlyEndpoint, RouteMatch, Router, RouterOptions
+};
+use super::schema::SchemaValidator;
+use super::options::{AmqpPrefix, ReplyQueueOptions, RpcOptions};
+use super::serializer::{ContentType, JsonMessage, Serializer};
+use super::session::Session;
+use super::transformer::{EmptyTransformer, TransformerChain, Transformers};
+use super::utils::{convert_message, get_correlation_id, with_correlation_id, with_event_name, ErrorTemplate};
+
+/// The URL of messages that request the statistics of the proxy.
+pub const STATS_URL: &str = "/__stats";
+/// The middlewares that are replaced by the user headers of the session
+/// when connections are authenticated by their first message.
+const SESSION_MIDDLEWARES: [&str; 2] = ["jwt", "jwt_optional"];
+
+/// Type alias for the registered middlewares by their names.
+type Middlewares = HashMap<String, Arc<Box<dyn Middleware>>>;
+
+/// Type alias for the chain of middlewares with their names.
+type MiddlewareChain = Vec<(String, Arc<Box<dyn Middleware>>)>;
+
+/// Endpoints with the related data, that were read from the configuration
+/// file and can be replaced without restarting the proxy.
+struct RoutingTable {
+    router: Router,
+    schemas: HashMap<String, Arc<SchemaValidator>>,
+    transformers: HashMap<String, Arc<TransformerChain>>
+}
+
+impl RoutingTable {
+    /// Returns a new routing table for the endpoints and the list of errors
+    /// for endpoints that reference unknown middlewares, unknown transformers
+    /// or invalid schemas.
+    fn new(
+        endpoints: HashMap<String, ReadOnlyEndpoint>,
+        router_options: RouterOptions,
+        middlewares: &Middlewares,
+        registered_transformers: &Transformers
+    ) -> (RoutingTable, Vec<PathfinderError>) {
+        let mut errors = Vec::new();
+        for endpoint in endpoints.values() {
+            for name in endpoint.get_middlewares().unwrap_or_default() {
+                if !middlewares.contains_key(name) {
+                    let error = format!(
+                        "middleware \"{}\" for the \"{}\" endpoint isn't registered. Available: {:?}",
+                        name, endpoint.get_url(), middlewares.keys().collect::<Vec<_>>()
+                    );
+                    errors.push(PathfinderError::InvalidEndpoint(error));
+                }
+            }
+        }
+
+        let mut schemas = HashMap::new();
+        for endpoint in endpoints.values() {
+            if let Some(file_path) = endpoint.get_schema() {
+                match SchemaValidator::from_file(file_path) {
+                    Ok(validator) => { schemas.insert(endpoint.get_url().to_string(), Arc::new(validator)); },
+                    Err(err) => {
+                        let error = format!("schema for the \"{}\" endpoint wasn't loaded: {}", endpoint.get_url(), err);
+                        errors.push(PathfinderError::InvalidEndpoint(error));
+                    }
+                }
+            }
+        }
+
+        let mut transformers = HashMap::new();
+        for endpoint in endpoints.values() {
+            if endpoint.get_transformers().is_empty() {
+                continue;
+            }
+            match TransformerChain::new(endpoint.get_transformers(), registered_transformers) {
+                Ok(chain) => { transformers.insert(endpoint.get_url().to_string(), Arc::new(chain)); },
+                Err(err) => {
+                    let error = format!("transformers of the \"{}\" endpoint weren't loaded: {}", endpoint.get_url(), err);
+                    errors.push(PathfinderError::InvalidEndpoint(error));
+                }
+            }
+        }
+
+        let router = Router::new(endpoints).with_options(router_options);
+        (RoutingTable { router, schemas, transformers }, errors)
+    }
+
+    /// Returns an endpoint with the captured data based on specified URL.
+    fn match_route(&self, url: &str) -> Result<RouteMatch> {
+        self.router.match_route(url)
+    }
+
+    /// Checks that the message has the content when the endpoint requires
+    /// it, and the content against the JSON schema of the endpoint, if it
+    /// was specified.
+    fn validate_content(&self, json_message: &JsonMessage, endpoint: &ReadOnlyEndpoint) -> Result<()> {
+        if endpoint.is_content_required() && json_message["content"].is_null() {
+            let message = String::from("The `content` field is required for this endpoint.");
+            return Err(PathfinderError::DecodingError(message));
+        }
+
+        match self.schemas.get(endpoint.get_url()) {
+            Some(validator) => validator.validate(&json_message["content"]),
+            None => Ok(())
+        }
+    }
+
+    /// Returns the transformers of the endpoint. The chain is empty when
+    /// the endpoint doesn't have any transformers.
+    fn get_transformers(&self, endpoint: &ReadOnlyEndpoint) -> Arc<TransformerChain> {
+        self.transformers.get(endpoint.get_url()).cloned().unwrap_or_default()
+    }
+}
 
 /// Proxy engine for processing messages, handling errors and communicating
 /// with a message broker.
 pub struct Engine {
-    router: Arc<Router>,
-    middlewares: Arc<HashMap<String, Arc<Box<Middleware>>>>
+    config_path: String,
+    config_error: Option<String>,
+    routing: RwLock<Arc<RoutingTable>>,
+    middlewares: Arc<Middlewares>,
+    transformers: Arc<Transformers>,
+    metrics: Arc<Metrics>,
+    broadcaster: Arc<Broadcaster>,
+    request_limits: Arc<RequestLimits>,
+    reply_queue: ReplyQueueOptions,
+    amqp_prefix: AmqpPrefix,
+    error_template: Arc<ErrorTemplate>,
+    rpc_timeout: Duration,
+    slow_request_threshold: Option<Duration>,
+    confirm_timeout: Duration,
+    health_check_routing_key: Option<String>,
+    health_check_timeout: Duration,
+    message_format: ContentType,
+    max_message_size: usize,
+    expose_stats: bool,
+    stats_token: String,
+    event_name_field: String,
+    connection_auth: bool,
+    reauth_interval: Duration,
+    tracer: Option<Arc<Tracer>>
 }
 
 impl Engine {
     /// Returns a new instance of `Engine`.
-    pub fn new(cli: &CliOptions) -> Engine {
-        let config = get_config(&cli.config);
-        let endpoints = extract_endpoints(config);
-        let router = Router::new(endpoints);
+    pub fn new(proxy_config: &ProxyConfig) -> Engine {
+        Engine::new_with_transformers(proxy_config, Transformers::new())
+    }
+
+    /// Returns a new instance of `Engine` with the custom transformers, that
+    /// can be referenced by their names in the configuration of endpoints.
+    pub fn new_with_transformers(proxy_config: &ProxyConfig, custom_transformers: Transformers) -> Engine {
+        // Without the remote configuration the proxy would start without any
+        // endpoints, so the failed fetch is reported by `validate`
+        let (config, config_error) = match is_remote_config(&proxy_config.config) {
+            true => match load_config(&proxy_config.config) {
+                Ok(config) => (config, None),
+                Err(PathfinderError::SettingsError(error)) => (Box::new(Config::default()), Some(error.to_string())),
+                Err(error) => (Box::new(Config::default()), Some(error.to_string()))
+            },
+            false => (get_config(&proxy_config.config), None)
+        };
+        let amqp_prefix = AmqpPrefix::new(&proxy_config.amqp_prefix);
+        let router_options = RouterOptions::from_config(&config);
+        let jwt_options = JwtOptions::from_config(&config).with_amqp_prefix(&amqp_prefix);
+        let reply_queue = ReplyQueueOptions::from_config(&config);
+        let error_template = ErrorTemplate::from_config(&config);
+        let endpoints = with_amqp_prefix(extract_endpoints(config), &amqp_prefix);
+        let mut jwt_middleware = JwtTokenMiddleware::new(jwt_options);
+        if proxy_config.token_cache_ttl > Duration::from_secs(0) {
+            let cache = TokenCache::new(proxy_config.token_cache_size, proxy_config.token_cache_ttl);
+            jwt_middleware = jwt_middleware.with_cache(cache);
+        }
+
+        let optional_jwt_middleware = jwt_middleware.clone().with_optional_token();
+        let replay_protection_middleware = ReplayProtectionMiddleware::new(proxy_config.replay_window, proxy_config.replay_cache_size);
+
         let middlewares_list: Vec<(&str, Box<Middleware>)> = vec![
-            ("jwt", Box::new(JwtTokenMiddleware::new())),
+            ("jwt", Box::new(jwt_middleware)),
+            ("jwt_optional", Box::new(optional_jwt_middleware)),
+            ("replay_protection", Box::new(replay_protection_middleware)),
             ("empty", Box::new(EmptyMiddleware::new())),
         ];
-        let middlewares = middlewares_list
+        let middlewares: Middlewares = middlewares_list
             .into_iter()
             .map(|(key, middleware)| (String::from(key), Arc::new(middleware)))
             .collect();
 
+        let mut transformers = Transformers::new();
+        transformers.insert(String::from("empty"), Arc::new(Box::new(EmptyTransformer::new())));
+        transformers.extend(custom_transformers);
+
+        let (routing, errors) = RoutingTable::new(endpoints, router_options, &middlewares, &transformers);
+        for error in errors {
+            error!("{}", error);
+        }
+
         Engine {
-            router: Arc::new(router),
+            config_path: proxy_config.config.clone(),
+            config_error,
+            routing: RwLock::new(Arc::new(routing)),
             middlewares: Arc::new(middlewares),
+            transformers: Arc::new(transformers),
+            metrics: Arc::new(Metrics::new()),
+            broadcaster: Arc::new(Broadcaster::new()),
+            request_limits: Arc::new(RequestLimits::new()),
+            reply_queue,
+            amqp_prefix,
+            error_template: Arc::new(error_template),
+            rpc_timeout: proxy_config.rpc_timeout,
+            slow_request_threshold: Some(proxy_config.slow_request_threshold).filter(|threshold| *threshold > Duration::from_millis(0)),
+            confirm_timeout: proxy_config.rabbitmq_confirm_timeout,
+            health_check_routing_key: Some(proxy_config.health_check_routing_key.clone()).filter(|key| !key.is_empty()),
+            health_check_timeout: proxy_config.health_check_timeout,
+            message_format: get_message_format(proxy_config),
+            max_message_size: proxy_config.max_message_size,
+            expose_stats: proxy_config.expose_stats,
+            stats_token: proxy_config.stats_token.get_value().to_string(),
+            event_name_field: proxy_config.event_name_field.clone(),
+            connection_auth: proxy_config.connection_auth,
+            reauth_interval: proxy_config.reauth_interval,
+            tracer: None
+        }
+    }
+
+    /// Enables recording spans for the processed requests.
+    pub fn with_tracer(mut self, tracer: Arc<Tracer>) -> Engine {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Returns the authentication state for a new connection, when the
+    /// connections are authenticated by their first message.
+    pub fn new_session(&self) -> Option<Arc<Session>> {
+        match self.connection_auth {
+            true => Some(Arc::new(Session::new(self.reauth_interval))),
+            false => None
         }
     }
 
     /// Performs deserializing an incoming message into JSON, searching for
     /// a route, applying a middleware and sending a request to microservice
-    /// in the certain format.
+    /// in the certain format. The user headers of the authenticated session
+    /// are used instead of verifying the token of each request.
     pub fn process_request(
         &self,
         message: Message,
+        subprotocol: Option<&str>,
         transmitter: MessageSender,
-        rabbitmq_context: Arc<RabbitMQContext>
-    ) -> Box<Future<Item=(), Error=PathfinderError> + Send + Sync + 'static> {
+        rabbitmq_context: Arc<RabbitMQContext>,
+        session: Option<Arc<Session>>
+    ) -> Box<dyn Future<Item=(), Error=RequestError> + Send + Sync + 'static> {
         // 1. Deserialize message into JSON
-        let json_message = match deserialize_message(&message) {
-            Ok(json_message) => json_message,
-            Err(error) => return Box::new(lazy(move || Err(error)))
+        let content_type = self.get_content_type(&message, subprotocol);
+        let serializer = Serializer::with_content_type(content_type).with_max_message_size(self.max_message_size);
+        let json_message = match serializer.deserialize(&message) {
+            Ok(json_message) => with_event_name(with_correlation_id(json_message), &self.event_name_field),
+            Err(error) => {
+                self.metrics.inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                return Box::new(lazy(move || Err(RequestError::new(error))))
+            }
         };
+        let correlation_id = get_correlation_id(&json_message);
+
+        // The connection is authenticated by the first message, and the
+        // clients that didn't do it are disconnected
+        if let Some(session) = session.clone() {
+            if Session::is_authentication_message(&json_message) {
+                return self.authenticate(json_message, session, content_type, transmitter, rabbitmq_context);
+            }
+            if session.is_anonymous() {
+                let error = session.get_headers().unwrap_err();
+                self.metrics.inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                return Box::new(lazy(move || Err(RequestError::new(error).with_correlation_id(&correlation_id).with_closing())))
+            }
+        }
 
-        // 2. Finding an endpoint in according to the URL in the message body
-        let url = json_message["url"].as_str().unwrap();
-        let endpoint = match self.get_endpoint(url) {
-            Ok(endpoint) => endpoint.clone(),
-            Err(error) => return Box::new(lazy(move || Err(error)))
+        // Subscriptions onto broadcasted messages are handled by the proxy itself
+        if let Some(subscription) = Subscription::from_message(&json_message) {
+            let result = subscription.and_then(|subscription| {
+                self.broadcaster.apply(&subscription, transmitter, content_type, &correlation_id)
+            });
+            return Box::new(lazy(move || result.map_err(|error| RequestError::new(error).with_correlation_id(&correlation_id))))
+        }
+
+        // The statistics are collected and sent by the proxy itself as well
+        if self.expose_stats && json_message["url"].as_str() == Some(STATS_URL) {
+            let result = self.get_stats(&json_message).and_then(|stats| {
+                let response = object!{"content" => stats, "correlation-id" => correlation_id.clone()};
+                transmitter
+                    .unbounded_send(convert_message(Message::Text(response.dump()), content_type))
+                    .map_err(|_| PathfinderError::MessageBrokerError(String::from("The connection was closed.")))
+            });
+            if let Err(ref error) = result {
+                self.metrics.inc_errors(UNKNOWN_ROUTING_KEY, error);
+            }
+            return Box::new(lazy(move || result.map_err(|error| RequestError::new(error).with_correlation_id(&correlation_id))))
+        }
+
+        // 2. Finding an endpoint in according to the URL in the message body. The
+        // routing table could be replaced later, but the request will keep using it
+        let routing = self.get_routing_table();
+        let url = match json_message["url"].as_str() {
+            Some(url) => url,
+            None => {
+                let error = PathfinderError::DecodingError(String::from("The `url` field must be a string"));
+                self.metrics.inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                return Box::new(lazy(move || Err(RequestError::new(error).with_correlation_id(&correlation_id))))
+            }
+        };
+        let route = match routing.match_route(url) {
+            Ok(route) => route,
+            Err(error) => {
+                self.metrics.inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                return Box::new(lazy(move || Err(RequestError::new(error).with_correlation_id(&correlation_id))))
+            }
+        };
+        let endpoint = route.get_endpoint();
+        let routing_key = endpoint.get_routing_key().to_string();
+        debug!("[{}] Processing request to \"{}\".", correlation_id, routing_key);
+        if let Err(error) = routing.validate_content(&json_message, &endpoint) {
+            self.metrics.inc_errors(&routing_key, &error);
+            return Box::new(lazy(move || Err(RequestError::new(error).with_correlation_id(&correlation_id))))
+        }
+        let selected_routing_key = match select_routing_key(&route, &json_message) {
+            Ok(selected_routing_key) => selected_routing_key,
+            Err(error) => {
+                self.metrics.inc_errors(&routing_key, &error);
+                return Box::new(lazy(move || Err(RequestError::new(error).with_correlation_id(&correlation_id))))
+            }
+        };
+        // The slot is kept until the response is sent, so that a slow
+        // microservice can't tie up unlimited resources of the proxy
+        let request_slot = match self.acquire_request_slot(&endpoint) {
+            Ok(request_slot) => request_slot,
+            Err(error) => {
+                self.metrics.inc_errors(&routing_key, &error);
+                return Box::new(lazy(move || Err(RequestError::new(error).with_correlation_id(&correlation_id))))
+            }
         };
+        let metrics = self.metrics.clone();
+        let metrics_inner = self.metrics.clone();
+        let slow_request_threshold = self.slow_request_threshold;
+        let correlation_id_inner = correlation_id.clone();
+        metrics.inc_requests(&routing_key);
+        metrics.inc_in_flight();
+
+        // The span of the request continues the trace of the client, when
+        // the message contains the `traceparent` field
+        let mut request_span = self.tracer.as_ref().map(|tracer| {
+            let parent = json_message[TRACEPARENT_HEADER].as_str().and_then(SpanContext::from_traceparent);
+            let mut span = Tracer::start_span(tracer, "request", SpanKind::Server, parent.as_ref());
+            span.set_attribute("url", url);
+            span.set_attribute("routing_key", &routing_key);
+            span.set_attribute("correlation_id", &correlation_id);
+            span
+        });
 
         // 3. Instantiate futures that will be processing client credentials and a request
-        let default_headers = self.generate_default_headers(&json_message.clone(), endpoint.clone());
+        let default_headers = self.generate_default_headers(&json_message.clone(), &route);
         let transmitter_inner = transmitter.clone();
         let rabbitmq_context_inner = rabbitmq_context.clone();
-        let rpc_options = Arc::new(RpcOptions::default()
+        let queue_name = self.reply_queue.get_queue_name(&selected_routing_key);
+        let mut rpc_options = RpcOptions::default()
             .with_endpoint(endpoint.clone())
+            .with_routing_key(selected_routing_key)
             .with_message(json_message.clone())
-            .with_queue_name(Arc::new(format!("{}", Uuid::new_v4()))
-        ));
+            .with_queue_name(Arc::new(queue_name))
+            .with_timeout(endpoint.get_timeout().unwrap_or(self.rpc_timeout))
+            .with_content_type(content_type)
+            .with_reply_queue(self.reply_queue.clone())
+            .with_confirm_timeout(self.confirm_timeout)
+            .with_transformers(routing.get_transformers(&endpoint))
+            .with_max_message_size(self.max_message_size);
+        if let Some(ref span) = request_span {
+            rpc_options = rpc_options.with_trace(span.get_trace_context());
+        }
+        let rpc_options = Arc::new(rpc_options);
 
-        let middleware_future = self.get_middleware_future(json_message.clone(), endpoint.clone(), rabbitmq_context.clone());
+        let middleware_future = self.get_middleware_future(json_message.clone(), endpoint.clone(), rabbitmq_context.clone(), session);
         Box::new(
             middleware_future.and_then(move |custom_headers: CustomUserHeaders| {
-                let mut request_headers = default_headers.clone();
-                for (key, value) in custom_headers.clone().iter() {
-                    let header_name = key.to_string();
-                    let header_value = value.to_string();
-                    request_headers.insert(header_name, header_value);
+                // The permissions are taken only from the middlewares, because
+                // the client could pass anything in the message
+                let endpoint = rpc_options.get_endpoint().unwrap();
+                if let Err(error) = check_permission(&endpoint, &custom_headers) {
+                    return Either::A(err(error));
                 }
-                rpc_request_future(
+
+                let rpc_options = match transform_request(&rpc_options, &custom_headers) {
+                    Ok(rpc_options) => rpc_options,
+                    Err(error) => return Either::A(err(error))
+                };
+                let request_headers = merge_headers(&default_headers, endpoint.get_headers(), &custom_headers);
+                let routing_key_inner = endpoint.get_routing_key().to_string();
+                let selected_routing_key = rpc_options.get_routing_key().unwrap();
+                let started_at = Instant::now();
+                let future = rpc_request_future(
                     transmitter_inner.clone(),
                     rabbitmq_context_inner.clone(),
                     rpc_options.clone(),
                     request_headers.clone()
                 )
+                .then(move |result| {
+                    let elapsed = started_at.elapsed();
+                    metrics_inner.observe_latency(&routing_key_inner, elapsed);
+                    log_slow_request(slow_request_threshold, &selected_routing_key, &correlation_id_inner, elapsed);
+                    result
+                });
+                Either::B(future)
+            })
+            .then(move |result| {
+                drop(request_slot);
+                if let (Some(span), Err(_)) = (request_span.as_mut(), &result) {
+                    span.set_error();
+                }
+                drop(request_span);
+                metrics.dec_in_flight();
+                if let Err(ref error) = result {
+                    metrics.inc_errors(&routing_key, error);
+                }
+                result.map_err(|error| RequestError::new(error).with_correlation_id(&correlation_id))
             })
         )
     }
 
-    /// Returns an endpoint based on specified URL.
-    fn get_endpoint(&self, url: &str) -> Result<ReadOnlyEndpoint> {
-        let router = self.router.clone();
-        router.match_url(&url)
+    /// Verifies the token of the authentication message by the JWT middleware
+    /// and stores the user headers in the session. The client gets a response
+    /// with the same correlation ID after the verification. The connection is
+    /// closed when its first token is invalid.
+    fn authenticate(
+        &self,
+        json_message: JsonMessage,
+        session: Arc<Session>,
+        content_type: ContentType,
+        transmitter: MessageSender,
+        rabbitmq_context: Arc<RabbitMQContext>
+    ) -> Box<dyn Future<Item=(), Error=RequestError> + Send + Sync + 'static> {
+        let correlation_id = get_correlation_id(&json_message);
+        let middleware = match self.get_middleware("jwt") {
+            Ok(middleware) => middleware,
+            Err(error) => {
+                self.metrics.inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                return Box::new(err(RequestError::new(error).with_correlation_id(&correlation_id)));
+            }
+        };
+        let is_first_token = session.is_anonymous();
+        let metrics = self.metrics.clone();
+        session.start_authentication();
+        Box::new(
+            middleware
+                .process_request(json_message, None, rabbitmq_context)
+                .then(move |result| {
+                    session.finish_authentication(&result);
+                    let result = result.and_then(|_| {
+                        debug!("[{}] The connection was authenticated.", correlation_id);
+                        let response = object!{"content" => object!{"authenticated" => true}, "correlation-id" => correlation_id.clone()};
+                        transmitter
+                            .unbounded_send(convert_message(Message::Text(response.dump()), content_type))
+                            .map_err(|_| PathfinderError::MessageBrokerError(String::from("The connection was closed.")))
+                    });
+                    if let Err(ref error) = result {
+                        metrics.inc_errors(UNKNOWN_ROUTING_KEY, error);
+                    }
+                    result.map_err(|error| {
+                        let request_error = RequestError::new(error).with_correlation_id(&correlation_id);
+                        match is_first_token {
+                            true => request_error.with_closing(),
+                            false => request_error
+                        }
+                    })
+                })
+        )
+    }
+
+    /// Reserves a place for the request when the number of in-flight requests
+    /// to the endpoint is limited.
+    fn acquire_request_slot(&self, endpoint: &ReadOnlyEndpoint) -> Result<Option<RequestSlot>> {
+        match endpoint.get_max_concurrency() {
+            Some(max_concurrency) => {
+                RequestLimits::acquire(&self.request_limits, endpoint.get_routing_key(), max_concurrency)
+                    .map(Some)
+                    .map_err(|error| {
+                        let message = format!("The endpoint is busy: {}.", error);
+                        PathfinderError::MessageBrokerError(message)
+                    })
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the currently loaded endpoints as a JSON array, so that
+    /// operators could check what the proxy actually uses for routing.
+    pub fn get_routes(&self) -> JsonValue {
+        let routes: Vec<JsonValue> = self.get_routing_table()
+            .router
+            .get_endpoints()
+            .iter()
+            .map(|endpoint| object!{
+                "url" => endpoint.get_url(),
+                "routing_key" => endpoint.get_routing_key(),
+                "request_exchange" => endpoint.get_request_exchange(),
+                "response_exchange" => endpoint.get_response_exchange(),
+                "token_required" => endpoint.is_token_required()
+            })
+            .collect();
+        JsonValue::Array(routes)
+    }
+
+    /// Checks the settings that can't be fixed after starting the proxy. Fails
+    /// when the remote configuration couldn't be fetched as well.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref message) = self.config_error {
+            return Err(ConfigError::Message(message.clone()).into());
+        }
+        self.reply_queue.validate()?;
+        self.amqp_prefix.validate()?;
+        self.error_template.validate()
+    }
+
+    /// Returns the template of errors that are sent to clients.
+    pub fn get_error_template(&self) -> Arc<ErrorTemplate> {
+        self.error_template.clone()
+    }
+
+    /// Returns the storage for connections subscribed onto topics.
+    pub fn get_broadcaster(&self) -> Arc<Broadcaster> {
+        self.broadcaster.clone()
+    }
+
+    /// Returns the format of the message and the expected response. The
+    /// format selected by the negotiated subprotocol takes precedence. Otherwise
+    /// binary messages are decoded from the configured message format, while
+    /// text messages are always JSON.
+    pub fn get_content_type(&self, message: &Message, subprotocol: Option<&str>) -> ContentType {
+        if let Some(content_type) = subprotocol.and_then(ContentType::from_subprotocol) {
+            return content_type;
+        }
+
+        match message.is_binary() {
+            true => self.message_format,
+            false => ContentType::Json
+        }
+    }
+
+    /// Returns the options of the ping request sent on health checks, when
+    /// the routing key of the microservice that answers to pings was specified.
+    pub fn get_health_check_options(&self) -> Option<Arc<RpcOptions>> {
+        let endpoint = Endpoint::new(HEALTH_CHECK_PATH, self.health_check_routing_key.as_ref()?, REQUEST_EXCHANGE, RESPONSE_EXCHANGE, false)
+            .with_amqp_prefix(&self.amqp_prefix);
+        let routing_key = endpoint.get_routing_key().to_string();
+        let message = with_correlation_id(Arc::new(Box::new(object!{
+            "url" => HEALTH_CHECK_PATH,
+            "content" => object!{"type" => "ping"}
+        })));
+        let rpc_options = RpcOptions::default()
+            .with_endpoint(Arc::new(endpoint))
+            .with_routing_key(routing_key.to_string())
+            .with_message(message)
+            .with_queue_name(Arc::new(self.reply_queue.get_queue_name(&routing_key)))
+            .with_timeout(self.health_check_timeout)
+            .with_reply_queue(self.reply_queue.clone())
+            .with_confirm_timeout(self.health_check_timeout)
+            .with_max_message_size(self.max_message_size);
+        Some(Arc::new(rpc_options))
+    }
+
+    /// Checks that the incoming message doesn't exceed the maximum size.
+    pub fn check_message_size(&self, message: &Message) -> Result<()> {
+        Serializer::new()
+            .with_max_message_size(self.max_message_size)
+            .validate_size(message)
+    }
+
+    /// Returns the requests of the message. The batch of requests (a top-level
+    /// array) is split into separate messages, so that each request is processed
+    /// independently and its failure doesn't affect the others.
+    pub fn split_batch(&self, message: Message, subprotocol: Option<&str>) -> Result<Vec<Message>> {
+        let content_type = self.get_content_type(&message, subprotocol);
+        let serializer = Serializer::with_content_type(content_type).with_max_message_size(self.max_message_size);
+        match serializer.split_batch(&message)? {
+            Some(messages) => Ok(messages),
+            None => Ok(vec![message])
+        }
+    }
+
+    /// Returns the statistics of connections, requests and errors. When
+    /// the token for statistics was specified, the `token` field of the
+    /// message must match it.
+    pub fn get_stats(&self, json_message: &JsonValue) -> Result<JsonValue> {
+        match self.stats_token.is_empty() || json_message["token"].as_str() == Some(self.stats_token.as_str()) {
+            true => Ok(self.metrics.get_stats()),
+            false => {
+                let message = String::from("The token is invalid for getting the statistics.");
+                Err(PathfinderError::AuthenticationError(message))
+            }
+        }
+    }
+
+    /// Returns the metrics collected during processing requests.
+    pub fn get_metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Reads the configuration file (or fetches the remote configuration)
+    /// again and replaces the routing table. When the configuration is
+    /// invalid or can't be fetched, the current routing table is kept and
+    /// an error is returned.
+    pub fn reload(&self) -> Result<()> {
+        let config = load_config(&self.config_path)?;
+        let routing = self.load_routing_table(&config)?;
+        let endpoints_count = routing.router.get_endpoints_count();
+        *self.routing.write().unwrap() = Arc::new(routing);
+        info!("Configuration was reloaded. Endpoints: {}.", endpoints_count);
+        Ok(())
+    }
+
+    /// Returns a routing table for the configuration when all endpoints are valid.
+    fn load_routing_table(&self, config: &Config) -> Result<RoutingTable> {
+        let router_options = RouterOptions::from_config(config);
+        let (endpoints, mut errors) = parse_endpoints(config);
+        let endpoints = with_amqp_prefix(endpoints, &self.amqp_prefix);
+        let (routing, routing_errors) = RoutingTable::new(endpoints, router_options, &self.middlewares, &self.transformers);
+        errors.extend(routing_errors);
+
+        match errors.is_empty() {
+            true => Ok(routing),
+            false => {
+                let messages: Vec<String> = errors.iter().map(|error| format!("{}", error)).collect();
+                let error = ConfigError::Message(messages.join("; "));
+                Err(error.into())
+            }
+        }
+    }
+
+    /// Returns the current routing table.
+    fn get_routing_table(&self) -> Arc<RoutingTable> {
+        self.routing.read().unwrap().clone()
     }
 
-    /// Returns a middleware for processing client credentials.
+    /// Returns a chain of middlewares for processing client credentials. Each
+    /// middleware is applied after the previous one, so that the custom user
+    /// headers are accumulated across the chain.
+    /// When the connection is authenticated by its first message, the user
+    /// headers of the session are taken instead of verifying the token.
     fn get_middleware_future(
         &self,
         json_message: JsonMessage,
         endpoint: ReadOnlyEndpoint,
-        rabbitmq_context: Arc<RabbitMQContext>
+        rabbitmq_context: Arc<RabbitMQContext>,
+        session: Option<Arc<Session>>
     ) -> MiddlewareFuture {
-        let middleware = self.get_middleware_by_endpoint(endpoint);
-        middleware.process_request(json_message, rabbitmq_context.clone())
+        let middlewares = match self.get_middlewares_by_endpoint(endpoint.clone()) {
+            Ok(middlewares) => middlewares,
+            Err(error) => return Box::new(err(error))
+        };
+        let mut middleware_future: MiddlewareFuture = Box::new(lazy(move || Ok(HashMap::new())));
+        for (name, middleware) in middlewares {
+            let json_message_inner = json_message.clone();
+            let endpoint_inner = endpoint.clone();
+            let rabbitmq_context_inner = rabbitmq_context.clone();
+            let session_inner = session.clone().filter(|_| SESSION_MIDDLEWARES.contains(&name.as_str()));
+            middleware_future = Box::new(middleware_future.and_then(move |mut headers: CustomUserHeaders| {
+                let future: MiddlewareFuture = match session_inner {
+                    Some(session) => Box::new(lazy(move || session.get_headers())),
+                    None => middleware.process_request(json_message_inner, Some(endpoint_inner), rabbitmq_context_inner)
+                };
+                future
+                    .map(move |custom_headers: CustomUserHeaders| {
+                        headers.extend(custom_headers);
+                        headers
+                    })
+            }));
+        }
+        middleware_future
+    }
+
+    /// Returns middlewares with their names that matches to the passed
+    /// endpoint. Fails when any of them isn't registered, so that requests
+    /// aren't processed without the expected checks.
+    fn get_middlewares_by_endpoint(&self, endpoint: ReadOnlyEndpoint) -> Result<MiddlewareChain> {
+        match endpoint.get_middlewares() {
+            Some(names) => names
+                .iter()
+                .map(|name| self.get_middleware(name).map(|middleware| (name.clone(), middleware)))
+                .collect(),
+            None => {
+                let name = get_default_middleware_name(&endpoint);
+                self.get_middleware(name).map(|middleware| vec![(name.to_string(), middleware)])
+            }
+        }
+    }
+
+    /// Returns the registered middleware by its name.
+    fn get_middleware(&self, name: &str) -> Result<Arc<Box<dyn Middleware>>> {
+        match self.middlewares.get(name) {
+            Some(middleware) => Ok(middleware.clone()),
+            None => {
+                let message = format!("The \"{}\" middleware isn't registered.", name);
+                Err(PathfinderError::InvalidEndpoint(message))
+            }
+        }
+    }
+
+    /// Generates default headers for the message. The identity of the user
+    /// is never taken from the message, so that it can be set only by the
+    /// middlewares after verifying the token.
+    fn generate_default_headers(&self, json: &JsonMessage, route: &RouteMatch) -> HashMap<String, String> {
+        let endpoint = route.get_endpoint();
+        let mut headers: HashMap<String, String> = [
+            (String::from("routing_key"), endpoint.get_routing_key().to_string()),
+            (String::from("request_url"), route.get_url().to_string()),
+            (String::from("correlation_id"), get_correlation_id(json)),
+            (String::from("permissions"), String::new()),
+            (String::from("user_id"), String::new()),
+        ].iter().cloned().collect();
+
+        if let Some(suffix) = route.get_suffix() {
+            headers.insert(String::from("path_suffix"), suffix.to_string());
+        }
+
+        for (name, value) in route.get_params().iter() {
+            headers.insert(format!("path_{}", name), value.clone());
+        }
+
+        headers
+    }
+}
+
+/// Returns the name of the middleware that is applied to the endpoint
+/// without the configured list of middlewares.
+fn get_default_middleware_name(endpoint: &ReadOnlyEndpoint) -> &'static str {
+    match (endpoint.is_token_required(), endpoint.is_token_optional()) {
+        (true, _) => "jwt",
+        (false, true) => "jwt_optional",
+        (false, false) => "empty"
     }
+}
+
+/// Combines headers of the request to the microservice. The static headers
+/// of the endpoint override the default ones, and the headers passed by
+/// middlewares override both of them.
+fn merge_headers(
+    default_headers: &HashMap<String, String>,
+    static_headers: &HashMap<String, String>,
+    custom_headers: &CustomUserHeaders
+) -> HashMap<String, String> {
+    let mut headers = default_headers.clone();
+    headers.extend(static_headers.iter().map(|(key, value)| (key.clone(), value.clone())));
+    headers.extend(custom_headers.iter().map(|(key, value)| (key.clone(), value.clone())));
+    headers
+}
 
-    /// Returns a middleware that matches to the passed endpoint
-    fn get_middleware_by_endpoint(&self, endpoint: ReadOnlyEndpoint) -> Arc<Box<Middleware>> {
-        match endpoint.is_token_required() {
-            true => self.middlewares.clone()["jwt"].clone(),
-            false => self.middlewares.clone()["empty"].clone()
+/// Returns the routing key for the request. For topic exchanges the routing
+/// key is built from the template by the path parameters and the content.
+fn select_routing_key(route: &RouteMatch, json_message: &JsonMessage) -> Result<String> {
+    let endpoint = route.get_endpoint();
+    let routing_key = endpoint.select_routing_key();
+    match endpoint.is_topic_exchange() {
+        true => expand_routing_key(&routing_key, route.get_params(), json_message),
+        false => Ok(routing_key)
+    }
+}
+
+/// Logs a warning when the request to the microservice took longer than the
+/// threshold. Returns whether the request was considered as slow.
+fn log_slow_request(threshold: Option<Duration>, routing_key: &str, correlation_id: &str, elapsed: Duration) -> bool {
+    match threshold {
+        Some(threshold) if elapsed > threshold => {
+            warn!(
+                "[{}] The request to \"{}\" took {} ms, which exceeds the threshold of {} ms.",
+                correlation_id, routing_key, elapsed.as_millis(), threshold.as_millis()
+            );
+            true
+        },
+        _ => false
+    }
+}
+
+/// Returns the endpoints with the prefix prepended to their exchanges and
+/// routing keys.
+fn with_amqp_prefix(endpoints: HashMap<String, ReadOnlyEndpoint>, prefix: &AmqpPrefix) -> HashMap<String, ReadOnlyEndpoint> {
+    if prefix.is_empty() {
+        return endpoints;
+    }
+
+    endpoints
+        .into_iter()
+        .map(|(url, endpoint)| (url, Arc::new(endpoint.as_ref().clone().with_amqp_prefix(prefix))))
+        .collect()
+}
+
+/// Returns the format of binary messages. The `--message-pack` option is
+/// kept as a shortcut for the `msgpack` format, and unknown formats fall
+/// back to JSON.
+fn get_message_format(proxy_config: &ProxyConfig) -> ContentType {
+    match ContentType::from_format(&proxy_config.message_format) {
+        Some(ContentType::Json) | None if proxy_config.message_pack => ContentType::MessagePack,
+        Some(content_type) => content_type,
+        None => {
+            warn!(
+                "Message format with value={} is invalid. Use one of available formats: {:?}",
+                proxy_config.message_format, vec!["json", "msgpack", "cbor"]
+            );
+            ContentType::Json
         }
     }
+}
+
+/// Returns the options of the request with the content of the message
+/// modified by the transformers of the endpoint.
+fn transform_request(options: &Arc<RpcOptions>, headers: &CustomUserHeaders) -> Result<Arc<RpcOptions>> {
+    let transformers = options.get_transformers().unwrap_or_default();
+    if transformers.is_empty() {
+        return Ok(options.clone());
+    }
+
+    let message = options.get_message().unwrap();
+    let mut json = JsonValue::clone(&message);
+    json["content"] = transformers.transform_request(message["content"].clone(), headers)?;
+    let options = RpcOptions::clone(options).with_message(Arc::new(Box::new(json)));
+    Ok(Arc::new(options))
+}
+
+/// Checks that the user has the permission required by the endpoint. The
+/// list of user's permissions is passed by middlewares in the headers.
+fn check_permission(endpoint: &ReadOnlyEndpoint, headers: &CustomUserHeaders) -> Result<()> {
+    let permission = match endpoint.get_required_permission() {
+        Some(permission) => permission,
+        None => return Ok(())
+    };
+
+    match has_permission(&get_user_permissions(headers), permission) {
+        true => Ok(()),
+        false => {
+            let message = format!("The \"{}\" permission is required for this endpoint.", permission);
+            Err(PathfinderError::AuthenticationError(message))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use json::{object, JsonValue};
+    use tungstenite::Message;
+    use uuid::Uuid;
+
+    use crate::config::{ProxyConfig, Secret};
+    use crate::engine::engine::{
+        check_permission, log_slow_request, merge_headers, select_routing_key, transform_request, Engine, STATS_URL
+    };
+    use crate::engine::middleware::{encode_permissions, get_permissions, CustomUserHeaders, PermissionsFormat};
+    use crate::engine::options::RpcOptions;
+    use crate::engine::router::{Endpoint, ReadOnlyEndpoint};
+    use crate::engine::serializer::{ContentType, JsonMessage};
+    use crate::engine::transformer::{Transformer, TransformerChain, Transformers};
+    use crate::error::{PathfinderError, Result};
+
+    const VALID_CONFIG: &str = "\
+endpoints:
+  - search:
+      url: \"/api/matchmaking/search\"
+      routing_key: \"microservice.search\"
+";
+
+    const UPDATED_CONFIG: &str = "\
+endpoints:
+  - search:
+      url: \"/api/matchmaking/search\"
+      routing_key: \"microservice.search\"
+  - leaderboard:
+      url: \"/api/matchmaking/leaderboard\"
+      routing_key: \"microservice.leaderboard\"
+";
+
+    const INVALID_CONFIG: &str = "\
+endpoints:
+  - leaderboard:
+      url: \"/api/matchmaking/leaderboard\"
+";
+
+    struct TenantTransformer;
+
+    impl Transformer for TenantTransformer {
+        fn transform_request(&self, mut content: JsonValue, headers: &CustomUserHeaders) -> Result<JsonValue> {
+            content["tenant_id"] = headers.get("tenant_id").cloned().unwrap_or_default().into();
+            Ok(content)
+        }
+    }
+
+    fn get_transformers() -> Transformers {
+        let mut transformers: Transformers = HashMap::new();
+        transformers.insert(String::from("tenant"), Arc::new(Box::new(TenantTransformer)));
+        transformers
+    }
+
+    fn get_engine(config_path: &str) -> Engine {
+        let proxy_config = ProxyConfig {
+            config: String::from(config_path),
+            ..ProxyConfig::default()
+        };
+        Engine::new(&proxy_config)
+    }
+
+    /// Serves the configuration bodies one per request (or the error when the
+    /// body is `None`) and returns the URL of the configuration.
+    fn serve_configs(bodies: Vec<Option<&'static str>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer).unwrap();
+                let response = match body {
+                    Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body),
+                    None => String::from("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}/pathfinder.yaml", address)
+    }
+
+    fn has_route(engine: &Engine, url: &str) -> bool {
+        engine.get_routing_table().match_route(url).is_ok()
+    }
+
+    fn get_endpoint(required_permission: Option<&str>) -> ReadOnlyEndpoint {
+        let endpoint = Endpoint::new("/api/matchmaking/search", "microservice.search", "request", "response", true);
+        match required_permission {
+            Some(permission) => Arc::new(endpoint.with_required_permission(permission)),
+            None => Arc::new(endpoint)
+        }
+    }
+
+    fn get_headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn get_user_headers(permissions: Vec<&str>) -> CustomUserHeaders {
+        let response = object!{"content" => object!{"permissions" => permissions}};
+        let mut headers = HashMap::new();
+        let permissions = encode_permissions(&get_permissions(&response), PermissionsFormat::Json);
+        headers.insert(String::from("permissions"), permissions);
+        headers
+    }
+
+    #[test]
+    fn test_get_middlewares_by_endpoint_uses_optional_jwt_for_optional_tokens() {
+        let engine = get_engine("./tests/files/config_with_optional_tokens.yaml");
+        let get_middleware_name = |url: &str| {
+            let endpoint = engine.get_routing_table().match_route(url).unwrap().get_endpoint();
+            let middlewares = engine.get_middlewares_by_endpoint(endpoint).unwrap();
+            let (name, middleware) = &middlewares[0];
+            assert!(Arc::ptr_eq(&engine.middlewares[name], middleware));
+            name.clone()
+        };
+
+        assert_eq!(get_middleware_name("/api/matchmaking/leaderboard"), "jwt_optional");
+        assert_eq!(get_middleware_name("/api/matchmaking/search"), "jwt");
+        assert_eq!(get_middleware_name("/api/matchmaking/health"), "empty");
+    }
+
+    #[test]
+    fn test_get_middlewares_by_endpoint_fails_for_missing_middlewares() {
+        let mut engine = get_engine("./tests/files/config_with_valid_endpoints.yaml");
+        let mut middlewares = (*engine.middlewares).clone();
+        middlewares.remove("jwt");
+        engine.middlewares = Arc::new(middlewares);
+
+        match engine.get_middlewares_by_endpoint(get_endpoint(None)) {
+            Err(PathfinderError::InvalidEndpoint(message)) => assert_eq!(message, "The \"jwt\" middleware isn't registered."),
+            _ => panic!("Expected an error about the missing middleware")
+        }
+
+        let endpoint = Arc::new(Endpoint::new("/api/matchmaking/health", "microservice.health", "request", "response", false));
+        assert_eq!(engine.get_middlewares_by_endpoint(endpoint).unwrap()[0].0, "empty");
+    }
+
+    #[test]
+    fn test_reload_replaces_endpoints() {
+        let config_path = env::temp_dir().join(format!("pathfinder-{}.yaml", Uuid::new_v4()));
+        let config_path = config_path.to_str().unwrap();
+        fs::write(config_path, VALID_CONFIG).unwrap();
+        let engine = get_engine(config_path);
+        assert!(has_route(&engine, "/api/matchmaking/search"));
+        assert!(!has_route(&engine, "/api/matchmaking/leaderboard"));
+
+        fs::write(config_path, UPDATED_CONFIG).unwrap();
+        assert!(engine.reload().is_ok());
+        assert!(has_route(&engine, "/api/matchmaking/search"));
+        assert!(has_route(&engine, "/api/matchmaking/leaderboard"));
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_fetches_the_remote_config() {
+        let url = serve_configs(vec![Some(VALID_CONFIG), Some(UPDATED_CONFIG), None]);
+        let engine = get_engine(&url);
+        assert!(engine.validate().is_ok());
+        assert!(!has_route(&engine, "/api/matchmaking/leaderboard"));
+
+        assert!(engine.reload().is_ok());
+        assert!(has_route(&engine, "/api/matchmaking/leaderboard"));
+
+        // The last valid configuration is kept when the source is unavailable
+        assert!(engine.reload().is_err());
+        assert!(has_route(&engine, "/api/matchmaking/leaderboard"));
+    }
+
+    #[test]
+    fn test_validate_fails_when_the_remote_config_is_unavailable() {
+        let url = serve_configs(vec![None]);
+        let engine = get_engine(&url);
+        let error = engine.validate().unwrap_err();
+        assert!(format!("{}", error).contains("503 Service Unavailable"));
+        assert!(!has_route(&engine, "/api/matchmaking/search"));
+    }
+
+    #[test]
+    fn test_get_routes_returns_the_loaded_endpoints() {
+        let engine = get_engine("./tests/files/config_with_valid_endpoints.yaml");
+        let routes = engine.get_routes();
+
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0]["url"], "/api/matchmaking/leaderboard");
+        assert_eq!(routes[1]["url"], "/api/matchmaking/player-of-the-game");
+        assert_eq!(routes[2]["url"], "/api/matchmaking/search");
+        assert_eq!(routes[2]["routing_key"], "microservice.search");
+        assert_eq!(routes[2]["request_exchange"], "open-matchmaking.direct");
+        assert_eq!(routes[2]["response_exchange"], "open-matchmaking.responses.direct");
+        assert_eq!(routes[2]["token_required"], true);
+    }
+
+    #[test]
+    fn test_get_routes_reflects_the_reloaded_config() {
+        let config_path = env::temp_dir().join(format!("pathfinder-{}.yaml", Uuid::new_v4()));
+        let config_path = config_path.to_str().unwrap();
+        fs::write(config_path, VALID_CONFIG).unwrap();
+        let engine = get_engine(config_path);
+        assert_eq!(engine.get_routes().len(), 1);
+
+        fs::write(config_path, UPDATED_CONFIG).unwrap();
+        assert!(engine.reload().is_ok());
+        assert_eq!(engine.get_routes().len(), 2);
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_endpoints_for_invalid_config() {
+        let config_path = env::temp_dir().join(format!("pathfinder-{}.yaml", Uuid::new_v4()));
+        let config_path = config_path.to_str().unwrap();
+        fs::write(config_path, VALID_CONFIG).unwrap();
+        let engine = get_engine(config_path);
+
+        fs::write(config_path, INVALID_CONFIG).unwrap();
+        assert!(engine.reload().is_err());
+        assert!(has_route(&engine, "/api/matchmaking/search"));
+
+        fs::remove_file(config_path).unwrap();
+        assert!(engine.reload().is_err());
+        assert!(has_route(&engine, "/api/matchmaking/search"));
+    }
+
+    #[test]
+    fn test_acquire_request_slot_rejects_requests_to_the_busy_endpoint() {
+        let engine = get_engine("./tests/files/config_with_timeouts.yaml");
+        let get_endpoint = |url: &str| engine.get_routing_table().match_route(url).unwrap().get_endpoint();
+        let search = get_endpoint("/api/matchmaking/search");
+        let leaderboard = get_endpoint("/api/matchmaking/leaderboard");
+
+        let slots: Vec<_> = (0..10)
+            .map(|_| engine.acquire_request_slot(&search).unwrap())
+            .collect();
+        assert!(slots.iter().all(|slot| slot.is_some()));
+        match engine.acquire_request_slot(&search) {
+            Err(PathfinderError::MessageBrokerError(message)) => assert!(message.starts_with("The endpoint is busy")),
+            _ => panic!("Expected the endpoint to be busy")
+        }
+        assert!(engine.acquire_request_slot(&leaderboard).unwrap().is_none());
+
+        drop(slots);
+        assert!(engine.acquire_request_slot(&search).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_check_permission_allows_users_with_the_required_permission() {
+        let endpoint = get_endpoint(Some("matchmaking.search.start"));
+        let headers = get_user_headers(vec!["matchmaking.leaderboard.view", "matchmaking.search.start"]);
+        assert!(check_permission(&endpoint, &headers).is_ok());
+    }
+
+    #[test]
+    fn test_check_permission_denies_users_without_the_required_permission() {
+        let endpoint = get_endpoint(Some("matchmaking.search.start"));
+        let headers = get_user_headers(vec!["matchmaking.leaderboard.view", "matchmaking.search"]);
+        match check_permission(&endpoint, &headers) {
+            Err(PathfinderError::AuthenticationError(message)) => {
+                assert_eq!(message, "The \"matchmaking.search.start\" permission is required for this endpoint.");
+            },
+            _ => panic!("Expected an authentication error")
+        }
+
+        assert!(check_permission(&endpoint, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_check_permission_allows_everyone_without_the_required_permission() {
+        let endpoint = get_endpoint(None);
+        assert!(check_permission(&endpoint, &get_user_headers(vec![])).is_ok());
+        assert!(check_permission(&endpoint, &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn test_get_stats_returns_counters_of_processed_requests() {
+        let engine = get_engine("./tests/files/config_with_valid_endpoints.yaml");
+        let message = object!{"url" => STATS_URL};
+        assert_eq!(engine.get_stats(&message).unwrap()["requests"], 0);
+
+        let metrics = engine.get_metrics();
+        metrics.inc_connections();
+        metrics.inc_requests("microservice.search");
+        metrics.inc_errors("microservice.search", &PathfinderError::EndpointNotFound(String::from("/api/unknown")));
+
+        let stats = engine.get_stats(&message).unwrap();
+        assert_eq!(stats["connections"], 1);
+        assert_eq!(stats["requests"], 1);
+        assert_eq!(stats["errors"]["EndpointNotFound"], 1);
+    }
+
+    #[test]
+    fn test_get_stats_requires_the_configured_token() {
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            expose_stats: true,
+            stats_token: Secret::from("secret"),
+            ..ProxyConfig::default()
+        };
+        let engine = Engine::new(&proxy_config);
+
+        assert!(engine.get_stats(&object!{"url" => STATS_URL, "token" => "secret"}).is_ok());
+        match engine.get_stats(&object!{"url" => STATS_URL, "token" => "invalid"}) {
+            Err(PathfinderError::AuthenticationError(_)) => {},
+            _ => panic!("Expected an authentication error")
+        }
+        assert!(engine.get_stats(&object!{"url" => STATS_URL}).is_err());
+    }
+
+    #[test]
+    fn test_merge_headers_applies_static_headers_after_default_ones() {
+        let default_headers = get_headers(&[("routing_key", "microservice.search"), ("user_id", "")]);
+        let static_headers = get_headers(&[("user_id", "static"), ("tenant_id", "eu-west")]);
+        let custom_headers = get_headers(&[("user_id", "7b0d21f0"), ("permissions", "")]);
+
+        let headers = merge_headers(&default_headers, &static_headers, &HashMap::new());
+        assert_eq!(headers, get_headers(&[
+            ("routing_key", "microservice.search"),
+            ("user_id", "static"),
+            ("tenant_id", "eu-west"),
+        ]));
+
+        let headers = merge_headers(&default_headers, &static_headers, &custom_headers);
+        assert_eq!(headers, get_headers(&[
+            ("routing_key", "microservice.search"),
+            ("user_id", "7b0d21f0"),
+            ("tenant_id", "eu-west"),
+            ("permissions", ""),
+        ]));
+    }
+
+    #[test]
+    fn test_anonymous_request_does_not_carry_identity_headers_of_the_client() {
+        let engine = get_engine("./tests/files/config_with_optional_tokens.yaml");
+        let route = engine.get_routing_table().match_route("/api/matchmaking/leaderboard").unwrap();
+        let message: JsonMessage = Arc::new(Box::new(object!{
+            "url" => "/api/matchmaking/leaderboard",
+            "permissions" => "admin",
+            "user_id" => "7b0d21f0"
+        }));
+
+        // The optional JWT middleware doesn't pass any headers without a token
+        let default_headers = engine.generate_default_headers(&message, &route);
+        let headers = merge_headers(&default_headers, &HashMap::new(), &HashMap::new());
+        assert_eq!(headers["permissions"], "");
+        assert_eq!(headers["user_id"], "");
+    }
+
+    #[test]
+    fn test_routing_table_contains_transformers_of_endpoints() {
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_transformers.yaml"),
+            ..ProxyConfig::default()
+        };
+        let engine = Engine::new_with_transformers(&proxy_config, get_transformers());
+        let routing = engine.get_routing_table();
+
+        let search = routing.match_route("/api/matchmaking/search").unwrap().get_endpoint();
+        assert_eq!(format!("{:?}", routing.get_transformers(&search)), "TransformerChain { transformers: [\"empty\", \"tenant\"] }");
+        let leaderboard = routing.match_route("/api/matchmaking/leaderboard").unwrap().get_endpoint();
+        assert!(routing.get_transformers(&leaderboard).is_empty());
+    }
+
+    #[test]
+    fn test_routing_table_skips_unknown_transformers() {
+        let engine = get_engine("./tests/files/config_with_transformers.yaml");
+        let routing = engine.get_routing_table();
+        let search = routing.match_route("/api/matchmaking/search").unwrap().get_endpoint();
+        assert!(routing.get_transformers(&search).is_empty());
+    }
+
+    #[test]
+    fn test_transform_request_modifies_the_content_of_the_message() {
+        let message = object!{"url" => "/api/matchmaking/search", "content" => object!{"query" => "ranked"}};
+        let chain = TransformerChain::new(&[String::from("tenant")], &get_transformers()).unwrap();
+        let options = Arc::new(
+            RpcOptions::default()
+                .with_message(Arc::new(Box::new(message.clone())))
+                .with_transformers(Arc::new(chain))
+        );
+        let headers = get_headers(&[("tenant_id", "eu-west")]);
+
+        let transformed = transform_request(&options, &headers).unwrap().get_message().unwrap();
+        assert_eq!(transformed["url"], "/api/matchmaking/search");
+        assert_eq!(transformed["content"], object!{"query" => "ranked", "tenant_id" => "eu-west"});
+        assert_eq!(**options.get_message().unwrap(), message);
+    }
+
+    #[test]
+    fn test_new_session_is_created_only_for_connection_auth() {
+        assert!(get_engine("./tests/files/config_with_valid_endpoints.yaml").new_session().is_none());
+
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            connection_auth: true,
+            ..ProxyConfig::default()
+        };
+        let session = Engine::new(&proxy_config).new_session().unwrap();
+        assert!(session.is_anonymous());
+    }
+
+    #[test]
+    fn test_split_batch_returns_requests_in_the_format_of_the_message() {
+        let engine = get_engine("./tests/files/config_with_valid_endpoints.yaml");
+        let batch = r#"[{"url": "/api/matchmaking/search"}, {"url": null}]"#;
+        let messages = engine.split_batch(Message::Text(String::from(batch)), None).unwrap();
+        assert_eq!(messages, vec![
+            Message::Text(String::from(r#"{"url":"/api/matchmaking/search"}"#)),
+            Message::Text(String::from(r#"{"url":null}"#)),
+        ]);
+
+        let messages = engine.split_batch(Message::Text(String::from(batch)), Some("msgpack")).unwrap();
+        assert!(messages.iter().all(|message| message.is_binary()));
+
+        let message = Message::Text(String::from(r#"{"url": "/api/matchmaking/search"}"#));
+        assert_eq!(engine.split_batch(message.clone(), None).unwrap(), vec![message]);
+    }
+
+    #[test]
+    fn test_get_content_type_uses_the_message_format_for_binary_messages() {
+        let text = Message::Text(String::from("{}"));
+        let binary = Message::Binary(vec![0xa0]);
+        let engine = get_engine("./tests/files/config_with_valid_endpoints.yaml");
+        assert_eq!(engine.get_content_type(&text, None), ContentType::Json);
+        assert_eq!(engine.get_content_type(&binary, None), ContentType::Json);
+
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            message_format: String::from("cbor"),
+            ..ProxyConfig::default()
+        };
+        let engine = Engine::new(&proxy_config);
+        assert_eq!(engine.get_content_type(&text, None), ContentType::Json);
+        assert_eq!(engine.get_content_type(&binary, None), ContentType::Cbor);
+        assert_eq!(engine.get_content_type(&binary, Some("msgpack")), ContentType::MessagePack);
+
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            message_pack: true,
+            ..ProxyConfig::default()
+        };
+        assert_eq!(Engine::new(&proxy_config).get_content_type(&binary, None), ContentType::MessagePack);
+    }
+
+    #[test]
+    fn test_get_health_check_options_only_for_the_specified_routing_key() {
+        assert!(get_engine("./tests/files/config_with_valid_endpoints.yaml").get_health_check_options().is_none());
+
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            health_check_routing_key: String::from("microservice.health"),
+            health_check_timeout: Duration::from_millis(300),
+            ..ProxyConfig::default()
+        };
+        let options = Engine::new(&proxy_config).get_health_check_options().unwrap();
+        let message = options.get_message().unwrap();
+        assert_eq!(options.get_routing_key().unwrap(), "microservice.health");
+        assert_eq!(options.get_endpoint().unwrap().get_request_exchange(), "open-matchmaking.direct");
+        assert_eq!(options.get_timeout(), Some(Duration::from_millis(300)));
+        assert_eq!(message["content"]["type"], "ping");
+        assert!(message["correlation-id"].is_string());
+    }
+
+    #[test]
+    fn test_amqp_prefix_is_applied_to_endpoints_and_health_checks() {
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            health_check_routing_key: String::from("microservice.health"),
+            amqp_prefix: String::from("staging"),
+            ..ProxyConfig::default()
+        };
+        let engine = Engine::new(&proxy_config);
+        assert!(engine.validate().is_ok());
+
+        let search = engine.get_routing_table().match_route("/api/matchmaking/search").unwrap().get_endpoint();
+        assert_eq!(search.get_routing_key(), "staging.microservice.search");
+        assert_eq!(search.get_request_exchange(), "staging.open-matchmaking.direct");
+        assert_eq!(search.get_response_exchange(), "staging.open-matchmaking.responses.direct");
+
+        let options = engine.get_health_check_options().unwrap();
+        assert_eq!(options.get_routing_key().unwrap(), "staging.microservice.health");
+        assert_eq!(options.get_endpoint().unwrap().get_request_exchange(), "staging.open-matchmaking.direct");
+    }
+
+    #[test]
+    fn test_validate_rejects_the_invalid_amqp_prefix() {
+        let proxy_config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            amqp_prefix: String::from("staging."),
+            ..ProxyConfig::default()
+        };
+        assert!(Engine::new(&proxy_config).validate().is_err());
+    }
+
+    #[test]
+    fn test_log_slow_request_warns_only_past_the_threshold() {
+        let threshold = Some(Duration::from_millis(1000));
+        assert!(log_slow_request(threshold, "microservice.search", "id", Duration::from_millis(1500)));
+        assert!(!log_slow_request(threshold, "microservice.search", "id", Duration::from_millis(1000)));
+        assert!(!log_slow_request(threshold, "microservice.search", "id", Duration::from_millis(200)));
+        assert!(!log_slow_request(None, "microservice.search", "id", Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_validate_content_requires_the_content_for_some_endpoints() {
+        let engine = get_engine("./tests/files/config_with_content_types.yaml");
+        let routing = engine.get_routing_table();
+        let message: JsonMessage = Arc::new(Box::new(object!{"url" => "/api/matchmaking/leaderboard"}));
+
+        let leaderboard = routing.match_route("/api/matchmaking/leaderboard").unwrap().get_endpoint();
+        match routing.validate_content(&message, &leaderboard) {
+            Err(PathfinderError::DecodingError(message)) => {
+                assert_eq!(message, "The `content` field is required for this endpoint.")
+            },
+            _ => panic!("Expected a decoding error")
+        }
+        let message_with_content: JsonMessage = Arc::new(Box::new(object!{"url" => "/api/matchmaking/leaderboard", "content" => object!{}}));
+        assert!(routing.validate_content(&message_with_content, &leaderboard).is_ok());
+
+        let avatar = routing.match_route("/api/profiles/avatar").unwrap().get_endpoint();
+        assert!(routing.validate_content(&message, &avatar).is_ok());
+    }
+
+    #[test]
+    fn test_select_routing_key_expands_templates_of_topic_exchanges() {
+        let engine = get_engine("./tests/files/config_with_topic_exchanges.yaml");
+        let routing = engine.get_routing_table();
+        let message: JsonMessage = Arc::new(Box::new(object!{"content" => object!{"mode" => "ranked"}}));
+
+        let route = routing.match_route("/api/matchmaking/eu/search").unwrap();
+        assert_eq!(select_routing_key(&route, &message).unwrap(), "matchmaking.eu.ranked.search");
+
+        let route = routing.match_route("/api/matchmaking/leaderboard").unwrap();
+        assert_eq!(select_routing_key(&route, &message).unwrap(), "matchmaking.leaderboard");
 
-    /// Generates default headers for the message.
-    fn generate_default_headers(&self, json: &JsonMessage, endpoint: ReadOnlyEndpoint) -> HashMap<String, String> {
-        [
-            (String::from("routing_key"), endpoint.get_routing_key()),
-            (String::from("request_url"), endpoint.get_url()),
-            (String::from("permissions"), json["permissions"].as_str().unwrap_or("").to_string()),
-            (String::from("user_id"), json["user_id"].as_str().unwrap_or("").to_string()),
-        ].iter().cloned().collect()
+        let route = routing.match_route("/api/matchmaking/eu/search").unwrap();
+        let message: JsonMessage = Arc::new(Box::new(object!{"content" => object!{}}));
+        assert!(select_routing_key(&route, &message).is_err());
     }
 }
@@ -2,25 +2,106 @@
 /// sending request to other microservices by proxy engine.
 ///
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 use std::str::from_utf8;
 use std::sync::Arc;
+use std::time::Duration;
 
-use futures::future::{Future};
+use futures::future::{loop_fn, ok, Either, Future, Loop};
+use futures::sync::mpsc;
 use futures::Stream;
-use json::parse as json_parse;
+use json::{object, parse as json_parse, JsonValue};
+use lapin_futures::error::{Error as LapinError};
+use lapin_futures::message::Delivery;
 use lapin_futures_rustls::lapin::channel::{
     BasicConsumeOptions, BasicProperties, BasicPublishOptions, QueueBindOptions,
-    QueueDeclareOptions, QueueDeleteOptions, QueueUnbindOptions,
+    QueueDeleteOptions, QueueUnbindOptions,
 };
 use lapin_futures_rustls::lapin::types::{AMQPValue, FieldTable};
 use log::{error, info, warn};
+use tokio::timer::Timeout;
+use tungstenite::Message;
 
 use crate::error::PathfinderError;
-use crate::rabbitmq::{RabbitMQContext};
+use crate::rabbitmq::{DirectReplyTo, RabbitMQContext, DIRECT_REPLY_TO_QUEUE};
 use crate::engine::MessageSender;
+use crate::engine::encoding::ContentEncoding;
 use crate::engine::options::RpcOptions;
-use crate::engine::serializer::Serializer;
+use crate::engine::router::ReadOnlyEndpoint;
+use crate::engine::serializer::{ContentType, JsonMessage, Serializer};
+use crate::engine::transformer::TransformerChain;
+use crate::engine::utils::{get_correlation_id, get_event_name};
+use crate::tracing::{ActiveSpan, SpanKind, TRACEPARENT_HEADER};
+
+/// Default time to wait for a response from a microservice.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default time to wait for a confirmation of the published request.
+pub const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default maximum size of the decompressed response in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1_048_576;
+/// The header of the response part, that is followed by other parts.
+pub const PARTIAL_RESPONSE_HEADER: &str = "partial_response";
+/// The message for clients when the request failed in RabbitMQ client.
+const BROKER_ERROR_MESSAGE: &str = "The request wasn't processed. Please, try once again.";
+
+/// The step of the RPC request, that is mentioned in the logs when it fails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RpcStage {
+    DeclareQueue,
+    BindQueue,
+    Publish,
+    Consume,
+    Acknowledge
+}
+
+impl fmt::Display for RpcStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            RpcStage::DeclareQueue => "declaring the reply queue",
+            RpcStage::BindQueue => "binding the reply queue",
+            RpcStage::Publish => "publishing the request",
+            RpcStage::Consume => "consuming the response",
+            RpcStage::Acknowledge => "acknowledging the response"
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// The response of the microservice, that was consumed from the response queue.
+enum ConsumedResponse {
+    /// A single response that must be sent to the client.
+    Single(Box<Delivery>),
+    /// All parts of the streamed response were forwarded to the client.
+    Streamed,
+    /// Nothing was received in time.
+    Missing
+}
+
+/// The way of receiving the response of the microservice.
+enum ReplyMode {
+    /// The response isn't awaited, so that nothing is declared or consumed.
+    None,
+    /// The response is received via the direct reply-to pseudo-queue.
+    DirectReplyTo(Arc<DirectReplyTo>),
+    /// The response is consumed from a separate queue of the request.
+    ReplyQueue
+}
+
+/// Returns the way of receiving the response for the request. Endpoints
+/// in the "fire and forget" mode don't wait for responses in any case.
+fn get_reply_mode(options: &RpcOptions, direct_reply_to: Option<Arc<DirectReplyTo>>) -> ReplyMode {
+    let is_response_required = match options.get_endpoint() {
+        Some(endpoint) => endpoint.is_response_required(),
+        None => true
+    };
+    match (is_response_required, direct_reply_to) {
+        (false, _) => ReplyMode::None,
+        (true, Some(direct_reply_to)) => ReplyMode::DirectReplyTo(direct_reply_to),
+        (true, None) => ReplyMode::ReplyQueue
+    }
+}
 
 /// Simple future that sends a RPC request to the certain microservice,
 /// consumes from a response from a separate queue and then returns a
@@ -31,24 +112,38 @@ pub fn rpc_request_future(
     options: Arc<RpcOptions>,
     headers: HashMap<String, String>
 ) -> Box<Future<Item=(), Error=PathfinderError> + Send + Sync + 'static> {
+    match get_reply_mode(&options, rabbitmq_context.get_direct_reply_to()) {
+        ReplyMode::None => return publish_request_future(transmitter, rabbitmq_context, options, headers),
+        ReplyMode::DirectReplyTo(direct_reply_to) => {
+            return direct_rpc_request_future(transmitter, rabbitmq_context, direct_reply_to, options, headers);
+        },
+        ReplyMode::ReplyQueue => {}
+    }
+
+    let correlation_id = get_correlation_id(&options.get_message().unwrap());
+    let content_type = options.get_content_type().unwrap_or_default();
+    let transmitter_for_ack = transmitter.clone();
+    let transmitter_for_parts = transmitter.clone();
     let rabbitmq_context_local = rabbitmq_context.clone();
     let publish_channel = rabbitmq_context_local.get_publish_channel();
     let consume_channel = rabbitmq_context_local.get_consume_channel();
+    let is_confirm_mode = rabbitmq_context_local.is_confirm_mode();
 
     let queue_name = options.get_queue_name().unwrap().clone();
-    let queue_declare_options = QueueDeclareOptions {
-        passive: false,
-        durable: true,
-        exclusive: true,
-        auto_delete: false,
-        ..Default::default()
-    };
+    let reply_queue = options.get_reply_queue().unwrap_or_default();
+    let queue_declare_options = reply_queue.get_declare_options();
+    let queue_declare_arguments = reply_queue.get_declare_arguments();
 
     Box::new(
-        // 1. Declare a response queue
-        consume_channel
-            .queue_declare(&queue_name, queue_declare_options, FieldTable::new())
-            .map(move |queue| (publish_channel, consume_channel, queue, options))
+        // 1. Declare a response queue, when the rate of declarations allows it
+        rabbitmq_context_local
+            .wait_for_queue_declare()
+            .and_then(move |_| {
+                consume_channel
+                    .queue_declare(&queue_name, queue_declare_options, queue_declare_arguments)
+                    .map(move |queue| (publish_channel, consume_channel, queue, options))
+            })
+            .map_err(|err| (RpcStage::DeclareQueue, err))
         // 2. Link the response queue the exchange
         .and_then(move |(publish_channel, consume_channel, queue, options)| {
             let queue_name = options.get_queue_name().unwrap().clone();
@@ -58,123 +153,1220 @@ pub fn rpc_request_future(
             consume_channel
                 .queue_bind(
                     &queue_name,
-                    &endpoint.get_response_exchange(),
+                    endpoint.get_response_exchange(),
                     &routing_key,
                     QueueBindOptions::default(),
                     FieldTable::new()
                 )
+                .map_err(|err| (RpcStage::BindQueue, err))
                 .map(move |_| (publish_channel, consume_channel, queue, options))
         })
         // 3. Publish message into the microservice queue and make ensure that it's delivered
         .and_then(move |(publish_channel, consume_channel, queue, options)| {
+            // lapin 0.17 doesn't handle `basic.return`, so unroutable requests can't be detected here
             let publish_message_options = BasicPublishOptions {
                 mandatory: true,
                 immediate: false,
                 ..Default::default()
             };
 
-            let mut message_headers = FieldTable::new();
-            for (key, value) in headers.clone().iter() {
-                let header_name = key.clone();
-                let header_value = AMQPValue::LongString(value.clone());
-                message_headers.insert(header_name, header_value);
-            }
-
             let endpoint = options.get_endpoint().unwrap().clone();
             let message = options.get_message().unwrap().clone();
             let queue_name_response = options.get_queue_name().unwrap().clone();
-            let event_name = message["event-name"].as_str().unwrap_or("null");
-            let basic_properties = BasicProperties::default()
-                .with_content_type("application/json".to_string())    // Content type
+            let correlation_id = get_correlation_id(&message);
+            let mut message_headers = get_message_headers(&headers, &message);
+            let mut publish_span = start_span(&options, "publish", SpanKind::Producer, &mut message_headers);
+            let mut basic_properties = BasicProperties::default()
+                .with_content_type(endpoint.get_content_type().to_string())       // Content type
                 .with_headers(message_headers)                        // Headers for the message
                 .with_delivery_mode(2)                                // Message must be persistent
                 .with_reply_to(queue_name_response.to_string())       // Response queue
-                .with_correlation_id(event_name.clone().to_string()); // Event name
-
-            publish_channel
-                .basic_publish(
-                    &endpoint.get_request_exchange(),
-                    &endpoint.get_routing_key(),
-                    message["content"].dump().as_bytes().to_vec(),
-                    publish_message_options,
-                    basic_properties
-                )
-                .map(move |confirmation| {
-                    match confirmation {
-                        Some(_) => info!("Publish message got confirmation."),
-                        None => warn!("Request wasn't delivered."),
-                    };
+                .with_correlation_id(correlation_id.clone());         // Request ID
+            if let Some(content_encoding) = endpoint.get_content_encoding() {
+                basic_properties = basic_properties.with_content_encoding(content_encoding.as_str().to_string());
+            }
+
+            let confirm_timeout = options.get_confirm_timeout().unwrap_or(DEFAULT_CONFIRM_TIMEOUT);
+            let publish_future = publish_channel.basic_publish(
+                endpoint.get_request_exchange(),
+                &options.get_routing_key().unwrap(),
+                get_request_body(&message, &endpoint),
+                publish_message_options,
+                basic_properties
+            );
 
-                    (publish_channel, consume_channel, queue, options)
+            wait_for_confirmation(publish_future, is_confirm_mode, confirm_timeout, correlation_id)
+                .map_err(|err| (RpcStage::Publish, err))
+                .then(move |result| {
+                    if let (Some(span), false) = (publish_span.as_mut(), matches!(result, Ok(Ok(_)))) {
+                        span.set_error();
+                    }
+                    result
                 })
-        })
-        // 4. Consume a response message from the queue, that was declared on the 1st step
-        .and_then(move |(publish_channel, consume_channel, queue, options)| {
-            consume_channel
-                .basic_consume(
-                    &queue,
-                    "response_consumer",
-                    BasicConsumeOptions::default(),
-                    FieldTable::new()
-                )
-                .and_then(move |stream| {
-                    stream
-                        .take(1)
-                        .into_future()
-                        .map_err(|(err, _)| err)
-                        .map(move |(message, _)| (publish_channel, consume_channel, queue, message.unwrap(), options))
+                .map(move |confirmation| {
+                    send_ack_if_required(&confirmation, &options, &transmitter_for_ack);
+                    (publish_channel, consume_channel, queue, confirmation, options)
                 })
         })
+        // 4. Consume a response message from the queue, that was declared on the 1st step,
+        // unless the request wasn't confirmed and the response will never come
+        .and_then(move |(publish_channel, consume_channel, queue, confirmation, options)| {
+            if let Err(error) = confirmation {
+                return Either::A(ok((publish_channel, consume_channel, queue, Err(error), options)));
+            }
+
+            let timeout = options.get_timeout().unwrap_or(DEFAULT_RPC_TIMEOUT);
+            let mut consume_span = options.get_trace().map(|trace| trace.start_span("consume", SpanKind::Consumer));
+            let consumer_tag = options.get_reply_queue().unwrap_or_default().get_consumer_tag(&options.get_queue_name().unwrap());
+            let consumer_future = consume_channel.basic_consume(
+                &queue,
+                &consumer_tag,
+                BasicConsumeOptions::default(),
+                FieldTable::new()
+            ).map_err(|err| (RpcStage::Consume, err));
+
+            // The parts of the streamed response are acknowledged and forwarded
+            // to the client one by one
+            if options.get_endpoint().unwrap().is_response_streamed() {
+                let ack_channel = consume_channel.clone();
+                let transmitter = transmitter_for_parts.clone();
+                let options_inner = options.clone();
+                let correlation_id = get_correlation_id(&options.get_message().unwrap());
+                return Either::B(Either::A(
+                    consumer_future
+                        .map(move |stream| {
+                            stream
+                                .and_then(move |message| ack_channel.basic_ack(message.delivery_tag, false).map(move |_| message))
+                                .map_err(move |err| get_broker_error(err, &correlation_id))
+                        })
+                        .and_then(move |parts| forward_response_parts(parts, options_inner, transmitter).then(Ok))
+                        .then(move |result| {
+                            if let (Some(span), false) = (consume_span.as_mut(), matches!(result, Ok(Ok(_)))) {
+                                span.set_error();
+                            }
+                            result
+                        })
+                        .map(move |result| {
+                            let response = result.map(|_| ConsumedResponse::Streamed);
+                            (publish_channel, consume_channel, queue, response, options)
+                        })
+                ));
+            }
+
+            Either::B(Either::B(
+                consumer_future
+                    .and_then(move |stream| take_first_with_timeout(stream, timeout).map_err(|err| (RpcStage::Consume, err)))
+                    .then(move |result| {
+                        if let (Some(span), false) = (consume_span.as_mut(), matches!(result, Ok(Some(_)))) {
+                            span.set_error();
+                        }
+                        result
+                    })
+                    .map(move |message| (publish_channel, consume_channel, queue, Ok(get_consumed_response(message)), options))
+            ))
+        })
         // 5. Prepare a response for a client, serialize and sent via WebSocket transmitter
         .and_then(move |(publish_channel, consume_channel, queue, message, options)| {
-            let raw_data = from_utf8(&message.data).unwrap();
-            let json = Arc::new(Box::new(json_parse(raw_data).unwrap()));
-            let serializer = Serializer::new();
-            let response = serializer.serialize(json.dump()).unwrap();
-            let transmitter_local = transmitter.clone();
-            transmitter_local.unbounded_send(response).unwrap_or(());
+            let message = match message {
+                Ok(ConsumedResponse::Single(message)) => *message,
+                Ok(ConsumedResponse::Streamed) => {
+                    let result: Box<dyn Future<Item=_, Error=_> + Send + Sync + 'static> = Box::new(
+                        futures::future::ok((publish_channel, consume_channel, queue, options, Ok(())))
+                    );
+                    return result;
+                }
+                Err(error) => {
+                    let result: Box<dyn Future<Item=_, Error=_> + Send + Sync + 'static> = Box::new(
+                        futures::future::ok((publish_channel, consume_channel, queue, options, Err(error)))
+                    );
+                    return result;
+                }
+                Ok(ConsumedResponse::Missing) => {
+                    let response = get_timed_out_response(&options);
+                    let result: Box<dyn Future<Item=_, Error=_> + Send + Sync + 'static> = Box::new(
+                        futures::future::ok((publish_channel, consume_channel, queue, options, response))
+                    );
+                    return result;
+                }
+            };
 
-            consume_channel
-                .basic_ack(message.delivery_tag, false)
-                .map(move |_confirmation| (publish_channel, consume_channel, queue, options))
+            let endpoint = options.get_endpoint().unwrap();
+            let transformers = options.get_transformers().unwrap_or_default();
+            let max_message_size = options.get_max_message_size().unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+            let response = send_response(&message, &endpoint, &transformers, content_type, max_message_size, &transmitter);
+            Box::new(
+                consume_channel
+                    .basic_ack(message.delivery_tag, false)
+                    .map_err(|err| (RpcStage::Acknowledge, err))
+                    .map(move |_confirmation| (publish_channel, consume_channel, queue, options, response))
+            )
         })
-        // 6. Unbind the response queue from the exchange point
-        .and_then(move |(publish_channel, consume_channel, _queue, options)| {
+        // 6. Unbind the response queue from the exchange point and delete it. The client
+        // already got the response, so failures of the clean up are only logged
+        .and_then(move |(_publish_channel, consume_channel, _queue, options, response)| {
             let queue_name = options.get_queue_name().unwrap().clone();
             let routing_key = options.get_queue_name().unwrap().clone();
             let endpoint = options.get_endpoint().unwrap().clone();
-
-            consume_channel
-                .queue_unbind(
-                    &queue_name,
-                    &endpoint.get_response_exchange(),
-                    &routing_key,
-                    QueueUnbindOptions::default(),
-                    FieldTable::new(),
-                )
-                .map(move |_| (publish_channel, consume_channel, options))
-        })
-        // 7. Delete the response queue
-        .and_then(move |(_publish_channel, consume_channel, options)| {
             let queue_delete_options = QueueDeleteOptions {
                 if_unused: false,
                 if_empty: false,
                 ..Default::default()
             };
-            let queue_name = options.get_queue_name().unwrap().clone();
 
-            consume_channel
-                .queue_delete(&queue_name, queue_delete_options)
-                .map(move |_| ())
+            let unbind_future = consume_channel.queue_unbind(
+                &queue_name,
+                endpoint.get_response_exchange(),
+                &routing_key,
+                QueueUnbindOptions::default(),
+                FieldTable::new(),
+            );
+            let get_delete_future = move || consume_channel.queue_delete(&queue_name, queue_delete_options);
+            let correlation_id = get_correlation_id(&options.get_message().unwrap());
+            clean_up_reply_queue(unbind_future, get_delete_future, correlation_id).then(move |_| Ok(response))
         })
-        // 8. Returns the result to the caller as future
+        // 7. Returns the result to the caller as future
         .then(move |result| match result {
-            Ok(_) => Ok(()),
+            Ok(response) => response,
+            Err((stage, err)) => Err(get_broker_error_at_stage(stage, err, &correlation_id))
+        })
+    )
+}
+
+/// Sends the ping request on health checks in the same way as the
+/// `rpc_request_future` does and checks that any response was received in
+/// time. The content of the response doesn't matter, so it's dropped.
+pub fn ping_request_future(
+    rabbitmq_context: Arc<RabbitMQContext>,
+    options: Arc<RpcOptions>
+) -> impl Future<Item=bool, Error=()> {
+    let (transmitter, receiver) = mpsc::unbounded();
+    let correlation_id = get_correlation_id(&options.get_message().unwrap());
+    rpc_request_future(Arc::new(transmitter), rabbitmq_context, options, HashMap::new())
+        .then(move |result| {
+            drop(receiver);
+            if let Err(ref err) = result {
+                warn!("[{}] The health check ping failed: {}", correlation_id, err);
+            }
+            Ok(result.is_ok())
+        })
+}
+
+/// Publishes the request of the endpoint in the "fire and forget" mode and
+/// completes right after the confirmation, without declaring a reply queue
+/// and waiting for the response. The client gets only the acknowledgement,
+/// when the endpoint requires it.
+fn publish_request_future(
+    transmitter: MessageSender,
+    rabbitmq_context: Arc<RabbitMQContext>,
+    options: Arc<RpcOptions>,
+    headers: HashMap<String, String>
+) -> Box<dyn Future<Item=(), Error=PathfinderError> + Send + Sync + 'static> {
+    let endpoint = options.get_endpoint().unwrap().clone();
+    let message = options.get_message().unwrap().clone();
+    let correlation_id = get_correlation_id(&message);
+    let mut message_headers = get_message_headers(&headers, &message);
+    let mut publish_span = start_span(&options, "publish", SpanKind::Producer, &mut message_headers);
+
+    let publish_future = publish_request(
+        rabbitmq_context,
+        correlation_id.clone(),
+        correlation_id,
+        None,
+        endpoint.get_request_exchange(),
+        options.get_routing_key().unwrap(),
+        get_request_body(&message, &endpoint),
+        endpoint.get_content_type(),
+        endpoint.get_content_encoding(),
+        message_headers,
+        options.get_confirm_timeout().unwrap_or(DEFAULT_CONFIRM_TIMEOUT)
+    );
+    Box::new(
+        publish_future.then(move |result| {
+            send_ack_if_required(&result, &options, &transmitter);
+            if let (Some(span), true) = (publish_span.as_mut(), result.is_err()) {
+                span.set_error();
+            }
+            result
+        })
+    )
+}
+
+/// Wraps the first response taken from the reply queue, if any.
+fn get_consumed_response(message: Option<Delivery>) -> ConsumedResponse {
+    match message {
+        Some(message) => ConsumedResponse::Single(Box::new(message)),
+        None => ConsumedResponse::Missing
+    }
+}
+
+/// Returns the error for the client, when the response of the microservice
+/// wasn't received in time.
+fn get_timed_out_response(options: &RpcOptions) -> Result<(), PathfinderError> {
+    let routing_key = options.get_routing_key().unwrap();
+    let correlation_id = get_correlation_id(&options.get_message().unwrap());
+    warn!("[{}] The response for \"{}\" wasn't received in time.", correlation_id, routing_key);
+    let message = String::from("The request timed out. Please, try once again.");
+    Err(PathfinderError::MessageBrokerError(message))
+}
+
+/// Unbinds the reply queue and deletes it. The queue is deleted even when it
+/// wasn't unbound, and failures of both operations are logged instead of
+/// being returned, because they don't affect the response of the request.
+fn clean_up_reply_queue<U, D, F>(unbind_future: U, get_delete_future: F, correlation_id: String) -> impl Future<Item=(), Error=()>
+    where U: Future<Error=LapinError>,
+          F: FnOnce() -> D,
+          D: Future<Error=LapinError>
+{
+    let correlation_id_inner = correlation_id.clone();
+    unbind_future
+        .then(move |result| {
+            if let Err(err) = result {
+                warn!("[{}] The reply queue wasn't unbound: {}", correlation_id_inner, err);
+            }
+            get_delete_future()
+        })
+        .then(move |result| {
+            if let Err(err) = result {
+                warn!("[{}] The reply queue wasn't deleted: {}", correlation_id, err);
+            }
+            Ok(())
+        })
+}
+
+/// Sends a RPC request to the certain microservice in the same way as the
+/// `rpc_request_future` does, but receives the response via the direct
+/// reply-to instead of declaring a separate response queue.
+fn direct_rpc_request_future(
+    transmitter: MessageSender,
+    rabbitmq_context: Arc<RabbitMQContext>,
+    direct_reply_to: Arc<DirectReplyTo>,
+    options: Arc<RpcOptions>,
+    headers: HashMap<String, String>
+) -> Box<dyn Future<Item=(), Error=PathfinderError> + Send + Sync + 'static> {
+    let endpoint = options.get_endpoint().unwrap().clone();
+    let routing_key = options.get_routing_key().unwrap();
+    let message = options.get_message().unwrap().clone();
+    let timeout = options.get_timeout().unwrap_or(DEFAULT_RPC_TIMEOUT);
+    let content_type = options.get_content_type().unwrap_or_default();
+    let transformers = options.get_transformers().unwrap_or_default();
+    let max_message_size = options.get_max_message_size().unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+
+    let correlation_id = get_correlation_id(&message);
+    let mut message_headers = get_message_headers(&headers, &message);
+    // The response is received by the shared consumer, so the publishing
+    // and the waiting for the response are recorded as one span
+    let mut rpc_span = start_span(&options, "rpc", SpanKind::Client, &mut message_headers);
+
+    // The parts of the streamed response are forwarded to the client until
+    // the last one is received
+    if endpoint.is_response_streamed() {
+        let (request_id, parts) = direct_reply_to.register_stream(&correlation_id);
+        let parts = parts.map_err(|_| PathfinderError::MessageBrokerError(String::from("The response stream was closed.")));
+        let publish_future = publish_request(
+            rabbitmq_context,
+            correlation_id,
+            request_id.clone(),
+            Some(DIRECT_REPLY_TO_QUEUE),
+            endpoint.get_request_exchange(),
+            routing_key,
+            get_request_body(&message, &endpoint),
+            endpoint.get_content_type(),
+            endpoint.get_content_encoding(),
+            message_headers,
+            options.get_confirm_timeout().unwrap_or(DEFAULT_CONFIRM_TIMEOUT)
+        );
+        return Box::new(
+            publish_future
+                .then(move |result| {
+                    send_ack_if_required(&result, &options, &transmitter);
+                    result.map(|_| (options, transmitter))
+                })
+                .and_then(move |(options, transmitter)| forward_response_parts(parts, options, transmitter))
+                .then(move |result| {
+                    direct_reply_to.cancel(&request_id);
+                    if let (Some(span), true) = (rpc_span.as_mut(), result.is_err()) {
+                        span.set_error();
+                    }
+                    result
+                })
+        );
+    }
+
+    // The acknowledgement is sent between the publishing and the waiting for
+    // the response, so the request is published here instead of using the
+    // `direct_reply_to_future`
+    let (request_id, receiver) = direct_reply_to.register(&correlation_id);
+    let publish_future = publish_request(
+        rabbitmq_context,
+        correlation_id.clone(),
+        request_id.clone(),
+        Some(DIRECT_REPLY_TO_QUEUE),
+        endpoint.get_request_exchange(),
+        routing_key.clone(),
+        get_request_body(&message, &endpoint),
+        endpoint.get_content_type(),
+        endpoint.get_content_encoding(),
+        message_headers,
+        options.get_confirm_timeout().unwrap_or(DEFAULT_CONFIRM_TIMEOUT)
+    );
+    let transmitter_for_ack = transmitter.clone();
+    Box::new(
+        publish_future
+            .then(move |result| {
+                send_ack_if_required(&result, &options, &transmitter_for_ack);
+                result
+            })
+            .and_then(move |_| Timeout::new(receiver, timeout).then(|result| Ok(result.ok())))
+            .then(move |result| {
+                direct_reply_to.cancel(&request_id);
+                if let (Some(span), false) = (rpc_span.as_mut(), matches!(result, Ok(Some(_)))) {
+                    span.set_error();
+                }
+                drop(rpc_span);
+                get_direct_reply_to_response(result, &routing_key, &correlation_id)
+                    .and_then(|message| send_response(&message, &endpoint, &transformers, content_type, max_message_size, &transmitter))
+            })
+    )
+}
+
+/// Publishes a request with the `reply_to` property set to the
+/// `amq.rabbitmq.reply-to` pseudo-queue and waits for the response, that
+/// will be routed to the request by the correlation ID. Returns `None`
+/// when the response wasn't received in time and an error when the request
+/// wasn't confirmed by the broker.
+#[allow(clippy::too_many_arguments)]
+pub fn direct_reply_to_future(
+    rabbitmq_context: Arc<RabbitMQContext>,
+    direct_reply_to: Arc<DirectReplyTo>,
+    correlation_id: String,
+    exchange: String,
+    routing_key: String,
+    body: Vec<u8>,
+    content_type: String,
+    headers: FieldTable,
+    timeout: Duration,
+    confirm_timeout: Duration
+) -> impl Future<Item=Option<Delivery>, Error=PathfinderError> + Send + Sync + 'static {
+    let (request_id, receiver) = direct_reply_to.register(&correlation_id);
+    publish_request(
+        rabbitmq_context, correlation_id, request_id.clone(), Some(DIRECT_REPLY_TO_QUEUE), &exchange, routing_key, body,
+        &content_type, None, headers, confirm_timeout
+    )
+        .and_then(move |_| Timeout::new(receiver, timeout).then(|result| Ok(result.ok())))
+        .then(move |result| {
+            direct_reply_to.cancel(&request_id);
+            result
+        })
+}
+
+/// Publishes a request with the `reply_to` property set to the queue of
+/// responses (e.g. the `amq.rabbitmq.reply-to` pseudo-queue) and waits for
+/// the confirmation. Requests without the queue don't expect responses. The
+/// request is published with the `request_id` as the correlation ID, whereas
+/// the correlation ID of the client is used for logging.
+#[allow(clippy::too_many_arguments)]
+fn publish_request(
+    rabbitmq_context: Arc<RabbitMQContext>,
+    correlation_id: String,
+    request_id: String,
+    reply_to: Option<&str>,
+    exchange: &str,
+    routing_key: String,
+    body: Vec<u8>,
+    content_type: &str,
+    content_encoding: Option<ContentEncoding>,
+    headers: FieldTable,
+    confirm_timeout: Duration
+) -> impl Future<Item=(), Error=PathfinderError> + Send + Sync + 'static {
+    let publish_channel = rabbitmq_context.get_publish_channel();
+    let publish_message_options = BasicPublishOptions {
+        mandatory: true,
+        immediate: false,
+        ..Default::default()
+    };
+    let mut basic_properties = BasicProperties::default()
+        .with_content_type(content_type.to_string())          // Content type
+        .with_headers(headers)                                // Headers for the message
+        .with_delivery_mode(2)                                // Message must be persistent
+        .with_correlation_id(request_id);                     // Request ID
+    if let Some(reply_to) = reply_to {
+        basic_properties = basic_properties.with_reply_to(reply_to.to_string());
+    }
+    if let Some(content_encoding) = content_encoding {
+        basic_properties = basic_properties.with_content_encoding(content_encoding.as_str().to_string());
+    }
+
+    let publish_future = publish_channel.basic_publish(exchange, &routing_key, body, publish_message_options, basic_properties);
+    let correlation_id_inner = correlation_id.clone();
+    wait_for_confirmation(publish_future, rabbitmq_context.is_confirm_mode(), confirm_timeout, correlation_id)
+        .map_err(move |err| get_broker_error(err, &correlation_id_inner))
+        .and_then(|confirmation| confirmation)
+}
+
+/// Forwards the parts of the streamed response to the client as soon as
+/// they're received, until the part without the `partial_response` header.
+/// The time to wait for a response is applied to each part separately.
+fn forward_response_parts<S>(
+    parts: S,
+    options: Arc<RpcOptions>,
+    transmitter: MessageSender
+) -> impl Future<Item=(), Error=PathfinderError> + Send + Sync + 'static
+    where S: Stream<Item=Delivery, Error=PathfinderError> + Send + Sync + 'static
+{
+    let timeout = options.get_timeout().unwrap_or(DEFAULT_RPC_TIMEOUT);
+    loop_fn((parts, 0), move |(parts, index)| {
+        let options = options.clone();
+        let transmitter = transmitter.clone();
+        Timeout::new(parts.into_future(), timeout).then(move |result| match result {
+            Ok((Some(message), parts)) => {
+                let is_last = !is_partial_response(&message);
+                let transformers = options.get_transformers().unwrap_or_default();
+                let content_type = options.get_content_type().unwrap_or_default();
+                let max_message_size = options.get_max_message_size().unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+                send_response_part(&message, &transformers, content_type, max_message_size, &transmitter, index, is_last)?;
+                match is_last {
+                    true => Ok(Loop::Break(())),
+                    false => Ok(Loop::Continue((parts, index + 1)))
+                }
+            },
+            Ok((None, _)) => {
+                let message = String::from("The response stream was closed before the last part.");
+                Err(PathfinderError::MessageBrokerError(message))
+            },
             Err(err) => {
-                error!("Error in RabbitMQ client. Reason: {}", err);
-                let message = String::from("The request wasn't processed. Please, try once again.");
+                if let Some((error, _)) = err.into_inner() {
+                    return Err(error);
+                }
+                let routing_key = options.get_routing_key().unwrap();
+                let correlation_id = get_correlation_id(&options.get_message().unwrap());
+                warn!("[{}] The part {} of the response for \"{}\" wasn't received in time.", correlation_id, index, routing_key);
+                let message = String::from("The request timed out. Please, try once again.");
                 Err(PathfinderError::MessageBrokerError(message))
             }
         })
-    )
+    })
+}
+
+/// Checks whether the part of the streamed response is followed by other
+/// parts. The header can be a boolean or a `"true"` string.
+fn is_partial_response(message: &Delivery) -> bool {
+    let value = message.properties.headers().as_ref().and_then(|headers| headers.get(PARTIAL_RESPONSE_HEADER));
+    match value {
+        Some(AMQPValue::Boolean(value)) => *value,
+        Some(AMQPValue::LongString(value)) => value == "true",
+        _ => false
+    }
+}
+
+/// Converts the result of the `direct_reply_to_future` into the received
+/// response or an error, that will be returned to the client.
+pub fn get_direct_reply_to_response(
+    result: Result<Option<Delivery>, PathfinderError>,
+    routing_key: &str,
+    correlation_id: &str
+) -> Result<Delivery, PathfinderError> {
+    match result {
+        Ok(Some(message)) => Ok(message),
+        Ok(None) => {
+            warn!("[{}] The response for \"{}\" wasn't received in time.", correlation_id, routing_key);
+            let message = String::from("The request timed out. Please, try once again.");
+            Err(PathfinderError::MessageBrokerError(message))
+        },
+        Err(error) => Err(error)
+    }
+}
+
+/// Logs the error of the RabbitMQ client and returns the error, that will
+/// be returned to the client.
+fn get_broker_error(err: LapinError, correlation_id: &str) -> PathfinderError {
+    error!("[{}] Error in RabbitMQ client. Reason: {}", correlation_id, err);
+    let message = String::from(BROKER_ERROR_MESSAGE);
+    PathfinderError::MessageBrokerError(message)
+}
+
+/// Logs the error of RabbitMQ client with the step of the request that
+/// failed. The client gets the same generic error as for other failures.
+fn get_broker_error_at_stage(stage: RpcStage, err: LapinError, correlation_id: &str) -> PathfinderError {
+    error!("{}", get_broker_error_log(stage, &err, correlation_id));
+    let message = String::from(BROKER_ERROR_MESSAGE);
+    PathfinderError::MessageBrokerError(message)
+}
+
+/// Returns the log record for the error of RabbitMQ client at the step.
+fn get_broker_error_log(stage: RpcStage, err: &LapinError, correlation_id: &str) -> String {
+    format!("[{}] Error in RabbitMQ client while {}. Reason: {}", correlation_id, stage, err)
+}
+
+/// Waits for the confirmation of the published request. Returns an error
+/// in the result when the broker rejected the request or didn't confirm it
+/// in time, so that the caller doesn't wait for a response that will never
+/// come. Errors of the RabbitMQ client are passed as is.
+pub fn confirm_publish<F>(
+    publish_future: F,
+    timeout: Duration,
+    correlation_id: String
+) -> impl Future<Item=Result<(), PathfinderError>, Error=LapinError>
+    where F: Future<Item=Option<u64>, Error=LapinError>
+{
+    Timeout::new(publish_future, timeout)
+        .then(move |result| match result {
+            Ok(Some(_)) => {
+                info!("[{}] Publish message got confirmation.", correlation_id);
+                Ok(Ok(()))
+            },
+            Ok(None) => {
+                warn!("[{}] Request was rejected by RabbitMQ.", correlation_id);
+                let message = String::from("The request wasn't accepted by the message broker. Please, try once again.");
+                Ok(Err(PathfinderError::MessageBrokerError(message)))
+            },
+            Err(err) => {
+                if err.is_inner() {
+                    return Err(err.into_inner().unwrap());
+                }
+                warn!("[{}] Request wasn't confirmed in time.", correlation_id);
+                let message = String::from("The request wasn't confirmed by the message broker. Please, try once again.");
+                Ok(Err(PathfinderError::MessageBrokerError(message)))
+            }
+        })
+}
+
+/// Waits for the confirmation of the published request when the channel is
+/// in the confirm mode. Otherwise the broker doesn't confirm anything, so the
+/// request is considered as accepted right after it was sent.
+fn wait_for_confirmation<F>(
+    publish_future: F,
+    is_confirm_mode: bool,
+    timeout: Duration,
+    correlation_id: String
+) -> impl Future<Item=Result<(), PathfinderError>, Error=LapinError>
+    where F: Future<Item=Option<u64>, Error=LapinError>
+{
+    match is_confirm_mode {
+        true => Either::A(confirm_publish(publish_future, timeout, correlation_id)),
+        false => Either::B(publish_future.map(|_| Ok(())))
+    }
+}
+
+/// Notifies the client that the request was accepted by the message broker,
+/// when it's required by the endpoint. Nothing is sent for rejected requests.
+fn send_ack_if_required<T>(confirmation: &Result<T, PathfinderError>, options: &RpcOptions, transmitter: &MessageSender) {
+    let is_acked = options.get_endpoint().is_some_and(|endpoint| endpoint.is_request_acked());
+    if !is_acked || confirmation.is_err() {
+        return;
+    }
+
+    let correlation_id = get_correlation_id(&options.get_message().unwrap());
+    let ack = object!{"type" => "ack", "correlation-id" => correlation_id.clone()};
+    if let Err(err) = send_json_response(ack, options.get_content_type().unwrap_or_default(), transmitter) {
+        warn!("[{}] The acknowledgement wasn't sent: {}", correlation_id, err);
+    }
+}
+
+/// Returns the body of the request to the microservice. For microservices
+/// that don't communicate in JSON the string content is passed as is. When
+/// the message doesn't have any content, an empty object is published to
+/// microservices that communicate in JSON, and an empty body to the others.
+/// The body is compressed when the endpoint requires it.
+pub fn get_request_body(message: &JsonMessage, endpoint: &ReadOnlyEndpoint) -> Vec<u8> {
+    let content = &message["content"];
+    let body = match (endpoint.is_json(), content.is_null(), content.as_str()) {
+        (true, true, _) => b"{}".to_vec(),
+        (false, true, _) => Vec::new(),
+        (false, false, Some(content)) => content.as_bytes().to_vec(),
+        _ => content.dump().as_bytes().to_vec()
+    };
+
+    match endpoint.get_content_encoding() {
+        Some(content_encoding) => content_encoding.encode(&body),
+        None => body
+    }
+}
+
+/// Returns the body of the response, decompressed according to its
+/// `content_encoding` property. Bodies in unknown encodings are passed as is.
+/// Fails when the decompressed body exceeds `max_message_size` bytes.
+fn get_response_body(message: &Delivery, max_message_size: usize) -> Result<Cow<'_, [u8]>, PathfinderError> {
+    let content_encoding = message.properties
+        .content_encoding()
+        .as_ref()
+        .and_then(|name| ContentEncoding::from_name(name));
+
+    match content_encoding {
+        Some(content_encoding) => content_encoding
+            .decode(&message.data, max_message_size)
+            .map(Cow::Owned)
+            .map_err(|err| {
+                let message = format!("The response of the microservice couldn't be decompressed: {}", err);
+                PathfinderError::MessageBrokerError(message)
+            }),
+        None => Ok(Cow::Borrowed(&message.data))
+    }
+}
+
+/// Sends the response of the microservice to the client. When the microservice
+/// returned an error, it's passed to the caller instead, so that the client
+/// will get it with the status and the code like any other error. Successful
+/// responses are modified by the transformers of the endpoint, whereas responses
+/// of microservices that don't communicate in JSON are passed as is.
+fn send_response(
+    message: &Delivery,
+    endpoint: &ReadOnlyEndpoint,
+    transformers: &TransformerChain,
+    content_type: ContentType,
+    max_message_size: usize,
+    transmitter: &MessageSender
+) -> Result<(), PathfinderError> {
+    if !endpoint.is_json() {
+        let response = match String::from_utf8(get_response_body(message, max_message_size)?.into_owned()) {
+            Ok(text) => Message::Text(text),
+            Err(error) => Message::Binary(error.into_bytes())
+        };
+        transmitter.unbounded_send(response).unwrap_or(());
+        return Ok(());
+    }
+
+    let json = get_json_response(message, transformers, max_message_size)?;
+    send_json_response(json, content_type, transmitter)
+}
+
+/// Sends the part of the streamed response to the client. The part contains
+/// the `part` field with its index and the `last-part` field, that is `true`
+/// only for the last part.
+fn send_response_part(
+    message: &Delivery,
+    transformers: &TransformerChain,
+    content_type: ContentType,
+    max_message_size: usize,
+    transmitter: &MessageSender,
+    index: usize,
+    is_last: bool
+) -> Result<(), PathfinderError> {
+    let mut json = get_json_response(message, transformers, max_message_size)?;
+    json["part"] = index.into();
+    json["last-part"] = is_last.into();
+    send_json_response(json, content_type, transmitter)
+}
+
+/// Returns the JSON response of the microservice modified by the transformers.
+/// An error of the microservice is returned as is.
+fn get_json_response(message: &Delivery, transformers: &TransformerChain, max_message_size: usize) -> Result<JsonValue, PathfinderError> {
+    let body = get_response_body(message, max_message_size)?;
+    let json = from_utf8(&body)
+        .map_err(|err| err.to_string())
+        .and_then(|raw_data| json_parse(raw_data).map_err(|err| err.to_string()))
+        .map_err(|err| {
+            let message = format!("The response of the microservice can't be decoded: {}", err);
+            PathfinderError::MessageBrokerError(message)
+        })?;
+    if !json["error"].is_null() {
+        return Err(PathfinderError::MicroserviceError(json["error"].clone()));
+    }
+    transformers.transform_response(json)
+}
+
+/// Serializes the response in the format of the client and sends it.
+fn send_json_response(json: JsonValue, content_type: ContentType, transmitter: &MessageSender) -> Result<(), PathfinderError> {
+    let serializer = Serializer::with_content_type(content_type);
+    let response = serializer.serialize(json.dump())?;
+    transmitter.unbounded_send(response).unwrap_or(());
+    Ok(())
+}
+
+/// Converts headers into the AMQP format. The event name of the message (or
+/// the correlation ID, when it wasn't specified) is passed in the `event_name` header.
+pub fn get_message_headers(headers: &HashMap<String, String>, message: &JsonMessage) -> FieldTable {
+    let mut message_headers = FieldTable::new();
+    for (key, value) in headers.iter() {
+        message_headers.insert(key.clone(), AMQPValue::LongString(value.clone()));
+    }
+
+    message_headers.insert(String::from("event_name"), AMQPValue::LongString(get_event_name(message)));
+    message_headers
+}
+
+/// Starts a child span of the request, when it's traced, and passes the
+/// context of the span to the microservice in the `traceparent` header.
+fn start_span(options: &RpcOptions, name: &str, kind: SpanKind, headers: &mut FieldTable) -> Option<ActiveSpan> {
+    let span = options.get_trace()?.start_span(name, kind);
+    let traceparent = span.get_context().to_traceparent();
+    headers.insert(TRACEPARENT_HEADER.to_string(), AMQPValue::LongString(traceparent));
+    Some(span)
+}
+
+/// Takes the first item from the stream. Returns `None` when the stream was
+/// finished or nothing was received during the specified time.
+pub fn take_first_with_timeout<S>(stream: S, timeout: Duration) -> impl Future<Item=Option<S::Item>, Error=S::Error>
+    where S: Stream
+{
+    let first_item_future = stream
+        .take(1)
+        .into_future()
+        .map(|(item, _)| item)
+        .map_err(|(err, _)| err);
+
+    Timeout::new(first_item_future, timeout)
+        .then(|result| match result {
+            Ok(item) => Ok(item),
+            Err(err) => {
+                if err.is_timer() {
+                    error!("The timer for the response failed: {:?}", err.into_timer());
+                    return Ok(None);
+                }
+                match err.into_inner() {
+                    Some(err) => Err(err),
+                    None => Ok(None)
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::{empty, err, ok};
+    use futures::stream::iter_ok;
+    use futures::sync::mpsc;
+    use futures::{Future, Stream};
+    use json::parse as json_parse;
+    use lapin_futures::error::{Error as LapinError, ErrorKind as LapinErrorKind};
+    use lapin_futures::message::Delivery;
+    use lapin_futures_rustls::lapin::types::{AMQPValue, FieldTable};
+    use tokio::runtime::Runtime;
+    use tungstenite::Message;
+
+    use lapin_futures_rustls::lapin::channel::BasicProperties;
+
+    use crate::engine::futures::{
+        clean_up_reply_queue, confirm_publish, forward_response_parts, get_broker_error_at_stage, get_broker_error_log, get_consumed_response,
+        get_message_headers, get_reply_mode, get_request_body, get_timed_out_response, send_ack_if_required, send_response, start_span,
+        take_first_with_timeout, wait_for_confirmation, ConsumedResponse, ReplyMode, RpcStage, DEFAULT_MAX_MESSAGE_SIZE, PARTIAL_RESPONSE_HEADER
+    };
+    use crate::engine::encoding::ContentEncoding;
+    use crate::engine::options::RpcOptions;
+    use crate::error::PathfinderError;
+    use crate::engine::router::{Endpoint, ReadOnlyEndpoint};
+    use crate::engine::serializer::{ContentType, JsonMessage};
+    use crate::engine::transformer::TransformerChain;
+    use crate::engine::utils::with_correlation_id;
+    use crate::rabbitmq::DirectReplyTo;
+    use crate::tracing::{SpanKind, Tracer};
+
+    fn get_endpoint(content_type: &str) -> ReadOnlyEndpoint {
+        let endpoint = Endpoint::new("/api/test", "api.test", "", "", false).with_content_type(content_type);
+        Arc::new(endpoint)
+    }
+
+    fn get_message(raw_message: &str) -> JsonMessage {
+        Arc::new(Box::new(json_parse(raw_message).unwrap()))
+    }
+
+    fn get_delivery(data: &[u8]) -> Delivery {
+        let mut delivery = Delivery::new(1, String::from(""), String::from("api.test"), false);
+        delivery.receive_content(data.to_vec());
+        delivery
+    }
+
+    fn get_response_part(data: &[u8], partial: Option<AMQPValue>) -> Delivery {
+        let mut delivery = get_delivery(data);
+        if let Some(value) = partial {
+            let mut headers = FieldTable::new();
+            headers.insert(PARTIAL_RESPONSE_HEADER.to_string(), value);
+            delivery.properties = BasicProperties::default().with_headers(headers);
+        }
+        delivery
+    }
+
+    fn get_stream_options() -> Arc<RpcOptions> {
+        let options = RpcOptions::default()
+            .with_message(get_message(r#"{"url": "/api/test", "correlation-id": "request-id"}"#))
+            .with_routing_key(String::from("api.test"))
+            .with_timeout(Duration::from_millis(50));
+        Arc::new(options)
+    }
+
+    fn get_sent_message(endpoint: &ReadOnlyEndpoint, data: &[u8]) -> Message {
+        let (transmitter, receiver) = mpsc::unbounded();
+        send_response(&get_delivery(data), endpoint, &TransformerChain::default(), ContentType::Json, DEFAULT_MAX_MESSAGE_SIZE, &Arc::new(transmitter)).unwrap();
+        receiver.take(1).collect().wait().unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_start_span_passes_the_trace_context_in_headers() {
+        let tracer = Arc::new(Tracer::new("http://127.0.0.1:4318", 1.0).unwrap());
+        let request_span = Tracer::start_span(&tracer, "request", SpanKind::Server, None);
+        let options = RpcOptions::default().with_trace(request_span.get_trace_context());
+        let mut headers = FieldTable::new();
+
+        let span = start_span(&options, "publish", SpanKind::Producer, &mut headers).unwrap();
+        assert_eq!(span.get_context().get_trace_id(), request_span.get_context().get_trace_id());
+        assert_eq!(headers["traceparent"], AMQPValue::LongString(span.get_context().to_traceparent()));
+    }
+
+    #[test]
+    fn test_start_span_does_nothing_without_tracing() {
+        let mut headers = FieldTable::new();
+        assert!(start_span(&RpcOptions::default(), "publish", SpanKind::Producer, &mut headers).is_none());
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_get_message_headers_passes_the_event_name() {
+        let message = get_message(r#"{"url": "/api/test", "event-name": "search", "correlation-id": "a1b2c3"}"#);
+        let headers = get_message_headers(&HashMap::new(), &message);
+        assert_eq!(headers.get("event_name"), Some(&AMQPValue::LongString(String::from("search"))));
+    }
+
+    #[test]
+    fn test_get_message_headers_passes_the_correlation_id_without_the_event_name() {
+        let message = with_correlation_id(get_message(r#"{"url": "/api/test"}"#));
+        let headers = get_message_headers(&HashMap::new(), &message);
+        let event_name = match headers.get("event_name") {
+            Some(AMQPValue::LongString(event_name)) => event_name.clone(),
+            other => panic!("Unexpected event name: {:?}", other)
+        };
+
+        assert_ne!(event_name, "null");
+        assert_eq!(event_name.len(), 36);
+        assert_eq!(event_name, message["correlation-id"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_get_request_body_dumps_json_content() {
+        let message = get_message(r#"{"url": "/api/test", "content": "text"}"#);
+        assert_eq!(get_request_body(&message, &get_endpoint("application/json")), b"\"text\"".to_vec());
+    }
+
+    #[test]
+    fn test_get_request_body_passes_string_content_as_is_for_other_formats() {
+        let message = get_message(r#"{"url": "/api/test", "content": "text"}"#);
+        assert_eq!(get_request_body(&message, &get_endpoint("text/plain")), b"text".to_vec());
+
+        let message = get_message(r#"{"url": "/api/test", "content": {"key": "value"}}"#);
+        assert_eq!(get_request_body(&message, &get_endpoint("text/plain")), br#"{"key":"value"}"#.to_vec());
+    }
+
+    #[test]
+    fn test_get_request_body_without_content() {
+        let message = get_message(r#"{"url": "/api/test"}"#);
+        assert_eq!(get_request_body(&message, &get_endpoint("application/json")), b"{}".to_vec());
+        assert_eq!(get_request_body(&message, &get_endpoint("text/plain")), Vec::<u8>::new());
+
+        let message = get_message(r#"{"url": "/api/test", "content": null}"#);
+        assert_eq!(get_request_body(&message, &get_endpoint("application/json")), b"{}".to_vec());
+    }
+
+    #[test]
+    fn test_get_request_body_is_compressed_for_endpoints_with_the_content_encoding() {
+        let message = get_message(r#"{"url": "/api/test", "content": {"key": "value"}}"#);
+        for content_encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let endpoint = Arc::new(get_endpoint("application/json").as_ref().clone().with_content_encoding(content_encoding));
+            let body = get_request_body(&message, &endpoint);
+            assert_eq!(content_encoding.decode(&body, DEFAULT_MAX_MESSAGE_SIZE).unwrap(), br#"{"key":"value"}"#.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_send_response_decompresses_responses_with_the_content_encoding() {
+        let endpoint = get_endpoint("application/json");
+        for content_encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let mut delivery = get_delivery(&content_encoding.encode(br#"{ "key": "value" }"#));
+            delivery.properties = BasicProperties::default().with_content_encoding(content_encoding.as_str().to_string());
+            let (transmitter, receiver) = mpsc::unbounded();
+            send_response(&delivery, &endpoint, &TransformerChain::default(), ContentType::Json, DEFAULT_MAX_MESSAGE_SIZE, &Arc::new(transmitter)).unwrap();
+            let message = receiver.take(1).collect().wait().unwrap().remove(0);
+            assert_eq!(message, Message::Text(String::from(r#"{"key":"value"}"#)));
+        }
+    }
+
+    #[test]
+    fn test_send_response_fails_for_corrupted_compressed_responses() {
+        let mut delivery = get_delivery(br#"{ "key": "value" }"#);
+        delivery.properties = BasicProperties::default().with_content_encoding(String::from("gzip"));
+        let (transmitter, _receiver) = mpsc::unbounded();
+        let result = send_response(&delivery, &get_endpoint("application/json"), &TransformerChain::default(), ContentType::Json, DEFAULT_MAX_MESSAGE_SIZE, &Arc::new(transmitter));
+        assert!(matches!(result, Err(PathfinderError::MessageBrokerError(_))));
+    }
+
+    #[test]
+    fn test_send_response_fails_for_too_large_decompressed_responses() {
+        let body = format!(r#"{{"content": "{}"}}"#, "a".repeat(1024));
+        let mut delivery = get_delivery(&ContentEncoding::Gzip.encode(body.as_bytes()));
+        delivery.properties = BasicProperties::default().with_content_encoding(String::from("gzip"));
+        for endpoint in [get_endpoint("application/json"), get_endpoint("text/plain")] {
+            let (transmitter, receiver) = mpsc::unbounded();
+            let result = send_response(&delivery, &endpoint, &TransformerChain::default(), ContentType::Json, 1024, &Arc::new(transmitter));
+            assert!(matches!(result, Err(PathfinderError::MessageBrokerError(ref message)) if message.contains("exceeds 1024 bytes")));
+            assert!(receiver.collect().wait().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_send_response_fails_for_malformed_json_responses() {
+        for data in [&b"{ \"key\": "[..], &[0xff, 0xfe, 0xfd][..]] {
+            let (transmitter, receiver) = mpsc::unbounded();
+            let result = send_response(&get_delivery(data), &get_endpoint("application/json"), &TransformerChain::default(), ContentType::Json, DEFAULT_MAX_MESSAGE_SIZE, &Arc::new(transmitter));
+            assert!(matches!(result, Err(PathfinderError::MessageBrokerError(_))));
+            assert!(receiver.collect().wait().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_send_response_passes_responses_in_other_formats_as_is() {
+        let endpoint = get_endpoint("text/plain");
+        assert_eq!(get_sent_message(&endpoint, b"not a json"), Message::Text(String::from("not a json")));
+        assert_eq!(get_sent_message(&endpoint, &[0xff, 0x00]), Message::Binary(vec![0xff, 0x00]));
+    }
+
+    #[test]
+    fn test_send_response_serializes_json_responses() {
+        let endpoint = get_endpoint("application/json");
+        let message = get_sent_message(&endpoint, br#"{ "key": "value" }"#);
+        assert_eq!(message, Message::Text(String::from(r#"{"key":"value"}"#)));
+    }
+
+    #[test]
+    fn test_take_first_with_timeout_returns_the_first_item() {
+        let mut runtime = Runtime::new().unwrap();
+        let stream = iter_ok::<_, ()>(vec![1, 2, 3]);
+        let result = runtime.block_on(take_first_with_timeout(stream, Duration::from_secs(5)));
+
+        assert_eq!(result, Ok(Some(1)));
+    }
+
+    #[test]
+    fn test_take_first_with_timeout_returns_none_when_nothing_was_received() {
+        let mut runtime = Runtime::new().unwrap();
+        let (_transmitter, receiver) = mpsc::unbounded::<i32>();
+        let result = runtime.block_on(take_first_with_timeout(receiver, Duration::from_millis(50)));
+
+        assert_eq!(result, Ok(None));
+    }
+
+    #[test]
+    fn test_confirm_publish_for_the_acked_request() {
+        let mut runtime = Runtime::new().unwrap();
+        let publish_future = ok::<_, LapinError>(Some(1));
+        let result = runtime.block_on(confirm_publish(publish_future, Duration::from_secs(5), String::from("id")));
+
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_confirm_publish_returns_an_error_for_the_nacked_request() {
+        let mut runtime = Runtime::new().unwrap();
+        let publish_future = ok::<_, LapinError>(None);
+        let result = runtime.block_on(confirm_publish(publish_future, Duration::from_secs(5), String::from("id")));
+
+        match result.unwrap() {
+            Err(PathfinderError::MessageBrokerError(message)) => assert!(message.contains("wasn't accepted")),
+            _ => panic!("Expected the nacked request to fail")
+        }
+    }
+
+    #[test]
+    fn test_confirm_publish_returns_an_error_when_the_confirmation_timed_out() {
+        let mut runtime = Runtime::new().unwrap();
+        let publish_future = empty::<Option<u64>, LapinError>();
+        let result = runtime.block_on(confirm_publish(publish_future, Duration::from_millis(50), String::from("id")));
+
+        match result.unwrap() {
+            Err(PathfinderError::MessageBrokerError(message)) => assert!(message.contains("wasn't confirmed")),
+            _ => panic!("Expected the unconfirmed request to fail")
+        }
+    }
+
+    #[test]
+    fn test_wait_for_confirmation_returns_an_error_for_the_nacked_request_in_the_confirm_mode() {
+        let mut runtime = Runtime::new().unwrap();
+        let publish_future = ok::<_, LapinError>(None);
+        let result = runtime.block_on(wait_for_confirmation(publish_future, true, Duration::from_secs(5), String::from("id")));
+
+        assert!(result.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_wait_for_confirmation_accepts_the_sent_request_without_the_confirm_mode() {
+        let mut runtime = Runtime::new().unwrap();
+        let publish_future = ok::<_, LapinError>(None);
+        let result = runtime.block_on(wait_for_confirmation(publish_future, false, Duration::from_secs(5), String::from("id")));
+
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_send_ack_if_required_sends_the_ack_before_the_response() {
+        let endpoint = Arc::new(Endpoint::new("/api/test", "api.test", "", "", false).with_request_ack());
+        let options = get_stream_options().as_ref().clone().with_endpoint(endpoint.clone());
+        let (transmitter, receiver) = mpsc::unbounded();
+        let transmitter = Arc::new(transmitter);
+
+        send_ack_if_required(&Ok(()), &options, &transmitter);
+        send_response(&get_delivery(br#"{"content": [1]}"#), &endpoint, &TransformerChain::default(), ContentType::Json, DEFAULT_MAX_MESSAGE_SIZE, &transmitter).unwrap();
+
+        let messages: Vec<Message> = receiver.take(2).collect().wait().unwrap();
+        assert_eq!(messages, vec![
+            Message::Text(String::from(r#"{"type":"ack","correlation-id":"request-id"}"#)),
+            Message::Text(String::from(r#"{"content":[1]}"#)),
+        ]);
+    }
+
+    #[test]
+    fn test_send_ack_if_required_skips_rejected_and_not_acked_requests() {
+        let endpoint = Arc::new(Endpoint::new("/api/test", "api.test", "", "", false).with_request_ack());
+        let acked_options = get_stream_options().as_ref().clone().with_endpoint(endpoint);
+        let options = get_stream_options().as_ref().clone().with_endpoint(get_endpoint("application/json"));
+        let (transmitter, receiver) = mpsc::unbounded();
+        let transmitter = Arc::new(transmitter);
+
+        let rejected: Result<(), PathfinderError> = Err(PathfinderError::MessageBrokerError(String::from("nack")));
+        send_ack_if_required(&rejected, &acked_options, &transmitter);
+        send_ack_if_required(&Ok(()), &options, &transmitter);
+        drop(transmitter);
+
+        assert!(receiver.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_reply_mode_doesnt_declare_reply_queues_for_fire_and_forget_endpoints() {
+        let endpoint = Arc::new(Endpoint::new("/api/test", "api.test", "", "", false).without_response());
+        let options = get_stream_options().as_ref().clone().with_endpoint(endpoint);
+        assert!(matches!(get_reply_mode(&options, None), ReplyMode::None));
+        assert!(matches!(get_reply_mode(&options, Some(Arc::new(DirectReplyTo::new()))), ReplyMode::None));
+    }
+
+    #[test]
+    fn test_get_reply_mode_awaits_responses_by_default() {
+        let options = get_stream_options().as_ref().clone().with_endpoint(get_endpoint("application/json"));
+        assert!(matches!(get_reply_mode(&options, None), ReplyMode::ReplyQueue));
+        assert!(matches!(get_reply_mode(&options, Some(Arc::new(DirectReplyTo::new()))), ReplyMode::DirectReplyTo(_)));
+    }
+
+    #[test]
+    fn test_broker_errors_name_the_failed_stage_only_in_logs() {
+        let declare_future = err::<(), _>(LapinError::from(LapinErrorKind::ConnectionClosed))
+            .map_err(|err| (RpcStage::DeclareQueue, err));
+        let (stage, error) = declare_future.wait().unwrap_err();
+        assert_eq!(stage, RpcStage::DeclareQueue);
+
+        let log = get_broker_error_log(stage, &error, "request-id");
+        assert!(log.starts_with("[request-id] Error in RabbitMQ client while declaring the reply queue. Reason: "));
+        assert!(log.ends_with(&format!("{}", error)));
+
+        match get_broker_error_at_stage(stage, error, "request-id") {
+            PathfinderError::MessageBrokerError(message) => {
+                assert_eq!(message, "The request wasn't processed. Please, try once again.")
+            },
+            _ => panic!("Expected a message broker error")
+        }
+    }
+
+    #[test]
+    fn test_clean_up_reply_queue_ignores_failures_after_the_response() {
+        let mut runtime = Runtime::new().unwrap();
+        let endpoint = get_endpoint("application/json");
+        let (transmitter, receiver) = mpsc::unbounded();
+        let is_deleted = Arc::new(AtomicBool::new(false));
+        let is_deleted_inner = is_deleted.clone();
+
+        let response = send_response(&get_delivery(br#"{"content": [1]}"#), &endpoint, &TransformerChain::default(), ContentType::Json, DEFAULT_MAX_MESSAGE_SIZE, &Arc::new(transmitter));
+        let unbind_future = err::<(), _>(LapinError::from(LapinErrorKind::ConnectionClosed));
+        let get_delete_future = move || {
+            is_deleted_inner.store(true, Ordering::SeqCst);
+            err::<(), _>(LapinError::from(LapinErrorKind::ConnectionClosed))
+        };
+        let result = runtime.block_on(clean_up_reply_queue(unbind_future, get_delete_future, String::from("id")).then(move |_| response));
+
+        assert!(result.is_ok());
+        assert!(is_deleted.load(Ordering::SeqCst));
+        let messages: Vec<Message> = receiver.take(1).collect().wait().unwrap();
+        assert_eq!(messages, vec![Message::Text(String::from(r#"{"content":[1]}"#))]);
+    }
+
+    #[test]
+    fn test_clean_up_reply_queue_deletes_the_queue_when_the_response_is_missing() {
+        let mut runtime = Runtime::new().unwrap();
+        let options = get_stream_options();
+        let (_transmitter, receiver) = mpsc::unbounded::<Delivery>();
+        let is_deleted = Arc::new(AtomicBool::new(false));
+        let is_deleted_inner = is_deleted.clone();
+
+        let future = take_first_with_timeout(receiver, options.get_timeout().unwrap())
+            .map(get_consumed_response)
+            .then(move |result| {
+                let response = match result {
+                    Ok(ConsumedResponse::Missing) => get_timed_out_response(&options),
+                    _ => panic!("The response must be missing")
+                };
+                let unbind_future = ok::<_, LapinError>(());
+                let get_delete_future = move || {
+                    is_deleted_inner.store(true, Ordering::SeqCst);
+                    ok::<_, LapinError>(())
+                };
+                clean_up_reply_queue(unbind_future, get_delete_future, String::from("request-id")).then(move |_| response)
+            });
+        let result = runtime.block_on(future);
+
+        assert!(is_deleted.load(Ordering::SeqCst));
+        match result {
+            Err(PathfinderError::MessageBrokerError(message)) => assert_eq!(message, "The request timed out. Please, try once again."),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_forward_response_parts_sends_parts_until_the_last_one() {
+        let mut runtime = Runtime::new().unwrap();
+        let (transmitter, receiver) = mpsc::unbounded();
+        let parts = iter_ok::<_, PathfinderError>(vec![
+            get_response_part(br#"{"content": [1, 2]}"#, Some(AMQPValue::Boolean(true))),
+            get_response_part(br#"{"content": [3]}"#, Some(AMQPValue::LongString(String::from("true")))),
+            get_response_part(br#"{"content": []}"#, None),
+            get_response_part(br#"{"content": [4]}"#, None),
+        ]);
+        runtime.block_on(forward_response_parts(parts, get_stream_options(), Arc::new(transmitter))).unwrap();
+
+        let messages: Vec<Message> = receiver.take(3).collect().wait().unwrap();
+        assert_eq!(messages, vec![
+            Message::Text(String::from(r#"{"content":[1,2],"part":0,"last-part":false}"#)),
+            Message::Text(String::from(r#"{"content":[3],"part":1,"last-part":false}"#)),
+            Message::Text(String::from(r#"{"content":[],"part":2,"last-part":true}"#)),
+        ]);
+    }
+
+    #[test]
+    fn test_forward_response_parts_fails_when_the_next_part_timed_out() {
+        let mut runtime = Runtime::new().unwrap();
+        let (transmitter, receiver) = mpsc::unbounded();
+        let (parts_transmitter, parts) = mpsc::unbounded();
+        parts_transmitter.unbounded_send(get_response_part(br#"{"content": [1]}"#, Some(AMQPValue::Boolean(true)))).unwrap();
+        let parts = parts.map_err(|_| PathfinderError::MessageBrokerError(String::from("closed")));
+        let result = runtime.block_on(forward_response_parts(parts, get_stream_options(), Arc::new(transmitter)));
+
+        match result {
+            Err(PathfinderError::MessageBrokerError(message)) => assert!(message.contains("timed out")),
+            _ => panic!("Expected the stream to time out")
+        }
+        assert_eq!(receiver.take(1).collect().wait().unwrap().len(), 1);
+        drop(parts_transmitter);
+    }
+
+    #[test]
+    fn test_forward_response_parts_stops_on_microservice_errors() {
+        let mut runtime = Runtime::new().unwrap();
+        let (transmitter, _receiver) = mpsc::unbounded();
+        let parts = iter_ok::<_, PathfinderError>(vec![
+            get_response_part(br#"{"error": {"type": "NotFound"}}"#, Some(AMQPValue::Boolean(true))),
+            get_response_part(br#"{"content": []}"#, None),
+        ]);
+        let result = runtime.block_on(forward_response_parts(parts, get_stream_options(), Arc::new(transmitter)));
+
+        match result {
+            Err(PathfinderError::MicroserviceError(json)) => assert_eq!(json["type"], "NotFound"),
+            _ => panic!("Expected the error of the microservice")
+        }
+    }
 }
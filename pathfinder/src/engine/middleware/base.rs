@@ -6,6 +6,7 @@ use std::sync::Arc;
 
 use futures::Future;
 
+use crate::engine::router::ReadOnlyEndpoint;
 use crate::engine::serializer::JsonMessage;
 use crate::error::PathfinderError;
 use crate::rabbitmq::RabbitMQContext;
@@ -20,6 +21,13 @@ pub type MiddlewareFuture = Box<Future<Item=CustomUserHeaders, Error=PathfinderE
 /// during processing a request from a client.
 pub trait Middleware: Send + Sync {
     /// Applied transforms and checks to an incoming request. If it failed,
-    /// then should return a `PathfinderError` instance.
-    fn process_request(&self, message: JsonMessage, rabbitmq_context: Arc<RabbitMQContext>) -> MiddlewareFuture;
+    /// then should return a `PathfinderError` instance. The endpoint that
+    /// matched the request is `None` for messages that aren't routed to
+    /// microservices, e.g. the authentication message of the connection.
+    fn process_request(
+        &self,
+        message: JsonMessage,
+        endpoint: Option<ReadOnlyEndpoint>,
+        rabbitmq_context: Arc<RabbitMQContext>
+    ) -> MiddlewareFuture;
 }
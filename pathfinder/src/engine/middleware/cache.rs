@@ -0,0 +1,227 @@
+//! In-memory caches for results of the token verification and for the
+//! nonces of recent requests.
+//!
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use crate::engine::middleware::base::CustomUserHeaders;
+
+/// A bounded cache which stores custom user headers for the verified tokens
+/// during the specified time, but no longer than the tokens are valid. When
+/// the cache is full, the least recently used token is evicted.
+pub struct TokenCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<String, (Instant, CustomUserHeaders)>>
+}
+
+impl TokenCache {
+    /// Returns a new instance of `TokenCache`.
+    pub fn new(capacity: usize, ttl: Duration) -> TokenCache {
+        TokenCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity.max(1)))
+        }
+    }
+
+    /// Returns the cached headers for the token. Expired entries are removed
+    /// and never returned to the caller.
+    pub fn get(&self, token: &str) -> Option<CustomUserHeaders> {
+        let mut entries = self.entries.lock().unwrap();
+        let is_expired = match entries.get(token) {
+            Some((expires_at, _)) => Instant::now() >= *expires_at,
+            None => return None
+        };
+
+        match is_expired {
+            true => {
+                entries.pop(token);
+                None
+            },
+            false => entries.get(token).map(|(_, headers)| headers.clone())
+        }
+    }
+
+    /// Stores the headers for the successfully verified token. The entry
+    /// expires after the TTL or after the remaining lifetime of the token,
+    /// whichever is shorter.
+    pub fn insert(&self, token: &str, headers: CustomUserHeaders, lifetime: Option<Duration>) {
+        let lifetime = lifetime.map_or(self.ttl, |lifetime| lifetime.min(self.ttl));
+        let mut entries = self.entries.lock().unwrap();
+        entries.put(token.to_string(), (Instant::now() + lifetime, headers));
+    }
+
+    /// Removes the token from the cache, so that it will be verified by
+    /// Auth/Auth microservice once again.
+    pub fn invalidate(&self, token: &str) {
+        self.entries.lock().unwrap().pop(token);
+    }
+}
+
+/// The result of storing the nonce in the `NonceCache`.
+#[derive(Debug, PartialEq)]
+pub enum NonceStatus {
+    /// The nonce wasn't used during the TTL and now is stored.
+    Fresh,
+    /// The nonce was already used during the TTL.
+    Repeated,
+    /// The set is full of unexpired nonces, so the nonce can't be stored.
+    Overflowed
+}
+
+/// A bounded set of the nonces of recent requests, which are kept during the
+/// specified time. When the set is full, new nonces are rejected until the
+/// oldest ones expire, so that no nonce is forgotten before its TTL.
+pub struct NonceCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<String, Instant>>
+}
+
+impl NonceCache {
+    /// Returns a new instance of `NonceCache`.
+    pub fn new(capacity: usize, ttl: Duration) -> NonceCache {
+        NonceCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity.max(1)))
+        }
+    }
+
+    /// Stores the nonce, unless it was already stored during the TTL or
+    /// the set is full.
+    pub fn insert(&self, nonce: &str) -> NonceStatus {
+        self.insert_at(nonce, Instant::now())
+    }
+
+    fn insert_at(&self, nonce: &str, now: Instant) -> NonceStatus {
+        let mut entries = self.entries.lock().unwrap();
+        // The stored nonces are never promoted, so the least recently used
+        // nonce is the oldest one and the expired nonces are at the end
+        loop {
+            let is_expired = match entries.peek_lru() {
+                Some((_, seen_at)) => now.saturating_duration_since(*seen_at) >= self.ttl,
+                None => false
+            };
+            if !is_expired {
+                break;
+            }
+            entries.pop_lru();
+        }
+
+        if entries.contains(nonce) {
+            return NonceStatus::Repeated;
+        }
+        if entries.len() >= entries.cap() {
+            return NonceStatus::Overflowed;
+        }
+        entries.put(nonce.to_string(), now);
+        NonceStatus::Fresh
+    }
+
+    /// Returns the number of the stored nonces.
+    pub fn get_count(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::engine::middleware::cache::{NonceCache, NonceStatus, TokenCache};
+
+    fn get_headers(permissions: &str) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        headers.insert(String::from("permissions"), String::from(permissions));
+        headers
+    }
+
+    #[test]
+    fn test_get_returns_cached_headers() {
+        let cache = TokenCache::new(10, Duration::from_secs(60));
+        cache.insert("token", get_headers("read"), None);
+
+        assert_eq!(cache.get("token"), Some(get_headers("read")));
+        assert_eq!(cache.get("unknown"), None);
+    }
+
+    #[test]
+    fn test_get_does_not_return_expired_headers() {
+        let cache = TokenCache::new(10, Duration::from_secs(0));
+        cache.insert("token", get_headers("read"), None);
+
+        assert_eq!(cache.get("token"), None);
+    }
+
+    #[test]
+    fn test_get_does_not_return_headers_of_tokens_expiring_before_the_ttl() {
+        let cache = TokenCache::new(10, Duration::from_secs(60));
+        cache.insert("expired", get_headers("read"), Some(Duration::from_secs(0)));
+        cache.insert("valid", get_headers("write"), Some(Duration::from_secs(3600)));
+
+        assert_eq!(cache.get("expired"), None);
+        assert_eq!(cache.get("valid"), Some(get_headers("write")));
+    }
+
+    #[test]
+    fn test_invalidate_removes_the_token() {
+        let cache = TokenCache::new(10, Duration::from_secs(60));
+        cache.insert("token", get_headers("read"), None);
+        cache.invalidate("token");
+
+        assert_eq!(cache.get("token"), None);
+    }
+
+    #[test]
+    fn test_insert_evicts_the_least_recently_used_token() {
+        let cache = TokenCache::new(2, Duration::from_secs(60));
+        cache.insert("first", get_headers("read"), None);
+        cache.insert("second", get_headers("write"), None);
+        assert!(cache.get("first").is_some());
+        cache.insert("third", get_headers("delete"), None);
+
+        assert!(cache.get("first").is_some());
+        assert!(cache.get("second").is_none());
+        assert!(cache.get("third").is_some());
+    }
+
+    #[test]
+    fn test_insert_rejects_repeated_nonces() {
+        let nonces = NonceCache::new(10, Duration::from_secs(60));
+        assert_eq!(nonces.insert("first"), NonceStatus::Fresh);
+        assert_eq!(nonces.insert("second"), NonceStatus::Fresh);
+
+        assert_eq!(nonces.insert("first"), NonceStatus::Repeated);
+        assert_eq!(nonces.get_count(), 2);
+    }
+
+    #[test]
+    fn test_insert_removes_expired_nonces() {
+        let nonces = NonceCache::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(nonces.insert_at("first", now), NonceStatus::Fresh);
+        assert_eq!(nonces.insert_at("second", now + Duration::from_secs(30)), NonceStatus::Fresh);
+
+        assert_eq!(nonces.insert_at("first", now + Duration::from_secs(60)), NonceStatus::Fresh);
+        assert_eq!(nonces.get_count(), 2);
+        assert_eq!(nonces.insert_at("second", now + Duration::from_secs(60)), NonceStatus::Repeated);
+    }
+
+    #[test]
+    fn test_insert_rejects_nonces_when_full() {
+        let nonces = NonceCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(nonces.insert_at("first", now), NonceStatus::Fresh);
+        assert_eq!(nonces.insert_at("second", now + Duration::from_secs(30)), NonceStatus::Fresh);
+
+        // None of the stored nonces is evicted before its expiration
+        assert_eq!(nonces.insert_at("third", now + Duration::from_secs(30)), NonceStatus::Overflowed);
+        assert_eq!(nonces.get_count(), 2);
+        assert_eq!(nonces.insert_at("first", now + Duration::from_secs(30)), NonceStatus::Repeated);
+
+        assert_eq!(nonces.insert_at("third", now + Duration::from_secs(60)), NonceStatus::Fresh);
+        assert_eq!(nonces.insert_at("second", now + Duration::from_secs(60)), NonceStatus::Repeated);
+    }
+}
@@ -8,6 +8,7 @@ use std::sync::Arc;
 use futures::future::lazy;
 
 use crate::engine::middleware::base::{Middleware, MiddlewareFuture};
+use crate::engine::router::ReadOnlyEndpoint;
 use crate::engine::serializer::JsonMessage;
 use crate::rabbitmq::RabbitMQContext;
 
@@ -23,7 +24,12 @@ impl EmptyMiddleware {
 
 impl Middleware for EmptyMiddleware {
     /// Returns an empty future which is doesn't doing anything.
-    fn process_request(&self, _message: JsonMessage, _rabbitmq_context: Arc<RabbitMQContext>) -> MiddlewareFuture {
+    fn process_request(
+        &self,
+        _message: JsonMessage,
+        _endpoint: Option<ReadOnlyEndpoint>,
+        _rabbitmq_context: Arc<RabbitMQContext>
+    ) -> MiddlewareFuture {
         Box::new(lazy(move || Ok(HashMap::new())))
     }
 }
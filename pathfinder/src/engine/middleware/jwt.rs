@@ -4,70 +4,175 @@
 use std::collections::HashMap;
 use std::str::from_utf8;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
-use futures::future::{lazy, Future};
+use futures::future::{lazy, ok, Either, Future};
 use futures::Stream;
-use json::{object, parse as parse_json};
+use json::{object, parse as parse_json, Error as JsonError, JsonValue};
 use lapin_futures_rustls::lapin::channel::{
     BasicConsumeOptions, BasicProperties, BasicPublishOptions, QueueBindOptions,
-    QueueDeclareOptions, QueueDeleteOptions, QueueUnbindOptions,
+    QueueDeleteOptions, QueueUnbindOptions,
 };
+use lapin_futures::message::Delivery;
 use lapin_futures_rustls::lapin::types::{AMQPValue, FieldTable};
 use log::{error, info, warn};
-use uuid::Uuid;
 
 use crate::error::PathfinderError;
-use crate::engine::{RESPONSE_EXCHANGE};
-use crate::engine::middleware::{
-    TOKEN_VERIFY_ROUTING_KEY,
-    TOKEN_VERIFY_EXCHANGE,
-    TOKEN_USER_PROFILE_ROUTING_KEY,
-    TOKEN_USER_PROFILE_EXCHANGE
+use crate::engine::DEFAULT_CONTENT_TYPE;
+use crate::engine::futures::{
+    direct_reply_to_future, get_direct_reply_to_response, take_first_with_timeout,
+    DEFAULT_CONFIRM_TIMEOUT, DEFAULT_RPC_TIMEOUT
 };
+use crate::engine::utils::{get_correlation_id, get_event_name};
 use crate::engine::middleware::base::{Middleware, MiddlewareFuture, CustomUserHeaders};
-use crate::engine::middleware::utils::get_permissions;
+use crate::engine::middleware::cache::TokenCache;
+use crate::engine::middleware::options::JwtOptions;
+use crate::engine::middleware::retry::retry_future;
+use crate::engine::middleware::utils::{encode_permissions, get_permissions, PermissionsFormat, PERMISSIONS_HEADER};
 use crate::engine::options::RpcOptions;
+use crate::engine::router::ReadOnlyEndpoint;
 use crate::engine::serializer::JsonMessage;
-use crate::rabbitmq::RabbitMQContext;
+use crate::rabbitmq::{DirectReplyTo, RabbitMQContext};
 
 /// A middleware class, that will check a JSON Web Token in WebSocket message.
-/// If token wasn't specified or it's invalid returns a `PathfinderError` object.
-pub struct JwtTokenMiddleware;
+/// If token wasn't specified (unless it's optional) or it's invalid returns a
+/// `PathfinderError` object.
+#[derive(Clone)]
+pub struct JwtTokenMiddleware {
+    options: Arc<JwtOptions>,
+    cache: Option<Arc<TokenCache>>,
+    is_token_optional: bool
+}
 
 impl JwtTokenMiddleware {
-    /// Returns a new instance of `JwtTokenMiddleware` structure.
-    pub fn new() -> JwtTokenMiddleware {
-        JwtTokenMiddleware {}
+    /// Returns a new instance of `JwtTokenMiddleware` structure, that sends
+    /// requests to Auth/Auth microservice via the specified exchanges.
+    pub fn new(options: JwtOptions) -> JwtTokenMiddleware {
+        JwtTokenMiddleware {
+            options: Arc::new(options),
+            cache: None,
+            is_token_optional: false
+        }
+    }
+
+    /// Allows requests without a token, so that they're processed with empty
+    /// user headers. A passed token is still verified.
+    pub fn with_optional_token(mut self) -> JwtTokenMiddleware {
+        self.is_token_optional = true;
+        self
+    }
+
+    /// Extracts a token from a JSON object. Returns `None` when the token
+    /// is optional and it wasn't passed.
+    fn extract_token(&self, message: &JsonMessage) -> Result<Option<String>, PathfinderError> {
+        match (message["token"].as_str(), message["token"].is_null()) {
+            (Some(token), _) => Ok(Some(String::from(token))),
+            (None, true) if self.is_token_optional => Ok(None),
+            _ => {
+                let message = String::from("The `token` field must be specified.");
+                Err(PathfinderError::AuthenticationError(message))
+            }
+        }
+    }
+
+    /// Enables caching of the verified tokens, so that repeated requests
+    /// with the same token will not be sent to Auth/Auth microservice.
+    pub fn with_cache(mut self, cache: TokenCache) -> JwtTokenMiddleware {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Returns the user headers for the token of the message. The token is
+    /// verified and the headers are requested via the passed functions,
+    /// unless they were cached for this token before.
+    fn authenticate<V, VF, H, HF>(&self, message: &JsonMessage, verify_token: V, get_headers: H) -> MiddlewareFuture
+        where V: FnOnce(String) -> VF,
+              VF: Future<Item=(), Error=PathfinderError> + Sync + Send + 'static,
+              H: FnOnce(String) -> HF,
+              HF: Future<Item=CustomUserHeaders, Error=PathfinderError> + Sync + Send + 'static
+    {
+        // Extract a token from a JSON object. Anonymous requests are allowed only
+        // when the token is optional
+        let token = match self.extract_token(message) {
+            Ok(Some(token)) => token,
+            Ok(None) => return Box::new(lazy(move || Ok(HashMap::new()))),
+            Err(error) => return Box::new(lazy(move || Err(error)))
+        };
+
+        // Return the headers for the recently verified token without calling the microservice
+        if let Some(headers) = self.cache.as_ref().and_then(|cache| cache.get(&token)) {
+            return Box::new(lazy(move || Ok(headers)));
+        }
+
+        // The headers are cached until the token expires, and the token that
+        // wasn't accepted is removed from the cache
+        let cache = self.cache.clone();
+        let verify_token_future = verify_token(token.clone());
+        let get_headers_future = get_headers(token.clone());
+        Box::new(
+            verify_token_future
+                .and_then(move |_| get_headers_future)
+                .then(move |result: Result<CustomUserHeaders, PathfinderError>| {
+                    if let Some(cache) = cache {
+                        match result {
+                            Ok(ref headers) => cache.insert(&token, headers.clone(), get_token_lifetime(&token)),
+                            Err(_) => cache.invalidate(&token)
+                        }
+                    }
+                    result
+                })
+        )
     }
 
     /// Performs a request to Auth/Auth microservice with the taken token
     /// that must be verified before doing any actions later.
-    fn verify_token(&self, message: JsonMessage, token: String, rabbitmq_context: Arc<RabbitMQContext>)
+    fn verify_token(options: &JwtOptions, message: JsonMessage, token: String, rabbitmq_context: Arc<RabbitMQContext>)
         -> impl Future<Item=(), Error=PathfinderError> + Sync + Send + 'static
     {
+        let exchange = options.get_token_verify_exchange();
+        let routing_key = options.get_token_verify_routing_key();
+        if let Some(direct_reply_to) = rabbitmq_context.get_direct_reply_to() {
+            let request_headers = vec![
+                (String::from("routing_key"), routing_key.clone()),
+                (String::from("request_url"), String::from("/auth/api/token/verify")),
+            ];
+            return Either::A(
+                JwtTokenMiddleware::direct_request(
+                    message, token, rabbitmq_context, direct_reply_to, request_headers,
+                    &exchange, &routing_key
+                )
+                .and_then(check_token_verification)
+            );
+        }
+
+        let queue_declare_options = options.get_reply_queue().get_declare_options();
+        let queue_declare_arguments = options.get_reply_queue().get_declare_arguments();
+        let max_response_size = options.get_max_response_size();
         let access_token = token.clone();
+        let correlation_id = get_correlation_id(&message);
+        let reply_queue_name = options.get_reply_queue().get_queue_name(&routing_key);
+        let consumer_tag = options.get_reply_queue().get_consumer_tag(&reply_queue_name);
+        let response_exchange = options.get_response_exchange();
+        let response_exchange_inner = response_exchange.clone();
         let options = Arc::new(RpcOptions::default()
             .with_message(message.clone())
-            .with_queue_name(Arc::new(format!("{}", Uuid::new_v4())))
+            .with_queue_name(Arc::new(reply_queue_name))
         );
         let rabbitmq_context_local = rabbitmq_context.clone();
         let publish_channel = rabbitmq_context_local.get_publish_channel();
         let consume_channel = rabbitmq_context_local.get_consume_channel();
 
         let queue_name = options.get_queue_name().unwrap().clone();
-        let queue_declare_options = QueueDeclareOptions {
-            passive: false,
-            durable: true,
-            exclusive: true,
-            auto_delete: false,
-            ..Default::default()
-        };
 
-        // 1. Declare a response queue
-        consume_channel
-            .queue_declare(&queue_name, queue_declare_options, FieldTable::new())
-            .map(move |queue| (publish_channel, consume_channel, queue, options))
+        // 1. Declare a response queue, when the rate of declarations allows it
+        let future = rabbitmq_context_local
+            .wait_for_queue_declare()
+            .and_then(move |_| {
+                consume_channel
+                    .queue_declare(&queue_name, queue_declare_options, queue_declare_arguments)
+                    .map(move |queue| (publish_channel, consume_channel, queue, options))
+            })
         // 2. Link the response queue the exchange
         .and_then(move |(publish_channel, consume_channel, queue, options)| {
             let queue_name = options.get_queue_name().unwrap().clone();
@@ -76,7 +181,7 @@ impl JwtTokenMiddleware {
             consume_channel
                 .queue_bind(
                     &queue_name,
-                    RESPONSE_EXCHANGE.clone(),
+                    &response_exchange,
                     &routing_key,
                     QueueBindOptions::default(),
                     FieldTable::new()
@@ -92,39 +197,41 @@ impl JwtTokenMiddleware {
             };
 
             let request_headers: Vec<(String, String)> = vec![
-                (String::from("routing_key"), String::from("auth.token.verify")),
+                (String::from("routing_key"), routing_key.clone()),
                 (String::from("request_url"), String::from("/auth/api/token/verify")),
             ];
+            let message = options.get_message().unwrap().clone();
+            let event_name = get_event_name(&message);
             let mut message_headers = FieldTable::new();
             for &(ref key, ref value) in request_headers.iter() {
                 let header_name = key.to_string();
                 let header_value = AMQPValue::LongString(value.to_string());
                 message_headers.insert(header_name, header_value);
             }
+            message_headers.insert(String::from("event_name"), AMQPValue::LongString(event_name));
 
-            let message = options.get_message().unwrap().clone();
             let queue_name_response = options.get_queue_name().unwrap().clone();
-            let event_name = message["event-name"].as_str().unwrap_or("null");
+            let correlation_id = get_correlation_id(&message);
             let request_body = object!{ "access_token" => access_token };
             let basic_properties = BasicProperties::default()
                 .with_content_type("application/json".to_string())    // Content type
                 .with_headers(message_headers)                        // Headers for the message
                 .with_delivery_mode(2)                                // Message must be persistent
                 .with_reply_to(queue_name_response.to_string())       // Response queue
-                .with_correlation_id(event_name.clone().to_string()); // Event name
+                .with_correlation_id(correlation_id.clone());         // Request ID
 
             publish_channel
                 .basic_publish(
-                    TOKEN_VERIFY_EXCHANGE.clone(),
-                    TOKEN_VERIFY_ROUTING_KEY.clone(),
+                    &exchange,
+                    &routing_key,
                     request_body.dump().as_bytes().to_vec(),
                     publish_message_options,
                     basic_properties
                 )
                 .map(move |confirmation| {
                     match confirmation {
-                        Some(_) => info!("Publish for verifying JWT got confirmation."),
-                        None => warn!("Request for verifying JWT wasn't delivered."),
+                        Some(_) => info!("[{}] Publish for verifying JWT got confirmation.", correlation_id),
+                        None => warn!("[{}] Request for verifying JWT wasn't delivered.", correlation_id),
                     };
 
                     (publish_channel, consume_channel, queue, options)
@@ -135,26 +242,29 @@ impl JwtTokenMiddleware {
             consume_channel
                 .basic_consume(
                     &queue,
-                    "response_consumer",
+                    &consumer_tag,
                     BasicConsumeOptions::default(),
                     FieldTable::new()
                 )
                 .and_then(move |stream| {
-                    stream
-                        .take(1)
-                        .into_future()
-                        .map_err(|(err, _)| err)
-                        .map(move |(message, _)| (publish_channel, consume_channel, queue, message.unwrap(), options))
+                    receive_response(stream, max_response_size, DEFAULT_RPC_TIMEOUT)
+                        .map(move |response| (publish_channel, consume_channel, queue, response, options))
                 })
         })
-        // 5. Prepare a response for a client, serialize and pass to the next processing stage
-        .and_then(move |(publish_channel, consume_channel, queue, message, options)| {
-            let raw_data = from_utf8(&message.data).unwrap();
-            let json = parse_json(raw_data).unwrap();
-
-            consume_channel
-                .basic_ack(message.delivery_tag, false)
-                .map(move |_confirmation| (publish_channel, consume_channel, queue, options, json))
+        // 5. Acknowledge all parts of the response and pass it to the next processing stage
+        .and_then(move |(publish_channel, consume_channel, queue, response, options)| match response {
+            Some((delivery_tag, json)) => {
+                Either::A(
+                    consume_channel
+                        .basic_ack(delivery_tag, true)
+                        .map(move |_confirmation| (publish_channel, consume_channel, queue, options, json))
+                )
+            },
+            None => {
+                let message = String::from("The response wasn't received. Please, try once again.");
+                let json = Err(PathfinderError::MessageBrokerError(message));
+                Either::B(ok((publish_channel, consume_channel, queue, options, json)))
+            }
         })
         // 6. Unbind the response queue from the exchange point
         .and_then(move |(publish_channel, consume_channel, _queue, options, json)| {
@@ -164,7 +274,7 @@ impl JwtTokenMiddleware {
             consume_channel
                 .queue_unbind(
                     &queue_name,
-                    RESPONSE_EXCHANGE.clone(),
+                    &response_exchange_inner,
                     &routing_key,
                     QueueUnbindOptions::default(),
                     FieldTable::new(),
@@ -186,58 +296,66 @@ impl JwtTokenMiddleware {
         })
         // 8. Prepare the response for the client
         .then(move |result| match result {
-            Ok(json) => {
-                let has_errors = !json["error"].is_null();
-                if has_errors {
-                    let errors = json["error"].clone();
-                    return Err(PathfinderError::MicroserviceError(errors))
-                };
-
-                let is_valid_response = !json["content"].is_null();
-                let is_valid_token = json["content"]["is_valid"].as_bool().unwrap();
-                match is_valid_response && is_valid_token {
-                    true => Ok(()),
-                    false => {
-                        let message = String::from("Token is invalid.");
-                        Err(PathfinderError::AuthenticationError(message))
-                    }
-                }
-            },
+            Ok(json) => json.and_then(check_token_verification),
             Err(err) => {
-                error!("Error in RabbitMQ client. Reason: {}", err);
+                error!("[{}] Error in RabbitMQ client. Reason: {}", correlation_id, err);
                 let message = String::from("The request wasn't processed. Please, try once again.");
                 Err(PathfinderError::MessageBrokerError(message))
             }
-        })
+        });
+
+        Either::B(future)
     }
 
     /// Performs a request to Auth/Auth microservice with the taken token
     /// that will be used for getting a list of permissions to other resources.
-    fn get_headers(&self, message: JsonMessage, token: String, rabbitmq_context: Arc<RabbitMQContext>)
+    fn get_headers(options: &JwtOptions, message: JsonMessage, token: String, rabbitmq_context: Arc<RabbitMQContext>)
         -> impl Future<Item=CustomUserHeaders, Error=PathfinderError> + Sync + Send + 'static
     {
+        let exchange = options.get_user_profile_exchange();
+        let routing_key = options.get_user_profile_routing_key();
+        let permissions_format = options.get_permissions_format();
+        if let Some(direct_reply_to) = rabbitmq_context.get_direct_reply_to() {
+            let request_headers = vec![
+                (String::from("microservice_name"), String::from("microservice-auth")),
+                (String::from("request_url"), String::from("/auth/api/users/profile")),
+            ];
+            return Either::A(
+                JwtTokenMiddleware::direct_request(
+                    message, token, rabbitmq_context, direct_reply_to, request_headers,
+                    &exchange, &routing_key
+                )
+                .and_then(move |json| extract_user_headers(json, permissions_format))
+            );
+        }
+
+        let queue_declare_options = options.get_reply_queue().get_declare_options();
+        let queue_declare_arguments = options.get_reply_queue().get_declare_arguments();
+        let max_response_size = options.get_max_response_size();
         let access_token = token.clone();
+        let correlation_id = get_correlation_id(&message);
+        let reply_queue_name = options.get_reply_queue().get_queue_name(&routing_key);
+        let consumer_tag = options.get_reply_queue().get_consumer_tag(&reply_queue_name);
+        let response_exchange = options.get_response_exchange();
+        let response_exchange_inner = response_exchange.clone();
         let options = Arc::new(RpcOptions::default()
             .with_message(message.clone())
-            .with_queue_name(Arc::new(format!("{}", Uuid::new_v4())))
+            .with_queue_name(Arc::new(reply_queue_name))
         );
         let rabbitmq_context_local = rabbitmq_context.clone();
         let publish_channel = rabbitmq_context_local.get_publish_channel();
         let consume_channel = rabbitmq_context_local.get_consume_channel();
 
         let queue_name = options.get_queue_name().unwrap().clone();
-        let queue_declare_options = QueueDeclareOptions {
-            passive: false,
-            durable: true,
-            exclusive: true,
-            auto_delete: false,
-            ..Default::default()
-        };
 
-        // 1. Declare a response queue
-        consume_channel
-            .queue_declare(&queue_name, queue_declare_options, FieldTable::new())
-            .map(move |queue| (publish_channel, consume_channel, queue, options))
+        // 1. Declare a response queue, when the rate of declarations allows it
+        let future = rabbitmq_context_local
+            .wait_for_queue_declare()
+            .and_then(move |_| {
+                consume_channel
+                    .queue_declare(&queue_name, queue_declare_options, queue_declare_arguments)
+                    .map(move |queue| (publish_channel, consume_channel, queue, options))
+            })
         // 2. Link the response queue the exchange
         .and_then(move |(publish_channel, consume_channel, queue, options)| {
             let queue_name = options.get_queue_name().unwrap().clone();
@@ -246,7 +364,7 @@ impl JwtTokenMiddleware {
             consume_channel
                 .queue_bind(
                     &queue_name,
-                    RESPONSE_EXCHANGE.clone(),
+                    &response_exchange,
                     &routing_key,
                     QueueBindOptions::default(),
                     FieldTable::new()
@@ -265,36 +383,38 @@ impl JwtTokenMiddleware {
                 (String::from("microservice_name"), String::from("microservice-auth")),
                 (String::from("request_url"), String::from("/auth/api/users/profile")),
             ];
+            let message = options.get_message().unwrap().clone();
+            let event_name = get_event_name(&message);
             let mut message_headers = FieldTable::new();
             for &(ref key, ref value) in request_headers.iter() {
                 let header_name = key.to_string();
                 let header_value = AMQPValue::LongString(value.to_string());
                 message_headers.insert(header_name, header_value);
             }
+            message_headers.insert(String::from("event_name"), AMQPValue::LongString(event_name));
 
-            let message = options.get_message().unwrap().clone();
             let queue_name_response = options.get_queue_name().unwrap().clone();
-            let event_name = message["event-name"].as_str().unwrap_or("null");
+            let correlation_id = get_correlation_id(&message);
             let request_body = object!{ "access_token" => access_token };
             let basic_properties = BasicProperties::default()
                 .with_content_type("application/json".to_string())    // Content type
                 .with_headers(message_headers)                        // Headers for the message
                 .with_delivery_mode(2)                                // Message must be persistent
                 .with_reply_to(queue_name_response.to_string())       // Response queue
-                .with_correlation_id(event_name.clone().to_string()); // Event name
+                .with_correlation_id(correlation_id.clone());         // Request ID
 
             publish_channel
                 .basic_publish(
-                    TOKEN_USER_PROFILE_EXCHANGE.clone(),
-                    TOKEN_USER_PROFILE_ROUTING_KEY.clone(),
+                    &exchange,
+                    &routing_key,
                     request_body.dump().as_bytes().to_vec(),
                     publish_message_options,
                     basic_properties
                 )
                 .map(move |confirmation| {
                     match confirmation {
-                        Some(_) => info!("Publish for getting headers got confirmation."),
-                        None => warn!("Request for getting headers wasn't delivered."),
+                        Some(_) => info!("[{}] Publish for getting headers got confirmation.", correlation_id),
+                        None => warn!("[{}] Request for getting headers wasn't delivered.", correlation_id),
                     };
 
                     (publish_channel, consume_channel, queue, options)
@@ -305,26 +425,29 @@ impl JwtTokenMiddleware {
             consume_channel
                 .basic_consume(
                     &queue,
-                    "response_consumer",
+                    &consumer_tag,
                     BasicConsumeOptions::default(),
                     FieldTable::new()
                 )
                 .and_then(move |stream| {
-                    stream
-                        .take(1)
-                        .into_future()
-                        .map_err(|(err, _)| err)
-                        .map(move |(message, _)| (publish_channel, consume_channel, queue, message.unwrap(), options))
+                    receive_response(stream, max_response_size, DEFAULT_RPC_TIMEOUT)
+                        .map(move |response| (publish_channel, consume_channel, queue, response, options))
                 })
         })
-        // 5. Prepare a response for a client, serialize and pass to the next processing stage
-        .and_then(move |(publish_channel, consume_channel, queue, message, options)| {
-            let raw_data = from_utf8(&message.data).unwrap();
-            let json = parse_json(raw_data).unwrap();
-
-            consume_channel
-                .basic_ack(message.delivery_tag, false)
-                .map(move |_confirmation| (publish_channel, consume_channel, queue, options, json))
+        // 5. Acknowledge all parts of the response and pass it to the next processing stage
+        .and_then(move |(publish_channel, consume_channel, queue, response, options)| match response {
+            Some((delivery_tag, json)) => {
+                Either::A(
+                    consume_channel
+                        .basic_ack(delivery_tag, true)
+                        .map(move |_confirmation| (publish_channel, consume_channel, queue, options, json))
+                )
+            },
+            None => {
+                let message = String::from("The response wasn't received. Please, try once again.");
+                let json = Err(PathfinderError::MessageBrokerError(message));
+                Either::B(ok((publish_channel, consume_channel, queue, options, json)))
+            }
         })
         // 6. Unbind the response queue from the exchange point
         .and_then(move |(publish_channel, consume_channel, _queue, options, json)| {
@@ -334,7 +457,7 @@ impl JwtTokenMiddleware {
             consume_channel
                 .queue_unbind(
                     &queue_name,
-                    RESPONSE_EXCHANGE.clone(),
+                    &response_exchange_inner,
                     &routing_key,
                     QueueUnbindOptions::default(),
                     FieldTable::new(),
@@ -356,48 +479,481 @@ impl JwtTokenMiddleware {
         })
         // 8. Prepare the response for the client
         .then(move |result| match result {
-            Ok(json) => {
-                let has_errors = !json["error"].is_null();
-                if has_errors {
-                    let errors = json["error"].clone();
-                    return Err(PathfinderError::MicroserviceError(errors))
-                };
-
-                let is_valid_response = !json["content"].is_null();
-                match is_valid_response {
-                    true => {
-                        let mut extra_headers: CustomUserHeaders = HashMap::new();
-                        extra_headers.insert(String::from("permissions"), get_permissions(&json));
-                        Ok(extra_headers)
-                    },
-                    false => Ok(HashMap::new())
-                }
-            },
+            Ok(json) => json.and_then(|json| extract_user_headers(json, permissions_format)),
             Err(err) => {
-                error!("Error in RabbitMQ client. Reason: {}", err);
+                error!("[{}] Error in RabbitMQ client. Reason: {}", correlation_id, err);
                 let message = String::from("The request wasn't processed. Please, try once again.");
                 Err(PathfinderError::MessageBrokerError(message))
             }
+        });
+
+        Either::B(future)
+    }
+
+    /// Performs a request to Auth/Auth microservice with the taken token via
+    /// the direct reply-to and returns the parsed response.
+    #[allow(clippy::too_many_arguments)]
+    fn direct_request(
+        message: JsonMessage,
+        token: String,
+        rabbitmq_context: Arc<RabbitMQContext>,
+        direct_reply_to: Arc<DirectReplyTo>,
+        request_headers: Vec<(String, String)>,
+        exchange: &str,
+        routing_key: &str
+    ) -> impl Future<Item=JsonValue, Error=PathfinderError> + Sync + Send + 'static {
+        let mut message_headers = FieldTable::new();
+        for (key, value) in request_headers.into_iter() {
+            message_headers.insert(key, AMQPValue::LongString(value));
+        }
+        let event_name = get_event_name(&message);
+        message_headers.insert(String::from("event_name"), AMQPValue::LongString(event_name));
+
+        // The request is published with a unique ID, so the ID of the client
+        // is passed in the headers
+        let correlation_id = get_correlation_id(&message);
+        message_headers.insert(String::from("correlation_id"), AMQPValue::LongString(correlation_id.clone()));
+        let routing_key = routing_key.to_string();
+        let request_body = object!{ "access_token" => token };
+        direct_reply_to_future(
+            rabbitmq_context,
+            direct_reply_to,
+            correlation_id.clone(),
+            exchange.to_string(),
+            routing_key.clone(),
+            request_body.dump().as_bytes().to_vec(),
+            DEFAULT_CONTENT_TYPE.to_string(),
+            message_headers,
+            DEFAULT_RPC_TIMEOUT,
+            DEFAULT_CONFIRM_TIMEOUT
+        )
+        .then(move |result| get_direct_reply_to_response(result, &routing_key, &correlation_id))
+        .and_then(|message| parse_response(&message.data))
+    }
+}
+
+/// Returns the remaining lifetime of the token by the `exp` claim of its
+/// payload. The signature isn't checked here, because the token is verified
+/// by Auth/Auth microservice anyway.
+fn get_token_lifetime(token: &str) -> Option<Duration> {
+    let payload = token.split('.').nth(1)?.trim_end_matches('=');
+    let data = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims = parse_json(from_utf8(&data).ok()?).ok()?;
+    let expires_at = UNIX_EPOCH + Duration::from_secs(claims["exp"].as_u64()?);
+    Some(expires_at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Parses the body of the response from Auth/Auth microservice. Responses
+/// that can't be decoded are treated as a failed authentication, because
+/// sending the same request again will not help.
+fn parse_response(data: &[u8]) -> Result<JsonValue, PathfinderError> {
+    from_utf8(data)
+        .map_err(|err| format!("{}", err))
+        .and_then(|raw_data| parse_json(raw_data).map_err(|err| format!("{}", err)))
+        .map_err(|err| {
+            let message = format!("The response of Auth/Auth microservice can't be decoded: {}", err);
+            PathfinderError::AuthenticationError(message)
         })
+}
+
+/// Collects the parts of the response from Auth/Auth microservice, until
+/// they form a complete JSON document.
+struct ResponseBuffer {
+    data: Vec<u8>,
+    max_size: usize
+}
+
+impl ResponseBuffer {
+    /// Returns a new instance of `ResponseBuffer`.
+    fn new(max_size: usize) -> ResponseBuffer {
+        ResponseBuffer {
+            data: Vec::new(),
+            max_size
+        }
+    }
+
+    /// Appends the next part of the response. Returns the parsed response
+    /// when it's complete, or an error when it can't be completed anymore.
+    fn push(&mut self, part: &[u8]) -> Option<Result<JsonValue, PathfinderError>> {
+        self.data.extend_from_slice(part);
+        if self.data.len() > self.max_size {
+            let message = format!("The response of Auth/Auth microservice exceeds {} bytes.", self.max_size);
+            return Some(Err(PathfinderError::MessageBrokerError(message)));
+        }
+
+        match parse_response(&self.data) {
+            Ok(json) => Some(Ok(json)),
+            Err(_) if is_incomplete_json(&self.data) => None,
+            Err(error) => Some(Err(error))
+        }
+    }
+}
+
+/// Checks whether the data is a beginning of a valid JSON document, so that
+/// the rest of it can be received in the next parts.
+fn is_incomplete_json(data: &[u8]) -> bool {
+    match from_utf8(data) {
+        Ok(raw_data) => matches!(parse_json(raw_data), Err(JsonError::UnexpectedEndOfJson)),
+        Err(error) => error.error_len().is_none()
+    }
+}
+
+/// Receives the parts of the response from Auth/Auth microservice until they
+/// form a complete JSON document. Returns the delivery tag of the last part
+/// with the parsed response, or `None` when the response wasn't completed
+/// during the specified time.
+fn receive_response<S>(stream: S, max_size: usize, timeout: Duration)
+    -> impl Future<Item=Option<(u64, Result<JsonValue, PathfinderError>)>, Error=S::Error>
+    where S: Stream<Item=Delivery>
+{
+    let mut buffer = ResponseBuffer::new(max_size);
+    let responses = stream.filter_map(move |delivery| {
+        buffer.push(&delivery.data).map(|json| (delivery.delivery_tag, json))
+    });
+    take_first_with_timeout(responses, timeout)
+}
+
+/// Checks the response from Auth/Auth microservice on the token verification.
+fn check_token_verification(json: JsonValue) -> Result<(), PathfinderError> {
+    let has_errors = !json["error"].is_null();
+    if has_errors {
+        let errors = json["error"].clone();
+        return Err(PathfinderError::MicroserviceError(errors))
+    };
+
+    match json["content"]["is_valid"].as_bool() {
+        Some(true) => Ok(()),
+        Some(false) => {
+            let message = String::from("Token is invalid.");
+            Err(PathfinderError::AuthenticationError(message))
+        },
+        None => {
+            let message = String::from("Token wasn't verified: the response of Auth/Auth microservice is invalid.");
+            Err(PathfinderError::AuthenticationError(message))
+        }
+    }
+}
+
+/// Extracts custom user headers from the response of Auth/Auth microservice.
+/// The permissions are encoded in the specified format.
+fn extract_user_headers(json: JsonValue, permissions_format: PermissionsFormat) -> Result<CustomUserHeaders, PathfinderError> {
+    let has_errors = !json["error"].is_null();
+    if has_errors {
+        let errors = json["error"].clone();
+        return Err(PathfinderError::MicroserviceError(errors))
+    };
+
+    let is_valid_response = !json["content"].is_null();
+    match is_valid_response {
+        true => {
+            let mut extra_headers: CustomUserHeaders = HashMap::new();
+            let permissions = encode_permissions(&get_permissions(&json), permissions_format);
+            extra_headers.insert(String::from(PERMISSIONS_HEADER), permissions);
+            Ok(extra_headers)
+        },
+        false => Ok(HashMap::new())
     }
 }
 
 impl Middleware for JwtTokenMiddleware {
-    fn process_request(&self, message: JsonMessage, rabbitmq_context: Arc<RabbitMQContext>) -> MiddlewareFuture {
-        // Extract a token from a JSON object
-        let token = match message["token"].as_str() {
-            Some(token) => String::from(token),
-            _ => {
-                return Box::new(lazy(move || {
-                    let message = String::from("The `token` field must be specified.");
-                    Err(PathfinderError::AuthenticationError(message))
-                }))
-            }
+    fn process_request(
+        &self,
+        message: JsonMessage,
+        _endpoint: Option<ReadOnlyEndpoint>,
+        rabbitmq_context: Arc<RabbitMQContext>
+    ) -> MiddlewareFuture {
+        // Verify the passed JSON Web Token and extract permissions. Requests that
+        // failed because of the message broker are sent once again
+        let correlation_id = get_correlation_id(&message);
+        let retry_attempts = self.options.get_retry_attempts();
+        let retry_delay = self.options.get_retry_delay();
+
+        let options = self.options.clone();
+        let (message_inner, rabbitmq_context_inner, correlation_id_inner) = (message.clone(), rabbitmq_context.clone(), correlation_id.clone());
+        let verify_token = move |token: String| retry_future(
+            retry_attempts, retry_delay, "Verifying the token", correlation_id_inner,
+            move || JwtTokenMiddleware::verify_token(&options, message_inner.clone(), token.clone(), rabbitmq_context_inner.clone())
+        );
+
+        let options = self.options.clone();
+        let (message_inner, rabbitmq_context_inner) = (message.clone(), rabbitmq_context);
+        let get_headers = move |token: String| retry_future(
+            retry_attempts, retry_delay, "Getting the user headers", correlation_id,
+            move || JwtTokenMiddleware::get_headers(&options, message_inner.clone(), token.clone(), rabbitmq_context_inner.clone())
+        );
+
+        self.authenticate(&message, verify_token, get_headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use futures::future::{ok, result, Future, FutureResult};
+    use futures::stream;
+    use json::{object, JsonValue};
+    use lapin_futures::message::Delivery;
+    use tokio::runtime::Runtime;
+
+    use crate::engine::middleware::base::CustomUserHeaders;
+    use crate::engine::middleware::cache::TokenCache;
+    use crate::engine::middleware::jwt::{
+        check_token_verification, extract_user_headers, get_token_lifetime, parse_response, receive_response,
+        JwtTokenMiddleware, ResponseBuffer
+    };
+    use crate::engine::middleware::options::JwtOptions;
+    use crate::engine::middleware::utils::PermissionsFormat;
+    use crate::engine::serializer::JsonMessage;
+    use crate::error::PathfinderError;
+
+    fn get_message(json: JsonValue) -> JsonMessage {
+        Arc::new(Box::new(json))
+    }
+
+    #[test]
+    fn test_extract_token_returns_the_passed_token() {
+        let message = get_message(object!{"url" => "/api/test", "token" => "jwt"});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default());
+        assert_eq!(middleware.extract_token(&message).unwrap(), Some(String::from("jwt")));
+
+        let middleware = middleware.with_optional_token();
+        assert_eq!(middleware.extract_token(&message).unwrap(), Some(String::from("jwt")));
+    }
+
+    #[test]
+    fn test_extract_token_requires_token_by_default() {
+        let message = get_message(object!{"url" => "/api/test"});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default());
+        match middleware.extract_token(&message) {
+            Err(PathfinderError::AuthenticationError(message)) => assert_eq!(message, "The `token` field must be specified."),
+            _ => panic!("Expected an authentication error")
+        }
+    }
+
+    #[test]
+    fn test_extract_token_allows_absent_optional_token() {
+        let message = get_message(object!{"url" => "/api/test"});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default()).with_optional_token();
+        assert_eq!(middleware.extract_token(&message).unwrap(), None);
+    }
+
+    #[test]
+    fn test_extract_token_rejects_invalid_optional_token() {
+        let message = get_message(object!{"url" => "/api/test", "token" => 42});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default()).with_optional_token();
+        assert!(middleware.extract_token(&message).is_err());
+    }
+
+    /// Processes the message as if Auth/Auth microservice returned the passed
+    /// responses for verifying the token and for getting the user profile.
+    fn authenticate(middleware: &JwtTokenMiddleware, message: JsonMessage, verify_response: JsonValue, profile_response: JsonValue)
+        -> Result<CustomUserHeaders, PathfinderError>
+    {
+        let verify_token = move |token: String| {
+            assert_eq!(token, "jwt");
+            result(check_token_verification(verify_response))
+        };
+        let get_headers = move |token: String| {
+            assert_eq!(token, "jwt");
+            result(extract_user_headers(profile_response, PermissionsFormat::Json))
         };
+        middleware.authenticate(&message, verify_token, get_headers).wait()
+    }
+
+    #[test]
+    fn test_authenticate_attaches_permissions_for_a_valid_optional_token() {
+        let message = get_message(object!{"url" => "/api/test", "token" => "jwt"});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default()).with_optional_token();
+        let verify_response = object!{"content" => object!{"is_valid" => true}};
+        let profile_response = object!{"content" => object!{"permissions" => vec!["chat.read"]}};
+
+        let headers = authenticate(&middleware, message, verify_response, profile_response).unwrap();
+        assert_eq!(headers["permissions"], "[\"chat.read\"]");
+    }
+
+    #[test]
+    fn test_authenticate_fails_for_an_optional_token_rejected_by_the_microservice() {
+        let message = get_message(object!{"url" => "/api/test", "token" => "jwt"});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default()).with_optional_token();
+        let verify_response = object!{"content" => object!{"is_valid" => false}};
+        let profile_response = object!{"content" => object!{"permissions" => vec!["chat.read"]}};
+
+        match authenticate(&middleware, message, verify_response, profile_response) {
+            Err(PathfinderError::AuthenticationError(message)) => assert_eq!(message, "Token is invalid."),
+            _ => panic!("Expected an authentication error")
+        }
+    }
+
+    #[test]
+    fn test_authenticate_returns_empty_headers_for_an_absent_optional_token() {
+        let message = get_message(object!{"url" => "/api/test"});
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default()).with_optional_token();
+        let verify_token = |_token: String| -> FutureResult<(), PathfinderError> { panic!("The token must not be verified") };
+        let get_headers = |_token: String| -> FutureResult<CustomUserHeaders, PathfinderError> { panic!("The headers must not be requested") };
+
+        let headers = middleware.authenticate(&message, verify_token, get_headers).wait().unwrap();
+        assert!(headers.is_empty());
+    }
+
+    fn get_token(claims: JsonValue) -> String {
+        let payload = base64::encode_config(claims.dump().as_bytes(), base64::URL_SAFE_NO_PAD);
+        format!("eyJhbGciOiJIUzI1NiJ9.{}.signature", payload)
+    }
+
+    #[test]
+    fn test_get_token_lifetime_is_taken_from_the_exp_claim() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let lifetime = get_token_lifetime(&get_token(object!{"exp" => now + 30})).unwrap();
+        assert!(lifetime > Duration::from_secs(25) && lifetime <= Duration::from_secs(30));
+
+        let lifetime = get_token_lifetime(&get_token(object!{"exp" => now - 30})).unwrap();
+        assert_eq!(lifetime, Duration::from_secs(0));
+
+        assert_eq!(get_token_lifetime(&get_token(object!{"user_id" => "1"})), None);
+        assert_eq!(get_token_lifetime("jwt"), None);
+    }
+
+    #[test]
+    fn test_authenticate_does_not_cache_tokens_after_their_expiration() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let token = get_token(object!{"exp" => now - 30});
+        let message = get_message(object!{"url" => "/api/test", "token" => token.as_str()});
+        let cache = TokenCache::new(10, Duration::from_secs(60));
+        let middleware = JwtTokenMiddleware::new(JwtOptions::default()).with_cache(cache);
+        let verify_token = |_token: String| ok::<(), PathfinderError>(());
+        let get_headers = |_token: String| ok::<CustomUserHeaders, PathfinderError>(HashMap::new());
+
+        assert!(middleware.authenticate(&message, verify_token, get_headers).wait().is_ok());
+        assert!(middleware.cache.as_ref().unwrap().get(&token).is_none());
+    }
+
+    fn get_authentication_error(result: Result<(), PathfinderError>) -> String {
+        match result {
+            Err(PathfinderError::AuthenticationError(message)) => message,
+            _ => panic!("Expected an authentication error")
+        }
+    }
+
+    #[test]
+    fn test_check_token_verification_for_valid_token() {
+        let json = object!{"content" => object!{"is_valid" => true}};
+        assert!(check_token_verification(json).is_ok());
+    }
+
+    #[test]
+    fn test_check_token_verification_for_invalid_token() {
+        let json = object!{"content" => object!{"is_valid" => false}};
+        assert_eq!(get_authentication_error(check_token_verification(json)), "Token is invalid.");
+    }
+
+    #[test]
+    fn test_check_token_verification_for_response_without_is_valid_field() {
+        let json = object!{"content" => object!{"user_id" => "1"}};
+        let message = get_authentication_error(check_token_verification(json));
+        assert_eq!(message, "Token wasn't verified: the response of Auth/Auth microservice is invalid.");
+
+        assert!(check_token_verification(object!{}).is_err());
+    }
+
+    #[test]
+    fn test_check_token_verification_passes_microservice_errors() {
+        let json = object!{"error" => object!{"type" => "ValidationError"}};
+        match check_token_verification(json) {
+            Err(PathfinderError::MicroserviceError(_)) => {},
+            _ => panic!("Expected a microservice error")
+        }
+    }
+
+    #[test]
+    fn test_parse_response_for_malformed_data() {
+        assert!(parse_response(b"{\"content\": {}}").is_ok());
+        assert!(parse_response(b"not a json").is_err());
+        assert!(parse_response(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn test_extract_user_headers_without_content() {
+        let headers = extract_user_headers(object!{}, PermissionsFormat::Json).unwrap();
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_extract_user_headers_encodes_permissions_in_the_format() {
+        let response = object!{"content" => object!{"permissions" => vec!["chat;read", "chat.write"]}};
+        let headers = extract_user_headers(response.clone(), PermissionsFormat::Json).unwrap();
+        assert_eq!(headers["permissions"], "[\"chat;read\",\"chat.write\"]");
+
+        let headers = extract_user_headers(response, PermissionsFormat::Legacy).unwrap();
+        assert_eq!(headers["permissions"], "chat;read;chat.write");
+    }
+
+    #[test]
+    fn test_response_buffer_returns_the_response_sent_in_one_part() {
+        let mut buffer = ResponseBuffer::new(1024);
+        let json = buffer.push(b"{\"content\": {\"is_valid\": true}}").unwrap().unwrap();
+        assert_eq!(json["content"]["is_valid"], true);
+    }
+
+    #[test]
+    fn test_response_buffer_waits_for_the_complete_response() {
+        let mut buffer = ResponseBuffer::new(1024);
+        assert!(buffer.push(b"{\"content\": {\"permis").is_none());
+        assert!(buffer.push("sions\": [\"caf\u{e9}".as_bytes().split_last().unwrap().1).is_none());
+        let json = buffer.push(&[0xa9, b'"', b']', b'}', b'}']).unwrap().unwrap();
+        assert_eq!(json["content"]["permissions"][0], "caf\u{e9}");
+    }
+
+    #[test]
+    fn test_response_buffer_rejects_malformed_responses_immediately() {
+        let mut buffer = ResponseBuffer::new(1024);
+        match buffer.push(b"not a json") {
+            Some(Err(PathfinderError::AuthenticationError(_))) => {},
+            _ => panic!("Expected an authentication error")
+        }
+    }
+
+    #[test]
+    fn test_response_buffer_rejects_too_large_responses() {
+        let mut buffer = ResponseBuffer::new(16);
+        assert!(buffer.push(b"{\"content\": ").is_none());
+        match buffer.push(b"{\"permissions\": []}}") {
+            Some(Err(PathfinderError::MessageBrokerError(message))) => {
+                assert_eq!(message, "The response of Auth/Auth microservice exceeds 16 bytes.");
+            },
+            _ => panic!("Expected a message broker error")
+        }
+    }
+
+    #[test]
+    fn test_receive_response_returns_the_tag_of_the_last_part() {
+        let parts: Vec<&[u8]> = vec![b"{\"content\":", b" {\"is_valid\": ", b"false}}", b"{}"];
+        let deliveries: Vec<Delivery> = parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let mut delivery = Delivery::new(index as u64 + 1, String::new(), String::new(), false);
+                delivery.data = data.to_vec();
+                delivery
+            })
+            .collect();
+
+        let mut runtime = Runtime::new().unwrap();
+        let stream = stream::iter_ok::<_, ()>(deliveries);
+        let (delivery_tag, json) = runtime.block_on(receive_response(stream, 1024, Duration::from_secs(5))).unwrap().unwrap();
+        assert_eq!(delivery_tag, 3);
+        assert_eq!(json.unwrap()["content"]["is_valid"], false);
+    }
+
+    #[test]
+    fn test_receive_response_returns_none_for_incomplete_responses() {
+        let mut delivery = Delivery::new(1, String::new(), String::new(), false);
+        delivery.data = b"{\"content\":".to_vec();
 
-        // Verify the passed JSON Web Token and extract permissions
-        let verify_token_future = self.verify_token(message.clone(),token.clone(), rabbitmq_context.clone());
-        let get_headers_future = self.get_headers(message.clone(),token.clone(), rabbitmq_context.clone());
-        Box::new(verify_token_future.and_then(move |_| get_headers_future))
+        let mut runtime = Runtime::new().unwrap();
+        let stream = stream::iter_ok::<_, ()>(vec![delivery]);
+        assert!(runtime.block_on(receive_response(stream, 1024, Duration::from_secs(5))).unwrap().is_none());
     }
 }
@@ -2,18 +2,29 @@
 //!
 
 pub mod base;
+pub mod cache;
 pub mod empty;
 pub mod jwt;
+pub mod options;
+pub mod replay;
+pub mod retry;
 pub mod utils;
 
 // For more details about used exchanges and routing keys look in the
-// Open Matchmaking documentation on GitHub.
+// Open Matchmaking documentation on GitHub. These values are used by default
+// and can be overridden in the `auth` section of the configuration file.
 pub const TOKEN_VERIFY_ROUTING_KEY: &'static str = "auth.token.verify";
 pub const TOKEN_VERIFY_EXCHANGE: &'static str = "open-matchmaking.auth.token.verify.direct";
 pub const TOKEN_USER_PROFILE_ROUTING_KEY: &'static str = "auth.users.retrieve";
 pub const TOKEN_USER_PROFILE_EXCHANGE: &'static str = "open-matchmaking.auth.users.retrieve.direct";
 
 pub use self::base::{Middleware, MiddlewareFuture, CustomUserHeaders};
+pub use self::cache::{NonceCache, NonceStatus, TokenCache};
 pub use self::empty::EmptyMiddleware;
 pub use self::jwt::JwtTokenMiddleware;
-pub use self::utils::get_permissions;
+pub use self::options::JwtOptions;
+pub use self::replay::ReplayProtectionMiddleware;
+pub use self::utils::{
+    decode_permissions, encode_permissions, get_permissions, get_user_permissions, has_permission,
+    PermissionsFormat
+};
@@ -0,0 +1,197 @@
+//! Options that define how the JWT middleware communicates with Auth/Auth microservice.
+//!
+
+use std::time::Duration;
+
+use config::Config;
+
+use crate::engine::RESPONSE_EXCHANGE;
+use crate::engine::options::{AmqpPrefix, ReplyQueueOptions};
+use crate::engine::middleware::utils::PermissionsFormat;
+use crate::engine::middleware::{
+    TOKEN_VERIFY_ROUTING_KEY,
+    TOKEN_VERIFY_EXCHANGE,
+    TOKEN_USER_PROFILE_ROUTING_KEY,
+    TOKEN_USER_PROFILE_EXCHANGE
+};
+
+/// The default number of additional attempts for failed requests.
+pub const DEFAULT_RETRY_ATTEMPTS: usize = 2;
+/// The default time to wait before the next attempt.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// The default maximum size of a response from Auth/Auth microservice in bytes.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 1_048_576;
+
+/// Simple wrapper for routing keys and exchanges of Auth/Auth microservice
+/// and the retry policy for requests that failed because of the message broker.
+#[derive(Clone, Debug)]
+pub struct JwtOptions {
+    token_verify_routing_key: String,
+    token_verify_exchange: String,
+    user_profile_routing_key: String,
+    user_profile_exchange: String,
+    response_exchange: String,
+    retry_attempts: usize,
+    retry_delay: Duration,
+    max_response_size: usize,
+    permissions_format: PermissionsFormat,
+    reply_queue: ReplyQueueOptions
+}
+
+impl Default for JwtOptions {
+    fn default() -> JwtOptions {
+        JwtOptions {
+            token_verify_routing_key: String::from(TOKEN_VERIFY_ROUTING_KEY),
+            token_verify_exchange: String::from(TOKEN_VERIFY_EXCHANGE),
+            user_profile_routing_key: String::from(TOKEN_USER_PROFILE_ROUTING_KEY),
+            user_profile_exchange: String::from(TOKEN_USER_PROFILE_EXCHANGE),
+            response_exchange: String::from(RESPONSE_EXCHANGE),
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            permissions_format: PermissionsFormat::Json,
+            reply_queue: ReplyQueueOptions::default()
+        }
+    }
+}
+
+impl JwtOptions {
+    /// Returns an instance of `JwtOptions` with values taken from the `auth`
+    /// section of the configuration. Missing keys are replaced by the default values.
+    pub fn from_config(conf: &Config) -> JwtOptions {
+        let defaults = JwtOptions::default();
+        let get_value = |key: &str, default: String| conf.get_str(&format!("auth.{}", key)).unwrap_or(default);
+        let get_number = |key: &str| conf.get_int(&format!("auth.{}", key)).ok().filter(|value| *value >= 0);
+
+        JwtOptions {
+            token_verify_routing_key: get_value("token_verify_routing_key", defaults.token_verify_routing_key),
+            token_verify_exchange: get_value("token_verify_exchange", defaults.token_verify_exchange),
+            user_profile_routing_key: get_value("user_profile_routing_key", defaults.user_profile_routing_key),
+            user_profile_exchange: get_value("user_profile_exchange", defaults.user_profile_exchange),
+            response_exchange: defaults.response_exchange,
+            retry_attempts: get_number("retry_attempts")
+                .map(|value| value as usize)
+                .unwrap_or(defaults.retry_attempts),
+            retry_delay: get_number("retry_delay_ms")
+                .map(|value| Duration::from_millis(value as u64))
+                .unwrap_or(defaults.retry_delay),
+            max_response_size: get_number("max_response_size")
+                .map(|value| value as usize)
+                .unwrap_or(defaults.max_response_size),
+            permissions_format: conf.get_str("auth.permissions_format")
+                .ok()
+                .and_then(|name| PermissionsFormat::from_name(&name))
+                .unwrap_or(defaults.permissions_format),
+            reply_queue: ReplyQueueOptions::from_config(conf)
+        }
+    }
+
+    /// Prepends the prefix to the exchanges and routing keys of Auth/Auth
+    /// microservice and to the exchange of its responses.
+    pub fn with_amqp_prefix(mut self, prefix: &AmqpPrefix) -> JwtOptions {
+        self.token_verify_routing_key = prefix.apply(&self.token_verify_routing_key);
+        self.token_verify_exchange = prefix.apply(&self.token_verify_exchange);
+        self.user_profile_routing_key = prefix.apply(&self.user_profile_routing_key);
+        self.user_profile_exchange = prefix.apply(&self.user_profile_exchange);
+        self.response_exchange = prefix.apply(&self.response_exchange);
+        self
+    }
+
+    pub fn get_token_verify_routing_key(&self) -> String {
+        self.token_verify_routing_key.clone()
+    }
+
+    pub fn get_token_verify_exchange(&self) -> String {
+        self.token_verify_exchange.clone()
+    }
+
+    pub fn get_user_profile_routing_key(&self) -> String {
+        self.user_profile_routing_key.clone()
+    }
+
+    pub fn get_user_profile_exchange(&self) -> String {
+        self.user_profile_exchange.clone()
+    }
+
+    pub fn get_response_exchange(&self) -> String {
+        self.response_exchange.clone()
+    }
+
+    pub fn get_retry_attempts(&self) -> usize {
+        self.retry_attempts
+    }
+
+    pub fn get_retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+
+    pub fn get_max_response_size(&self) -> usize {
+        self.max_response_size
+    }
+
+    pub fn get_permissions_format(&self) -> PermissionsFormat {
+        self.permissions_format
+    }
+
+    pub fn get_reply_queue(&self) -> ReplyQueueOptions {
+        self.reply_queue.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::get_config;
+    use crate::engine::RESPONSE_EXCHANGE;
+    use crate::engine::options::AmqpPrefix;
+    use crate::engine::middleware::options::{
+        JwtOptions, DEFAULT_MAX_RESPONSE_SIZE, DEFAULT_RETRY_ATTEMPTS, DEFAULT_RETRY_DELAY
+    };
+    use crate::engine::middleware::{
+        PermissionsFormat,
+        TOKEN_VERIFY_ROUTING_KEY,
+        TOKEN_VERIFY_EXCHANGE,
+        TOKEN_USER_PROFILE_ROUTING_KEY,
+        TOKEN_USER_PROFILE_EXCHANGE
+    };
+
+    #[test]
+    fn test_from_config_returns_default_values_for_missing_keys() {
+        let conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        let options = JwtOptions::from_config(&conf);
+        assert_eq!(options.get_token_verify_routing_key(), TOKEN_VERIFY_ROUTING_KEY);
+        assert_eq!(options.get_token_verify_exchange(), TOKEN_VERIFY_EXCHANGE);
+        assert_eq!(options.get_user_profile_routing_key(), TOKEN_USER_PROFILE_ROUTING_KEY);
+        assert_eq!(options.get_user_profile_exchange(), TOKEN_USER_PROFILE_EXCHANGE);
+        assert_eq!(options.get_retry_attempts(), DEFAULT_RETRY_ATTEMPTS);
+        assert_eq!(options.get_retry_delay(), DEFAULT_RETRY_DELAY);
+        assert_eq!(options.get_max_response_size(), DEFAULT_MAX_RESPONSE_SIZE);
+        assert_eq!(options.get_permissions_format(), PermissionsFormat::Json);
+    }
+
+    #[test]
+    fn test_from_config_returns_configured_values() {
+        let conf = get_config("./tests/files/config_with_auth_settings.yaml");
+        let options = JwtOptions::from_config(&conf);
+        assert_eq!(options.get_token_verify_routing_key(), "staging.auth.token.verify");
+        assert_eq!(options.get_token_verify_exchange(), "staging.auth.token.verify.direct");
+        assert_eq!(options.get_user_profile_routing_key(), "staging.auth.users.retrieve");
+        assert_eq!(options.get_user_profile_exchange(), TOKEN_USER_PROFILE_EXCHANGE);
+        assert_eq!(options.get_retry_attempts(), 3);
+        assert_eq!(options.get_retry_delay(), Duration::from_millis(250));
+        assert_eq!(options.get_max_response_size(), 65536);
+        assert_eq!(options.get_permissions_format(), PermissionsFormat::Legacy);
+    }
+
+    #[test]
+    fn test_with_amqp_prefix_prepends_the_prefix_to_exchanges_and_routing_keys() {
+        let conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        let options = JwtOptions::from_config(&conf).with_amqp_prefix(&AmqpPrefix::new("staging"));
+        assert_eq!(options.get_token_verify_routing_key(), format!("staging.{}", TOKEN_VERIFY_ROUTING_KEY));
+        assert_eq!(options.get_token_verify_exchange(), format!("staging.{}", TOKEN_VERIFY_EXCHANGE));
+        assert_eq!(options.get_user_profile_routing_key(), format!("staging.{}", TOKEN_USER_PROFILE_ROUTING_KEY));
+        assert_eq!(options.get_user_profile_exchange(), format!("staging.{}", TOKEN_USER_PROFILE_EXCHANGE));
+        assert_eq!(options.get_response_exchange(), format!("staging.{}", RESPONSE_EXCHANGE));
+    }
+}
@@ -0,0 +1,162 @@
+//! The middleware that rejects replayed requests.
+//!
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::result;
+
+use crate::error::PathfinderError;
+use crate::engine::middleware::base::{Middleware, MiddlewareFuture};
+use crate::engine::middleware::cache::{NonceCache, NonceStatus};
+use crate::engine::router::ReadOnlyEndpoint;
+use crate::engine::serializer::JsonMessage;
+use crate::rabbitmq::RabbitMQContext;
+
+/// A middleware that requires the unique `nonce` and the `timestamp` (in
+/// milliseconds since the Unix epoch) in each request. Requests with the
+/// timestamp outside of the window around the current time, or with the
+/// nonce that was already used during the window, are rejected. When too
+/// many nonces are stored, requests are rejected until the oldest expire.
+pub struct ReplayProtectionMiddleware {
+    window: Duration,
+    nonces: Arc<NonceCache>
+}
+
+impl ReplayProtectionMiddleware {
+    /// Returns a new instance of `ReplayProtectionMiddleware`, that keeps up
+    /// to `capacity` recent nonces.
+    pub fn new(window: Duration, capacity: usize) -> ReplayProtectionMiddleware {
+        // The nonce must be kept while its timestamp is accepted, which is up
+        // to twice the window for the timestamps ahead of the current time
+        ReplayProtectionMiddleware {
+            window,
+            nonces: Arc::new(NonceCache::new(capacity, window * 2))
+        }
+    }
+
+    /// Checks the timestamp and the nonce of the message. The nonce is stored
+    /// only when the timestamp is valid.
+    fn check_message(&self, message: &JsonMessage) -> Result<(), PathfinderError> {
+        let (nonce, timestamp) = match (message["nonce"].as_str(), message["timestamp"].as_u64()) {
+            (Some(nonce), Some(timestamp)) if !nonce.is_empty() => (nonce, Duration::from_millis(timestamp)),
+            _ => {
+                let message = String::from("The `nonce` and `timestamp` fields must be specified.");
+                return Err(PathfinderError::AuthenticationError(message));
+            }
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let skew = match now > timestamp {
+            true => now - timestamp,
+            false => timestamp - now
+        };
+        if skew > self.window {
+            let message = String::from("The `timestamp` of the request is outside of the allowed window.");
+            return Err(PathfinderError::AuthenticationError(message));
+        }
+
+        match self.nonces.insert(nonce) {
+            NonceStatus::Fresh => Ok(()),
+            NonceStatus::Repeated => {
+                let message = String::from("The `nonce` of the request was already used.");
+                Err(PathfinderError::AuthenticationError(message))
+            },
+            NonceStatus::Overflowed => {
+                let message = String::from("Too many protected requests were received recently. Please, try once again later.");
+                Err(PathfinderError::AuthenticationError(message))
+            }
+        }
+    }
+}
+
+impl Middleware for ReplayProtectionMiddleware {
+    /// Returns empty headers for the fresh request, otherwise an error.
+    fn process_request(
+        &self,
+        message: JsonMessage,
+        _endpoint: Option<ReadOnlyEndpoint>,
+        _rabbitmq_context: Arc<RabbitMQContext>
+    ) -> MiddlewareFuture {
+        Box::new(result(self.check_message(&message).map(|_| HashMap::new())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use json::object;
+
+    use crate::engine::middleware::replay::ReplayProtectionMiddleware;
+    use crate::engine::serializer::JsonMessage;
+    use crate::error::PathfinderError;
+
+    fn get_message(nonce: &str, shift_ms: i64) -> JsonMessage {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let timestamp = now.as_millis() as i64 + shift_ms;
+        Arc::new(Box::new(object!{
+            "url" => "/api/matchmaking/search",
+            "nonce" => nonce,
+            "timestamp" => timestamp
+        }))
+    }
+
+    fn get_error_message(result: Result<(), PathfinderError>) -> String {
+        match result {
+            Err(PathfinderError::AuthenticationError(message)) => message,
+            other => panic!("Expected the authentication error, got {:?}", other.is_ok())
+        }
+    }
+
+    #[test]
+    fn test_check_message_accepts_fresh_nonces() {
+        let middleware = ReplayProtectionMiddleware::new(Duration::from_secs(60), 10);
+        assert!(middleware.check_message(&get_message("first", 0)).is_ok());
+        assert!(middleware.check_message(&get_message("second", -30_000)).is_ok());
+        assert!(middleware.check_message(&get_message("third", 30_000)).is_ok());
+    }
+
+    #[test]
+    fn test_check_message_rejects_replayed_nonces() {
+        let middleware = ReplayProtectionMiddleware::new(Duration::from_secs(60), 10);
+        assert!(middleware.check_message(&get_message("first", 0)).is_ok());
+
+        let result = middleware.check_message(&get_message("first", 0));
+        assert_eq!(get_error_message(result), "The `nonce` of the request was already used.");
+    }
+
+    #[test]
+    fn test_check_message_rejects_nonces_when_the_cache_is_full() {
+        let middleware = ReplayProtectionMiddleware::new(Duration::from_secs(60), 1);
+        assert!(middleware.check_message(&get_message("first", 0)).is_ok());
+
+        let result = middleware.check_message(&get_message("second", 0));
+        assert_eq!(get_error_message(result), "Too many protected requests were received recently. Please, try once again later.");
+        let result = middleware.check_message(&get_message("first", 0));
+        assert_eq!(get_error_message(result), "The `nonce` of the request was already used.");
+    }
+
+    #[test]
+    fn test_check_message_rejects_expired_timestamps() {
+        let middleware = ReplayProtectionMiddleware::new(Duration::from_secs(60), 10);
+        let result = middleware.check_message(&get_message("first", -61_000));
+        assert_eq!(get_error_message(result), "The `timestamp` of the request is outside of the allowed window.");
+
+        let result = middleware.check_message(&get_message("second", 61_000));
+        assert_eq!(get_error_message(result), "The `timestamp` of the request is outside of the allowed window.");
+
+        // The nonce of the rejected request can be used once again
+        assert!(middleware.check_message(&get_message("first", 0)).is_ok());
+    }
+
+    #[test]
+    fn test_check_message_requires_the_nonce_and_the_timestamp() {
+        let middleware = ReplayProtectionMiddleware::new(Duration::from_secs(60), 10);
+        let message = Arc::new(Box::new(object!{"url" => "/api/matchmaking/search", "nonce" => "first"}));
+        let result = middleware.check_message(&message);
+        assert_eq!(get_error_message(result), "The `nonce` and `timestamp` fields must be specified.");
+    }
+}
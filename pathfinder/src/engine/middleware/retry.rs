@@ -0,0 +1,113 @@
+//! Retries of requests to other microservices made by middlewares.
+//!
+
+use std::time::{Duration, Instant};
+
+use futures::future::{loop_fn, Either, Future, IntoFuture, Loop};
+use log::warn;
+use tokio::timer::Delay;
+
+use crate::error::PathfinderError;
+
+/// Runs the future created by `make_future` and repeats it after the delay
+/// when it failed because of the message broker. The number of additional
+/// attempts is limited, so that the last error is returned to the caller.
+/// Other errors (e.g. an invalid token) are definitive and never retried.
+pub fn retry_future<F, R, T>(
+    retry_attempts: usize,
+    retry_delay: Duration,
+    description: &'static str,
+    correlation_id: String,
+    make_future: F
+) -> impl Future<Item=T, Error=PathfinderError> + Send + Sync + 'static
+    where F: Fn() -> R + Send + Sync + 'static,
+          R: Future<Item=T, Error=PathfinderError> + Send + Sync + 'static,
+          T: Send + Sync + 'static
+{
+    loop_fn(1, move |attempt: usize| {
+        let correlation_id = correlation_id.clone();
+        make_future().then(move |result| match result {
+            Err(PathfinderError::MessageBrokerError(ref message)) if attempt <= retry_attempts => {
+                warn!(
+                    "[{}] {} failed on the attempt {} of {}: {}",
+                    correlation_id, description, attempt, retry_attempts + 1, message
+                );
+                Either::A(
+                    Delay::new(Instant::now() + retry_delay)
+                        .then(move |_| Ok(Loop::Continue(attempt + 1)))
+                )
+            },
+            result => Either::B(result.map(Loop::Break).into_future())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::{err, ok, FutureResult};
+    use tokio::runtime::Runtime;
+
+    use crate::engine::middleware::retry::retry_future;
+    use crate::error::PathfinderError;
+
+    fn get_broker_error() -> PathfinderError {
+        PathfinderError::MessageBrokerError(String::from("The request wasn't processed. Please, try once again."))
+    }
+
+    fn run_with_failures(retry_attempts: usize, failures: usize, error: fn() -> PathfinderError)
+        -> (Result<usize, PathfinderError>, usize)
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_inner = calls.clone();
+        let make_future = move || -> FutureResult<usize, PathfinderError> {
+            let call = calls_inner.fetch_add(1, Ordering::SeqCst) + 1;
+            match call <= failures {
+                true => err(error()),
+                false => ok(call)
+            }
+        };
+
+        let future = retry_future(retry_attempts, Duration::from_millis(1), "Test request", String::from("id"), make_future);
+        let result = Runtime::new().unwrap().block_on(future);
+        (result, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_retry_future_retries_broker_errors() {
+        let (result, calls) = run_with_failures(2, 2, get_broker_error);
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_future_returns_the_last_error_when_attempts_are_exhausted() {
+        let (result, calls) = run_with_failures(2, 5, get_broker_error);
+        match result {
+            Err(PathfinderError::MessageBrokerError(_)) => {},
+            _ => panic!("Expected a message broker error")
+        }
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_future_doesnt_retry_definitive_errors() {
+        let error = || PathfinderError::AuthenticationError(String::from("Token is invalid."));
+        let (result, calls) = run_with_failures(2, 1, error);
+        match result {
+            Err(PathfinderError::AuthenticationError(_)) => {},
+            _ => panic!("Expected an authentication error")
+        }
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_retry_future_without_retry_attempts() {
+        let (result, calls) = run_with_failures(0, 1, get_broker_error);
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -6,18 +6,129 @@
 /// https://github.com/OpenMatchmaking/documentation/blob/master/docs/protocol.md
 ///
 
-use json::JsonValue;
+use json::{parse as parse_json, JsonValue};
 
+use crate::engine::middleware::base::CustomUserHeaders;
 
-/// Converts a list of user's permissions into a string, where each permission
-/// is separated by a semicolon.
-pub fn get_permissions(json: &JsonValue) -> String {
-    let mut permissions: Vec<String> = Vec::new();
-    for value in json["content"]["permissions"].members() {
-        match value.as_str() {
-            Some(permission) => permissions.push(String::from(permission)),
-            _ => {}
+/// The name of the header with the list of user's permissions.
+pub const PERMISSIONS_HEADER: &str = "permissions";
+
+/// Defines how the list of user's permissions is encoded in the header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PermissionsFormat {
+    /// A JSON array of strings, e.g. `["matchmaking.search","chat.read"]`.
+    Json,
+    /// Permissions separated by a semicolon, e.g. `matchmaking.search;chat.read`.
+    Legacy
+}
+
+impl PermissionsFormat {
+    /// Returns the format by its name in the configuration.
+    pub fn from_name(name: &str) -> Option<PermissionsFormat> {
+        match name {
+            "json" => Some(PermissionsFormat::Json),
+            "legacy" => Some(PermissionsFormat::Legacy),
+            _ => None
         }
+    }
+}
+
+/// Extracts a list of user's permissions from the response.
+pub fn get_permissions(json: &JsonValue) -> Vec<String> {
+    json["content"]["permissions"]
+        .members()
+        .filter_map(|value| value.as_str())
+        .map(String::from)
+        .collect()
+}
+
+/// Converts a list of user's permissions into a string for the header.
+pub fn encode_permissions(permissions: &[String], format: PermissionsFormat) -> String {
+    match format {
+        PermissionsFormat::Json => JsonValue::from(permissions.to_vec()).dump(),
+        PermissionsFormat::Legacy => permissions.join(";")
+    }
+}
+
+/// Converts the header into a list of user's permissions. Both the JSON
+/// array and the legacy semicolon-separated form are accepted.
+pub fn decode_permissions(header: &str) -> Vec<String> {
+    match parse_json(header) {
+        Ok(ref json) if json.is_array() => json.members().filter_map(|value| value.as_str()).map(String::from).collect(),
+        _ => header.split(';').filter(|value| !value.is_empty()).map(String::from).collect()
+    }
+}
+
+/// Returns the list of user's permissions passed by middlewares.
+pub fn get_user_permissions(headers: &CustomUserHeaders) -> Vec<String> {
+    headers.get(PERMISSIONS_HEADER).map(|header| decode_permissions(header)).unwrap_or_default()
+}
+
+/// Checks that the list of permissions contains the certain permission.
+pub fn has_permission(permissions: &[String], permission: &str) -> bool {
+    permissions.iter().any(|value| value == permission)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use json::{array, object};
+
+    use crate::engine::middleware::utils::{
+        decode_permissions, encode_permissions, get_permissions, get_user_permissions, has_permission,
+        PermissionsFormat
     };
-    permissions.join(";")
+
+    fn get_names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_get_permissions_skips_non_string_values() {
+        let json = object!{"content" => object!{"permissions" => array!["chat.read", 42, "chat.write"]}};
+        assert_eq!(get_permissions(&json), get_names(&["chat.read", "chat.write"]));
+        assert!(get_permissions(&object!{}).is_empty());
+    }
+
+    #[test]
+    fn test_encode_permissions_with_special_characters() {
+        let permissions = get_names(&["chat;read", "room \"42\"", "caf\u{e9}"]);
+        let header = encode_permissions(&permissions, PermissionsFormat::Json);
+        assert_eq!(header, "[\"chat;read\",\"room \\\"42\\\"\",\"caf\u{e9}\"]");
+        assert_eq!(decode_permissions(&header), permissions);
+    }
+
+    #[test]
+    fn test_encode_permissions_in_legacy_format() {
+        let permissions = get_names(&["chat.read", "chat.write"]);
+        let header = encode_permissions(&permissions, PermissionsFormat::Legacy);
+        assert_eq!(header, "chat.read;chat.write");
+        assert_eq!(decode_permissions(&header), permissions);
+    }
+
+    #[test]
+    fn test_decode_permissions_of_empty_header() {
+        assert!(decode_permissions("").is_empty());
+        assert!(decode_permissions("[]").is_empty());
+    }
+
+    #[test]
+    fn test_has_permission_compares_whole_names() {
+        let mut headers = HashMap::new();
+        headers.insert(String::from("permissions"), String::from("[\"chat;read\"]"));
+        let permissions = get_user_permissions(&headers);
+
+        assert!(has_permission(&permissions, "chat;read"));
+        assert!(!has_permission(&permissions, "chat"));
+        assert!(!has_permission(&permissions, "read"));
+        assert!(get_user_permissions(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_permissions_format_from_name() {
+        assert_eq!(PermissionsFormat::from_name("json"), Some(PermissionsFormat::Json));
+        assert_eq!(PermissionsFormat::from_name("legacy"), Some(PermissionsFormat::Legacy));
+        assert_eq!(PermissionsFormat::from_name("csv"), None);
+    }
 }
@@ -1,9 +1,14 @@
+pub mod broadcast;
+pub mod encoding;
 pub mod engine;
 pub mod futures;
 pub mod middleware;
 pub mod router;
+pub mod schema;
 pub mod options;
 pub mod serializer;
+pub mod session;
+pub mod transformer;
 pub mod utils;
 
 use std::sync::Arc;
@@ -15,19 +20,28 @@ use tungstenite::Message;
 pub const REQUEST_EXCHANGE: &'static str = "open-matchmaking.direct";
 /// Default AMQP exchange point for responses
 pub const RESPONSE_EXCHANGE: &'static str = "open-matchmaking.responses.direct";
+/// Default content type of requests and responses of microservices
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
 
 /// Alias type for msps sender.
 pub type MessageSender = Arc<mpsc::UnboundedSender<Message>>;
 
+pub use self::broadcast::{Broadcaster, Subscription};
 pub use self::engine::{Engine};
-pub use self::futures::rpc_request_future;
+pub use self::futures::{ping_request_future, rpc_request_future};
 pub use self::middleware::{
     EmptyMiddleware,
     JwtTokenMiddleware,
     Middleware,
     MiddlewareFuture
 };
-pub use self::router::{extract_endpoints, Endpoint, ReadOnlyEndpoint, Router};
+pub use self::router::{extract_endpoints, Endpoint, ReadOnlyEndpoint, RouteMatch, Router, RouterOptions};
 pub use self::options::{RpcOptions};
-pub use self::serializer::{JsonMessage, Serializer};
-pub use self::utils::{deserialize_message, serialize_message, wrap_a_string_error};
+pub use self::serializer::{ContentType, JsonMessage, ProtocolVersion, Serializer};
+pub use self::session::Session;
+pub use self::transformer::{EmptyTransformer, Transformer, TransformerChain, Transformers};
+pub use self::utils::{
+    convert_message, deserialize_message, get_correlation_id, serialize_message,
+    with_correlation_id, wrap_a_fatal_error, wrap_a_microservice_error, wrap_a_string_error,
+    wrap_an_error, ErrorTemplate
+};
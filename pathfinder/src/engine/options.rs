@@ -2,24 +2,49 @@
 //!
 
 use std::sync::Arc;
+use std::time::Duration;
+
+use config::{Config, ConfigError};
+use lapin_futures_rustls::lapin::channel::QueueDeclareOptions;
+use lapin_futures_rustls::lapin::types::{AMQPValue, FieldTable};
+use uuid::Uuid;
 
 use crate::engine::router::ReadOnlyEndpoint;
-use crate::engine::serializer::JsonMessage;
+use crate::engine::serializer::{ContentType, JsonMessage};
+use crate::engine::transformer::TransformerChain;
+use crate::error::{PathfinderError, Result};
+use crate::tracing::TraceContext;
 
 /// Simple wrapper for options that will be passed to futures.
 #[derive(Clone, Debug)]
 pub struct RpcOptions {
     endpoint: Option<ReadOnlyEndpoint>,
+    routing_key: Option<String>,
     message: Option<JsonMessage>,
-    queue_name: Option<Arc<String>>
+    queue_name: Option<Arc<String>>,
+    timeout: Option<Duration>,
+    content_type: Option<ContentType>,
+    reply_queue: Option<ReplyQueueOptions>,
+    confirm_timeout: Option<Duration>,
+    trace: Option<TraceContext>,
+    transformers: Option<Arc<TransformerChain>>,
+    max_message_size: Option<usize>
 }
 
 impl Default for RpcOptions {
     fn default() -> RpcOptions {
         RpcOptions {
             endpoint: None,
+            routing_key: None,
             message: None,
             queue_name: None,
+            timeout: None,
+            content_type: None,
+            reply_queue: None,
+            confirm_timeout: None,
+            trace: None,
+            transformers: None,
+            max_message_size: None,
         }
     }
 }
@@ -30,6 +55,11 @@ impl RpcOptions {
         self
     }
 
+    pub fn with_routing_key(mut self, value: String) -> RpcOptions {
+        self.routing_key = Some(value);
+        self
+    }
+
     pub fn with_message(mut self, value: JsonMessage) -> RpcOptions {
         self.message = Some(value);
         self
@@ -40,10 +70,51 @@ impl RpcOptions {
         self
     }
 
+    pub fn with_timeout(mut self, value: Duration) -> RpcOptions {
+        self.timeout = Some(value);
+        self
+    }
+
+    pub fn with_content_type(mut self, value: ContentType) -> RpcOptions {
+        self.content_type = Some(value);
+        self
+    }
+
+    pub fn with_reply_queue(mut self, value: ReplyQueueOptions) -> RpcOptions {
+        self.reply_queue = Some(value);
+        self
+    }
+
+    pub fn with_confirm_timeout(mut self, value: Duration) -> RpcOptions {
+        self.confirm_timeout = Some(value);
+        self
+    }
+
+    pub fn with_trace(mut self, value: TraceContext) -> RpcOptions {
+        self.trace = Some(value);
+        self
+    }
+
+    pub fn with_transformers(mut self, value: Arc<TransformerChain>) -> RpcOptions {
+        self.transformers = Some(value);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, value: usize) -> RpcOptions {
+        self.max_message_size = Some(value);
+        self
+    }
+
     pub fn get_endpoint(&self) -> Option<ReadOnlyEndpoint> {
         self.endpoint.clone()
     }
 
+    /// Returns the routing key selected for the request, or the routing key
+    /// of the endpoint when it wasn't selected explicitly.
+    pub fn get_routing_key(&self) -> Option<String> {
+        self.routing_key.clone().or_else(|| self.endpoint.as_ref().map(|endpoint| endpoint.get_routing_key().to_string()))
+    }
+
     pub fn get_message(&self) -> Option<JsonMessage> {
         self.message.clone()
     }
@@ -51,4 +122,327 @@ impl RpcOptions {
     pub fn get_queue_name(&self) -> Option<Arc<String>> {
         self.queue_name.clone()
     }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn get_content_type(&self) -> Option<ContentType> {
+        self.content_type
+    }
+
+    pub fn get_reply_queue(&self) -> Option<ReplyQueueOptions> {
+        self.reply_queue.clone()
+    }
+
+    pub fn get_confirm_timeout(&self) -> Option<Duration> {
+        self.confirm_timeout
+    }
+
+    pub fn get_trace(&self) -> Option<TraceContext> {
+        self.trace.clone()
+    }
+
+    pub fn get_transformers(&self) -> Option<Arc<TransformerChain>> {
+        self.transformers.clone()
+    }
+
+    pub fn get_max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+}
+
+/// The placeholder for a unique part of the reply queue name.
+const UUID_PLACEHOLDER: &str = "{uuid}";
+/// The placeholder for the routing key of the request.
+const ROUTING_KEY_PLACEHOLDER: &str = "{routing_key}";
+/// The default prefix of consumer tags for reply queues.
+const DEFAULT_CONSUMER_TAG_PREFIX: &str = "pathfinder";
+/// The default time after which unused reply queues are deleted by the broker.
+const DEFAULT_QUEUE_EXPIRES: Duration = Duration::from_secs(60);
+/// The queue argument with the time after which the unused queue is deleted.
+const EXPIRES_ARGUMENT: &str = "x-expires";
+
+/// Properties of the temporary queues, that are declared for receiving
+/// responses. By default the queues are deleted by the broker when they
+/// aren't used anymore, even if the proxy didn't delete them explicitly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplyQueueOptions {
+    durable: bool,
+    exclusive: bool,
+    auto_delete: bool,
+    name_template: String,
+    consumer_tag_prefix: String,
+    expires: Option<Duration>
+}
+
+impl Default for ReplyQueueOptions {
+    fn default() -> ReplyQueueOptions {
+        ReplyQueueOptions {
+            durable: false,
+            exclusive: true,
+            auto_delete: true,
+            name_template: String::from(UUID_PLACEHOLDER),
+            consumer_tag_prefix: String::from(DEFAULT_CONSUMER_TAG_PREFIX),
+            expires: Some(DEFAULT_QUEUE_EXPIRES)
+        }
+    }
+}
+
+impl ReplyQueueOptions {
+    /// Returns an instance of `ReplyQueueOptions` with values taken from the
+    /// `reply_queue` section of the configuration. Missing keys are replaced
+    /// by the default values.
+    pub fn from_config(conf: &Config) -> ReplyQueueOptions {
+        let defaults = ReplyQueueOptions::default();
+        let get_flag = |key: &str, default: bool| conf.get_bool(&format!("reply_queue.{}", key)).unwrap_or(default);
+
+        ReplyQueueOptions {
+            durable: get_flag("durable", defaults.durable),
+            exclusive: get_flag("exclusive", defaults.exclusive),
+            auto_delete: get_flag("auto_delete", defaults.auto_delete),
+            name_template: conf.get_str("reply_queue.name").unwrap_or(defaults.name_template),
+            consumer_tag_prefix: conf.get_str("reply_queue.consumer_tag_prefix").unwrap_or(defaults.consumer_tag_prefix),
+            expires: match conf.get_int("reply_queue.expires_ms") {
+                Ok(expires) if expires > 0 => Some(Duration::from_millis(expires as u64)),
+                Ok(_) => None,
+                Err(_) => defaults.expires
+            }
+        }
+    }
+
+    /// Checks that the name template produces unique names, that can be
+    /// declared by the proxy.
+    pub fn validate(&self) -> Result<()> {
+        let placeholders = self.name_template.replace(UUID_PLACEHOLDER, "").replace(ROUTING_KEY_PLACEHOLDER, "");
+        let reason = match self.name_template.as_str() {
+            template if !template.contains(UUID_PLACEHOLDER) => Some(format!("it must contain the {} placeholder", UUID_PLACEHOLDER)),
+            _ if placeholders.contains('{') || placeholders.contains('}') => Some(String::from("only the {uuid} and {routing_key} placeholders are supported")),
+            template if template.starts_with("amq.") => Some(String::from("the \"amq.\" prefix is reserved by the broker")),
+            _ => None
+        };
+
+        match reason {
+            Some(reason) => {
+                let message = format!("The reply queue name \"{}\" is invalid: {}.", self.name_template, reason);
+                Err(PathfinderError::SettingsError(ConfigError::Message(message)))
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Returns a unique name of the reply queue for the request, that is
+    /// sent by the routing key.
+    pub fn get_queue_name(&self, routing_key: &str) -> String {
+        self.name_template
+            .replace(ROUTING_KEY_PLACEHOLDER, routing_key)
+            .replace(UUID_PLACEHOLDER, &Uuid::new_v4().to_string())
+    }
+
+    /// Returns the tag of the consumer of the reply queue. The tag contains
+    /// the queue name, so that consumers of concurrent requests can be told
+    /// apart in the management UI of the broker.
+    pub fn get_consumer_tag(&self, queue_name: &str) -> String {
+        match self.consumer_tag_prefix.is_empty() {
+            true => queue_name.to_string(),
+            false => format!("{}.{}", self.consumer_tag_prefix, queue_name)
+        }
+    }
+
+    /// Returns the arguments for declaring a reply queue. The queue that
+    /// wasn't deleted by the proxy (e.g. after a crash) is deleted by the
+    /// broker when it's unused during the specified time.
+    pub fn get_declare_arguments(&self) -> FieldTable {
+        let mut arguments = FieldTable::new();
+        if let Some(expires) = self.expires {
+            let expires = expires.as_secs() as i64 * 1000 + i64::from(expires.subsec_millis());
+            arguments.insert(EXPIRES_ARGUMENT.to_string(), AMQPValue::LongLongInt(expires));
+        }
+        arguments
+    }
+
+    /// Returns the options for declaring a reply queue.
+    pub fn get_declare_options(&self) -> QueueDeclareOptions {
+        QueueDeclareOptions {
+            passive: false,
+            durable: self.durable,
+            exclusive: self.exclusive,
+            auto_delete: self.auto_delete,
+            ..Default::default()
+        }
+    }
+}
+
+/// The prefix, that is prepended to the names of exchanges and routing keys,
+/// so that several isolated environments can share one broker. The prefix
+/// is separated from the name by a dot.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AmqpPrefix {
+    value: String
+}
+
+impl AmqpPrefix {
+    /// Returns a new instance of `AmqpPrefix`. An empty prefix keeps the
+    /// names as is.
+    pub fn new(value: &str) -> AmqpPrefix {
+        AmqpPrefix {
+            value: value.to_string()
+        }
+    }
+
+    /// Checks that each dot-separated word of the prefix is a non-empty
+    /// string of letters, digits, hyphens, underscores and colons, so that
+    /// the prefixed names are valid and don't contain empty words.
+    pub fn validate(&self) -> Result<()> {
+        if self.value.is_empty() {
+            return Ok(());
+        }
+
+        let is_valid_word = |word: &str| !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_:".contains(c));
+        let reason = match self.value.as_str() {
+            prefix if !prefix.split('.').all(is_valid_word) => Some("each dot-separated word must be a non-empty string of letters, digits, '-', '_' or ':'"),
+            prefix if prefix == "amq" || prefix.starts_with("amq.") => Some("the \"amq.\" prefix is reserved by the broker"),
+            _ => None
+        };
+
+        match reason {
+            Some(reason) => {
+                let message = format!("The AMQP prefix \"{}\" is invalid: {}.", self.value, reason);
+                Err(PathfinderError::SettingsError(ConfigError::Message(message)))
+            },
+            None => Ok(())
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Returns the name with the prefix. The empty name (e.g. of the default
+    /// exchange) is kept as is, because the default exchange can't be renamed.
+    pub fn apply(&self, name: &str) -> String {
+        match self.value.is_empty() || name.is_empty() {
+            true => name.to_string(),
+            false => format!("{}.{}", self.value, name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin_futures_rustls::lapin::types::AMQPValue;
+    use uuid::Uuid;
+
+    use crate::config::get_config;
+    use crate::engine::options::{AmqpPrefix, ReplyQueueOptions};
+
+    #[test]
+    fn test_from_config_returns_default_values_for_missing_keys() {
+        let conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        let options = ReplyQueueOptions::from_config(&conf).get_declare_options();
+        assert!(!options.durable);
+        assert!(options.exclusive);
+        assert!(options.auto_delete);
+    }
+
+    #[test]
+    fn test_from_config_returns_configured_values() {
+        let conf = get_config("./tests/files/config_with_reply_queue_settings.yaml");
+        let options = ReplyQueueOptions::from_config(&conf).get_declare_options();
+        assert!(options.durable);
+        assert!(options.exclusive);
+        assert!(!options.auto_delete);
+    }
+
+    #[test]
+    fn test_get_declare_arguments_contain_the_expiration_time() {
+        let conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        let arguments = ReplyQueueOptions::from_config(&conf).get_declare_arguments();
+        assert_eq!(arguments.get("x-expires"), Some(&AMQPValue::LongLongInt(60000)));
+
+        let conf = get_config("./tests/files/config_with_reply_queue_settings.yaml");
+        let arguments = ReplyQueueOptions::from_config(&conf).get_declare_arguments();
+        assert_eq!(arguments.get("x-expires"), Some(&AMQPValue::LongLongInt(120000)));
+
+        let mut conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        conf.set("reply_queue.expires_ms", 0).unwrap();
+        assert!(ReplyQueueOptions::from_config(&conf).get_declare_arguments().is_empty());
+    }
+
+    #[test]
+    fn test_get_queue_name_returns_uuid_by_default() {
+        let conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        let options = ReplyQueueOptions::from_config(&conf);
+        let queue_name = options.get_queue_name("microservice.search");
+        assert!(options.validate().is_ok());
+        assert!(Uuid::parse_str(&queue_name).is_ok());
+        assert_ne!(queue_name, options.get_queue_name("microservice.search"));
+    }
+
+    #[test]
+    fn test_get_queue_name_substitutes_placeholders() {
+        let conf = get_config("./tests/files/config_with_reply_queue_settings.yaml");
+        let options = ReplyQueueOptions::from_config(&conf);
+        let queue_name = options.get_queue_name("microservice.search");
+        assert!(options.validate().is_ok());
+        assert!(queue_name.starts_with("pathfinder.reply.microservice.search."));
+        assert!(Uuid::parse_str(&queue_name["pathfinder.reply.microservice.search.".len()..]).is_ok());
+    }
+
+    #[test]
+    fn test_get_consumer_tag_is_unique_for_each_request() {
+        let conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        let options = ReplyQueueOptions::from_config(&conf);
+        let first_queue = options.get_queue_name("microservice.search");
+        let second_queue = options.get_queue_name("microservice.search");
+
+        let first_tag = options.get_consumer_tag(&first_queue);
+        assert_eq!(first_tag, format!("pathfinder.{}", first_queue));
+        assert_ne!(first_tag, options.get_consumer_tag(&second_queue));
+    }
+
+    #[test]
+    fn test_get_consumer_tag_uses_configured_prefix() {
+        let conf = get_config("./tests/files/config_with_reply_queue_settings.yaml");
+        let options = ReplyQueueOptions::from_config(&conf);
+        assert_eq!(options.get_consumer_tag("reply"), "search-proxy.reply");
+
+        let mut conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        conf.set("reply_queue.consumer_tag_prefix", "").unwrap();
+        assert_eq!(ReplyQueueOptions::from_config(&conf).get_consumer_tag("reply"), "reply");
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_templates() {
+        for template in &["pathfinder.reply", "reply.{uuid}.{version}", "amq.reply.{uuid}", "reply.{uuid"] {
+            let mut conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+            conf.set("reply_queue.name", template.to_string()).unwrap();
+            let options = ReplyQueueOptions::from_config(&conf);
+            assert!(options.validate().is_err(), "{} must be rejected", template);
+        }
+    }
+
+    #[test]
+    fn test_amqp_prefix_is_prepended_to_names() {
+        let prefix = AmqpPrefix::new("staging");
+        assert!(prefix.validate().is_ok());
+        assert_eq!(prefix.apply("open-matchmaking.direct"), "staging.open-matchmaking.direct");
+        assert_eq!(prefix.apply("microservice.search"), "staging.microservice.search");
+        assert_eq!(prefix.apply(""), "");
+    }
+
+    #[test]
+    fn test_empty_amqp_prefix_keeps_names() {
+        let prefix = AmqpPrefix::default();
+        assert!(prefix.validate().is_ok());
+        assert_eq!(prefix.apply("microservice.search"), "microservice.search");
+    }
+
+    #[test]
+    fn test_amqp_prefix_validate_rejects_prefixes_with_empty_words() {
+        for value in &[".", "staging.", ".staging", "eu..staging", " ", "staging{id}", "amq"] {
+            assert!(AmqpPrefix::new(value).validate().is_err(), "{:?} must be rejected", value);
+        }
+        assert!(AmqpPrefix::new("eu-west.staging_2").validate().is_ok());
+    }
 }
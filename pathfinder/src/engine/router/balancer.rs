@@ -0,0 +1,147 @@
+//! Distribution of requests between several routing keys of one endpoint.
+//!
+
+use std::sync::Mutex;
+
+/// A routing key with its share of requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightedRoutingKey {
+    pub routing_key: String,
+    pub weight: u32
+}
+
+impl WeightedRoutingKey {
+    /// Returns a new instance of `WeightedRoutingKey`.
+    pub fn new(routing_key: &str, weight: u32) -> WeightedRoutingKey {
+        WeightedRoutingKey {
+            routing_key: routing_key.to_string(),
+            weight
+        }
+    }
+}
+
+/// Selects routing keys in the smooth weighted round-robin order, so that
+/// the requests are spread in proportion to the weights and the instances
+/// with a small weight aren't starved during bursts. The state is shared by
+/// all connections that send requests to the endpoint.
+#[derive(Debug)]
+pub struct WeightedRoutingKeys {
+    keys: Vec<WeightedRoutingKey>,
+    current_weights: Mutex<Vec<i64>>
+}
+
+impl WeightedRoutingKeys {
+    /// Returns a new instance of `WeightedRoutingKeys`. The keys with the
+    /// zero weight never get any requests.
+    pub fn new(keys: Vec<WeightedRoutingKey>) -> WeightedRoutingKeys {
+        let current_weights = vec![0; keys.len()];
+        WeightedRoutingKeys {
+            keys,
+            current_weights: Mutex::new(current_weights)
+        }
+    }
+
+    /// Returns the configured routing keys with their weights.
+    pub fn get_keys(&self) -> &[WeightedRoutingKey] {
+        &self.keys
+    }
+
+    /// Returns the routing key for the next request or `None` when there
+    /// are no keys with a positive weight.
+    pub fn next(&self) -> Option<String> {
+        let total_weight: i64 = self.keys.iter().map(|key| i64::from(key.weight)).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let mut selected = 0;
+        for (index, key) in self.keys.iter().enumerate() {
+            current_weights[index] += i64::from(key.weight);
+            if current_weights[index] > current_weights[selected] {
+                selected = index;
+            }
+        }
+        current_weights[selected] -= total_weight;
+        Some(self.keys[selected].routing_key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::engine::router::balancer::{WeightedRoutingKey, WeightedRoutingKeys};
+
+    fn get_distribution(balancer: &WeightedRoutingKeys, requests: usize) -> HashMap<String, usize> {
+        let mut distribution = HashMap::new();
+        for _ in 0..requests {
+            *distribution.entry(balancer.next().unwrap()).or_default() += 1;
+        }
+        distribution
+    }
+
+    #[test]
+    fn test_next_returns_the_single_key() {
+        let balancer = WeightedRoutingKeys::new(vec![WeightedRoutingKey::new("search", 1)]);
+        assert_eq!(balancer.next(), Some(String::from("search")));
+        assert_eq!(balancer.next(), Some(String::from("search")));
+    }
+
+    #[test]
+    fn test_next_distributes_requests_by_weights() {
+        let balancer = WeightedRoutingKeys::new(vec![
+            WeightedRoutingKey::new("search.1", 3),
+            WeightedRoutingKey::new("search.2", 1),
+            WeightedRoutingKey::new("search.3", 0),
+        ]);
+
+        let distribution = get_distribution(&balancer, 1000);
+        assert_eq!(distribution["search.1"], 750);
+        assert_eq!(distribution["search.2"], 250);
+        assert!(!distribution.contains_key("search.3"));
+    }
+
+    #[test]
+    fn test_next_interleaves_keys_with_different_weights() {
+        let balancer = WeightedRoutingKeys::new(vec![
+            WeightedRoutingKey::new("a", 2),
+            WeightedRoutingKey::new("b", 1),
+        ]);
+
+        let order: Vec<String> = (0..6).map(|_| balancer.next().unwrap()).collect();
+        assert_eq!(order, vec!["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn test_next_returns_none_without_positive_weights() {
+        let balancer = WeightedRoutingKeys::new(vec![WeightedRoutingKey::new("search", 0)]);
+        assert_eq!(balancer.next(), None);
+    }
+
+    #[test]
+    fn test_next_keeps_the_distribution_for_concurrent_requests() {
+        let balancer = Arc::new(WeightedRoutingKeys::new(vec![
+            WeightedRoutingKey::new("search.1", 1),
+            WeightedRoutingKey::new("search.2", 4),
+        ]));
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let balancer = balancer.clone();
+                thread::spawn(move || get_distribution(&balancer, 250))
+            })
+            .collect();
+        let mut distribution: HashMap<String, usize> = HashMap::new();
+        for worker in workers {
+            for (key, count) in worker.join().unwrap() {
+                *distribution.entry(key).or_default() += count;
+            }
+        }
+
+        assert_eq!(distribution["search.1"], 200);
+        assert_eq!(distribution["search.2"], 800);
+    }
+}
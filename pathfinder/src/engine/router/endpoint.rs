@@ -4,35 +4,62 @@
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use config::{Config, Value};
 use log::warn;
 
-use crate::engine::{REQUEST_EXCHANGE, RESPONSE_EXCHANGE};
+use crate::engine::{DEFAULT_CONTENT_TYPE, REQUEST_EXCHANGE, RESPONSE_EXCHANGE};
+use crate::engine::encoding::{ContentEncoding, CONTENT_ENCODINGS};
+use crate::engine::options::AmqpPrefix;
+use crate::engine::router::balancer::{WeightedRoutingKey, WeightedRoutingKeys};
+use crate::engine::router::options::RouterOptions;
+use crate::engine::router::template::{is_routing_key_template, validate_routing_key_template};
 use crate::error::PathfinderError;
 
 /// Type alias for thread-safe endpoint (only for read-only access)
 pub type ReadOnlyEndpoint = Arc<Endpoint>;
 
+/// The URL ending that marks an endpoint as matching for any nested resources.
+pub const WILDCARD_SUFFIX: &str = "/*";
+/// The supported types of request exchanges.
+const EXCHANGE_TYPES: [&str; 2] = ["direct", "topic"];
+
 /// A struct which stores an original URL that must be converted to the
 /// certain microservice endpoint.
 ///
 /// # Example
 /// ```
-/// use engine::router::{Endpoint};
+/// use pathfinder::engine::router::{Endpoint};
 ///
-/// let endpoint = Endpoint::new(&"/api/matchmaking/search/", &"matchmaking.search");
+/// let endpoint = Endpoint::new("/api/matchmaking/search/", "matchmaking.search", "open-matchmaking.direct", "open-matchmaking.responses.direct", false);
 /// assert_eq!(endpoint.get_url(), String::from("/api/matchmaking/search/"));
-/// assert_eq!(endpoint.get_microservice(), String::from("matchmaking.search"));
+/// assert_eq!(endpoint.get_routing_key(), String::from("matchmaking.search"));
 /// ```
 ///
 #[derive(Debug, Clone)]
 pub struct Endpoint {
     url: String,
     routing_key: String,
+    routing_keys: Option<Arc<WeightedRoutingKeys>>,
     request_exchange: String,
     response_exchange: String,
-    is_token_required: bool
+    is_token_required: bool,
+    is_token_optional: bool,
+    is_content_required: bool,
+    is_topic_exchange: bool,
+    is_response_streamed: bool,
+    is_request_acked: bool,
+    is_response_required: bool,
+    middlewares: Option<Vec<String>>,
+    transformers: Vec<String>,
+    timeout: Option<Duration>,
+    max_concurrency: Option<usize>,
+    schema: Option<String>,
+    required_permission: Option<String>,
+    content_type: String,
+    content_encoding: Option<ContentEncoding>,
+    headers: HashMap<String, String>
 }
 
 impl Endpoint {
@@ -41,36 +68,275 @@ impl Endpoint {
         Endpoint {
             url: url.to_string(),
             routing_key: routing_key.to_string(),
+            routing_keys: None,
             request_exchange: request_exchange.to_string(),
             response_exchange: response_exchange.to_string(),
-            is_token_required: is_token_required
+            is_token_required,
+            is_token_optional: false,
+            is_content_required: false,
+            is_topic_exchange: false,
+            is_response_streamed: false,
+            is_request_acked: false,
+            is_response_required: true,
+            middlewares: None,
+            transformers: Vec::new(),
+            timeout: None,
+            max_concurrency: None,
+            schema: None,
+            required_permission: None,
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            content_encoding: None,
+            headers: HashMap::new()
+        }
+    }
+
+    /// Allows requests without a token. A passed token is still verified.
+    pub fn with_optional_token(mut self) -> Endpoint {
+        self.is_token_required = false;
+        self.is_token_optional = true;
+        self
+    }
+
+    /// Rejects requests without the `content` field instead of publishing
+    /// an empty body.
+    pub fn with_required_content(mut self) -> Endpoint {
+        self.is_content_required = true;
+        self
+    }
+
+    /// Marks the request exchange as a topic one, so that the routing keys
+    /// are built for each request from the templates.
+    pub fn with_topic_exchange(mut self) -> Endpoint {
+        self.is_topic_exchange = true;
+        self
+    }
+
+    /// Forwards the parts of the multi-part response to the client as soon
+    /// as they're received, instead of waiting for a single response.
+    pub fn with_streamed_response(mut self) -> Endpoint {
+        self.is_response_streamed = true;
+        self
+    }
+
+    /// Notifies the client when the request was accepted by the message
+    /// broker, before the response of the microservice is received.
+    pub fn with_request_ack(mut self) -> Endpoint {
+        self.is_request_acked = true;
+        self
+    }
+
+    /// Publishes requests without waiting for a response (the "fire and
+    /// forget" mode), so that the reply queue isn't declared at all.
+    pub fn without_response(mut self) -> Endpoint {
+        self.is_response_required = false;
+        self
+    }
+
+    /// Sets several routing keys with weights, so that requests will be
+    /// distributed between them. The clones of the endpoint share the
+    /// state of the distribution.
+    pub fn with_routing_keys(mut self, routing_keys: Vec<WeightedRoutingKey>) -> Endpoint {
+        self.routing_keys = Some(Arc::new(WeightedRoutingKeys::new(routing_keys)));
+        self
+    }
+
+    /// Prepends the prefix to the exchanges and routing keys of the endpoint.
+    /// The distribution between the routing keys starts anew.
+    pub fn with_amqp_prefix(mut self, prefix: &AmqpPrefix) -> Endpoint {
+        self.routing_key = prefix.apply(&self.routing_key);
+        self.request_exchange = prefix.apply(&self.request_exchange);
+        self.response_exchange = prefix.apply(&self.response_exchange);
+        if let Some(routing_keys) = self.routing_keys.take() {
+            let routing_keys = routing_keys
+                .get_keys()
+                .iter()
+                .map(|key| WeightedRoutingKey::new(&prefix.apply(&key.routing_key), key.weight))
+                .collect();
+            self = self.with_routing_keys(routing_keys);
         }
+        self
+    }
+
+    /// Sets an ordered list of middleware names that must be applied to
+    /// the request instead of the default one.
+    pub fn with_middlewares(mut self, middlewares: Vec<String>) -> Endpoint {
+        self.middlewares = Some(middlewares);
+        self
+    }
+
+    /// Sets an ordered list of transformer names that are applied to the
+    /// payloads of requests and responses.
+    pub fn with_transformers(mut self, transformers: Vec<String>) -> Endpoint {
+        self.transformers = transformers;
+        self
+    }
+
+    /// Sets the time to wait for a response from the microservice.
+    pub fn with_timeout(mut self, timeout: Duration) -> Endpoint {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum number of in-flight requests to the microservice.
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Endpoint {
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
+    /// Sets the path to the JSON schema for validating request contents.
+    pub fn with_schema(mut self, schema: &str) -> Endpoint {
+        self.schema = Some(schema.to_string());
+        self
+    }
+
+    /// Sets the permission that the user must have for accessing the endpoint.
+    pub fn with_required_permission(mut self, permission: &str) -> Endpoint {
+        self.required_permission = Some(permission.to_string());
+        self
+    }
+
+    /// Sets the content type of requests and responses of the microservice.
+    pub fn with_content_type(mut self, content_type: &str) -> Endpoint {
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    /// Compresses the bodies of requests to the microservice.
+    pub fn with_content_encoding(mut self, content_encoding: ContentEncoding) -> Endpoint {
+        self.content_encoding = Some(content_encoding);
+        self
+    }
+
+    /// Sets the static headers that are passed with each request to the
+    /// microservice.
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Endpoint {
+        self.headers = headers;
+        self
     }
 
     /// Returns an original URL for which necessary to do a transformation.
-    pub fn get_url(&self) -> String {
-        self.url.clone()
+    pub fn get_url(&self) -> &str {
+        &self.url
     }
 
     /// Returns a routing key (which can considered as the microservice) name.
-    pub fn get_routing_key(&self) -> String {
-        self.routing_key.clone()
+    pub fn get_routing_key(&self) -> &str {
+        &self.routing_key
+    }
+
+    /// Returns the routing keys with weights if they were specified.
+    pub fn get_routing_keys(&self) -> &[WeightedRoutingKey] {
+        match self.routing_keys {
+            Some(ref routing_keys) => routing_keys.get_keys(),
+            None => &[]
+        }
+    }
+
+    /// Returns a routing key for the next request. When several routing keys
+    /// were specified, they are selected in the weighted round-robin order.
+    pub fn select_routing_key(&self) -> String {
+        self.routing_keys
+            .as_ref()
+            .and_then(|routing_keys| routing_keys.next())
+            .unwrap_or_else(|| self.routing_key.clone())
     }
 
     /// Returns a request exchange point name.
-    pub fn get_request_exchange(&self) -> String {
-        self.request_exchange.clone()
+    pub fn get_request_exchange(&self) -> &str {
+        &self.request_exchange
     }
 
     /// Returns a response exchange point name.
-    pub fn get_response_exchange(&self) -> String {
-        self.response_exchange.clone()
+    pub fn get_response_exchange(&self) -> &str {
+        &self.response_exchange
     }
 
     /// Determines whether to check tokens or not.
     pub fn is_token_required(&self) -> bool {
         self.is_token_required
     }
+
+    /// Determines whether to check tokens only when they were passed.
+    pub fn is_token_optional(&self) -> bool {
+        self.is_token_optional
+    }
+
+    /// Determines whether requests are published into a topic exchange.
+    pub fn is_topic_exchange(&self) -> bool {
+        self.is_topic_exchange
+    }
+
+    /// Determines whether requests must contain the `content` field.
+    pub fn is_content_required(&self) -> bool {
+        self.is_content_required
+    }
+
+    /// Determines whether the response is forwarded to the client in parts.
+    pub fn is_response_streamed(&self) -> bool {
+        self.is_response_streamed
+    }
+
+    /// Determines whether the client is notified about accepted requests.
+    pub fn is_request_acked(&self) -> bool {
+        self.is_request_acked
+    }
+
+    /// Determines whether the proxy waits for a response of the microservice.
+    pub fn is_response_required(&self) -> bool {
+        self.is_response_required
+    }
+
+    /// Returns a list of middleware names if they were specified explicitly.
+    pub fn get_middlewares(&self) -> Option<&[String]> {
+        self.middlewares.as_deref()
+    }
+
+    /// Returns a list of transformer names of the endpoint.
+    pub fn get_transformers(&self) -> &[String] {
+        &self.transformers
+    }
+
+    /// Returns the time to wait for a response if it was specified explicitly.
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns the maximum number of in-flight requests if it was specified explicitly.
+    pub fn get_max_concurrency(&self) -> Option<usize> {
+        self.max_concurrency
+    }
+
+    /// Returns the path to the JSON schema if it was specified.
+    pub fn get_schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    /// Returns the permission required for accessing the endpoint if it was specified.
+    pub fn get_required_permission(&self) -> Option<&str> {
+        self.required_permission.as_deref()
+    }
+
+    /// Returns the content type of requests and responses of the microservice.
+    pub fn get_content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Returns the compression of request bodies if it was specified.
+    pub fn get_content_encoding(&self) -> Option<ContentEncoding> {
+        self.content_encoding
+    }
+
+    /// Returns the static headers of requests to the microservice.
+    pub fn get_headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// Determines whether the microservice communicates in JSON, so that
+    /// its responses can be processed by the proxy.
+    pub fn is_json(&self) -> bool {
+        let media_type = self.content_type.split(';').next().unwrap_or("");
+        media_type.trim().eq_ignore_ascii_case(DEFAULT_CONTENT_TYPE)
+    }
 }
 
 /// Extracts a value configuration object as a string if it exists. Otherwise returns an default 
@@ -94,10 +360,132 @@ fn get_value_as_bool(conf: &HashMap<String, Value>, key: &str, default: bool) ->
     }
 }
 
+/// Checks whether the value of the configuration object is the `optional` string.
+fn is_optional_value(conf: &HashMap<String, Value>, key: &str) -> bool {
+    match conf.get(key) {
+        Some(value) => value.to_owned().into_str().map(|value| value == "optional").unwrap_or(false),
+        None => false
+    }
+}
+
+/// Extracts a value configuration object as an unsigned integer if it exists
+/// and valid. Otherwise returns `None`.
+fn get_value_as_u64(conf: &HashMap<String, Value>, key: &str) -> Option<u64> {
+    match conf.get(key) {
+        Some(value) => value.to_owned().into_int().ok().filter(|value| *value >= 0).map(|value| value as u64),
+        None => None
+    }
+}
+
+/// Extracts a value configuration object as a list of strings if it exists.
+/// Otherwise returns `None`. Non-string items are skipped.
+fn get_value_as_str_list(conf: &HashMap<String, Value>, key: &str) -> Option<Vec<String>> {
+    match conf.get(key) {
+        Some(value) => {
+            let items = value.to_owned().into_array().unwrap_or_default();
+            Some(items.into_iter().filter_map(|item| item.into_str().ok()).collect())
+        }
+        None => None
+    }
+}
+
+/// Converts a table of static headers into pairs of strings. Numbers and
+/// booleans are converted into strings as well.
+fn get_static_headers(value: &Value) -> Result<HashMap<String, String>, String> {
+    let table = value.to_owned().into_table().map_err(|_| String::from("a table is expected"))?;
+    let mut headers = HashMap::new();
+    for (name, value) in table {
+        let value = value.into_str().map_err(|_| format!("the value of the \"{}\" header must be a string", name))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Converts a list of routing keys into weighted routing keys. Each item is
+/// either a routing key (with the weight 1) or a table with the `routing_key`
+/// and `weight` keys.
+fn get_weighted_routing_keys(value: &Value) -> Result<Vec<WeightedRoutingKey>, String> {
+    let items = value.to_owned().into_array().map_err(|_| String::from("a list is expected"))?;
+    let mut routing_keys = Vec::new();
+    for item in items {
+        let routing_key = match item.clone().into_str() {
+            Ok(routing_key) => WeightedRoutingKey::new(&routing_key, 1),
+            Err(_) => {
+                let table = item.into_table().map_err(|_| String::from("a routing key or a table is expected"))?;
+                let routing_key = match table.get("routing_key") {
+                    Some(routing_key) => routing_key.to_owned().into_str().map_err(|error| error.to_string())?,
+                    None => return Err(String::from("the `routing_key` key is missing"))
+                };
+                let weight = match get_value_as_u64(&table, "weight") {
+                    Some(weight) => weight as u32,
+                    None if table.contains_key("weight") => return Err(String::from("the weight must be a non-negative integer")),
+                    None => 1
+                };
+                WeightedRoutingKey::new(&routing_key, weight)
+            }
+        };
+        routing_keys.push(routing_key);
+    }
+
+    match routing_keys.iter().any(|routing_key| routing_key.weight > 0) {
+        true => Ok(routing_keys),
+        false => Err(String::from("at least one routing key with a positive weight is required"))
+    }
+}
+
+/// Checks that the wildcard (if it was specified) is used only as the last
+/// segment of the URL, so that it's clear which part must be captured.
+fn is_valid_url_pattern(url: &str) -> bool {
+    match url.find('*') {
+        Some(position) => url.ends_with(WILDCARD_SUFFIX) && position == url.len() - 1,
+        None => true
+    }
+}
+
+/// Checks that each path parameter (e.g. `{room_id}`) takes the whole
+/// segment of the URL and that all parameter names are unique.
+fn is_valid_url_template(url: &str) -> bool {
+    let mut names = HashSet::new();
+    for segment in url.split('/') {
+        if !segment.contains('{') && !segment.contains('}') {
+            continue;
+        }
+
+        match get_parameter_name(segment) {
+            Some(name) if !name.is_empty() && names.insert(name) => {}
+            _ => return false
+        }
+    }
+    true
+}
+
+/// Returns a name of the path parameter when the segment is a placeholder.
+pub fn get_parameter_name(segment: &str) -> Option<&str> {
+    if segment.len() >= 2 && segment.starts_with('{') && segment.ends_with('}') {
+        let name = &segment[1..segment.len() - 1];
+        if !name.contains('{') && !name.contains('}') {
+            return Some(name);
+        }
+    }
+    None
+}
+
 /// Returns a HashMap with mapping for URL onto certain queue/topic name that
-/// were extracted from a configuration.
+/// were extracted from a configuration. Invalid endpoints are skipped.
 pub fn extract_endpoints(conf: Box<Config>) -> HashMap<String, ReadOnlyEndpoint> {
-    let mut endpoints = HashMap::new();
+    let (endpoints, errors) = parse_endpoints(&conf);
+    for error in errors {
+        warn!("{}", error);
+    }
+    endpoints
+}
+
+/// Returns a HashMap with valid endpoints from a configuration and the
+/// list of errors for the endpoints that are invalid. When several endpoints
+/// have the same URL, the first one is used.
+pub fn parse_endpoints(conf: &Config) -> (HashMap<String, ReadOnlyEndpoint>, Vec<PathfinderError>) {
+    let mut endpoints: HashMap<String, ReadOnlyEndpoint> = HashMap::new();
+    let mut errors = Vec::new();
 
     let config_endpoints: Vec<Value> = match conf.get_array("endpoints") {
         Ok(array) => array,
@@ -106,6 +494,7 @@ pub fn extract_endpoints(conf: Box<Config>) -> HashMap<String, ReadOnlyEndpoint>
 
     let default_request_exchange = String::from(REQUEST_EXCHANGE);
     let default_response_exchange = String::from(RESPONSE_EXCHANGE);
+    let router_options = RouterOptions::from_config(conf);
 
     for endpoint in &config_endpoints {
         // One the high level you have structure like
@@ -119,14 +508,18 @@ pub fn extract_endpoints(conf: Box<Config>) -> HashMap<String, ReadOnlyEndpoint>
             }
             Err(_) => {
                 let error = format!("endpoint \"{}\" is invalid.", endpoint);
-                warn!("{}", PathfinderError::InvalidEndpoint(error));
+                errors.push(PathfinderError::InvalidEndpoint(error));
                 continue;
             }
         };
 
-        // Check on required fields
+        // Check on required fields. The list of routing keys can be used
+        // instead of the single one
         let mut missing_fields = Vec::new();
-        let required_fields: HashSet<&str> = ["url", "routing_key"].iter().cloned().collect();
+        let required_fields: HashSet<&str> = match configuration.contains_key("routing_keys") {
+            true => ["url"].iter().cloned().collect(),
+            false => ["url", "routing_key"].iter().cloned().collect()
+        };
         for key in required_fields {
             if !configuration.contains_key(key) {
                 missing_fields.push(key);
@@ -138,26 +531,188 @@ pub fn extract_endpoints(conf: Box<Config>) -> HashMap<String, ReadOnlyEndpoint>
                 "keys {:?} for {} endpoint is missing.",
                 missing_fields, endpoint
             );
-            warn!("{}", PathfinderError::InvalidEndpoint(error));
+            errors.push(PathfinderError::InvalidEndpoint(error));
             continue;
         }
 
-        let url = get_value_as_str(&configuration, "url", "");
-        let routing_key = get_value_as_str(&configuration, "routing_key", "");
+        let url = router_options.normalize_url(&get_value_as_str(&configuration, "url", ""));
+        if !is_valid_url_pattern(&url) {
+            let error = format!(
+                "url \"{}\" is ambiguous. The wildcard is allowed only as the last segment.",
+                url
+            );
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
+
+        if !is_valid_url_template(&url) {
+            let error = format!(
+                "url \"{}\" is invalid. Each path parameter must be a whole segment with a unique name.",
+                url
+            );
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
+
+        let routing_keys = match configuration.get("routing_keys") {
+            Some(value) => match get_weighted_routing_keys(value) {
+                Ok(routing_keys) => Some(routing_keys),
+                Err(reason) => {
+                    let error = format!("routing keys for {} endpoint are invalid: {}.", endpoint, reason);
+                    errors.push(PathfinderError::InvalidEndpoint(error));
+                    continue;
+                }
+            },
+            None => None
+        };
+        let headers = match configuration.get("headers") {
+            Some(value) => match get_static_headers(value) {
+                Ok(headers) => Some(headers),
+                Err(reason) => {
+                    let error = format!("headers for {} endpoint are invalid: {}.", endpoint, reason);
+                    errors.push(PathfinderError::InvalidEndpoint(error));
+                    continue;
+                }
+            },
+            None => None
+        };
+        // The first routing key is used for metrics and limits of the endpoint
+        let default_routing_key = match routing_keys {
+            Some(ref routing_keys) => routing_keys[0].routing_key.clone(),
+            None => String::new()
+        };
+        let routing_key = get_value_as_str(&configuration, "routing_key", &default_routing_key);
+        let exchange_type = get_value_as_str(&configuration, "request_exchange_type", "direct");
+        if !EXCHANGE_TYPES.contains(&exchange_type.as_str()) {
+            let error = format!(
+                "exchange type \"{}\" for {} endpoint isn't supported. Available: {:?}.",
+                exchange_type, endpoint, EXCHANGE_TYPES
+            );
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
+        let is_topic_exchange = exchange_type == "topic";
+        let content_encoding = match configuration.get("content_encoding") {
+            Some(_) => {
+                let name = get_value_as_str(&configuration, "content_encoding", "");
+                match ContentEncoding::from_name(&name) {
+                    Some(content_encoding) => Some(content_encoding),
+                    None => {
+                        let error = format!(
+                            "content encoding \"{}\" for {} endpoint isn't supported. Available: {:?}.",
+                            name, endpoint, CONTENT_ENCODINGS
+                        );
+                        errors.push(PathfinderError::InvalidEndpoint(error));
+                        continue;
+                    }
+                }
+            },
+            None => None
+        };
+        let mut templates = vec![routing_key.clone()];
+        if let Some(ref routing_keys) = routing_keys {
+            templates.extend(routing_keys.iter().map(|routing_key| routing_key.routing_key.clone()));
+        }
+        let template_error = templates
+            .iter()
+            .filter(|template| is_routing_key_template(template))
+            .map(|template| match is_topic_exchange {
+                true => validate_routing_key_template(template, &url),
+                false => Err(String::from("templates are allowed only for the topic request exchange"))
+            })
+            .find_map(|result| result.err());
+        if let Some(reason) = template_error {
+            let error = format!("routing key for {} endpoint is invalid: {}.", endpoint, reason);
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
         let request_exchange = get_value_as_str(&configuration, "request_exchange", &default_request_exchange);
         let response_exchange = get_value_as_str(&configuration, "response_exchange", &default_response_exchange);
         let is_token_required = get_value_as_bool(&configuration, "token_required", true);
-        let endpoint = Endpoint::new(&url, &routing_key, &request_exchange, &response_exchange, is_token_required);
+        let mut endpoint = Endpoint::new(&url, &routing_key, &request_exchange, &response_exchange, is_token_required);
+        if is_optional_value(&configuration, "token_required") {
+            endpoint = endpoint.with_optional_token();
+        }
+        if is_topic_exchange {
+            endpoint = endpoint.with_topic_exchange();
+        }
+        if get_value_as_bool(&configuration, "content_required", false) {
+            endpoint = endpoint.with_required_content();
+        }
+        if get_value_as_bool(&configuration, "stream_response", false) {
+            endpoint = endpoint.with_streamed_response();
+        }
+        if get_value_as_bool(&configuration, "ack_requests", false) {
+            endpoint = endpoint.with_request_ack();
+        }
+        if !get_value_as_bool(&configuration, "response_required", true) {
+            endpoint = endpoint.without_response();
+        }
+        if let Some(routing_keys) = routing_keys {
+            endpoint = endpoint.with_routing_keys(routing_keys);
+        }
+        if let Some(middlewares) = get_value_as_str_list(&configuration, "middlewares") {
+            endpoint = endpoint.with_middlewares(middlewares);
+        }
+        if let Some(transformers) = get_value_as_str_list(&configuration, "transformers") {
+            endpoint = endpoint.with_transformers(transformers);
+        }
+        if let Some(timeout) = get_value_as_u64(&configuration, "timeout_ms") {
+            endpoint = endpoint.with_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(max_concurrency) = get_value_as_u64(&configuration, "max_concurrency").filter(|value| *value > 0) {
+            endpoint = endpoint.with_max_concurrency(max_concurrency as usize);
+        }
+        if configuration.contains_key("schema") {
+            endpoint = endpoint.with_schema(&get_value_as_str(&configuration, "schema", ""));
+        }
+        if configuration.contains_key("required_permission") {
+            endpoint = endpoint.with_required_permission(&get_value_as_str(&configuration, "required_permission", ""));
+        }
+        if configuration.contains_key("content_type") {
+            endpoint = endpoint.with_content_type(&get_value_as_str(&configuration, "content_type", DEFAULT_CONTENT_TYPE));
+        }
+        if let Some(content_encoding) = content_encoding {
+            endpoint = endpoint.with_content_encoding(content_encoding);
+        }
+        if let Some(headers) = headers {
+            endpoint = endpoint.with_headers(headers);
+        }
+        if endpoint.is_response_streamed() && !endpoint.is_json() {
+            let error = format!("streamed responses for {} endpoint are supported only for the JSON content type.", endpoint.get_url());
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
+        if endpoint.is_response_streamed() && !endpoint.is_response_required() {
+            let error = format!("streamed responses for {} endpoint require the response to be awaited.", endpoint.get_url());
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
+        // The first endpoint with the URL is kept, so that the routing
+        // doesn't depend on the order of processing
+        if let Some(existing) = endpoints.get(&url) {
+            let error = format!(
+                "url \"{}\" of the endpoint with the \"{}\" routing key is already used by the endpoint with the \"{}\" routing key.",
+                url, endpoint.get_routing_key(), existing.get_routing_key()
+            );
+            errors.push(PathfinderError::InvalidEndpoint(error));
+            continue;
+        }
         endpoints.insert(url, Arc::new(endpoint));
     }
 
-    endpoints
+    (endpoints, errors)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use crate::config::get_config;
-    use crate::engine::router::endpoint::{extract_endpoints, Endpoint};
+    use crate::engine::encoding::ContentEncoding;
+    use crate::engine::options::AmqpPrefix;
+    use crate::engine::router::balancer::WeightedRoutingKey;
+    use crate::engine::router::endpoint::{extract_endpoints, parse_endpoints, Endpoint};
 
     #[test]
     fn test_extract_endpoints_returns_an_empty_dict_by_default() {
@@ -197,6 +752,318 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_endpoints_returns_errors_for_invalid_endpoints() {
+        let conf = get_config("./tests/files/config_with_invalid_endpoints.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_endpoints_keeps_the_first_endpoint_with_the_duplicated_url() {
+        let conf = get_config("./tests/files/config_with_duplicated_urls.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints["/api/matchmaking/search"].get_routing_key(), "microservice.search");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            format!("{}", errors[0]),
+            "Parse error: url \"/api/matchmaking/search\" of the endpoint with the \"microservice.search.v2\" routing key \
+             is already used by the endpoint with the \"microservice.search\" routing key."
+        );
+    }
+
+    #[test]
+    fn test_extract_endpoints_skips_endpoints_with_ambiguous_wildcards() {
+        let conf = get_config("./tests/files/config_with_wildcard_endpoints.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 3);
+        assert!(endpoints.contains_key("/api/matchmaking/rooms/*"));
+        assert!(!endpoints.contains_key("/api/matchmaking/rooms/*/players"));
+        assert!(!endpoints.contains_key("/api/matchmaking/rooms*"));
+    }
+
+    #[test]
+    fn test_extract_endpoints_skips_endpoints_with_invalid_path_parameters() {
+        let conf = get_config("./tests/files/config_with_parameterized_endpoints.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 3);
+        assert!(endpoints.contains_key("/api/matchmaking/rooms/{room_id}/players/{player_id}"));
+        assert!(!endpoints.contains_key("/api/matchmaking/rooms/room-{room_id}"));
+        assert!(!endpoints.contains_key("/api/matchmaking/rooms/{id}/{id}"));
+    }
+
+    #[test]
+    fn test_extract_endpoints_normalizes_urls_in_lenient_mode() {
+        let conf = get_config("./tests/files/config_with_trailing_slashes.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+        assert!(endpoints.contains_key("/api/matchmaking/search"));
+        assert!(endpoints.contains_key("/api/matchmaking/leaderboard"));
+    }
+
+    #[test]
+    fn test_extract_endpoints_keeps_urls_as_is_in_strict_mode() {
+        let conf = get_config("./tests/files/config_with_strict_slashes.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+        assert!(endpoints.contains_key("/api/matchmaking/search/"));
+        assert!(endpoints.contains_key("/api//matchmaking/leaderboard"));
+    }
+
+    #[test]
+    fn test_extract_endpoints_lowercases_urls_in_case_insensitive_mode() {
+        let conf = get_config("./tests/files/config_with_case_insensitive_urls.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+        assert!(endpoints.contains_key("/api/matchmaking/search"));
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_middlewares() {
+        let conf = get_config("./tests/files/config_with_middlewares.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(
+            search.get_middlewares(),
+            Some(&[String::from("jwt"), String::from("rate_limit")][..])
+        );
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert_eq!(leaderboard.get_middlewares(), None);
+    }
+
+    #[test]
+    fn test_parse_endpoints_validates_routing_key_templates() {
+        let conf = get_config("./tests/files/config_with_topic_exchanges.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 2);
+        assert!(endpoints["/api/matchmaking/{region}/search"].is_topic_exchange());
+        assert!(endpoints["/api/matchmaking/leaderboard"].is_topic_exchange());
+
+        let mut messages: Vec<String> = errors.iter().map(|error| format!("{}", error)).collect();
+        messages.sort();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].contains("exchange type \"fanout\""));
+        assert!(messages[1].contains("templates are allowed only for the topic request exchange"));
+        assert!(messages[2].contains("the \"{mode}\" placeholder must be a path parameter"));
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_transformers() {
+        let conf = get_config("./tests/files/config_with_transformers.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(search.get_transformers(), &[String::from("empty"), String::from("tenant")]);
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert!(leaderboard.get_transformers().is_empty());
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_timeouts_and_concurrency_limits() {
+        let conf = get_config("./tests/files/config_with_timeouts.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(search.get_timeout(), Some(Duration::from_millis(1500)));
+        assert_eq!(search.get_max_concurrency(), Some(10));
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert_eq!(leaderboard.get_timeout(), None);
+        assert_eq!(leaderboard.get_max_concurrency(), None);
+    }
+
+    #[test]
+    fn test_parse_endpoints_returns_endpoints_with_weighted_routing_keys() {
+        let conf = get_config("./tests/files/config_with_routing_keys.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(errors.len(), 2);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(search.get_routing_key(), "microservice.search.1");
+        assert_eq!(search.get_routing_keys(), vec![
+            WeightedRoutingKey::new("microservice.search.1", 3),
+            WeightedRoutingKey::new("microservice.search.2", 1),
+        ]);
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert_eq!(leaderboard.get_routing_key(), "microservice.leaderboard");
+        assert_eq!(leaderboard.get_routing_keys(), vec![
+            WeightedRoutingKey::new("microservice.leaderboard.1", 1),
+            WeightedRoutingKey::new("microservice.leaderboard.2", 1),
+        ]);
+
+        let profile = endpoints["/api/matchmaking/profile"].clone();
+        assert!(profile.get_routing_keys().is_empty());
+        assert_eq!(profile.select_routing_key(), "microservice.profile");
+    }
+
+    #[test]
+    fn test_with_amqp_prefix_prepends_the_prefix_to_exchanges_and_routing_keys() {
+        let conf = get_config("./tests/files/config_with_routing_keys.yaml");
+        let (endpoints, _) = parse_endpoints(&conf);
+        let prefix = AmqpPrefix::new("staging");
+
+        let search = endpoints["/api/matchmaking/search"].as_ref().clone().with_amqp_prefix(&prefix);
+        assert_eq!(search.get_routing_key(), "staging.microservice.search.1");
+        assert_eq!(search.get_request_exchange(), "staging.open-matchmaking.direct");
+        assert_eq!(search.get_response_exchange(), "staging.open-matchmaking.responses.direct");
+        assert_eq!(search.get_routing_keys(), vec![
+            WeightedRoutingKey::new("staging.microservice.search.1", 3),
+            WeightedRoutingKey::new("staging.microservice.search.2", 1),
+        ]);
+
+        let profile = endpoints["/api/matchmaking/profile"].as_ref().clone().with_amqp_prefix(&prefix);
+        assert_eq!(profile.select_routing_key(), "staging.microservice.profile");
+    }
+
+    #[test]
+    fn test_select_routing_key_shares_the_distribution_between_clones() {
+        let endpoint = Endpoint::new("/api/matchmaking/search", "search", "", "", true).with_routing_keys(vec![
+            WeightedRoutingKey::new("search.1", 1),
+            WeightedRoutingKey::new("search.2", 1),
+        ]);
+        let clone = endpoint.clone();
+
+        assert_eq!(endpoint.select_routing_key(), "search.1");
+        assert_eq!(clone.select_routing_key(), "search.2");
+        assert_eq!(endpoint.select_routing_key(), "search.1");
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_schemas() {
+        let conf = get_config("./tests/files/config_with_schemas.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(search.get_schema(), Some("./tests/files/schemas/search.json"));
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert_eq!(leaderboard.get_schema(), None);
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_required_permissions() {
+        let conf = get_config("./tests/files/config_with_permissions.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(search.get_required_permission(), Some("matchmaking.search.start"));
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert_eq!(leaderboard.get_required_permission(), None);
+    }
+
+    #[test]
+    fn test_parse_endpoints_returns_endpoints_with_static_headers() {
+        let conf = get_config("./tests/files/config_with_static_headers.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(errors.len(), 1);
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert_eq!(search.get_headers().len(), 2);
+        assert_eq!(search.get_headers()["service_version"], "2");
+        assert_eq!(search.get_headers()["tenant_id"], "eu-west");
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert!(leaderboard.get_headers().is_empty());
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_content_types() {
+        let conf = get_config("./tests/files/config_with_content_types.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 2);
+
+        let avatar = endpoints["/api/profiles/avatar"].clone();
+        assert_eq!(avatar.get_content_type(), "text/plain; charset=utf-8");
+        assert!(!avatar.is_json());
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert_eq!(leaderboard.get_content_type(), "application/json");
+        assert!(leaderboard.is_json());
+        assert!(leaderboard.is_content_required());
+        assert!(!avatar.is_content_required());
+    }
+
+    #[test]
+    fn test_parse_endpoints_returns_endpoints_with_content_encodings() {
+        let conf = get_config("./tests/files/config_with_content_encodings.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{}", errors[0]).contains("content encoding \"br\""));
+
+        assert_eq!(endpoints["/api/matchmaking/search"].get_content_encoding(), Some(ContentEncoding::Gzip));
+        assert_eq!(endpoints["/api/matchmaking/history"].get_content_encoding(), Some(ContentEncoding::Deflate));
+        assert_eq!(endpoints["/api/matchmaking/leaderboard"].get_content_encoding(), None);
+    }
+
+    #[test]
+    fn test_parse_endpoints_allows_streamed_responses_only_for_json() {
+        let conf = get_config("./tests/files/config_with_streamed_responses.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{}", errors[0]).contains("supported only for the JSON content type"));
+
+        assert!(endpoints["/api/matchmaking/history"].is_response_streamed());
+        assert!(!endpoints["/api/matchmaking/leaderboard"].is_response_streamed());
+        assert!(endpoints["/api/matchmaking/leaderboard"].is_request_acked());
+        assert!(!endpoints["/api/matchmaking/history"].is_request_acked());
+    }
+
+    #[test]
+    fn test_parse_endpoints_with_the_fire_and_forget_mode() {
+        let conf = get_config("./tests/files/config_with_fire_and_forget_endpoints.yaml");
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{}", errors[0]).contains("require the response to be awaited"));
+
+        assert!(!endpoints["/api/notifications/send"].is_response_required());
+        assert!(!endpoints["/api/analytics/track"].is_response_required());
+        assert!(endpoints["/api/analytics/track"].is_request_acked());
+        assert!(endpoints["/api/matchmaking/search"].is_response_required());
+    }
+
+    #[test]
+    fn test_is_json_ignores_parameters_of_the_content_type() {
+        let endpoint = Endpoint::new("/api/test", "api.test", "", "", false)
+            .with_content_type("Application/JSON; charset=utf-8");
+        assert!(endpoint.is_json());
+    }
+
+    #[test]
+    fn test_extract_endpoints_returns_endpoints_with_optional_tokens() {
+        let conf = get_config("./tests/files/config_with_optional_tokens.yaml");
+        let endpoints = extract_endpoints(conf);
+        assert_eq!(endpoints.len(), 3);
+
+        let leaderboard = endpoints["/api/matchmaking/leaderboard"].clone();
+        assert!(!leaderboard.is_token_required());
+        assert!(leaderboard.is_token_optional());
+
+        let search = endpoints["/api/matchmaking/search"].clone();
+        assert!(search.is_token_required());
+        assert!(!search.is_token_optional());
+
+        let health = endpoints["/api/matchmaking/health"].clone();
+        assert!(!health.is_token_required());
+        assert!(!health.is_token_optional());
+    }
+
     #[test]
     fn test_get_url() {
         let url = "/api/matchmaking/test";
@@ -1,5 +1,11 @@
+pub mod balancer;
 pub mod endpoint;
+pub mod options;
 pub mod router;
+pub mod template;
 
-pub use self::endpoint::{extract_endpoints, Endpoint, ReadOnlyEndpoint};
-pub use self::router::{Router};
+pub use self::balancer::{WeightedRoutingKey, WeightedRoutingKeys};
+pub use self::endpoint::{extract_endpoints, parse_endpoints, Endpoint, ReadOnlyEndpoint, WILDCARD_SUFFIX};
+pub use self::options::{RouterOptions};
+pub use self::router::{RouteMatch, Router};
+pub use self::template::{expand_routing_key, is_routing_key_template};
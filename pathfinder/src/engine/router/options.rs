@@ -0,0 +1,123 @@
+//! Options that define how the router compares URLs.
+//!
+
+use config::Config;
+
+/// Simple wrapper for options that will be used during matching URLs.
+#[derive(Clone, Debug, Default)]
+pub struct RouterOptions {
+    strict_slashes: bool,
+    case_insensitive: bool
+}
+
+impl RouterOptions {
+    /// Returns an instance of `RouterOptions` with values taken from the
+    /// configuration. Missing keys are replaced by the default values.
+    pub fn from_config(conf: &Config) -> RouterOptions {
+        RouterOptions::default()
+            .with_strict_slashes(conf.get_bool("strict_slashes").unwrap_or(false))
+            .with_case_insensitive(conf.get_bool("case_insensitive").unwrap_or(false))
+    }
+
+    /// Sets whether URLs must be matched with the trailing and duplicate
+    /// slashes as is. By default, they are ignored.
+    pub fn with_strict_slashes(mut self, value: bool) -> RouterOptions {
+        self.strict_slashes = value;
+        self
+    }
+
+    /// Sets whether the case of letters is ignored during matching URLs.
+    /// By default, URLs are case-sensitive.
+    pub fn with_case_insensitive(mut self, value: bool) -> RouterOptions {
+        self.case_insensitive = value;
+        self
+    }
+
+    /// Returns `true` when the trailing and duplicate slashes are significant.
+    pub fn is_strict_slashes(&self) -> bool {
+        self.strict_slashes
+    }
+
+    /// Returns `true` when the case of letters in URLs is ignored.
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// Returns URL prepared for comparing with the configured endpoints.
+    pub fn normalize_url(&self, url: &str) -> String {
+        let normalized = self.normalize_slashes(url);
+        self.normalize_case(&normalized)
+    }
+
+    /// Lowercases URL when the case-insensitive mode is enabled. Only ASCII
+    /// letters are changed, so that the length of URL stays the same.
+    pub fn normalize_case(&self, url: &str) -> String {
+        match self.case_insensitive {
+            true => url.to_ascii_lowercase(),
+            false => url.to_string()
+        }
+    }
+
+    /// In the lenient mode the duplicate slashes are collapsed and a single
+    /// trailing slash is removed, except for the root URL.
+    pub fn normalize_slashes(&self, url: &str) -> String {
+        if self.strict_slashes {
+            return url.to_string();
+        }
+
+        let mut normalized = String::with_capacity(url.len());
+        for character in url.chars() {
+            if character == '/' && normalized.ends_with('/') {
+                continue;
+            }
+            normalized.push(character);
+        }
+
+        if normalized.len() > 1 && normalized.ends_with('/') {
+            normalized.pop();
+        }
+        normalized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::router::options::RouterOptions;
+
+    #[test]
+    fn test_normalize_url_removes_trailing_slash_in_lenient_mode() {
+        let options = RouterOptions::default();
+        assert_eq!(options.normalize_url("/api/matchmaking/search/"), "/api/matchmaking/search");
+    }
+
+    #[test]
+    fn test_normalize_url_collapses_duplicate_slashes_in_lenient_mode() {
+        let options = RouterOptions::default();
+        assert_eq!(options.normalize_url("/api//matchmaking///search"), "/api/matchmaking/search");
+    }
+
+    #[test]
+    fn test_normalize_url_preserves_root_url() {
+        let options = RouterOptions::default();
+        assert_eq!(options.normalize_url("/"), "/");
+        assert_eq!(options.normalize_url("//"), "/");
+    }
+
+    #[test]
+    fn test_normalize_url_returns_url_as_is_in_strict_mode() {
+        let options = RouterOptions::default().with_strict_slashes(true);
+        assert_eq!(options.normalize_url("/api//matchmaking/search/"), "/api//matchmaking/search/");
+    }
+
+    #[test]
+    fn test_normalize_url_lowercases_url_in_case_insensitive_mode() {
+        let options = RouterOptions::default().with_case_insensitive(true);
+        assert_eq!(options.normalize_url("/API/Matchmaking/Search/"), "/api/matchmaking/search");
+    }
+
+    #[test]
+    fn test_normalize_url_keeps_case_by_default() {
+        let options = RouterOptions::default();
+        assert_eq!(options.normalize_url("/API/Matchmaking/Search"), "/API/Matchmaking/Search");
+    }
+}
@@ -7,7 +7,8 @@
 use std::clone::Clone;
 use std::collections::HashMap;
 
-use crate::engine::router::endpoint::ReadOnlyEndpoint;
+use crate::engine::router::endpoint::{get_parameter_name, ReadOnlyEndpoint, WILDCARD_SUFFIX};
+use crate::engine::router::options::RouterOptions;
 use crate::error::{PathfinderError, Result};
 
 /// A struct which is stores a mapping of resources that can be
@@ -19,66 +20,246 @@ use crate::error::{PathfinderError, Result};
 /// Attempt to get an endpoint with correct URL will return all expected data:
 ///
 /// ```
-/// use config::{get_config};
-/// use engine::router::{Router, Endpoint, extract_endpoints};
+/// use pathfinder::config::{get_config};
+/// use pathfinder::engine::router::{Router, extract_endpoints};
 ///
 /// let url = "/api/matchmaking/search";
-/// let config = get_config(&"../../../tests/files/config_with_valid_endpoints.yaml");
+/// let config = get_config("tests/files/config_with_valid_endpoints.yaml");
 /// let endpoints = extract_endpoints(config);
-/// let router = Box::new(Router::new(endpoints))
+/// let router = Box::new(Router::new(endpoints));
 ///
-/// let endpoint = route.match_url(url).unwrap();
+/// let endpoint = router.match_url(url).unwrap();
 /// assert_eq!(endpoint.get_url(), "/api/matchmaking/search");
-/// assert_eq!(endpoint.get_microservice(), "microservice.search");
+/// assert_eq!(endpoint.get_routing_key(), "microservice.search");
 /// ```
 ///
 /// For not matched URL will be returned an error:
 ///
 /// ```
-/// use config::{get_config};
-/// use engine::router::{Router, Endpoint, extract_endpoints};
+/// use pathfinder::config::{get_config};
+/// use pathfinder::engine::router::{Router, extract_endpoints};
 ///
 /// let url = "/api/matchmaking/search";
-/// let config = get_config(&"../../../tests/files/config_with_invalid_endpoints.yaml");
+/// let config = get_config("tests/files/config_with_invalid_endpoints.yaml");
 /// let endpoints = extract_endpoints(config);
-/// let router = Box::new(Router::new(endpoints))
+/// let router = Box::new(Router::new(endpoints));
 ///
-/// assert_eq!(route.match_url(url).is_err(), true);
+/// assert_eq!(router.match_url(url).is_err(), true);
 /// ```
 ///
 pub struct Router {
-    endpoints: HashMap<String, ReadOnlyEndpoint>
+    endpoints: HashMap<String, ReadOnlyEndpoint>,
+    templates: Vec<(Vec<String>, ReadOnlyEndpoint)>,
+    wildcards: Vec<(String, ReadOnlyEndpoint)>,
+    prefixes: Vec<(String, ReadOnlyEndpoint)>,
+    options: RouterOptions
+}
+
+/// A struct which stores an endpoint that was found for the requested URL
+/// and the data that was captured during matching.
+#[derive(Debug, Clone)]
+pub struct RouteMatch {
+    url: String,
+    endpoint: ReadOnlyEndpoint,
+    suffix: Option<String>,
+    params: HashMap<String, String>
+}
+
+impl RouteMatch {
+    /// Returns a new instance of `RouteMatch` for the requested URL.
+    pub fn new(url: &str, endpoint: ReadOnlyEndpoint) -> RouteMatch {
+        RouteMatch {
+            url: url.to_string(),
+            endpoint,
+            suffix: None,
+            params: HashMap::new()
+        }
+    }
+
+    /// Sets a part of URL that was captured by the trailing wildcard.
+    pub fn with_suffix(mut self, suffix: String) -> RouteMatch {
+        self.suffix = Some(suffix);
+        self
+    }
+
+    /// Sets the path parameters that were extracted from URL.
+    pub fn with_params(mut self, params: HashMap<String, String>) -> RouteMatch {
+        self.params = params;
+        self
+    }
+
+    /// Returns URL as it was specified by a client.
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the matched endpoint.
+    pub fn get_endpoint(&self) -> ReadOnlyEndpoint {
+        self.endpoint.clone()
+    }
+
+    /// Returns a part of URL that was captured by the trailing wildcard.
+    pub fn get_suffix(&self) -> Option<&str> {
+        self.suffix.as_deref()
+    }
+
+    /// Returns the path parameters extracted from URL by their names.
+    pub fn get_params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
 }
 
 impl Router {
     /// Returns a new instance of `Router` that contains a mapping for resources.
     pub fn new(endpoints: HashMap<String, ReadOnlyEndpoint>) -> Router {
+        // The templates are sorted by the amount of static segments, so that
+        // the most specific template will be checked first.
+        let mut templates: Vec<(Vec<String>, ReadOnlyEndpoint)> = endpoints
+            .iter()
+            .filter(|(url, _)| url.contains('{') && !url.ends_with(WILDCARD_SUFFIX))
+            .map(|(url, endpoint)| {
+                let segments = url.split('/').map(String::from).collect();
+                (segments, endpoint.clone())
+            })
+            .collect();
+        templates.sort_by(|(a, a_endpoint), (b, b_endpoint)| {
+            let a_static = a.iter().filter(|segment| get_parameter_name(segment).is_none()).count();
+            let b_static = b.iter().filter(|segment| get_parameter_name(segment).is_none()).count();
+            b_static.cmp(&a_static).then(a_endpoint.get_url().cmp(b_endpoint.get_url()))
+        });
+
+        // The wildcard endpoints are sorted by the prefix length, so that
+        // during matching the most specific endpoint will be checked first.
+        let mut wildcards: Vec<(String, ReadOnlyEndpoint)> = endpoints
+            .iter()
+            .filter(|(url, _)| url.ends_with(WILDCARD_SUFFIX))
+            .map(|(url, endpoint)| {
+                let prefix = url.trim_end_matches('*').to_string();
+                (prefix, endpoint.clone())
+            })
+            .collect();
+        wildcards.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+
+        // The plain endpoints are used as a fallback for nested resources, so
+        // that the endpoint with the longest URL will be checked first. The
+        // root URL is skipped, otherwise it would catch any unknown URL.
+        let mut prefixes: Vec<(String, ReadOnlyEndpoint)> = endpoints
+            .iter()
+            .filter(|(url, _)| !url.contains('{') && !url.ends_with(WILDCARD_SUFFIX))
+            .map(|(url, endpoint)| {
+                let prefix = format!("{}/", url.trim_end_matches('/'));
+                (prefix, endpoint.clone())
+            })
+            .filter(|(prefix, _)| prefix != "/")
+            .collect();
+        prefixes.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+
         Router {
-            endpoints: endpoints
+            endpoints,
+            templates,
+            wildcards,
+            prefixes,
+            options: RouterOptions::default()
         }
     }
 
+    /// Sets the options that will be used for matching URLs. They must be
+    /// the same as were used for extracting the endpoints.
+    pub fn with_options(mut self, options: RouterOptions) -> Router {
+        self.options = options;
+        self
+    }
+
+    /// Returns the number of registered endpoints.
+    pub fn get_endpoints_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// Returns a snapshot of the registered endpoints sorted by their URLs.
+    pub fn get_endpoints(&self) -> Vec<ReadOnlyEndpoint> {
+        let mut endpoints: Vec<ReadOnlyEndpoint> = self.endpoints.values().cloned().collect();
+        endpoints.sort_by(|left, right| left.get_url().cmp(right.get_url()));
+        endpoints
+    }
+
     /// Returns an endpoint that was found for a passed URL.
     pub fn match_url(&self, url: &str) -> Result<ReadOnlyEndpoint> {
-        match self.endpoints.contains_key(url) {
-            true => {
-                let endpoint = self.endpoints[url].clone();
-                Ok(endpoint)
+        self.match_route(url).map(|route| route.get_endpoint())
+    }
+
+    /// Returns an endpoint that was found for a passed URL with the captured
+    /// data. The exact matches always have a priority over the templates with
+    /// path parameters, and then over the wildcard endpoints, which are
+    /// checked by the longest prefix. When nothing was matched, the plain
+    /// endpoint with the longest URL, which is a prefix of the passed URL by
+    /// whole segments, is used and the rest of URL is captured as a suffix.
+    pub fn match_route(&self, url: &str) -> Result<RouteMatch> {
+        // The path parameters and the suffix are taken from URL in the
+        // original case, whereas the lookup is doing by the normalized one.
+        let url_with_slashes = self.options.normalize_slashes(url);
+        let lookup_url = self.options.normalize_case(&url_with_slashes);
+
+        if let Some(endpoint) = self.endpoints.get(&lookup_url) {
+            return Ok(RouteMatch::new(url, endpoint.clone()));
+        }
+
+        let url_segments: Vec<&str> = url_with_slashes.split('/').collect();
+        let lookup_segments: Vec<&str> = lookup_url.split('/').collect();
+        for (segments, endpoint) in self.templates.iter() {
+            if let Some(params) = match_template(segments, &url_segments, &lookup_segments) {
+                return Ok(RouteMatch::new(url, endpoint.clone()).with_params(params));
             }
-            false => Err(PathfinderError::EndpointNotFound(url.to_string()))
         }
+
+        for (prefix, endpoint) in self.wildcards.iter() {
+            if lookup_url.starts_with(prefix.as_str()) {
+                let suffix = url_with_slashes[prefix.len()..].to_string();
+                return Ok(RouteMatch::new(url, endpoint.clone()).with_suffix(suffix));
+            }
+        }
+
+        for (prefix, endpoint) in self.prefixes.iter() {
+            if lookup_url.starts_with(prefix.as_str()) {
+                let suffix = url_with_slashes[prefix.len()..].to_string();
+                return Ok(RouteMatch::new(url, endpoint.clone()).with_suffix(suffix));
+            }
+        }
+
+        Err(PathfinderError::EndpointNotFound(url.to_string()))
     }
 }
 
+/// Compares URL segments with the template and returns the values of path
+/// parameters when all static segments are equal.
+fn match_template(template: &[String], url_segments: &[&str], lookup_segments: &[&str]) -> Option<HashMap<String, String>> {
+    if template.len() != url_segments.len() {
+        return None;
+    }
+
+    let mut params = HashMap::new();
+    for (index, segment) in template.iter().enumerate() {
+        match get_parameter_name(segment) {
+            Some(name) if !url_segments[index].is_empty() => {
+                params.insert(name.to_string(), url_segments[index].to_string());
+            }
+            Some(_) => return None,
+            None if segment != lookup_segments[index] => return None,
+            None => {}
+        }
+    }
+    Some(params)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::get_config;
-    use crate::engine::router::{extract_endpoints, Router};
+    use crate::engine::router::{extract_endpoints, Router, RouterOptions};
 
     fn get_router(file_path: &str) -> Box<Router> {
         let config = get_config(file_path);
+        let options = RouterOptions::from_config(&config);
         let endpoints = extract_endpoints(config);
-        Box::new(Router::new(endpoints))
+        Box::new(Router::new(endpoints).with_options(options))
     }
 
     #[test]
@@ -99,4 +280,145 @@ mod tests {
 
         assert_eq!(result_match.is_err(), true);
     }
+
+    #[test]
+    fn test_router_match_route_returns_a_suffix_for_a_wildcard_url() {
+        let router = get_router("./tests/files/config_with_wildcard_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/rooms/123/players");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.rooms");
+        assert_eq!(route.get_suffix(), Some("123/players"));
+    }
+
+    #[test]
+    fn test_router_match_route_prefers_an_exact_match_over_a_wildcard() {
+        let router = get_router("./tests/files/config_with_wildcard_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/rooms/lobby");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.lobby");
+        assert_eq!(route.get_suffix(), None);
+    }
+
+    #[test]
+    fn test_router_match_route_prefers_the_longest_wildcard_prefix() {
+        let router = get_router("./tests/files/config_with_wildcard_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/rooms/archive/2018");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.archive");
+        assert_eq!(route.get_suffix(), Some("2018"));
+    }
+
+    #[test]
+    fn test_router_match_route_returns_an_error_for_an_unknown_prefix() {
+        let router = get_router("./tests/files/config_with_wildcard_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/search/123");
+
+        assert!(result_match.is_err());
+    }
+
+    #[test]
+    fn test_router_match_route_falls_back_to_the_longest_plain_prefix() {
+        let router = get_router("./tests/files/config_with_valid_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/search/v2");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.search");
+        assert_eq!(route.get_suffix(), Some("v2"));
+    }
+
+    #[test]
+    fn test_router_match_route_falls_back_to_a_prefix_by_whole_segments_only() {
+        let router = get_router("./tests/files/config_with_valid_endpoints.yaml");
+
+        assert!(router.match_route("/api/matchmaking/searching").is_err());
+        assert!(router.match_route("/api/matchmaking").is_err());
+    }
+
+    #[test]
+    fn test_router_match_route_extracts_path_parameters() {
+        let router = get_router("./tests/files/config_with_parameterized_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/rooms/42/players/7");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.players.retrieve");
+        assert_eq!(route.get_params().len(), 2);
+        assert_eq!(route.get_params()["room_id"], "42");
+        assert_eq!(route.get_params()["player_id"], "7");
+    }
+
+    #[test]
+    fn test_router_match_route_prefers_the_most_specific_template() {
+        let router = get_router("./tests/files/config_with_parameterized_endpoints.yaml");
+        let result_match = router.match_route("/api/matchmaking/rooms/42/players/me");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.players.me");
+        assert_eq!(route.get_params().len(), 1);
+        assert_eq!(route.get_params()["room_id"], "42");
+    }
+
+    #[test]
+    fn test_router_match_route_returns_an_error_for_a_different_segments_count() {
+        let router = get_router("./tests/files/config_with_parameterized_endpoints.yaml");
+
+        assert!(router.match_route("/api/matchmaking/rooms/42/players").is_err());
+        assert!(router.match_route("/api/matchmaking/rooms/42/players/7/stats").is_err());
+    }
+
+    #[test]
+    fn test_router_match_url_ignores_trailing_and_duplicate_slashes_in_lenient_mode() {
+        let router = get_router("./tests/files/config_with_trailing_slashes.yaml");
+
+        assert!(router.match_url("/api/matchmaking/search").is_ok());
+        assert!(router.match_url("/api/matchmaking/search/").is_ok());
+        assert!(router.match_url("/api//matchmaking/leaderboard").is_ok());
+    }
+
+    #[test]
+    fn test_router_match_url_requires_the_exact_form_in_strict_mode() {
+        let router = get_router("./tests/files/config_with_strict_slashes.yaml");
+
+        assert!(router.match_url("/api/matchmaking/search/").is_ok());
+        assert!(router.match_url("/api/matchmaking/search").is_err());
+        assert!(router.match_url("/api/matchmaking/leaderboard").is_err());
+    }
+
+    #[test]
+    fn test_router_match_route_ignores_case_when_enabled() {
+        let router = get_router("./tests/files/config_with_case_insensitive_urls.yaml");
+        let result_match = router.match_route("/API/Matchmaking/Search");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.search");
+        assert_eq!(route.get_url(), "/API/Matchmaking/Search");
+    }
+
+    #[test]
+    fn test_router_match_route_keeps_the_original_case_of_captured_values() {
+        let router = get_router("./tests/files/config_with_case_insensitive_urls.yaml");
+        let result_match = router.match_route("/Api/Matchmaking/Rooms/AbC/Players/XyZ");
+
+        assert!(result_match.is_ok());
+        let route = result_match.unwrap();
+        assert_eq!(route.get_endpoint().get_routing_key(), "microservice.players");
+        assert_eq!(route.get_params()["room_id"], "AbC");
+        assert_eq!(route.get_params()["player_id"], "XyZ");
+    }
+
+    #[test]
+    fn test_router_match_route_is_case_sensitive_by_default() {
+        let router = get_router("./tests/files/config_with_valid_endpoints.yaml");
+
+        assert!(router.match_route("/API/Matchmaking/Search").is_err());
+    }
 }
@@ -0,0 +1,244 @@
+//! Routing keys that are built for each request.
+//!
+//! Endpoints that publish requests into topic exchanges can specify the
+//! routing key as a template with placeholders in curly braces, e.g.
+//! `matchmaking.{region}.search`. A placeholder refers either to a path
+//! parameter of the endpoint URL (`{region}`) or to a field of the message
+//! content (`{content.region}`, nested fields are separated by dots).
+//!
+
+use std::collections::HashMap;
+
+use json::JsonValue;
+
+use crate::engine::router::endpoint::get_parameter_name;
+use crate::error::{PathfinderError, Result};
+
+/// The prefix of placeholders that refer to fields of the message content.
+const CONTENT_PREFIX: &str = "content.";
+
+/// The maximal length of the routing key in bytes, allowed by AMQP.
+const MAX_ROUTING_KEY_LENGTH: usize = 255;
+
+/// Characters that have a special meaning for topic exchanges, so that they
+/// can't be passed by clients in the values of placeholders.
+const RESERVED_CHARACTERS: [char; 3] = ['.', '*', '#'];
+
+/// A part of the parsed routing key template.
+#[derive(Debug, PartialEq)]
+enum TemplatePart<'a> {
+    Text(&'a str),
+    Placeholder(&'a str)
+}
+
+/// Checks whether the routing key contains any placeholders.
+pub fn is_routing_key_template(routing_key: &str) -> bool {
+    routing_key.contains('{') || routing_key.contains('}')
+}
+
+/// Checks that each placeholder of the template refers to a path parameter
+/// of the URL or to a field of the message content. Returns the reason
+/// when the template is invalid.
+pub fn validate_routing_key_template(template: &str, url: &str) -> std::result::Result<(), String> {
+    let parameters: Vec<&str> = url.split('/').filter_map(get_parameter_name).collect();
+    for part in parse_template(template)? {
+        if let TemplatePart::Placeholder(name) = part {
+            let is_valid = match name.starts_with(CONTENT_PREFIX) {
+                true => name[CONTENT_PREFIX.len()..].split('.').all(|field| !field.is_empty()),
+                false => parameters.contains(&name)
+            };
+            if !is_valid {
+                let reason = format!(
+                    "the \"{{{}}}\" placeholder must be a path parameter of the URL or a field of the content (e.g. \"{{content.region}}\")",
+                    name
+                );
+                return Err(reason);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the routing key for the request with the placeholders of the
+/// template replaced by the path parameters and the fields of the message
+/// content. Fails when a field of the content is missing or isn't a string
+/// or a number, when a value contains the words separator or wildcards of
+/// topic exchanges, or when the routing key is too long.
+pub fn expand_routing_key(template: &str, params: &HashMap<String, String>, message: &JsonValue) -> Result<String> {
+    let parts = parse_template(template).map_err(PathfinderError::InvalidEndpoint)?;
+    let mut routing_key = String::new();
+    for part in parts {
+        match part {
+            TemplatePart::Text(text) => routing_key.push_str(text),
+            TemplatePart::Placeholder(name) => {
+                let value = get_placeholder_value(name, params, message)?;
+                if value.contains(&RESERVED_CHARACTERS[..]) {
+                    let message = format!("The value of the \"{}\" placeholder can't contain `.`, `*` or `#`.", name);
+                    return Err(PathfinderError::DecodingError(message));
+                }
+                routing_key.push_str(&value)
+            }
+        }
+    }
+
+    match routing_key.len() > MAX_ROUTING_KEY_LENGTH {
+        true => {
+            let message = format!("The routing key exceeds {} bytes.", MAX_ROUTING_KEY_LENGTH);
+            Err(PathfinderError::DecodingError(message))
+        },
+        false => Ok(routing_key)
+    }
+}
+
+/// Returns the value for the placeholder of the routing key template.
+fn get_placeholder_value(name: &str, params: &HashMap<String, String>, message: &JsonValue) -> Result<String> {
+    if !name.starts_with(CONTENT_PREFIX) {
+        return params.get(name).cloned().ok_or_else(|| {
+            let message = format!("The \"{}\" path parameter is missing for the routing key.", name);
+            PathfinderError::DecodingError(message)
+        });
+    }
+
+    let value = name[CONTENT_PREFIX.len()..]
+        .split('.')
+        .fold(&message["content"], |value, field| &value[field]);
+    match (value.as_str(), value.is_number()) {
+        (Some(text), _) if !text.is_empty() => Ok(text.to_string()),
+        (None, true) => Ok(value.dump()),
+        _ => {
+            let message = format!("The `{}` field must be a non-empty string or a number.", name);
+            Err(PathfinderError::DecodingError(message))
+        }
+    }
+}
+
+/// Splits the template into the static text and the placeholders.
+fn parse_template(template: &str) -> std::result::Result<Vec<TemplatePart<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        match (rest.find('{'), rest.find('}')) {
+            (None, None) => {
+                parts.push(TemplatePart::Text(rest));
+                break;
+            },
+            (Some(start), Some(end)) if start < end => {
+                let name = &rest[start + 1..end];
+                if name.is_empty() || name.contains('{') {
+                    return Err(format!("the \"{}\" template has an invalid placeholder", template));
+                }
+                if start > 0 {
+                    parts.push(TemplatePart::Text(&rest[..start]));
+                }
+                parts.push(TemplatePart::Placeholder(name));
+                rest = &rest[end + 1..];
+            },
+            _ => return Err(format!("the \"{}\" template has unbalanced braces", template))
+        }
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use json::object;
+
+    use crate::engine::router::template::{
+        expand_routing_key, is_routing_key_template, parse_template, validate_routing_key_template,
+        TemplatePart
+    };
+    use crate::error::PathfinderError;
+
+    fn get_params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_is_routing_key_template() {
+        assert!(is_routing_key_template("matchmaking.{region}.search"));
+        assert!(!is_routing_key_template("matchmaking.search"));
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(parse_template("matchmaking.{region}.{content.mode}").unwrap(), vec![
+            TemplatePart::Text("matchmaking."),
+            TemplatePart::Placeholder("region"),
+            TemplatePart::Text("."),
+            TemplatePart::Placeholder("content.mode"),
+        ]);
+        assert!(parse_template("matchmaking.{region.search").is_err());
+        assert!(parse_template("matchmaking.region}.search").is_err());
+        assert!(parse_template("matchmaking.{}.search").is_err());
+        assert!(parse_template("matchmaking.{{region}}.search").is_err());
+    }
+
+    #[test]
+    fn test_validate_routing_key_template() {
+        let url = "/api/{region}/search";
+        assert!(validate_routing_key_template("matchmaking.{region}.search", url).is_ok());
+        assert!(validate_routing_key_template("matchmaking.{content.queue.mode}", url).is_ok());
+        assert!(validate_routing_key_template("matchmaking.search", url).is_ok());
+
+        let error = validate_routing_key_template("matchmaking.{mode}.search", url).unwrap_err();
+        assert!(error.starts_with("the \"{mode}\" placeholder must be a path parameter"));
+        assert!(validate_routing_key_template("matchmaking.{content.}", url).is_err());
+        assert!(validate_routing_key_template("matchmaking.{region", url).is_err());
+    }
+
+    #[test]
+    fn test_expand_routing_key_with_path_parameters_and_content_fields() {
+        let params = get_params(&[("region", "eu")]);
+        let message = object!{"content" => object!{"queue" => object!{"mode" => "ranked"}, "level" => 7}};
+
+        let routing_key = expand_routing_key("matchmaking.{region}.{content.queue.mode}.{content.level}", &params, &message);
+        assert_eq!(routing_key.unwrap(), "matchmaking.eu.ranked.7");
+        assert_eq!(expand_routing_key("matchmaking.search", &params, &message).unwrap(), "matchmaking.search");
+    }
+
+    #[test]
+    fn test_expand_routing_key_rejects_reserved_characters_in_values() {
+        let message = object!{"content" => object!{"mode" => "ranked.*", "level" => 7.5}};
+        for region in &["eu.admin", "eu*", "#"] {
+            let params = get_params(&[("region", region)]);
+            match expand_routing_key("matchmaking.{region}.search", &params, &message) {
+                Err(PathfinderError::DecodingError(message)) => {
+                    assert_eq!(message, "The value of the \"region\" placeholder can't contain `.`, `*` or `#`.");
+                },
+                _ => panic!("Expected a decoding error for {}", region)
+            }
+        }
+
+        let params = get_params(&[("region", "eu")]);
+        assert!(expand_routing_key("matchmaking.{content.mode}", &params, &message).is_err());
+        assert!(expand_routing_key("matchmaking.{content.level}", &params, &message).is_err());
+    }
+
+    #[test]
+    fn test_expand_routing_key_rejects_too_long_routing_keys() {
+        let message = object!{"content" => object!{}};
+        let region = "e".repeat(243);
+        let params = get_params(&[("region", &region)]);
+        assert_eq!(expand_routing_key("matchmaking.{region}", &params, &message).unwrap().len(), 255);
+
+        let region = "e".repeat(244);
+        let params = get_params(&[("region", &region)]);
+        match expand_routing_key("matchmaking.{region}", &params, &message) {
+            Err(PathfinderError::DecodingError(message)) => assert_eq!(message, "The routing key exceeds 255 bytes."),
+            _ => panic!("Expected a decoding error")
+        }
+    }
+
+    #[test]
+    fn test_expand_routing_key_fails_for_missing_content_fields() {
+        let message = object!{"content" => object!{"mode" => object!{}}};
+        for template in &["matchmaking.{content.region}", "matchmaking.{content.mode}"] {
+            match expand_routing_key(template, &HashMap::new(), &message) {
+                Err(PathfinderError::DecodingError(_)) => {},
+                _ => panic!("Expected a decoding error for {}", template)
+            }
+        }
+    }
+}
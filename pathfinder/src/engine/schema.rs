@@ -0,0 +1,114 @@
+//! Validation of request contents against JSON schemas
+//!
+//! Each endpoint can reference a file with the JSON schema, which is
+//! used for checking the `content` field of incoming requests before
+//! sending them to microservices.
+//!
+
+use std::fs;
+
+use json::JsonValue;
+use jsonschema::JSONSchema;
+
+use crate::error::{Result, PathfinderError};
+
+/// A compiled JSON schema for validating request contents.
+pub struct SchemaValidator {
+    schema: JSONSchema
+}
+
+impl SchemaValidator {
+    /// Returns a new instance of `SchemaValidator` with the schema that
+    /// was read from the file.
+    pub fn from_file(file_path: &str) -> Result<SchemaValidator> {
+        let raw_schema = fs::read_to_string(file_path)?;
+        SchemaValidator::from_string(&raw_schema)
+            .map_err(|err| PathfinderError::InvalidEndpoint(format!("schema \"{}\" is invalid: {}", file_path, err)))
+    }
+
+    /// Returns a new instance of `SchemaValidator` with the schema from
+    /// the string.
+    pub fn from_string(raw_schema: &str) -> Result<SchemaValidator> {
+        let document = serde_json::from_str(raw_schema)
+            .map_err(|err| PathfinderError::InvalidEndpoint(format!("{}", err)))?;
+        let schema = JSONSchema::compile(&document)
+            .map_err(|err| PathfinderError::InvalidEndpoint(format!("{}", err)))?;
+        Ok(SchemaValidator { schema })
+    }
+
+    /// Checks that the content matches the schema. Otherwise returns an
+    /// error with the list of all failing fields.
+    pub fn validate(&self, content: &JsonValue) -> Result<()> {
+        let instance = serde_json::from_str(&content.dump())
+            .map_err(|err| PathfinderError::DecodingError(format!("{}", err)))?;
+
+        let fields: Vec<String> = match self.schema.validate(&instance) {
+            Ok(_) => return Ok(()),
+            Err(errors) => errors
+                .map(|error| format!("\"{}\": {}", get_field_name(&error.instance_path.to_string()), error))
+                .collect()
+        };
+        let error_message = format!("The \"content\" field is invalid. Failing fields: {}", fields.join("; "));
+        Err(PathfinderError::DecodingError(error_message))
+    }
+}
+
+/// Converts the JSON pointer into the name of the field in the content.
+fn get_field_name(pointer: &str) -> String {
+    match pointer.trim_start_matches('/') {
+        "" => String::from("content"),
+        path => format!("content.{}", path.replace('/', "."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json::object;
+
+    use crate::engine::schema::SchemaValidator;
+    use crate::error::PathfinderError;
+
+    fn get_validator() -> SchemaValidator {
+        SchemaValidator::from_file("./tests/files/schemas/search.json").unwrap()
+    }
+
+    #[test]
+    fn test_validate_returns_ok_for_valid_content() {
+        let validator = get_validator();
+        let content = object!{"game_mode" => "1v1", "rating" => 2500};
+        assert!(validator.validate(&content).is_ok());
+    }
+
+    #[test]
+    fn test_validate_returns_all_failing_fields() {
+        let validator = get_validator();
+        let content = object!{"rating" => "high"};
+        let result = validator.validate(&content);
+
+        match result {
+            Err(PathfinderError::DecodingError(message)) => {
+                assert!(message.contains("\"content\": \"game_mode\" is a required property"));
+                assert!(message.contains("\"content.rating\": \"high\" is not of type \"integer\""));
+            }
+            _ => panic!("Expected a decoding error"),
+        }
+    }
+
+    #[test]
+    fn test_validate_returns_error_for_missing_content() {
+        let validator = get_validator();
+        let result = validator.validate(&json::Null);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_from_string_returns_error_for_invalid_schema() {
+        assert!(SchemaValidator::from_string("{\"type\": 42}").is_err());
+        assert!(SchemaValidator::from_string("not a json").is_err());
+    }
+
+    #[test]
+    fn test_from_file_returns_error_for_missing_file() {
+        assert!(SchemaValidator::from_file("./tests/files/schemas/unknown.json").is_err());
+    }
+}
@@ -8,13 +8,87 @@
 use std::sync::Arc;
 
 use json::{parse as parse_json, JsonValue};
+use rmpv::Value as MessagePackValue;
+use rmpv::decode::read_value;
+use rmpv::encode::write_value;
+use serde_json::Value as SerdeJsonValue;
 use tungstenite::protocol::Message;
 
+use crate::engine::session::Session;
 use crate::error::{PathfinderError, Result};
 
 /// Type alias for JSON object
 pub type JsonMessage = Arc<Box<JsonValue>>;
 
+/// Versions of the message protocol, that are supported by the serializer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolVersion {
+    /// The initial version, that is used for messages without the `version`
+    /// field.
+    V1
+}
+
+impl ProtocolVersion {
+    /// Returns the version of the protocol specified by the `version` field
+    /// of the message. Messages without the field use the first version.
+    pub fn from_message(json: &JsonValue) -> Result<ProtocolVersion> {
+        if json["version"].is_null() {
+            return Ok(ProtocolVersion::V1);
+        }
+
+        match json["version"].as_u32() {
+            Some(1) => Ok(ProtocolVersion::V1),
+            Some(version) => {
+                let error_message = format!("The protocol version {} isn't supported. Supported versions: 1", version);
+                Err(PathfinderError::DecodingError(error_message))
+            },
+            None => {
+                let error_message = String::from("The `version` field must be a positive integer");
+                Err(PathfinderError::DecodingError(error_message))
+            }
+        }
+    }
+}
+
+/// Formats of messages that are supported by the serializer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ContentType {
+    /// JSON data in text frames.
+    #[default]
+    Json,
+    /// MessagePack data in binary frames.
+    MessagePack,
+    /// CBOR data in binary frames.
+    Cbor
+}
+
+impl ContentType {
+    /// Returns the content type of the message based on its frame type.
+    pub fn from_message(message: &Message) -> ContentType {
+        match message.is_binary() {
+            true => ContentType::MessagePack,
+            false => ContentType::Json
+        }
+    }
+
+    /// Returns the content type by the name of the format, i.e. `json`,
+    /// `msgpack` or `cbor`.
+    pub fn from_format(format: &str) -> Option<ContentType> {
+        match format {
+            "json" => Some(ContentType::Json),
+            "msgpack" => Some(ContentType::MessagePack),
+            "cbor" => Some(ContentType::Cbor),
+            _ => None
+        }
+    }
+
+    /// Returns the content type selected by the WebSocket subprotocol. Other
+    /// subprotocols (e.g. API versions) don't affect the format of messages.
+    pub fn from_subprotocol(subprotocol: &str) -> Option<ContentType> {
+        ContentType::from_format(subprotocol)
+    }
+}
+
 /// A specialized struct for deserializing incoming messages into JSON and
 /// serializing responses into `tungstenite::Message` objects, so, that they
 /// could be send to a client.
@@ -24,7 +98,8 @@ pub type JsonMessage = Arc<Box<JsonValue>>;
 /// Serializing a JSON object into message:
 ///
 /// ```
-/// use engine::{Serializer};
+/// use json::object;
+/// use pathfinder::engine::{Serializer};
 ///
 /// let instance = Serializer::new();
 /// let json = object!{"test" => "serialize"};
@@ -35,7 +110,8 @@ pub type JsonMessage = Arc<Box<JsonValue>>;
 /// Deserializing a message to JSON object:
 ///
 /// ```
-/// use engine::{Serializer};
+/// use json::object;
+/// use pathfinder::engine::{Serializer};
 /// use tungstenite::{Message};
 ///
 /// let json = object!{"test" => "serialize"};
@@ -44,27 +120,141 @@ pub type JsonMessage = Arc<Box<JsonValue>>;
 /// println!("{:?}", instance.deserialize(&message))
 /// ```
 ///
-pub struct Serializer;
+pub struct Serializer {
+    content_type: ContentType,
+    max_message_size: Option<usize>
+}
 
 impl Serializer {
     /// Returns a new instance of `Serializer`.
     pub fn new() -> Serializer {
-        Serializer {}
+        Serializer {
+            content_type: ContentType::Json,
+            max_message_size: None
+        }
+    }
+
+    /// Returns a new instance of `Serializer` that serializes responses
+    /// into the specified format.
+    pub fn with_content_type(content_type: ContentType) -> Serializer {
+        Serializer {
+            content_type,
+            max_message_size: None
+        }
+    }
+
+    /// Limits the size of incoming messages in bytes.
+    pub fn with_max_message_size(mut self, value: usize) -> Serializer {
+        self.max_message_size = Some(value);
+        self
     }
 
     /// Converts a UTF-8 encoded `std::string::String` into an instance of
     /// the `tungstenite::Message` type, so that this message can be send to
-    /// a client.
+    /// a client. For the MessagePack and CBOR content types the string must
+    /// contain a valid JSON.
     pub fn serialize(&self, message: String) -> Result<Message> {
-        Ok(Message::Text(message))
+        match self.content_type {
+            ContentType::Json => Ok(Message::Text(message)),
+            ContentType::MessagePack => {
+                let json = self.parse_into_json(message.as_str())?;
+                let mut data = Vec::new();
+                write_value(&mut data, &json_to_message_pack(&json))
+                    .map_err(|err| PathfinderError::DecodingError(format!("{}", err)))?;
+                Ok(Message::Binary(data))
+            },
+            ContentType::Cbor => {
+                let json: SerdeJsonValue = serde_json::from_str(message.as_str())
+                    .map_err(|err| PathfinderError::DecodingError(format!("{}", err)))?;
+                let data = serde_cbor::to_vec(&json)
+                    .map_err(|err| PathfinderError::DecodingError(format!("{}", err)))?;
+                Ok(Message::Binary(data))
+            }
+        }
     }
 
-    /// Transforms an instance of the `tungstenite::Message` type into JSON object.
+    /// Transforms an instance of the `tungstenite::Message` type into JSON
+    /// object. For the MessagePack and CBOR content types binary messages are
+    /// decoded from the corresponding format.
     pub fn deserialize(&self, message: &Message) -> Result<JsonMessage> {
-        let text_message = self.parse_into_text(message)?;
-        let mut json_message = self.parse_into_json(text_message.as_str())?;
-        json_message = self.validate_json(json_message)?;
-        Ok(json_message)
+        self.validate_size(message)?;
+        let json_message = self.decode(message)?;
+        self.validate_json(json_message)
+    }
+
+    /// Splits the batch of requests (a top-level array) into separate
+    /// messages of the same format, so that each request can be processed
+    /// independently. Returns `None` for a single request.
+    pub fn split_batch(&self, message: &Message) -> Result<Option<Vec<Message>>> {
+        if !self.is_batch(message) {
+            return Ok(None);
+        }
+
+        self.validate_size(message)?;
+        let json_message = self.decode(message)?;
+        if json_message.is_empty() {
+            let error_message = String::from("The batch of requests is empty");
+            return Err(PathfinderError::DecodingError(error_message));
+        }
+
+        json_message
+            .members()
+            .map(|request| self.serialize(request.dump()))
+            .collect::<Result<Vec<Message>>>()
+            .map(Some)
+    }
+
+    /// Checks that the message doesn't exceed the maximum size.
+    pub fn validate_size(&self, message: &Message) -> Result<()> {
+        match self.max_message_size {
+            Some(max_size) if message.len() > max_size => {
+                let error_message = format!("The message size exceeds the limit of {} bytes", max_size);
+                Err(PathfinderError::DecodingError(error_message))
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Decodes the message into JSON according to the content type.
+    fn decode(&self, message: &Message) -> Result<JsonMessage> {
+        match (self.content_type, message) {
+            (ContentType::MessagePack, Message::Binary(data)) => self.parse_message_pack(data),
+            (ContentType::Cbor, Message::Binary(data)) => self.parse_cbor(data),
+            _ => {
+                let text_message = self.parse_into_text(message)?;
+                self.parse_into_json(text_message.as_str())
+            }
+        }
+    }
+
+    /// Checks whether the message contains an array by its first byte,
+    /// without decoding the whole message.
+    fn is_batch(&self, message: &Message) -> bool {
+        match (self.content_type, message) {
+            (ContentType::MessagePack, Message::Binary(data)) => {
+                data.first().is_some_and(|byte| (0x90..=0x9f).contains(byte) || *byte == 0xdc || *byte == 0xdd)
+            },
+            (ContentType::Cbor, Message::Binary(data)) => data.first().is_some_and(|byte| byte >> 5 == 4),
+            (_, Message::Text(text)) => text.trim_start().starts_with('['),
+            (_, Message::Binary(data)) => data.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'['),
+            _ => false
+        }
+    }
+
+    /// Decodes MessagePack data and converts it into JSON object.
+    fn parse_message_pack(&self, data: &[u8]) -> Result<JsonMessage> {
+        let mut reader = data;
+        let value = read_value(&mut reader)
+            .map_err(|err| PathfinderError::DecodingError(format!("{}", err)))?;
+        let json = message_pack_to_json(value)?;
+        Ok(Arc::new(Box::new(json)))
+    }
+
+    /// Decodes CBOR data and converts it into JSON object.
+    fn parse_cbor(&self, data: &[u8]) -> Result<JsonMessage> {
+        let value: SerdeJsonValue = serde_cbor::from_slice(data)
+            .map_err(|err| PathfinderError::DecodingError(format!("{}", err)))?;
+        self.parse_into_json(&value.to_string())
     }
 
     /// Parses an instance of the `tungstenite::Message` type and returns a UTF-8
@@ -90,13 +280,31 @@ impl Serializer {
         }
     }
 
-    /// Validates a JSON object on required fields and values.
+    /// Validates a JSON object on required fields and values, according to
+    /// the version of the protocol used by the message.
     fn validate_json(&self, json: JsonMessage) -> Result<JsonMessage> {
+        match ProtocolVersion::from_message(&json)? {
+            ProtocolVersion::V1 => self.validate_json_v1(json)
+        }
+    }
+
+    /// Validates a message of the first version of the protocol. Each message
+    /// must contain the URL, except the one that authenticates the connection.
+    fn validate_json_v1(&self, json: JsonMessage) -> Result<JsonMessage> {
+        if Session::is_authentication_message(&json) {
+            return Ok(json);
+        }
+
         if json["url"].is_null() {
             let error_message = String::from("The `url` field is missing or value is `null`");
             return Err(PathfinderError::DecodingError(error_message));
         }
 
+        if !json["url"].is_string() {
+            let error_message = String::from("The `url` field must be a string");
+            return Err(PathfinderError::DecodingError(error_message));
+        }
+
         if json.has_key("microservice") {
             let error_message = String::from("The `microservice` field must not be specified");
             return Err(PathfinderError::DecodingError(error_message));
@@ -106,12 +314,150 @@ impl Serializer {
     }
 }
 
+/// Converts a MessagePack value into JSON.
+fn message_pack_to_json(value: MessagePackValue) -> Result<JsonValue> {
+    let json = match value {
+        MessagePackValue::Nil => JsonValue::Null,
+        MessagePackValue::Boolean(value) => JsonValue::from(value),
+        MessagePackValue::Integer(value) => match value.as_i64() {
+            Some(value) => JsonValue::from(value),
+            None => JsonValue::from(value.as_u64().unwrap_or(0))
+        },
+        MessagePackValue::F32(value) => JsonValue::from(value),
+        MessagePackValue::F64(value) => JsonValue::from(value),
+        MessagePackValue::String(value) => match value.into_str() {
+            Some(value) => JsonValue::from(value),
+            None => {
+                let error_message = String::from("Strings must be encoded in UTF-8");
+                return Err(PathfinderError::DecodingError(error_message));
+            }
+        },
+        MessagePackValue::Array(values) => {
+            let mut array = JsonValue::new_array();
+            for value in values {
+                array.push(message_pack_to_json(value)?).unwrap();
+            }
+            array
+        },
+        MessagePackValue::Map(pairs) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in pairs {
+                let key = match key.as_str() {
+                    Some(key) => key.to_string(),
+                    None => {
+                        let error_message = String::from("Keys of maps must be strings");
+                        return Err(PathfinderError::DecodingError(error_message));
+                    }
+                };
+                object[key] = message_pack_to_json(value)?;
+            }
+            object
+        },
+        MessagePackValue::Binary(_) | MessagePackValue::Ext(_, _) => {
+            let error_message = String::from("Binary and extension types are not supported");
+            return Err(PathfinderError::DecodingError(error_message));
+        }
+    };
+    Ok(json)
+}
+
+/// Converts JSON into a MessagePack value.
+fn json_to_message_pack(json: &JsonValue) -> MessagePackValue {
+    match json {
+        JsonValue::Null => MessagePackValue::Nil,
+        JsonValue::Boolean(value) => MessagePackValue::from(*value),
+        JsonValue::Short(_) | JsonValue::String(_) => MessagePackValue::from(json.as_str().unwrap()),
+        JsonValue::Number(number) => match number.as_parts() {
+            (true, mantissa, 0) => MessagePackValue::from(mantissa),
+            (false, mantissa, 0) if mantissa <= i64::MAX as u64 => MessagePackValue::from(-(mantissa as i64)),
+            _ => MessagePackValue::from(json.as_f64().unwrap())
+        },
+        JsonValue::Array(values) => {
+            MessagePackValue::Array(values.iter().map(json_to_message_pack).collect())
+        },
+        JsonValue::Object(object) => {
+            let pairs = object
+                .iter()
+                .map(|(key, value)| (MessagePackValue::from(key), json_to_message_pack(value)))
+                .collect();
+            MessagePackValue::Map(pairs)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use json::{Null, object};
+    use json::{array, Null, object};
     use tungstenite::Message;
 
-    use crate::engine::serializer::Serializer;
+    use crate::engine::serializer::{ContentType, ProtocolVersion, Serializer};
+
+    #[test]
+    fn test_content_type_from_subprotocol() {
+        assert_eq!(ContentType::from_subprotocol("json"), Some(ContentType::Json));
+        assert_eq!(ContentType::from_subprotocol("msgpack"), Some(ContentType::MessagePack));
+        assert_eq!(ContentType::from_subprotocol("cbor"), Some(ContentType::Cbor));
+        assert_eq!(ContentType::from_subprotocol("v2"), None);
+    }
+
+    #[test]
+    fn test_split_batch_returns_each_request_of_the_batch() {
+        let batch = array![
+            object!{"url" => "/api/matchmaking/search", "correlation-id" => "1"},
+            object!{"correlation-id" => "2"},
+            "request",
+            object!{"url" => "/api/matchmaking/leaderboard", "correlation-id" => "3"}
+        ];
+        for content_type in [ContentType::Json, ContentType::MessagePack, ContentType::Cbor] {
+            let instance = Serializer::with_content_type(content_type);
+            let message = instance.serialize(batch.dump()).unwrap();
+            let messages = instance.split_batch(&message).unwrap().unwrap();
+            assert_eq!(messages.len(), 4);
+
+            let results: Vec<_> = messages.iter().map(|message| instance.deserialize(message)).collect();
+            assert_eq!(results[0].as_ref().unwrap()["correlation-id"], "1");
+            assert!(results[1].is_err());
+            assert!(results[2].is_err());
+            assert_eq!(results[3].as_ref().unwrap()["correlation-id"], "3");
+        }
+    }
+
+    #[test]
+    fn test_split_batch_keeps_a_single_request() {
+        let instance = Serializer::new();
+        let message = Message::Text(String::from(r#"{"url": "/api/matchmaking/search"}"#));
+        assert!(instance.split_batch(&message).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_split_batch_rejects_an_empty_batch() {
+        let instance = Serializer::new();
+        assert!(instance.split_batch(&Message::Text(String::from(" []"))).is_err());
+        assert!(instance.split_batch(&Message::Text(String::from("[{"))).is_err());
+    }
+
+    #[test]
+    fn test_serializer_round_trip_for_each_format() {
+        let dictionary = object!{
+            "url" => "test",
+            "content" => object!{"number" => -5, "float" => 1.5, "list" => array![true, Null, "value"]}
+        };
+        for content_type in [ContentType::Json, ContentType::MessagePack, ContentType::Cbor] {
+            let instance = Serializer::with_content_type(content_type);
+            let message = instance.serialize(dictionary.dump()).unwrap();
+            assert_eq!(message.is_binary(), content_type != ContentType::Json);
+            assert_eq!(**instance.deserialize(&message).unwrap(), dictionary);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_returns_decoding_error_for_invalid_cbor() {
+        let message = Message::Binary(vec![0xff]);
+        let instance = Serializer::with_content_type(ContentType::Cbor);
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_err());
+    }
 
     #[test]
     fn test_serialize_returns_a_new_message_instance() {
@@ -179,6 +525,42 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_deserialize_accepts_messages_without_the_version_as_v1() {
+        let message = Message::Text(object!{"url" => "/api/test"}.dump());
+        let result = Serializer::new().deserialize(&message).unwrap();
+        assert_eq!(ProtocolVersion::from_message(&result).unwrap(), ProtocolVersion::V1);
+    }
+
+    #[test]
+    fn test_deserialize_accepts_messages_of_the_known_version() {
+        let message = Message::Text(object!{"url" => "/api/test", "version" => 1}.dump());
+        let result = Serializer::new().deserialize(&message).unwrap();
+        assert_eq!(ProtocolVersion::from_message(&result).unwrap(), ProtocolVersion::V1);
+    }
+
+    #[test]
+    fn test_deserialize_returns_decoding_error_for_unknown_versions() {
+        let message = Message::Text(object!{"url" => "/api/test", "version" => 2}.dump());
+        assert_eq!(
+            format!("{}", Serializer::new().deserialize(&message).unwrap_err()),
+            "Decoding error: The protocol version 2 isn't supported. Supported versions: 1"
+        );
+
+        let message = Message::Text(object!{"url" => "/api/test", "version" => "v1"}.dump());
+        assert_eq!(
+            format!("{}", Serializer::new().deserialize(&message).unwrap_err()),
+            "Decoding error: The `version` field must be a positive integer"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_accepts_authentication_messages_without_url() {
+        let message = Message::Text(object!{"token" => "secret"}.dump());
+        let result = Serializer::new().deserialize(&message).unwrap();
+        assert_eq!(result["token"], "secret");
+    }
+
     #[test]
     fn test_deserialize_returns_validation_error_for_invalid_url_value_in_json() {
         let dictionary = object!{"url" => Null};
@@ -193,6 +575,20 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_deserialize_returns_validation_error_for_non_string_url_value_in_json() {
+        let dictionary = object!{"url" => 123};
+        let message = Message::Text(dictionary.dump());
+        let instance = Serializer::new();
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_err());
+        assert_eq!(
+            format!("{}", result.unwrap_err()),
+            "Decoding error: The `url` field must be a string"
+        )
+    }
+
     #[test]
     fn test_deserialize_returns_validation_error_for_the_specified_matchmaking_key_in_json() {
         let dictionary = object!{"url" => "value", "microservice" => "some microservice"};
@@ -206,4 +602,72 @@ mod tests {
             "Decoding error: The `microservice` field must not be specified"
         )
     }
+
+    #[test]
+    fn test_serialize_returns_a_binary_message_for_message_pack() {
+        let instance = Serializer::with_content_type(ContentType::MessagePack);
+        let dictionary = object!{"test" => "value"};
+        let result = instance.serialize(dictionary.dump());
+
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_binary());
+    }
+
+    #[test]
+    fn test_deserialize_returns_json_object_for_message_pack() {
+        let dictionary = object!{
+            "url" => "test",
+            "content" => object!{"number" => -5, "float" => 1.5, "list" => array![true, Null, "value"]}
+        };
+        let instance = Serializer::with_content_type(ContentType::MessagePack);
+        let message = instance.serialize(dictionary.dump()).unwrap();
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_ok());
+        assert_eq!(**result.unwrap(), dictionary);
+    }
+
+    #[test]
+    fn test_deserialize_returns_decoding_error_for_invalid_message_pack() {
+        let message = Message::Binary(vec![0xc1]);
+        let instance = Serializer::with_content_type(ContentType::MessagePack);
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_parses_text_messages_for_message_pack() {
+        let dictionary = object!{"url" => "test"};
+        let message = Message::Text(dictionary.dump());
+        let instance = Serializer::with_content_type(ContentType::MessagePack);
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap()["url"], dictionary["url"]);
+    }
+
+    #[test]
+    fn test_deserialize_returns_decoding_error_for_oversized_message() {
+        let dictionary = object!{"url" => "test", "content" => "x".repeat(100)};
+        let message = Message::Text(dictionary.dump());
+        let instance = Serializer::new().with_max_message_size(64);
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_err());
+        assert_eq!(
+            format!("{}", result.unwrap_err()),
+            "Decoding error: The message size exceeds the limit of 64 bytes"
+        )
+    }
+
+    #[test]
+    fn test_deserialize_accepts_message_within_the_size_limit() {
+        let dictionary = object!{"url" => "test"};
+        let message = Message::Text(dictionary.dump());
+        let instance = Serializer::new().with_max_message_size(message.len());
+        let result = instance.deserialize(&message);
+
+        assert!(result.is_ok());
+    }
 }
@@ -0,0 +1,173 @@
+//! Connection-level authentication
+//!
+//! When the mode is enabled, a client authenticates once right after the
+//! WebSocket handshake by sending a message with the `token` field only.
+//! The token is verified by the JWT middleware and the returned user headers
+//! are kept for the connection, so that the following requests to endpoints
+//! with the `jwt` and `jwt_optional` middlewares aren't verified again until
+//! the re-authentication interval elapses.
+//!
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use json::JsonValue;
+
+use crate::engine::middleware::CustomUserHeaders;
+use crate::error::{PathfinderError, Result};
+
+/// The stage of the connection authentication.
+#[derive(Debug)]
+enum SessionState {
+    /// The client hasn't sent the token yet.
+    Anonymous,
+    /// The first token is being verified.
+    Authenticating,
+    /// The token was verified at the specified time.
+    Authenticated(Instant, CustomUserHeaders),
+    /// The re-authentication interval elapsed or the next token was invalid.
+    Expired
+}
+
+/// The authentication state of one WebSocket connection.
+#[derive(Debug)]
+pub struct Session {
+    reauth_interval: Option<Duration>,
+    state: Mutex<SessionState>
+}
+
+impl Session {
+    /// Returns a new instance of `Session`. The zero interval means that
+    /// the client is authenticated until the connection is closed.
+    pub fn new(reauth_interval: Duration) -> Session {
+        Session {
+            reauth_interval: Some(reauth_interval).filter(|interval| *interval > Duration::from_secs(0)),
+            state: Mutex::new(SessionState::Anonymous)
+        }
+    }
+
+    /// Checks whether the message authenticates the connection, i.e. it
+    /// contains the `token` field and doesn't have the `url` one.
+    pub fn is_authentication_message(json: &JsonValue) -> bool {
+        json["token"].is_string() && json["url"].is_null()
+    }
+
+    /// Checks that the client hasn't sent any tokens yet. Only the
+    /// authentication message is allowed in this state.
+    pub fn is_anonymous(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), SessionState::Anonymous)
+    }
+
+    /// Marks the beginning of the token verification. An already
+    /// authenticated connection keeps its headers until the verification
+    /// of the new token is finished.
+    pub fn start_authentication(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let SessionState::Anonymous = *state {
+            *state = SessionState::Authenticating;
+        }
+    }
+
+    /// Stores the result of the token verification. A failed verification
+    /// drops the previously stored headers as well.
+    pub fn finish_authentication(&self, result: &Result<CustomUserHeaders>) {
+        let mut state = self.state.lock().unwrap();
+        *state = match result {
+            Ok(headers) => SessionState::Authenticated(Instant::now(), headers.clone()),
+            Err(_) => SessionState::Expired
+        };
+    }
+
+    /// Returns the user headers of the authenticated connection.
+    pub fn get_headers(&self) -> Result<CustomUserHeaders> {
+        let mut state = self.state.lock().unwrap();
+        let is_expired = match *state {
+            SessionState::Authenticated(authenticated_at, _) => {
+                self.reauth_interval.is_some_and(|interval| authenticated_at.elapsed() >= interval)
+            },
+            _ => false
+        };
+        if is_expired {
+            *state = SessionState::Expired;
+        }
+
+        match *state {
+            SessionState::Authenticated(_, ref headers) => Ok(headers.clone()),
+            SessionState::Authenticating => {
+                let message = String::from("The authentication of the connection isn't finished yet.");
+                Err(PathfinderError::AuthenticationError(message))
+            },
+            SessionState::Expired => {
+                let message = String::from("The session has expired. Send the message with the `token` field again.");
+                Err(PathfinderError::AuthenticationError(message))
+            },
+            SessionState::Anonymous => {
+                let message = String::from("The first message must contain only the `token` field.");
+                Err(PathfinderError::AuthenticationError(message))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use json::object;
+
+    use crate::engine::session::Session;
+    use crate::error::PathfinderError;
+
+    #[test]
+    fn test_is_authentication_message() {
+        assert!(Session::is_authentication_message(&object!{"token" => "secret"}));
+        assert!(!Session::is_authentication_message(&object!{"token" => "secret", "url" => "/api/search"}));
+        assert!(!Session::is_authentication_message(&object!{"url" => "/api/search"}));
+    }
+
+    #[test]
+    fn test_get_headers_returns_headers_of_authenticated_connection() {
+        let session = Session::new(Duration::from_secs(0));
+        assert!(session.is_anonymous());
+        assert!(session.get_headers().is_err());
+
+        session.start_authentication();
+        assert!(!session.is_anonymous());
+        assert!(session.get_headers().is_err());
+
+        let headers: HashMap<String, String> = vec![(String::from("permissions"), String::from("read"))].into_iter().collect();
+        session.finish_authentication(&Ok(headers.clone()));
+        assert_eq!(session.get_headers().unwrap(), headers);
+    }
+
+    #[test]
+    fn test_reauthentication_keeps_headers_until_finished() {
+        let session = Session::new(Duration::from_secs(0));
+        let headers: HashMap<String, String> = vec![(String::from("permissions"), String::from("read"))].into_iter().collect();
+        session.finish_authentication(&Ok(headers.clone()));
+
+        session.start_authentication();
+        assert_eq!(session.get_headers().unwrap(), headers);
+
+        session.finish_authentication(&Err(PathfinderError::AuthenticationError(String::from("Token is expired"))));
+        match session.get_headers() {
+            Err(PathfinderError::AuthenticationError(message)) => assert!(message.starts_with("The session has expired")),
+            _ => panic!("Expected an authentication error")
+        }
+    }
+
+    #[test]
+    fn test_get_headers_fails_after_reauth_interval() {
+        let session = Session::new(Duration::from_millis(1));
+        session.finish_authentication(&Ok(HashMap::new()));
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(session.get_headers().is_err());
+        assert!(!session.is_anonymous());
+
+        let headers: HashMap<String, String> = vec![(String::from("permissions"), String::from("write"))].into_iter().collect();
+        session.finish_authentication(&Ok(headers.clone()));
+        assert_eq!(session.get_headers().unwrap(), headers);
+    }
+}
@@ -0,0 +1,177 @@
+//! Transformations of request and response payloads
+//!
+//! Transformers are registered by their names in the same way as middlewares
+//! and are applied to the requests of the endpoints that list them in the
+//! `transformers` field of the configuration. They allow rewriting the content
+//! of the client message (e.g. injecting a tenant ID) before it's sent to the
+//! microservice, and reshaping the response before it's returned to the client.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use json::JsonValue;
+
+use crate::engine::middleware::CustomUserHeaders;
+use crate::error::{PathfinderError, Result};
+
+/// Type alias for the registered transformers by their names.
+pub type Transformers = HashMap<String, Arc<Box<dyn Transformer>>>;
+
+/// A trait for types that modify payloads of requests to microservices and
+/// their responses. By default the payloads are passed as is.
+pub trait Transformer: Send + Sync {
+    /// Returns the content of the request that will be sent to the
+    /// microservice. The headers contain the data passed by middlewares.
+    fn transform_request(&self, content: JsonValue, _headers: &CustomUserHeaders) -> Result<JsonValue> {
+        Ok(content)
+    }
+
+    /// Returns the response of the microservice that will be sent to the client.
+    fn transform_response(&self, response: JsonValue) -> Result<JsonValue> {
+        Ok(response)
+    }
+}
+
+/// A transformer that doesn't modify any payloads.
+pub struct EmptyTransformer;
+
+impl EmptyTransformer {
+    pub fn new() -> EmptyTransformer {
+        EmptyTransformer {}
+    }
+}
+
+impl Default for EmptyTransformer {
+    fn default() -> EmptyTransformer {
+        EmptyTransformer::new()
+    }
+}
+
+impl Transformer for EmptyTransformer {}
+
+/// An ordered list of transformers of the endpoint. Each transformer gets
+/// the payload returned by the previous one.
+#[derive(Clone, Default)]
+pub struct TransformerChain {
+    transformers: Vec<(String, Arc<Box<dyn Transformer>>)>
+}
+
+impl TransformerChain {
+    /// Returns a chain of the registered transformers with the specified
+    /// names. Fails when any of them isn't registered.
+    pub fn new(names: &[String], registered: &Transformers) -> Result<TransformerChain> {
+        let mut transformers = Vec::new();
+        for name in names {
+            match registered.get(name) {
+                Some(transformer) => transformers.push((name.clone(), transformer.clone())),
+                None => {
+                    let mut available: Vec<&String> = registered.keys().collect();
+                    available.sort();
+                    let message = format!("transformer \"{}\" isn't registered. Available: {:?}", name, available);
+                    return Err(PathfinderError::InvalidEndpoint(message));
+                }
+            }
+        }
+        Ok(TransformerChain { transformers })
+    }
+
+    /// Applies the transformers to the content of the request in order.
+    pub fn transform_request(&self, content: JsonValue, headers: &CustomUserHeaders) -> Result<JsonValue> {
+        self.transformers
+            .iter()
+            .try_fold(content, |content, (_, transformer)| transformer.transform_request(content, headers))
+    }
+
+    /// Applies the transformers to the response of the microservice in order.
+    pub fn transform_response(&self, response: JsonValue) -> Result<JsonValue> {
+        self.transformers
+            .iter()
+            .try_fold(response, |response, (_, transformer)| transformer.transform_response(response))
+    }
+
+    /// Checks that the chain doesn't contain any transformers.
+    pub fn is_empty(&self) -> bool {
+        self.transformers.is_empty()
+    }
+}
+
+impl fmt::Debug for TransformerChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&String> = self.transformers.iter().map(|(name, _)| name).collect();
+        f.debug_struct("TransformerChain").field("transformers", &names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use json::{object, JsonValue};
+
+    use crate::engine::middleware::CustomUserHeaders;
+    use crate::engine::transformer::{EmptyTransformer, Transformer, TransformerChain, Transformers};
+    use crate::error::Result;
+
+    struct TenantTransformer;
+
+    impl Transformer for TenantTransformer {
+        fn transform_request(&self, mut content: JsonValue, headers: &CustomUserHeaders) -> Result<JsonValue> {
+            content["tenant_id"] = headers.get("tenant_id").cloned().unwrap_or_default().into();
+            Ok(content)
+        }
+    }
+
+    struct EnvelopeTransformer;
+
+    impl Transformer for EnvelopeTransformer {
+        fn transform_response(&self, response: JsonValue) -> Result<JsonValue> {
+            Ok(object!{"content" => object!{"data" => response["content"].clone()}})
+        }
+    }
+
+    fn get_transformers() -> Transformers {
+        let mut transformers: Transformers = HashMap::new();
+        transformers.insert(String::from("empty"), Arc::new(Box::new(EmptyTransformer::new())));
+        transformers.insert(String::from("tenant"), Arc::new(Box::new(TenantTransformer)));
+        transformers.insert(String::from("envelope"), Arc::new(Box::new(EnvelopeTransformer)));
+        transformers
+    }
+
+    fn get_names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_empty_chain_keeps_payloads() {
+        let chain = TransformerChain::default();
+        let content = object!{"query" => "ranked"};
+        assert!(chain.is_empty());
+        assert_eq!(chain.transform_request(content.clone(), &HashMap::new()).unwrap(), content);
+        assert_eq!(chain.transform_response(content.clone()).unwrap(), content);
+    }
+
+    #[test]
+    fn test_chain_applies_transformers_in_order() {
+        let chain = TransformerChain::new(&get_names(&["empty", "tenant", "envelope"]), &get_transformers()).unwrap();
+        let mut headers = HashMap::new();
+        headers.insert(String::from("tenant_id"), String::from("eu-west"));
+
+        let content = chain.transform_request(object!{"query" => "ranked"}, &headers).unwrap();
+        assert_eq!(content, object!{"query" => "ranked", "tenant_id" => "eu-west"});
+
+        let response = chain.transform_response(object!{"content" => "found"}).unwrap();
+        assert_eq!(response, object!{"content" => object!{"data" => "found"}});
+    }
+
+    #[test]
+    fn test_chain_with_unknown_transformer_is_invalid() {
+        let error = TransformerChain::new(&get_names(&["tenant", "unknown"]), &get_transformers()).unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Parse error: transformer \"unknown\" isn't registered. Available: [\"empty\", \"envelope\", \"tenant\"]"
+        );
+    }
+}
@@ -1,11 +1,25 @@
 /// Utility module for handling data in Open Matchmaking project.
 ///
+use std::sync::Arc;
+
+use tungstenite::protocol::frame::coding::CloseCode;
 use tungstenite::protocol::Message;
 
-use json::object;
+use config::{Config, ConfigError};
+use json::{object, parse as parse_json, JsonValue};
+use strum::AsStaticRef;
+use uuid::Uuid;
+
+use crate::error::{PathfinderError, Result};
+use crate::engine::serializer::{ContentType, JsonMessage, Serializer};
 
-use crate::error::Result;
-use crate::engine::serializer::{JsonMessage, Serializer};
+/// The status of microservice errors that don't specify it explicitly.
+pub const DEFAULT_MICROSERVICE_ERROR_STATUS: u16 = 500;
+/// The field of the message with the event name, which is passed to
+/// microservices in the `event_name` header.
+pub const EVENT_NAME_FIELD: &str = "event-name";
+/// The placeholder of the correlation ID in the template of errors.
+const CORRELATION_ID_PLACEHOLDER: &str = "{correlation_id}";
 
 /// Transforms an error (which is a string) into JSON object in the special format.
 pub fn wrap_a_string_error(error_type: &str, err: &str) -> Message {
@@ -14,6 +28,165 @@ pub fn wrap_a_string_error(error_type: &str, err: &str) -> Message {
     serializer.serialize(json_error_message.dump()).unwrap()
 }
 
+/// Transforms an error into JSON object with the machine-readable code, the
+/// description and the correlation ID of the request (if it's known).
+pub fn wrap_an_error(error: &PathfinderError, correlation_id: Option<&str>) -> Message {
+    let serializer = Serializer::new();
+    serializer.serialize(get_error_json(error, correlation_id).dump()).unwrap()
+}
+
+/// Transforms a fatal error into JSON object in the same way as `wrap_an_error`
+/// does, but also specifies the code, with which the connection will be closed.
+pub fn wrap_a_fatal_error(error: &PathfinderError, correlation_id: Option<&str>, close_code: &CloseCode) -> Message {
+    let mut json_error_message = get_error_json(error, correlation_id);
+    let close_code: u16 = close_code.into();
+    json_error_message["close_code"] = close_code.into();
+
+    let serializer = Serializer::new();
+    serializer.serialize(json_error_message.dump()).unwrap()
+}
+
+fn get_error_json(error: &PathfinderError, correlation_id: Option<&str>) -> JsonValue {
+    let error_message = format!("{}", error);
+    let mut json_error_message = object!(
+        "type" => error.as_static(),
+        "details" => error_message.as_str(),
+        "code" => error.code(),
+        "message" => error_message.as_str()
+    );
+    if let Some(correlation_id) = correlation_id {
+        json_error_message["correlation_id"] = correlation_id.into();
+    }
+    if let PathfinderError::RateLimited(retry_after) = error {
+        json_error_message["retry_after_ms"] = (retry_after.as_millis() as u64).into();
+    }
+    json_error_message
+}
+
+/// The shape of errors of the proxy, that is sent to clients. By default
+/// errors are returned by the `wrap_an_error` function, otherwise the JSON
+/// template from the `error_template` key of the configuration file is used.
+/// String values of the template can contain the `{type}`, `{code}`,
+/// `{message}` and `{correlation_id}` placeholders. The value that consists
+/// only of the `{correlation_id}` placeholder is `null` for errors without
+/// the correlation ID.
+///
+/// # Examples
+///
+/// ```yaml
+/// error_template: |
+///   {"error": {"code": "{code}", "message": "{message}"}, "request_id": "{correlation_id}"}
+/// ```
+///
+#[derive(Clone, Debug, Default)]
+pub struct ErrorTemplate {
+    source: Option<String>,
+    template: Option<JsonValue>
+}
+
+impl ErrorTemplate {
+    /// Returns the template of errors specified in the configuration file.
+    pub fn from_config(conf: &Config) -> ErrorTemplate {
+        let source = conf.get_str("error_template").ok().filter(|source| !source.trim().is_empty());
+        let template = source
+            .as_ref()
+            .and_then(|source| parse_json(source).ok())
+            .filter(|template| template.is_object());
+        ErrorTemplate { source, template }
+    }
+
+    /// Checks that the specified template is a JSON object.
+    pub fn validate(&self) -> Result<()> {
+        match (&self.source, &self.template) {
+            (Some(source), None) => {
+                let message = format!("The error template \"{}\" is invalid: a JSON object is expected.", source.trim());
+                Err(PathfinderError::SettingsError(ConfigError::Message(message)))
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Transforms an error into JSON object in the same way as the `wrap_an_error`
+    /// function does, unless the template was specified.
+    pub fn wrap_an_error(&self, error: &PathfinderError, correlation_id: Option<&str>) -> Message {
+        let serializer = Serializer::new();
+        serializer.serialize(self.render(error, correlation_id).dump()).unwrap()
+    }
+
+    /// Transforms a fatal error into JSON object in the same way as the
+    /// `wrap_a_fatal_error` function does, unless the template was specified.
+    /// The close code is added into the rendered template.
+    pub fn wrap_a_fatal_error(&self, error: &PathfinderError, correlation_id: Option<&str>, close_code: &CloseCode) -> Message {
+        let mut json_error_message = self.render(error, correlation_id);
+        let close_code: u16 = close_code.into();
+        json_error_message["close_code"] = close_code.into();
+
+        let serializer = Serializer::new();
+        serializer.serialize(json_error_message.dump()).unwrap()
+    }
+
+    fn render(&self, error: &PathfinderError, correlation_id: Option<&str>) -> JsonValue {
+        match self.template {
+            Some(ref template) => render_error_template(template, error, correlation_id),
+            None => get_error_json(error, correlation_id)
+        }
+    }
+}
+
+/// Substitutes the placeholders in string values of the template.
+fn render_error_template(template: &JsonValue, error: &PathfinderError, correlation_id: Option<&str>) -> JsonValue {
+    match template {
+        JsonValue::Short(_) | JsonValue::String(_) => {
+            let value = template.as_str().unwrap();
+            if value == CORRELATION_ID_PLACEHOLDER && correlation_id.is_none() {
+                return JsonValue::Null;
+            }
+
+            value
+                .replace("{type}", error.as_static())
+                .replace("{code}", error.code())
+                .replace("{message}", &format!("{}", error))
+                .replace(CORRELATION_ID_PLACEHOLDER, correlation_id.unwrap_or(""))
+                .into()
+        },
+        JsonValue::Array(values) => {
+            JsonValue::Array(values.iter().map(|value| render_error_template(value, error, correlation_id)).collect())
+        },
+        JsonValue::Object(object) => {
+            let mut rendered = JsonValue::new_object();
+            for (key, value) in object.iter() {
+                rendered[key] = render_error_template(value, error, correlation_id);
+            }
+            rendered
+        },
+        _ => template.clone()
+    }
+}
+
+/// Transforms an error returned by a microservice into JSON object. The fields
+/// of the error are kept as is, but the HTTP-style `status` and the machine-readable
+/// `code` are always specified, so that clients could handle it in the same way.
+pub fn wrap_a_microservice_error(error: &JsonValue, correlation_id: Option<&str>) -> Message {
+    let mut json_error_message = match error.is_object() {
+        true => error.clone(),
+        false => object!("details" => error.clone())
+    };
+
+    let status = error["status"].as_u16()
+        .or_else(|| error["code"].as_u16())
+        .unwrap_or(DEFAULT_MICROSERVICE_ERROR_STATUS);
+    json_error_message["status"] = status.into();
+    if !error["code"].is_string() {
+        json_error_message["code"] = PathfinderError::MicroserviceError(JsonValue::Null).code().into();
+    }
+    if let Some(correlation_id) = correlation_id {
+        json_error_message["correlation_id"] = correlation_id.into();
+    }
+
+    let serializer = Serializer::new();
+    serializer.serialize(json_error_message.dump()).unwrap()
+}
+
 /// Serialize a JSON object into message.
 pub fn serialize_message(json: JsonMessage) -> Message {
     let serializer = Serializer::new();
@@ -26,14 +199,76 @@ pub fn deserialize_message(message: &Message) -> Result<JsonMessage> {
     serializer.deserialize(message)
 }
 
+/// Converts a text message with JSON into the specified format.
+pub fn convert_message(message: Message, content_type: ContentType) -> Message {
+    match (content_type, message) {
+        (ContentType::MessagePack, Message::Text(text)) | (ContentType::Cbor, Message::Text(text)) => {
+            let serializer = Serializer::with_content_type(content_type);
+            serializer.serialize(text.clone()).unwrap_or(Message::Text(text))
+        },
+        (_, message) => message
+    }
+}
+
+/// Returns the message with the correlation ID of the request. The ID that
+/// was specified by a client in the `correlation-id` field is reused,
+/// otherwise a new one is generated.
+pub fn with_correlation_id(json: JsonMessage) -> JsonMessage {
+    let is_specified = json["correlation-id"].as_str().is_some_and(|value| !value.is_empty());
+    match is_specified {
+        true => json,
+        false => {
+            let mut json_message = json.as_ref().as_ref().clone();
+            json_message["correlation-id"] = format!("{}", Uuid::new_v4()).into();
+            Arc::new(Box::new(json_message))
+        }
+    }
+}
+
+/// Returns the correlation ID of the request.
+pub fn get_correlation_id(json: &JsonMessage) -> String {
+    json["correlation-id"].as_str().unwrap_or("null").to_string()
+}
+
+/// Returns the message with the event name taken from the specified field,
+/// so that clients can keep using their own field instead of `event-name`.
+pub fn with_event_name(json: JsonMessage, field: &str) -> JsonMessage {
+    match field == EVENT_NAME_FIELD {
+        true => json,
+        false => {
+            let mut json_message = json.as_ref().as_ref().clone();
+            json_message[EVENT_NAME_FIELD] = json[field].clone();
+            Arc::new(Box::new(json_message))
+        }
+    }
+}
+
+/// Returns the event name of the request. When it wasn't specified, the
+/// correlation ID of the request is used instead.
+pub fn get_event_name(json: &JsonMessage) -> String {
+    match json[EVENT_NAME_FIELD].as_str() {
+        Some(event_name) if !event_name.is_empty() => event_name.to_string(),
+        _ => get_correlation_id(json)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
+    use std::time::Duration;
 
     use json::{object, parse as json_parse};
+    use tungstenite::protocol::frame::coding::CloseCode;
     use tungstenite::Message;
 
-    use crate::engine::utils::{deserialize_message, serialize_message, wrap_a_string_error};
+    use crate::engine::serializer::ContentType;
+    use crate::config::get_config;
+    use crate::engine::utils::{
+        convert_message, deserialize_message, get_correlation_id, get_event_name, serialize_message,
+        with_correlation_id, with_event_name, wrap_a_fatal_error, wrap_a_microservice_error, wrap_a_string_error,
+        wrap_an_error, ErrorTemplate, EVENT_NAME_FIELD
+    };
+    use crate::error::PathfinderError;
 
     #[test]
     fn test_wrap_an_string_error_returns_json_with_details_field() {
@@ -45,6 +280,142 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_wrap_an_error_returns_json_with_code_and_correlation_id() {
+        let error = PathfinderError::EndpointNotFound(String::from("/api/matchmaking/search"));
+        let result = json_parse(wrap_an_error(&error, Some("request-id")).to_text().unwrap()).unwrap();
+
+        assert_eq!(result["code"], "ENDPOINT_NOT_FOUND");
+        assert_eq!(result["message"], "Endpoint \"/api/matchmaking/search\" was not found");
+        assert_eq!(result["correlation_id"], "request-id");
+        assert_eq!(result["type"], "EndpointNotFound");
+        assert_eq!(result["details"], result["message"]);
+    }
+
+    #[test]
+    fn test_wrap_an_error_returns_json_without_unknown_correlation_id() {
+        let error = PathfinderError::DecodingError(String::from("Unexpected end of JSON"));
+        let result = json_parse(wrap_an_error(&error, None).to_text().unwrap()).unwrap();
+
+        assert_eq!(result["code"], "DECODING_ERROR");
+        assert!(!result.has_key("correlation_id"));
+    }
+
+    #[test]
+    fn test_wrap_an_error_returns_json_with_retry_after_for_rate_limited_requests() {
+        let error = PathfinderError::RateLimited(Duration::from_millis(250));
+        let result = json_parse(wrap_an_error(&error, None).to_text().unwrap()).unwrap();
+
+        assert_eq!(result["code"], "RATE_LIMITED");
+        assert_eq!(result["retry_after_ms"], 250);
+    }
+
+    #[test]
+    fn test_wrap_a_fatal_error_returns_json_with_close_code() {
+        let error = PathfinderError::AuthenticationError(String::from("Token is invalid."));
+        let result = json_parse(wrap_a_fatal_error(&error, Some("request-id"), &CloseCode::Policy).to_text().unwrap()).unwrap();
+
+        assert_eq!(result["code"], "AUTH_FAILED");
+        assert_eq!(result["correlation_id"], "request-id");
+        assert_eq!(result["close_code"], 1008);
+    }
+
+    #[test]
+    fn test_error_template_returns_the_default_shape_without_template() {
+        let template = ErrorTemplate::from_config(&get_config("./tests/files/config_with_valid_endpoints.yaml"));
+        let error = PathfinderError::EndpointNotFound(String::from("/api/matchmaking/search"));
+
+        assert!(template.validate().is_ok());
+        assert_eq!(template.wrap_an_error(&error, Some("request-id")), wrap_an_error(&error, Some("request-id")));
+    }
+
+    #[test]
+    fn test_error_template_renders_nested_fields() {
+        let template = ErrorTemplate::from_config(&get_config("./tests/files/config_with_error_template.yaml"));
+        let error = PathfinderError::EndpointNotFound(String::from("/api/matchmaking/search"));
+        let result = json_parse(template.wrap_an_error(&error, Some("request-id")).to_text().unwrap()).unwrap();
+
+        assert!(template.validate().is_ok());
+        assert_eq!(result, object!{
+            "error" => object!{
+                "code" => "ENDPOINT_NOT_FOUND",
+                "message" => "Endpoint \"/api/matchmaking/search\" was not found",
+                "status" => 400
+            },
+            "request_id" => "request-id",
+            "summary" => "EndpointNotFound: Endpoint \"/api/matchmaking/search\" was not found"
+        });
+
+        let error = PathfinderError::AuthenticationError(String::from("Token is invalid."));
+        let message = template.wrap_a_fatal_error(&error, None, &CloseCode::Policy);
+        let result = json_parse(message.to_text().unwrap()).unwrap();
+        assert_eq!(result["error"]["code"], "AUTH_FAILED");
+        assert!(result["request_id"].is_null());
+        assert_eq!(result["close_code"], 1008);
+    }
+
+    #[test]
+    fn test_error_template_renders_flat_fields() {
+        let mut conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        conf.set("error_template", r#"{"details": "{message}", "error_code": "{code}", "id": "[{correlation_id}]"}"#).unwrap();
+        let template = ErrorTemplate::from_config(&conf);
+        let error = PathfinderError::DecodingError(String::from("Unexpected end of JSON"));
+        let result = json_parse(template.wrap_an_error(&error, None).to_text().unwrap()).unwrap();
+
+        assert_eq!(result, object!{
+            "details" => "Decoding error: Unexpected end of JSON",
+            "error_code" => "DECODING_ERROR",
+            "id" => "[]"
+        });
+    }
+
+    #[test]
+    fn test_error_template_validate_requires_a_json_object() {
+        let mut conf = get_config("./tests/files/config_with_valid_endpoints.yaml");
+        conf.set("error_template", r#"["{message}"]"#).unwrap();
+        let result = ErrorTemplate::from_config(&conf).validate();
+
+        assert!(result.is_err());
+        assert_eq!(
+            format!("{}", result.unwrap_err()),
+            "Settings error: The error template \"[\"{message}\"]\" is invalid: a JSON object is expected."
+        );
+    }
+
+    #[test]
+    fn test_wrap_a_microservice_error_keeps_the_specified_status_and_code() {
+        let error = object!{"type" => "NotFound", "details" => "Player wasn't found", "status" => 404, "code" => "PLAYER_NOT_FOUND"};
+        let result = json_parse(wrap_a_microservice_error(&error, Some("request-id")).to_text().unwrap()).unwrap();
+
+        assert_eq!(result["status"], 404);
+        assert_eq!(result["code"], "PLAYER_NOT_FOUND");
+        assert_eq!(result["type"], "NotFound");
+        assert_eq!(result["details"], "Player wasn't found");
+        assert_eq!(result["correlation_id"], "request-id");
+    }
+
+    #[test]
+    fn test_wrap_a_microservice_error_takes_status_from_numeric_code() {
+        let error = object!{"details" => "Player wasn't found", "code" => 404};
+        let result = json_parse(wrap_a_microservice_error(&error, None).to_text().unwrap()).unwrap();
+
+        assert_eq!(result["status"], 404);
+        assert_eq!(result["code"], "MICROSERVICE_ERROR");
+        assert!(!result.has_key("correlation_id"));
+    }
+
+    #[test]
+    fn test_wrap_a_microservice_error_returns_default_status_and_code() {
+        let error = object!{"type" => "ValidationError", "details" => "Invalid data"};
+        let result = json_parse(wrap_a_microservice_error(&error, None).to_text().unwrap()).unwrap();
+        assert_eq!(result["status"], 500);
+        assert_eq!(result["code"], "MICROSERVICE_ERROR");
+
+        let result = json_parse(wrap_a_microservice_error(&"Invalid data".into(), None).to_text().unwrap()).unwrap();
+        assert_eq!(result["details"], "Invalid data");
+        assert_eq!(result["status"], 500);
+    }
+
     #[test]
     fn test_serialize_message_returns_a_message_struct() {
         let dictionary = object!{"test" => "value"};
@@ -79,4 +450,54 @@ mod tests {
             "Decoding error: Unexpected end of JSON"
         )
     }
+
+    #[test]
+    fn test_convert_message_into_message_pack() {
+        let message = wrap_a_string_error("test", "some error");
+
+        assert!(convert_message(message.clone(), ContentType::MessagePack).is_binary());
+        assert!(convert_message(message.clone(), ContentType::Cbor).is_binary());
+        assert_eq!(convert_message(message.clone(), ContentType::Json), message);
+    }
+
+    #[test]
+    fn test_with_correlation_id_reuses_the_specified_id() {
+        let dictionary = object!{"url" => "test", "correlation-id" => "client-id"};
+        let message = with_correlation_id(Arc::new(Box::new(dictionary)));
+
+        assert_eq!(get_correlation_id(&message), "client-id");
+    }
+
+    #[test]
+    fn test_with_event_name_takes_the_event_name_from_the_specified_field() {
+        let dictionary = object!{"url" => "test", "action" => "search", "event-name" => "ignored"};
+        let message = with_event_name(Arc::new(Box::new(dictionary)), "action");
+        assert_eq!(get_event_name(&message), "search");
+
+        let dictionary = object!{"url" => "test", "event-name" => "search"};
+        let message = with_event_name(Arc::new(Box::new(dictionary)), EVENT_NAME_FIELD);
+        assert_eq!(get_event_name(&message), "search");
+    }
+
+    #[test]
+    fn test_get_event_name_falls_back_to_the_correlation_id() {
+        let message = with_correlation_id(Arc::new(Box::new(object!{"url" => "test"})));
+        let message = with_event_name(message, "action");
+
+        assert_eq!(get_event_name(&message), get_correlation_id(&message));
+        assert_ne!(get_event_name(&message), "null");
+    }
+
+    #[test]
+    fn test_with_correlation_id_generates_a_new_id() {
+        let dictionary = object!{"url" => "test"};
+        let message = with_correlation_id(Arc::new(Box::new(dictionary)));
+        let correlation_id = get_correlation_id(&message);
+
+        assert_eq!(correlation_id.len(), 36);
+        assert_eq!(message["url"], "test");
+
+        let another_message = with_correlation_id(Arc::new(Box::new(object!{"url" => "test"})));
+        assert_ne!(get_correlation_id(&another_message), correlation_id);
+    }
 }
@@ -9,12 +9,15 @@ use std::error;
 use std::fmt;
 use std::io;
 use std::result;
+use std::time::Duration;
 
 use config::ConfigError;
 use failure::{Error as FailureError};
 use json::JsonValue;
 use lapin_futures::error::{Error as LapinError};
+use log::Level;
 use strum_macros::AsStaticStr;
+use tungstenite::protocol::frame::coding::CloseCode;
 
 /// Type alias for `Result` objects that return a Pathfinder error.
 pub type Result<T> = result::Result<T, PathfinderError>;
@@ -45,9 +48,107 @@ pub enum PathfinderError {
     /// The error that occurred with a message broker.
     MessageBrokerError(String),
     /// The error that occurred when returned an error from a microservice.
-    MicroserviceError(JsonValue)
+    MicroserviceError(JsonValue),
+    /// Occurs when the client sends requests too often. Contains the time
+    /// after which the next request will be accepted.
+    RateLimited(Duration),
+    /// Occurs when the client doesn't wait for the responses and sends more
+    /// concurrent requests than allowed for one connection.
+    TooManyRequests(String),
+    /// Occurs when the new connection exceeds the limits of concurrent
+    /// connections, so it's closed right after the handshake.
+    ServerBusy(String)
 }
 
+impl PathfinderError {
+    /// Returns a stable machine-readable code of the error, which is sent to
+    /// clients, so that they can handle errors without parsing the messages.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            PathfinderError::Io(_) => "IO_ERROR",
+            PathfinderError::LapinError(_) => "BROKER_ERROR",
+            PathfinderError::LapinChannelError(_) => "BROKER_ERROR",
+            PathfinderError::SettingsError(_) => "SETTINGS_ERROR",
+            PathfinderError::InvalidEndpoint(_) => "INVALID_ENDPOINT",
+            PathfinderError::EndpointNotFound(_) => "ENDPOINT_NOT_FOUND",
+            PathfinderError::DecodingError(_) => "DECODING_ERROR",
+            PathfinderError::AuthenticationError(_) => "AUTH_FAILED",
+            PathfinderError::MessageBrokerError(_) => "BROKER_ERROR",
+            PathfinderError::MicroserviceError(_) => "MICROSERVICE_ERROR",
+            PathfinderError::RateLimited(_) => "RATE_LIMITED",
+            PathfinderError::TooManyRequests(_) => "TOO_MANY_REQUESTS",
+            PathfinderError::ServerBusy(_) => "SERVER_BUSY",
+        }
+    }
+
+    /// Returns the WebSocket close code for fatal errors, after which the
+    /// client can't keep using the connection (e.g. it sends malformed data or
+    /// isn't authenticated). Other errors are related only to the certain request.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match *self {
+            PathfinderError::DecodingError(_) => Some(CloseCode::Invalid),
+            PathfinderError::AuthenticationError(_) => Some(CloseCode::Policy),
+            PathfinderError::ServerBusy(_) => Some(CloseCode::Again),
+            _ => None
+        }
+    }
+
+    /// Returns the HTTP status for the requests received via the HTTP fallback.
+    pub fn http_status(&self) -> &'static str {
+        match *self {
+            PathfinderError::DecodingError(_) => "400 Bad Request",
+            PathfinderError::AuthenticationError(_) => "401 Unauthorized",
+            PathfinderError::EndpointNotFound(_) => "404 Not Found",
+            PathfinderError::RateLimited(_) => "429 Too Many Requests",
+            PathfinderError::TooManyRequests(_) => "429 Too Many Requests",
+            PathfinderError::MessageBrokerError(_) => "502 Bad Gateway",
+            PathfinderError::MicroserviceError(_) => "502 Bad Gateway",
+            PathfinderError::ServerBusy(_) => "503 Service Unavailable",
+            _ => "500 Internal Server Error"
+        }
+    }
+
+    /// Checks whether the error was caused by the client (e.g. a malformed
+    /// message, an invalid token or a dropped connection) rather than by the
+    /// reverse proxy, the message broker or the configuration.
+    pub fn is_client_error(&self) -> bool {
+        match *self {
+            PathfinderError::DecodingError(_) => true,
+            PathfinderError::AuthenticationError(_) => true,
+            PathfinderError::EndpointNotFound(_) => true,
+            PathfinderError::RateLimited(_) => true,
+            PathfinderError::TooManyRequests(_) => true,
+            PathfinderError::Io(ref error) => CLIENT_IO_ERRORS.contains(&error.kind()),
+            _ => false
+        }
+    }
+
+    /// Returns the level for logging the error. Client errors are expected
+    /// and logged only for debugging, whereas failures of the server side
+    /// are visible at the default verbosity. Errors of microservices are
+    /// logged by the microservices themselves.
+    pub fn log_level(&self) -> Level {
+        match *self {
+            _ if self.is_client_error() => Level::Debug,
+            PathfinderError::MicroserviceError(_) => Level::Debug,
+            PathfinderError::MessageBrokerError(_) => Level::Warn,
+            PathfinderError::ServerBusy(_) => Level::Warn,
+            _ => Level::Error
+        }
+    }
+}
+
+/// The kinds of I/O errors that occur because of clients, e.g. when the
+/// connection is dropped or the WebSocket handshake is malformed.
+const CLIENT_IO_ERRORS: [io::ErrorKind; 6] = [
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::InvalidData,
+];
+
 impl fmt::Display for PathfinderError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -61,6 +162,11 @@ impl fmt::Display for PathfinderError {
             PathfinderError::AuthenticationError(ref msg) => write!(f, "Authentication error: {}", msg),
             PathfinderError::MessageBrokerError(ref msg) => write!(f, "{}", msg),
             PathfinderError::MicroserviceError(ref json) => write!(f, "{:?}", json),
+            PathfinderError::RateLimited(ref retry_after) => {
+                write!(f, "Too many requests. Please, retry after {} ms", retry_after.as_millis())
+            },
+            PathfinderError::TooManyRequests(ref msg) => write!(f, "Too many in-flight requests: {}", msg),
+            PathfinderError::ServerBusy(ref msg) => write!(f, "Server is busy: {}", msg),
         }
     }
 }
@@ -86,3 +192,177 @@ impl From<config::ConfigError> for PathfinderError {
         PathfinderError::SettingsError(err)
     }
 }
+
+/// An error that occurred during processing the request from a client. The
+/// correlation ID is known only when the request was successfully decoded.
+#[derive(Debug)]
+pub struct RequestError {
+    error: PathfinderError,
+    correlation_id: Option<String>,
+    is_closing: bool
+}
+
+impl RequestError {
+    /// Returns a new instance of `RequestError`.
+    pub fn new(error: PathfinderError) -> RequestError {
+        RequestError {
+            error,
+            correlation_id: None,
+            is_closing: false
+        }
+    }
+
+    /// Sets the correlation ID of the failed request.
+    pub fn with_correlation_id(mut self, correlation_id: &str) -> RequestError {
+        self.correlation_id = Some(correlation_id.to_string());
+        self
+    }
+
+    /// Requires closing the connection after sending the error, even when
+    /// the reverse proxy keeps connections open after fatal errors.
+    pub fn with_closing(mut self) -> RequestError {
+        self.is_closing = true;
+        self
+    }
+
+    /// Checks whether the connection must be closed after the error.
+    pub fn is_closing(&self) -> bool {
+        self.is_closing
+    }
+
+    /// Returns the occurred error.
+    pub fn get_error(&self) -> &PathfinderError {
+        &self.error
+    }
+
+    /// Returns the occurred error, consuming the `RequestError`.
+    pub fn into_error(self) -> PathfinderError {
+        self.error
+    }
+
+    /// Returns the correlation ID of the failed request if it's known.
+    pub fn get_correlation_id(&self) -> Option<String> {
+        self.correlation_id.clone()
+    }
+}
+
+impl From<PathfinderError> for RequestError {
+    fn from(err: PathfinderError) -> RequestError {
+        RequestError::new(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use log::Level;
+    use tungstenite::protocol::frame::coding::CloseCode;
+
+    use crate::error::{PathfinderError, RequestError};
+
+    #[test]
+    fn test_code_returns_stable_codes() {
+        let error = PathfinderError::EndpointNotFound(String::from("/api/matchmaking/search"));
+        assert_eq!(error.code(), "ENDPOINT_NOT_FOUND");
+
+        let error = PathfinderError::AuthenticationError(String::from("Token is expired"));
+        assert_eq!(error.code(), "AUTH_FAILED");
+
+        let error = PathfinderError::MessageBrokerError(String::from("Timeout"));
+        assert_eq!(error.code(), "BROKER_ERROR");
+
+        let error = PathfinderError::RateLimited(Duration::from_millis(250));
+        assert_eq!(error.code(), "RATE_LIMITED");
+        assert_eq!(format!("{}", error), "Too many requests. Please, retry after 250 ms");
+
+        let error = PathfinderError::TooManyRequests(String::from("the limit of 8 in-flight requests per connection is exceeded"));
+        assert_eq!(error.code(), "TOO_MANY_REQUESTS");
+        assert_eq!(format!("{}", error), "Too many in-flight requests: the limit of 8 in-flight requests per connection is exceeded");
+
+        let error = PathfinderError::ServerBusy(String::from("the limit of 2 connections is exceeded"));
+        assert_eq!(error.code(), "SERVER_BUSY");
+        assert_eq!(format!("{}", error), "Server is busy: the limit of 2 connections is exceeded");
+    }
+
+    #[test]
+    fn test_http_status_depends_on_the_error() {
+        let error = PathfinderError::DecodingError(String::from("Unexpected end of JSON"));
+        assert_eq!(error.http_status(), "400 Bad Request");
+
+        let error = PathfinderError::AuthenticationError(String::from("Token is expired"));
+        assert_eq!(error.http_status(), "401 Unauthorized");
+
+        let error = PathfinderError::MessageBrokerError(String::from("Timeout"));
+        assert_eq!(error.http_status(), "502 Bad Gateway");
+
+        let error = PathfinderError::InvalidEndpoint(String::from("The \"jwt\" middleware isn't registered."));
+        assert_eq!(error.http_status(), "500 Internal Server Error");
+    }
+
+    #[test]
+    fn test_close_code_is_returned_only_for_fatal_errors() {
+        let error = PathfinderError::DecodingError(String::from("Unexpected end of JSON"));
+        assert_eq!(error.close_code(), Some(CloseCode::Invalid));
+
+        let error = PathfinderError::AuthenticationError(String::from("Token is expired"));
+        assert_eq!(error.close_code(), Some(CloseCode::Policy));
+
+        let error = PathfinderError::ServerBusy(String::from("the limit of 2 connections is exceeded"));
+        assert_eq!(error.close_code(), Some(CloseCode::Again));
+
+        let error = PathfinderError::EndpointNotFound(String::from("/api/matchmaking/search"));
+        assert_eq!(error.close_code(), None);
+
+        let error = PathfinderError::MessageBrokerError(String::from("Timeout"));
+        assert_eq!(error.close_code(), None);
+    }
+
+    #[test]
+    fn test_client_errors_are_logged_at_debug_level() {
+        let errors = vec![
+            PathfinderError::DecodingError(String::from("Unexpected end of JSON")),
+            PathfinderError::AuthenticationError(String::from("Token is expired")),
+            PathfinderError::EndpointNotFound(String::from("/api/matchmaking/unknown")),
+            PathfinderError::RateLimited(Duration::from_millis(100)),
+            PathfinderError::TooManyRequests(String::from("the limit of 8 in-flight requests per connection is exceeded")),
+            PathfinderError::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer")),
+        ];
+        for error in errors {
+            assert!(error.is_client_error(), "{} must be a client error", error);
+            assert_eq!(error.log_level(), Level::Debug);
+        }
+    }
+
+    #[test]
+    fn test_server_errors_are_logged_at_warn_and_error_levels() {
+        let error = PathfinderError::MessageBrokerError(String::from("The request timed out."));
+        assert!(!error.is_client_error());
+        assert_eq!(error.log_level(), Level::Warn);
+
+        let error = PathfinderError::Io(io::Error::new(io::ErrorKind::AddrInUse, "address in use"));
+        assert!(!error.is_client_error());
+        assert_eq!(error.log_level(), Level::Error);
+
+        let error = PathfinderError::InvalidEndpoint(String::from("url is missing"));
+        assert_eq!(error.log_level(), Level::Error);
+    }
+
+    #[test]
+    fn test_request_error_keeps_correlation_id() {
+        let error = RequestError::from(PathfinderError::DecodingError(String::from("error")));
+        assert_eq!(error.get_correlation_id(), None);
+
+        let error = error.with_correlation_id("request-id");
+        assert_eq!(error.get_correlation_id(), Some(String::from("request-id")));
+        assert_eq!(error.get_error().code(), "DECODING_ERROR");
+    }
+
+    #[test]
+    fn test_request_error_requires_closing_only_when_specified() {
+        let error = RequestError::from(PathfinderError::AuthenticationError(String::from("error")));
+        assert!(!error.is_closing());
+        assert!(error.with_closing().is_closing());
+    }
+}
@@ -0,0 +1,75 @@
+//! Hooks for the lifecycle of WebSocket connections
+//!
+//! An application that embeds the proxy can run its own code when a client
+//! connects or disconnects (e.g. emit an audit event or update a counter),
+//! without changing the proxy itself. The hooks are called on the event
+//! loop, so they must not block.
+//!
+
+use std::net::SocketAddr;
+
+use crate::engine::middleware::CustomUserHeaders;
+
+/// Describes the client connection for the hooks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    addr: SocketAddr,
+    subprotocol: Option<String>,
+    user_headers: Option<CustomUserHeaders>
+}
+
+impl ConnectionInfo {
+    /// Returns a new instance of `ConnectionInfo` for the client address.
+    pub fn new(addr: SocketAddr) -> ConnectionInfo {
+        ConnectionInfo {
+            addr,
+            subprotocol: None,
+            user_headers: None
+        }
+    }
+
+    /// Sets the subprotocol that was negotiated during the handshake.
+    pub fn with_subprotocol(mut self, subprotocol: Option<String>) -> ConnectionInfo {
+        self.subprotocol = subprotocol;
+        self
+    }
+
+    /// Sets the user headers of the authenticated connection.
+    pub fn with_user_headers(mut self, user_headers: Option<CustomUserHeaders>) -> ConnectionInfo {
+        self.user_headers = user_headers;
+        self
+    }
+
+    /// Returns the address of the client.
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the negotiated subprotocol, if any.
+    pub fn get_subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    /// Returns the user headers (e.g. `user_id` and `permissions`) when the
+    /// connection was authenticated with the `--connection-auth` option.
+    /// They're known only on disconnect.
+    pub fn get_user_headers(&self) -> Option<&CustomUserHeaders> {
+        self.user_headers.as_ref()
+    }
+}
+
+/// A trait for handlers of the connection lifecycle. Each hook is called
+/// exactly once per accepted WebSocket connection. Connections rejected
+/// before or during the handshake don't trigger any hooks.
+pub trait ConnectionHooks: Send + Sync {
+    /// Called when the connection was accepted and registered.
+    fn on_connect(&self, _info: &ConnectionInfo) {}
+
+    /// Called when the connection was closed and its resources were released.
+    fn on_disconnect(&self, _info: &ConnectionInfo) {}
+}
+
+/// The default hooks that don't do anything.
+pub struct EmptyConnectionHooks;
+
+impl ConnectionHooks for EmptyConnectionHooks {}
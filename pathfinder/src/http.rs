@@ -0,0 +1,586 @@
+//! Handling of plain HTTP requests
+//!
+//! Besides WebSocket connections the proxy answers to a few plain HTTP
+//! requests. Health checks are received on the same port and detected
+//! before the WebSocket handshake, metrics (and optionally the list of the
+//! loaded routes) are exposed on a separate port.
+//! Handshakes from disallowed origins are rejected here as well, and the
+//! WebSocket subprotocol is negotiated during the handshake. Also the spans
+//! of traced requests are sent to the collector via plain HTTP requests.
+//! When the HTTP fallback is enabled, clients that can't use WebSockets send
+//! their messages in the body of POST requests.
+//!
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{loop_fn, ok, poll_fn, Either, Future, Loop};
+use futures::{try_ready, Async};
+use json::parse as parse_json;
+use tokio::io::{read, shutdown, write_all};
+use tokio::net::TcpStream;
+use tokio::timer::{Delay, Timeout};
+use tungstenite::handshake::server::Request;
+use tungstenite::protocol::Message;
+use tungstenite::{Error as WsError, Result as WsResult};
+
+/// The path that is used for checking the health of the proxy.
+pub const HEALTH_CHECK_PATH: &str = "/health";
+
+/// The state of the proxy reported on health checks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HealthStatus {
+    /// The broker is connected and answers to pings when they're enabled.
+    Healthy,
+    /// The connection to the broker is alive, but the ping wasn't answered.
+    Degraded,
+    /// The connection to the broker is lost.
+    Unavailable
+}
+/// The path that is used for listing the loaded routes.
+pub const ROUTES_PATH: &str = "/routes";
+/// The header with the subprotocols offered by the client and the selected one.
+const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+/// The maximum number of bytes that will be checked before the handshake.
+const MAX_REQUEST_HEAD_SIZE: usize = 2048;
+/// The time to wait for the head of the request before the handshake, so
+/// that a silent client can't keep the connection open.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// The interval of checking whether the rest of the request head arrived.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Waits for the head of the request and returns it as a string, without
+/// consuming data from the stream. Only the first `MAX_REQUEST_HEAD_SIZE`
+/// bytes are returned for larger heads, and fails when the head wasn't
+/// received in time.
+pub fn peek_request_head(stream: TcpStream) -> impl Future<Item=(TcpStream, String), Error=io::Error> {
+    peek_request_head_with_timeout(stream, REQUEST_HEAD_TIMEOUT)
+}
+
+// The errors are built via io::Error::new, as in the rest of the crate
+#[allow(clippy::io_other_error)]
+fn peek_request_head_with_timeout(stream: TcpStream, timeout: Duration) -> impl Future<Item=(TcpStream, String), Error=io::Error> {
+    let peek_future = loop_fn(stream, |stream| {
+        peek(stream).and_then(|(stream, data)| {
+            let is_complete = data.is_empty()
+                || data.len() >= MAX_REQUEST_HEAD_SIZE
+                || data.windows(4).any(|window| window == b"\r\n\r\n");
+            match is_complete {
+                true => {
+                    let head = String::from_utf8_lossy(&data).to_string();
+                    Either::A(ok(Loop::Break((stream, head))))
+                },
+                false => Either::B(
+                    Delay::new(Instant::now() + PEEK_INTERVAL)
+                        .map(move |_| Loop::Continue(stream))
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                )
+            }
+        })
+    });
+
+    Timeout::new(peek_future, timeout).map_err(|err| match err.into_inner() {
+        Some(err) => err,
+        None => io::Error::new(io::ErrorKind::TimedOut, "The request head wasn't received in time")
+    })
+}
+
+/// Waits for the first bytes of the request and returns them, without
+/// consuming data from the stream. Returns nothing when the stream is closed.
+fn peek(stream: TcpStream) -> impl Future<Item=(TcpStream, Vec<u8>), Error=io::Error> {
+    let mut stream = Some(stream);
+    poll_fn(move || {
+        let mut buffer = vec![0; MAX_REQUEST_HEAD_SIZE];
+        let size = try_ready!(stream.as_mut().unwrap().poll_peek(&mut buffer));
+        buffer.truncate(size);
+        Ok(Async::Ready((stream.take().unwrap(), buffer)))
+    })
+}
+
+/// Returns the method and the path from the request line.
+pub fn get_request_target(head: &str) -> Option<(&str, &str)> {
+    let request_line = head.lines().next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => Some((method, path)),
+        _ => None
+    }
+}
+
+/// Checks that the request is a plain `GET /health` request instead of
+/// the WebSocket handshake.
+pub fn is_health_check_request(head: &str) -> bool {
+    let is_health_check = get_request_target(head) == Some(("GET", HEALTH_CHECK_PATH));
+    let is_upgrade = head.to_ascii_lowercase().contains("upgrade: websocket");
+    is_health_check && !is_upgrade
+}
+
+/// Checks that the request is a plain `POST` request to the path of the
+/// HTTP fallback instead of the WebSocket handshake.
+pub fn is_http_fallback_request(head: &str, path: &str) -> bool {
+    let is_fallback = get_request_target(head) == Some(("POST", path));
+    let is_upgrade = head.to_ascii_lowercase().contains("upgrade: websocket");
+    is_fallback && !is_upgrade
+}
+
+/// Returns the value of the first header with the given name (case-insensitive).
+pub fn get_header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+                _ => None
+            }
+        })
+        .next()
+}
+
+/// Checks that the origin is in the allow-list. The empty list allows all
+/// origins. Requests without the `Origin` header (e.g. from non-browser
+/// clients) are allowed too, because it can't be verified anyway.
+pub fn is_allowed_origin(origin: Option<&str>, allowed_origins: &[String]) -> bool {
+    match origin {
+        Some(origin) if !allowed_origins.is_empty() => allowed_origins.iter().any(|allowed| allowed == origin),
+        _ => true
+    }
+}
+
+/// Checks the `Origin` header of the request before the WebSocket handshake.
+pub fn is_allowed_request(head: &str, allowed_origins: &[String]) -> bool {
+    is_allowed_origin(get_header(head, "Origin"), allowed_origins)
+}
+
+/// Checks that the request contains the `Authorization: Bearer <token>`
+/// header with the expected token. The empty token allows all requests.
+pub fn is_authorized_request(head: &str, token: &str) -> bool {
+    match token.is_empty() {
+        true => true,
+        false => get_header(head, "Authorization") == Some(&format!("Bearer {}", token))
+    }
+}
+
+/// Returns the token from the `Authorization: Bearer <token>` header.
+pub fn get_bearer_token(head: &str) -> Option<&str> {
+    get_header(head, "Authorization")
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| value["Bearer ".len()..].trim())
+}
+
+/// Returns the message for the engine from the body of the request to the
+/// HTTP fallback. The token from the `Authorization` header is passed in the
+/// `token` field, unless the body contains its own token. Malformed bodies
+/// are passed as is, so that the client gets the usual decoding error.
+pub fn get_http_fallback_message(head: &str, body: &[u8]) -> Message {
+    let text = String::from_utf8_lossy(body).to_string();
+    let token = match get_bearer_token(head) {
+        Some(token) => token,
+        None => return Message::Text(text)
+    };
+
+    match parse_json(&text) {
+        Ok(mut json) => {
+            if json.is_object() && json["token"].is_null() {
+                json["token"] = token.into();
+            }
+            Message::Text(json.dump())
+        },
+        Err(_) => Message::Text(text)
+    }
+}
+
+/// Returns a callback for the WebSocket handshake that rejects requests from
+/// disallowed origins. It ensures that the `Origin` header is checked even
+/// when it wasn't available before the handshake.
+pub fn get_origin_callback(allowed_origins: Arc<Vec<String>>) -> impl FnOnce(&Request) -> WsResult<Option<Vec<(String, String)>>> {
+    move |request: &Request| {
+        let origin = request.headers.find_first("Origin").map(String::from_utf8_lossy);
+        match is_allowed_origin(origin.as_ref().map(|origin| origin.as_ref()), &allowed_origins) {
+            true => Ok(None),
+            false => Err(WsError::Http(403))
+        }
+    }
+}
+
+/// Returns the first subprotocol offered by the client, that is supported by
+/// the proxy. The offer is a comma-separated list of subprotocols.
+pub fn select_subprotocol(offer: &str, supported_subprotocols: &[String]) -> Option<String> {
+    offer
+        .split(',')
+        .map(|subprotocol| subprotocol.trim())
+        .find(|subprotocol| supported_subprotocols.iter().any(|supported| supported == subprotocol))
+        .map(String::from)
+}
+
+/// Returns a callback for the WebSocket handshake that checks the origin and
+/// negotiates the subprotocol. The selected subprotocol is echoed back to the
+/// client and stored into the `subprotocol` slot for the connection. When
+/// the list of supported subprotocols isn't empty, handshakes without any
+/// supported subprotocols are rejected.
+pub fn get_handshake_callback(
+    allowed_origins: Arc<Vec<String>>,
+    supported_subprotocols: Arc<Vec<String>>,
+    subprotocol: Arc<Mutex<Option<String>>>
+) -> impl FnOnce(&Request) -> WsResult<Option<Vec<(String, String)>>> {
+    move |request: &Request| {
+        get_origin_callback(allowed_origins)(request)?;
+        if supported_subprotocols.is_empty() {
+            return Ok(None);
+        }
+
+        let offer = request.headers
+            .find(SUBPROTOCOL_HEADER)
+            .map(|value| String::from_utf8_lossy(value).to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        match select_subprotocol(&offer, &supported_subprotocols) {
+            Some(selected) => {
+                *subprotocol.lock().unwrap() = Some(selected.clone());
+                Ok(Some(vec![(String::from(SUBPROTOCOL_HEADER), selected)]))
+            },
+            None => Err(WsError::Http(400))
+        }
+    }
+}
+
+/// Reads the beginning of the request and returns it as a string.
+pub fn read_request_head(stream: TcpStream) -> impl Future<Item=(TcpStream, String), Error=io::Error> {
+    read(stream, vec![0; MAX_REQUEST_HEAD_SIZE])
+        .map(|(stream, buffer, size)| (stream, String::from_utf8_lossy(&buffer[..size]).to_string()))
+}
+
+/// Reads the whole request with the body of the length specified by the
+/// `Content-Length` header. Returns `None` instead of the body when it's
+/// larger than the limit, so that the request can be rejected without
+/// reading it.
+pub fn read_request(stream: TcpStream, max_body_size: usize)
+    -> impl Future<Item=(TcpStream, String, Option<Vec<u8>>), Error=io::Error>
+{
+    loop_fn((stream, Vec::new()), move |(stream, mut data): (TcpStream, Vec<u8>)| {
+        read(stream, vec![0; MAX_REQUEST_HEAD_SIZE]).and_then(move |(stream, buffer, size)| {
+            data.extend_from_slice(&buffer[..size]);
+            let head_size = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(position) => position + 4,
+                None if size == 0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The request is incomplete")),
+                None if data.len() > MAX_REQUEST_HEAD_SIZE => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "The request head is too large"))
+                },
+                None => return Ok(Loop::Continue((stream, data)))
+            };
+
+            let head = String::from_utf8_lossy(&data[..head_size]).to_string();
+            let body_size = get_header(&head, "Content-Length").and_then(|value| value.parse::<usize>().ok()).unwrap_or(0);
+            match (body_size > max_body_size, data.len() - head_size >= body_size) {
+                (true, _) => Ok(Loop::Break((stream, head, None))),
+                (false, true) => {
+                    let body = data[head_size..head_size + body_size].to_vec();
+                    Ok(Loop::Break((stream, head, Some(body))))
+                },
+                (false, false) if size == 0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The request is incomplete")),
+                (false, false) => Ok(Loop::Continue((stream, data)))
+            }
+        })
+    })
+}
+
+/// Writes the response and closes the connection.
+pub fn send_response(stream: TcpStream, status: &str, content_type: &str, body: String)
+    -> impl Future<Item=(), Error=io::Error>
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+
+    write_all(stream, response.into_bytes())
+        .and_then(|(stream, _)| shutdown(stream))
+        .map(|_| ())
+}
+
+/// Sends the POST request with the JSON body and returns the status line
+/// of the response.
+pub fn send_json_request(address: SocketAddr, host: &str, path: &str, body: String)
+    -> impl Future<Item=String, Error=io::Error>
+{
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, body.len(), body
+    );
+
+    TcpStream::connect(&address)
+        .and_then(move |stream| write_all(stream, request.into_bytes()))
+        .and_then(|(stream, _)| read_request_head(stream))
+        .map(|(_, head)| head.lines().next().unwrap_or("").to_string())
+}
+
+/// Responds to the health check request and closes the connection.
+pub fn send_health_status(stream: TcpStream, health: HealthStatus) -> impl Future<Item=(), Error=io::Error> {
+    let (status, body) = get_health_status(health);
+    read_request_head(stream)
+        .and_then(move |(stream, _)| send_response(stream, status, "text/plain", body.to_string()))
+}
+
+/// Responds that the access is denied and closes the connection.
+pub fn send_forbidden(stream: TcpStream) -> impl Future<Item=(), Error=io::Error> {
+    read_request_head(stream)
+        .and_then(|(stream, _)| send_response(stream, "403 Forbidden", "text/plain", String::from("Forbidden")))
+}
+
+/// Returns the HTTP status and the body with the health status of the proxy.
+pub fn get_health_status(health: HealthStatus) -> (&'static str, &'static str) {
+    match health {
+        HealthStatus::Healthy => ("200 OK", "OK"),
+        HealthStatus::Degraded => ("503 Service Unavailable", "RabbitMQ doesn't respond to pings"),
+        HealthStatus::Unavailable => ("503 Service Unavailable", "RabbitMQ is unavailable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{Future, Stream};
+    use tokio::runtime::Runtime;
+
+    use crate::http::{
+        get_bearer_token, get_handshake_callback, get_header, get_health_status, get_http_fallback_message,
+        get_origin_callback, get_request_target, is_allowed_request, is_authorized_request, is_health_check_request,
+        is_http_fallback_request, peek_request_head_with_timeout, select_subprotocol, HealthStatus
+    };
+    use tungstenite::Message;
+
+    fn get_handshake_request(origin: &str) -> String {
+        format!(
+            "GET / HTTP/1.1\r\nHost: localhost:9000\r\nOrigin: {}\r\nConnection: Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            origin
+        )
+    }
+
+    fn get_handshake_response(origin: &str, allowed_origins: Vec<String>) -> (bool, String) {
+        let request = get_handshake_request(origin);
+        let callback = get_origin_callback(Arc::new(allowed_origins));
+        perform_handshake(request, callback)
+    }
+
+    fn get_negotiation_response(offer: &str, subprotocols: Vec<&str>) -> (bool, String, Option<String>) {
+        let request = get_handshake_request("https://example.com")
+            .replace("\r\n\r\n", &format!("\r\nSec-WebSocket-Protocol: {}\r\n\r\n", offer));
+        let subprotocols = subprotocols.into_iter().map(String::from).collect();
+        let subprotocol = Arc::new(Mutex::new(None));
+        let callback = get_handshake_callback(Arc::new(Vec::new()), Arc::new(subprotocols), subprotocol.clone());
+        let (is_accepted, response) = perform_handshake(request, callback);
+        let negotiated = subprotocol.lock().unwrap().clone();
+        (is_accepted, response, negotiated)
+    }
+
+    /// Sends the parts of the request with a delay between them and returns
+    /// the peeked head. The connection is kept open until the head is peeked.
+    fn peek_sent_head(parts: Vec<&'static str>, timeout: Duration) -> io::Result<String> {
+        let listener = tokio::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (done_sender, done_receiver) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            for part in parts {
+                stream.write_all(part.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+            }
+            let _ = done_receiver.recv();
+        });
+
+        let future = listener
+            .incoming()
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(stream, _)| peek_request_head_with_timeout(stream.unwrap(), timeout));
+        let result = Runtime::new().unwrap().block_on(future).map(|(_, head)| head);
+        drop(done_sender);
+        result
+    }
+
+    fn perform_handshake<C>(request: String, callback: C) -> (bool, String)
+        where C: tungstenite::handshake::server::Callback
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = vec![0; 1024];
+            let size = stream.read(&mut response).unwrap_or(0);
+            String::from_utf8_lossy(&response[..size]).to_string()
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let is_accepted = tungstenite::accept_hdr(stream, callback).is_ok();
+        (is_accepted, client.join().unwrap())
+    }
+
+    #[test]
+    fn test_peek_request_head_waits_for_the_whole_head() {
+        let parts = vec!["GET /health HTTP/1.1\r\n", "Host: localhost:9000\r\n", "\r\n"];
+        let head = peek_sent_head(parts, Duration::from_secs(5)).unwrap();
+        assert_eq!(head, "GET /health HTTP/1.1\r\nHost: localhost:9000\r\n\r\n");
+    }
+
+    #[test]
+    fn test_peek_request_head_fails_for_silent_clients() {
+        let parts = vec!["GET /health HTTP/1.1\r\n"];
+        let error = peek_sent_head(parts, Duration::from_millis(500)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_is_health_check_request_for_plain_get_request() {
+        let head = "GET /health HTTP/1.1\r\nHost: localhost:9000\r\n\r\n";
+        assert!(is_health_check_request(head));
+    }
+
+    #[test]
+    fn test_is_health_check_request_for_websocket_handshake() {
+        let head = "GET /health HTTP/1.1\r\nHost: localhost:9000\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        assert!(!is_health_check_request(head));
+
+        let head = "GET / HTTP/1.1\r\nHost: localhost:9000\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        assert!(!is_health_check_request(head));
+    }
+
+    #[test]
+    fn test_is_health_check_request_for_other_requests() {
+        assert!(!is_health_check_request("POST /health HTTP/1.1\r\n\r\n"));
+        assert!(!is_health_check_request("GET /healthz HTTP/1.1\r\n\r\n"));
+        assert!(!is_health_check_request(""));
+    }
+
+    #[test]
+    fn test_is_http_fallback_request() {
+        assert!(is_http_fallback_request("POST /rpc HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}", "/rpc"));
+        assert!(!is_http_fallback_request("GET /rpc HTTP/1.1\r\n\r\n", "/rpc"));
+        assert!(!is_http_fallback_request("POST /api HTTP/1.1\r\n\r\n", "/rpc"));
+        assert!(!is_http_fallback_request("POST /rpc HTTP/1.1\r\nUpgrade: websocket\r\n\r\n", "/rpc"));
+    }
+
+    #[test]
+    fn test_get_http_fallback_message_passes_the_bearer_token() {
+        let head = "POST /rpc HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert_eq!(get_bearer_token(head), Some("secret"));
+        assert_eq!(
+            get_http_fallback_message(head, br#"{"url": "/api/test"}"#),
+            Message::Text(String::from(r#"{"url":"/api/test","token":"secret"}"#))
+        );
+        assert_eq!(
+            get_http_fallback_message(head, br#"{"url": "/api/test", "token": "own"}"#),
+            Message::Text(String::from(r#"{"url":"/api/test","token":"own"}"#))
+        );
+        assert_eq!(get_http_fallback_message(head, b"{"), Message::Text(String::from("{")));
+
+        let head = "POST /rpc HTTP/1.1\r\n\r\n";
+        assert_eq!(get_bearer_token(head), None);
+        assert_eq!(get_http_fallback_message(head, br#"{"url": "/api/test"}"#), Message::Text(String::from(r#"{"url": "/api/test"}"#)));
+    }
+
+    #[test]
+    fn test_get_request_target() {
+        assert_eq!(get_request_target("GET /metrics HTTP/1.1\r\n\r\n"), Some(("GET", "/metrics")));
+        assert_eq!(get_request_target("GET\r\n\r\n"), None);
+        assert_eq!(get_request_target(""), None);
+    }
+
+    #[test]
+    fn test_get_header() {
+        let head = "GET / HTTP/1.1\r\nHost: localhost:9000\r\norigin:  https://example.com \r\n\r\n";
+        assert_eq!(get_header(head, "Origin"), Some("https://example.com"));
+        assert_eq!(get_header(head, "Host"), Some("localhost:9000"));
+        assert_eq!(get_header(head, "Upgrade"), None);
+        assert_eq!(get_header("", "Origin"), None);
+    }
+
+    #[test]
+    fn test_is_allowed_request() {
+        let allowed_origins = vec![String::from("https://example.com")];
+        let head = get_handshake_request("https://example.com");
+        assert!(is_allowed_request(&head, &allowed_origins));
+
+        let head = get_handshake_request("https://evil.com");
+        assert!(!is_allowed_request(&head, &allowed_origins));
+        assert!(is_allowed_request(&head, &[]));
+
+        let head = "GET / HTTP/1.1\r\nHost: localhost:9000\r\n\r\n";
+        assert!(is_allowed_request(head, &allowed_origins));
+    }
+
+    #[test]
+    fn test_is_authorized_request() {
+        let head = "GET /routes HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert!(is_authorized_request(head, "secret"));
+        assert!(is_authorized_request(head, ""));
+        assert!(!is_authorized_request(head, "other"));
+        assert!(!is_authorized_request("GET /routes HTTP/1.1\r\n\r\n", "secret"));
+    }
+
+    #[test]
+    fn test_handshake_from_disallowed_origin_is_refused() {
+        let allowed_origins = vec![String::from("https://example.com")];
+        let (is_accepted, response) = get_handshake_response("https://evil.com", allowed_origins);
+        assert!(!is_accepted);
+        assert!(!response.starts_with("HTTP/1.1 101"));
+    }
+
+    #[test]
+    fn test_handshake_from_allowed_origin_proceeds() {
+        let allowed_origins = vec![String::from("https://example.com")];
+        let (is_accepted, response) = get_handshake_response("https://example.com", allowed_origins);
+        assert!(is_accepted);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+    }
+
+    #[test]
+    fn test_select_subprotocol() {
+        let supported = vec![String::from("msgpack"), String::from("json")];
+        assert_eq!(select_subprotocol("v2, json, msgpack", &supported), Some(String::from("json")));
+        assert_eq!(select_subprotocol("msgpack", &supported), Some(String::from("msgpack")));
+        assert_eq!(select_subprotocol("v2", &supported), None);
+        assert_eq!(select_subprotocol("", &supported), None);
+    }
+
+    #[test]
+    fn test_handshake_echoes_the_negotiated_subprotocol() {
+        let (is_accepted, response, negotiated) = get_negotiation_response("v2, msgpack", vec!["json", "msgpack"]);
+        assert!(is_accepted);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(response.contains("Sec-WebSocket-Protocol: msgpack\r\n"));
+        assert_eq!(negotiated, Some(String::from("msgpack")));
+    }
+
+    #[test]
+    fn test_handshake_without_supported_subprotocols_is_refused() {
+        let (is_accepted, response, negotiated) = get_negotiation_response("v2", vec!["json", "msgpack"]);
+        assert!(!is_accepted);
+        assert!(!response.starts_with("HTTP/1.1 101"));
+        assert_eq!(negotiated, None);
+    }
+
+    #[test]
+    fn test_handshake_ignores_subprotocols_when_they_are_not_configured() {
+        let (is_accepted, response, negotiated) = get_negotiation_response("v2", vec![]);
+        assert!(is_accepted);
+        assert!(!response.contains("Sec-WebSocket-Protocol"));
+        assert_eq!(negotiated, None);
+    }
+
+    #[test]
+    fn test_get_health_status() {
+        assert_eq!(get_health_status(HealthStatus::Healthy), ("200 OK", "OK"));
+        assert_eq!(get_health_status(HealthStatus::Degraded).0, "503 Service Unavailable");
+        assert_eq!(get_health_status(HealthStatus::Unavailable).0, "503 Service Unavailable");
+    }
+}
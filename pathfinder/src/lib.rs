@@ -0,0 +1,35 @@
+//! WebSocket-over-RabbitMQ reverse proxy
+//!
+//! The proxy can be embedded into another application (or started in-process
+//! by tests) by building a `ProxyConfig` and running the `Proxy`:
+//!
+//! ```rust,no_run
+//! use pathfinder::config::ProxyConfig;
+//! use pathfinder::proxy::Proxy;
+//!
+//! let config = ProxyConfig {
+//!     rabbitmq_host: String::from("rabbitmq.local"),
+//!     ..ProxyConfig::default()
+//! };
+//! let proxy = Proxy::new(&config).unwrap();
+//! let handle = proxy.run("127.0.0.1:0".parse().unwrap());
+//! println!("Listening on: {}", handle.local_addr());
+//! handle.shutdown();
+//! ```
+//!
+
+pub mod cli;
+pub mod config;
+#[macro_use]
+pub mod engine;
+pub mod error;
+pub mod hooks;
+pub mod http;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod proxy;
+pub mod rabbitmq;
+pub mod remote;
+pub mod tracing;
+pub mod writer;
@@ -0,0 +1,489 @@
+//! Limits of concurrent connections and requests
+//!
+//! This module keeps track of opened WebSocket connections, so that a
+//! single client can't exhaust the resources of the reverse proxy. In the
+//! same way the number of in-flight requests to slow microservices can be
+//! limited per routing key, and the number and the rate of requests per
+//! connection. Costly operations on the message broker can be throttled
+//! for the whole proxy.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Describes which limit was exceeded by the new connection.
+#[derive(Debug, PartialEq)]
+pub enum LimitError {
+    /// Too many connections from the same IP address.
+    PerIp(usize),
+    /// Too many connections in total.
+    Total(usize),
+    /// Too many in-flight requests to the same microservice.
+    Requests(usize),
+    /// Too many in-flight requests from the same connection.
+    InFlight(usize)
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitError::PerIp(limit) => write!(f, "the limit of {} connections per IP is exceeded", limit),
+            LimitError::Total(limit) => write!(f, "the limit of {} connections is exceeded", limit),
+            LimitError::Requests(limit) => write!(f, "the limit of {} concurrent requests is exceeded", limit),
+            LimitError::InFlight(limit) => write!(f, "the limit of {} in-flight requests per connection is exceeded", limit),
+        }
+    }
+}
+
+/// Numbers of the opened connections.
+#[derive(Default)]
+struct Counters {
+    per_ip: HashMap<IpAddr, usize>,
+    total: usize
+}
+
+/// Storage for the numbers of connections with the configured limits.
+/// A zero limit means that there is no limit.
+pub struct ConnectionLimits {
+    max_per_ip: usize,
+    max_total: usize,
+    counters: Mutex<Counters>
+}
+
+impl ConnectionLimits {
+    /// Returns a new instance of `ConnectionLimits`.
+    pub fn new(max_per_ip: usize, max_total: usize) -> ConnectionLimits {
+        ConnectionLimits {
+            max_per_ip,
+            max_total,
+            counters: Mutex::new(Counters::default())
+        }
+    }
+
+    /// Reserves a place for the new connection from the IP address. The place
+    /// is released when the returned slot is dropped.
+    pub fn acquire(limits: &Arc<ConnectionLimits>, ip: IpAddr) -> Result<ConnectionSlot, LimitError> {
+        let mut counters = limits.counters.lock().unwrap();
+        if limits.max_total > 0 && counters.total >= limits.max_total {
+            return Err(LimitError::Total(limits.max_total));
+        }
+
+        let count = counters.per_ip.entry(ip).or_insert(0);
+        if limits.max_per_ip > 0 && *count >= limits.max_per_ip {
+            return Err(LimitError::PerIp(limits.max_per_ip));
+        }
+
+        *count += 1;
+        counters.total += 1;
+        Ok(ConnectionSlot { limits: limits.clone(), ip })
+    }
+
+    /// Returns the number of connections from the IP address.
+    pub fn get_count(&self, ip: &IpAddr) -> usize {
+        self.counters.lock().unwrap().per_ip.get(ip).cloned().unwrap_or(0)
+    }
+
+    /// Returns the total number of connections.
+    pub fn get_total(&self) -> usize {
+        self.counters.lock().unwrap().total
+    }
+
+    fn release(&self, ip: &IpAddr) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total = counters.total.saturating_sub(1);
+        let is_last = match counters.per_ip.get_mut(ip) {
+            Some(count) => {
+                *count = count.saturating_sub(1);
+                *count == 0
+            },
+            None => false
+        };
+        if is_last {
+            counters.per_ip.remove(ip);
+        }
+    }
+}
+
+/// A place reserved for the connection.
+pub struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limits.release(&self.ip);
+    }
+}
+
+/// Storage for the numbers of in-flight requests per routing key.
+#[derive(Default)]
+pub struct RequestLimits {
+    counters: Mutex<HashMap<String, usize>>
+}
+
+impl RequestLimits {
+    /// Returns a new instance of `RequestLimits`.
+    pub fn new() -> RequestLimits {
+        RequestLimits {
+            counters: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Reserves a place for the request to the microservice, unless the
+    /// specified number of requests is in flight already. The place is
+    /// released when the returned slot is dropped.
+    pub fn acquire(limits: &Arc<RequestLimits>, routing_key: &str, max_concurrency: usize) -> Result<RequestSlot, LimitError> {
+        let mut counters = limits.counters.lock().unwrap();
+        let count = counters.entry(routing_key.to_string()).or_insert(0);
+        if *count >= max_concurrency {
+            return Err(LimitError::Requests(max_concurrency));
+        }
+
+        *count += 1;
+        Ok(RequestSlot { limits: limits.clone(), routing_key: routing_key.to_string() })
+    }
+
+    /// Returns the number of in-flight requests to the microservice.
+    pub fn get_count(&self, routing_key: &str) -> usize {
+        self.counters.lock().unwrap().get(routing_key).cloned().unwrap_or(0)
+    }
+
+    fn release(&self, routing_key: &str) {
+        let mut counters = self.counters.lock().unwrap();
+        let is_last = match counters.get_mut(routing_key) {
+            Some(count) => {
+                *count = count.saturating_sub(1);
+                *count == 0
+            },
+            None => false
+        };
+        if is_last {
+            counters.remove(routing_key);
+        }
+    }
+}
+
+/// A place reserved for the in-flight request.
+pub struct RequestSlot {
+    limits: Arc<RequestLimits>,
+    routing_key: String
+}
+
+impl Drop for RequestSlot {
+    fn drop(&mut self) {
+        self.limits.release(&self.routing_key);
+    }
+}
+
+/// The number of in-flight requests of a single connection.
+pub struct InFlightRequests {
+    count: Mutex<usize>,
+    max_count: usize
+}
+
+impl InFlightRequests {
+    /// Returns a new instance of `InFlightRequests`.
+    pub fn new(max_count: usize) -> InFlightRequests {
+        InFlightRequests {
+            count: Mutex::new(0),
+            max_count
+        }
+    }
+
+    /// Reserves a place for the request of the connection, unless the
+    /// maximum number of requests is in flight already. The place is
+    /// released when the returned slot is dropped, i.e. the request is processed.
+    pub fn acquire(requests: &Arc<InFlightRequests>) -> Result<InFlightSlot, LimitError> {
+        let mut count = requests.count.lock().unwrap();
+        if *count >= requests.max_count {
+            return Err(LimitError::InFlight(requests.max_count));
+        }
+
+        *count += 1;
+        Ok(InFlightSlot { requests: requests.clone() })
+    }
+
+    /// Returns the number of in-flight requests.
+    pub fn get_count(&self) -> usize {
+        *self.count.lock().unwrap()
+    }
+
+    fn release(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count = count.saturating_sub(1);
+    }
+}
+
+/// A place reserved for the in-flight request of the connection.
+pub struct InFlightSlot {
+    requests: Arc<InFlightRequests>
+}
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        self.requests.release();
+    }
+}
+
+/// A token bucket, that limits the rate of requests from a single connection.
+/// The bucket is refilled with `rate` tokens per second and holds up to
+/// `burst` tokens, so that short bursts of requests are allowed.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+    /// Returns a new full bucket. A zero burst means that the bucket holds
+    /// as many tokens as it receives per second.
+    pub fn new(rate: u32, burst: u32) -> TokenBucket {
+        let burst = match burst {
+            0 => rate.max(1),
+            burst => burst
+        };
+
+        TokenBucket {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            last_refill: Instant::now()
+        }
+    }
+
+    /// Takes a token for the request. When the bucket is empty, returns the
+    /// time after which the next token will be available.
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = (1.0 - self.tokens) / self.rate;
+        Err(Duration::from_secs_f64(retry_after))
+    }
+}
+
+/// A token bucket shared by all connections, that smooths the rate of
+/// operations instead of rejecting the excess ones. Each operation reserves
+/// the next token in advance and waits until it's available, so that a burst
+/// of operations is spread over time in the order of arrival.
+pub struct Throttle {
+    bucket: Mutex<TokenBucket>
+}
+
+impl Throttle {
+    /// Returns a new instance of `Throttle`, that allows `rate` operations
+    /// per second.
+    pub fn new(rate: u32) -> Throttle {
+        Throttle {
+            bucket: Mutex::new(TokenBucket::new(rate, 0))
+        }
+    }
+
+    /// Reserves a token for the operation and returns the time to wait
+    /// before performing it.
+    pub fn reserve(&self) -> Duration {
+        self.reserve_at(Instant::now())
+    }
+
+    fn reserve_at(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        match bucket.try_acquire_at(now) {
+            Ok(()) => Duration::from_secs(0),
+            Err(delay) => {
+                // The token is taken in debt and repaid by the next refills
+                bucket.tokens -= 1.0;
+                delay
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::limits::{ConnectionLimits, InFlightRequests, LimitError, RequestLimits, Throttle, TokenBucket};
+
+    fn get_ip(raw_ip: &str) -> IpAddr {
+        raw_ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_acquire_rejects_connections_over_the_per_ip_limit() {
+        let limits = Arc::new(ConnectionLimits::new(2, 0));
+        let first = ConnectionLimits::acquire(&limits, get_ip("10.0.0.1")).unwrap();
+        let _second = ConnectionLimits::acquire(&limits, get_ip("10.0.0.1")).unwrap();
+        let other = ConnectionLimits::acquire(&limits, get_ip("10.0.0.2"));
+        assert!(other.is_ok());
+
+        let result = ConnectionLimits::acquire(&limits, get_ip("10.0.0.1"));
+        assert_eq!(result.err(), Some(LimitError::PerIp(2)));
+
+        drop(first);
+        assert_eq!(limits.get_count(&get_ip("10.0.0.1")), 1);
+        assert!(ConnectionLimits::acquire(&limits, get_ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn test_acquire_rejects_connections_over_the_total_limit() {
+        let limits = Arc::new(ConnectionLimits::new(0, 2));
+        let _first = ConnectionLimits::acquire(&limits, get_ip("10.0.0.1")).unwrap();
+        let _second = ConnectionLimits::acquire(&limits, get_ip("10.0.0.2")).unwrap();
+
+        let result = ConnectionLimits::acquire(&limits, get_ip("10.0.0.3"));
+        assert_eq!(result.err(), Some(LimitError::Total(2)));
+        assert_eq!(limits.get_count(&get_ip("10.0.0.3")), 0);
+    }
+
+    #[test]
+    fn test_acquire_keeps_the_total_limit_for_simultaneous_connections() {
+        let limits = Arc::new(ConnectionLimits::new(0, 10));
+        let workers: Vec<_> = (0..50)
+            .map(|index| {
+                let limits = limits.clone();
+                thread::spawn(move || ConnectionLimits::acquire(&limits, get_ip(&format!("10.0.0.{}", index))).ok())
+            })
+            .collect();
+        let slots: Vec<_> = workers.into_iter().filter_map(|worker| worker.join().unwrap()).collect();
+
+        assert_eq!(slots.len(), 10);
+        assert_eq!(limits.get_total(), 10);
+        drop(slots);
+        assert_eq!(limits.get_total(), 0);
+    }
+
+    #[test]
+    fn test_dropping_slots_releases_counters() {
+        let limits = Arc::new(ConnectionLimits::new(0, 0));
+        let slots: Vec<_> = (0..10)
+            .map(|_| ConnectionLimits::acquire(&limits, get_ip("10.0.0.1")).unwrap())
+            .collect();
+        assert_eq!(limits.get_count(&get_ip("10.0.0.1")), 10);
+        assert_eq!(limits.get_total(), 10);
+
+        drop(slots);
+        assert_eq!(limits.get_count(&get_ip("10.0.0.1")), 0);
+        assert_eq!(limits.get_total(), 0);
+    }
+
+    #[test]
+    fn test_acquire_rejects_requests_when_the_endpoint_is_saturated() {
+        let limits = Arc::new(RequestLimits::new());
+        let first = RequestLimits::acquire(&limits, "microservice.search", 2).unwrap();
+        let _second = RequestLimits::acquire(&limits, "microservice.search", 2).unwrap();
+        assert!(RequestLimits::acquire(&limits, "microservice.leaderboard", 2).is_ok());
+
+        let result = RequestLimits::acquire(&limits, "microservice.search", 2);
+        assert_eq!(result.err(), Some(LimitError::Requests(2)));
+        assert_eq!(limits.get_count("microservice.search"), 2);
+
+        drop(first);
+        assert_eq!(limits.get_count("microservice.search"), 1);
+        assert!(RequestLimits::acquire(&limits, "microservice.search", 2).is_ok());
+    }
+
+    #[test]
+    fn test_dropping_request_slots_releases_counters() {
+        let limits = Arc::new(RequestLimits::new());
+        let slots: Vec<_> = (0..5)
+            .map(|_| RequestLimits::acquire(&limits, "microservice.search", 5).unwrap())
+            .collect();
+        assert_eq!(limits.get_count("microservice.search"), 5);
+
+        drop(slots);
+        assert_eq!(limits.get_count("microservice.search"), 0);
+        assert!(RequestLimits::acquire(&limits, "microservice.search", 1).is_ok());
+    }
+
+    #[test]
+    fn test_acquire_rejects_requests_over_the_in_flight_limit() {
+        let requests = Arc::new(InFlightRequests::new(3));
+        let mut slots: Vec<_> = (0..3).map(|_| InFlightRequests::acquire(&requests).unwrap()).collect();
+        assert_eq!(requests.get_count(), 3);
+
+        let result = InFlightRequests::acquire(&requests);
+        assert_eq!(result.err(), Some(LimitError::InFlight(3)));
+        assert_eq!(requests.get_count(), 3);
+
+        slots.pop();
+        assert_eq!(requests.get_count(), 2);
+        slots.push(InFlightRequests::acquire(&requests).unwrap());
+
+        drop(slots);
+        assert_eq!(requests.get_count(), 0);
+    }
+
+    #[test]
+    fn test_token_bucket_allows_bursts_and_rejects_excess_requests() {
+        let mut bucket = TokenBucket::new(2, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(bucket.try_acquire_at(now).is_ok());
+        }
+
+        let retry_after = bucket.try_acquire_at(now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_token_bucket_is_refilled_over_time() {
+        let mut bucket = TokenBucket::new(2, 0);
+        let now = Instant::now();
+        assert!(bucket.try_acquire_at(now).is_ok());
+        assert!(bucket.try_acquire_at(now).is_ok());
+        assert!(bucket.try_acquire_at(now).is_err());
+
+        assert!(bucket.try_acquire_at(now + Duration::from_millis(500)).is_ok());
+        assert!(bucket.try_acquire_at(now + Duration::from_millis(500)).is_err());
+
+        // The bucket never holds more tokens than the burst allows
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.try_acquire_at(later).is_ok());
+        assert!(bucket.try_acquire_at(later).is_ok());
+        assert!(bucket.try_acquire_at(later).is_err());
+    }
+
+    #[test]
+    fn test_throttle_delays_operations_in_the_order_of_arrival() {
+        let throttle = Throttle::new(2);
+        let now = Instant::now();
+        assert_eq!(throttle.reserve_at(now), Duration::from_secs(0));
+        assert_eq!(throttle.reserve_at(now), Duration::from_secs(0));
+        assert_eq!(throttle.reserve_at(now), Duration::from_millis(500));
+        assert_eq!(throttle.reserve_at(now), Duration::from_millis(1000));
+        assert_eq!(throttle.reserve_at(now), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_throttle_repays_the_reserved_tokens_over_time() {
+        let throttle = Throttle::new(2);
+        let now = Instant::now();
+        for _ in 0..4 {
+            throttle.reserve_at(now);
+        }
+
+        let later = now + Duration::from_millis(1000);
+        assert_eq!(throttle.reserve_at(later), Duration::from_millis(500));
+
+        let much_later = now + Duration::from_secs(60);
+        assert_eq!(throttle.reserve_at(much_later), Duration::from_secs(0));
+    }
+}
@@ -13,6 +13,7 @@ use std;
 use chrono::Local;
 use fern::{Dispatch, InitError};
 use fern::colors::ColoredLevelConfig;
+use json::object;
 use log::{LevelFilter, warn};
 
 use crate::cli::CliOptions;
@@ -41,18 +42,76 @@ pub fn setup_logger(cli: &CliOptions) -> Result<(), InitError> {
         }
     };
 
-    let colors = ColoredLevelConfig::new();
-    Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                colors.color(record.level()),
-                message
-            ))
-        }).level(logging_level)
+    let dispatch = match cli.log_format.as_str() {
+        "json" => Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "{}",
+                    format_json_record(
+                        &Local::now().to_rfc3339(),
+                        &record.level().to_string(),
+                        record.target(),
+                        &message.to_string()
+                    )
+                ))
+            }),
+        log_format => {
+            if log_format != "text" {
+                warn!(
+                    "Logging format with value={} is invalid. Use one of available formats: {:?}",
+                    log_format, vec!["text", "json"]
+                );
+            }
+
+            let colors = ColoredLevelConfig::new();
+            Dispatch::new()
+                .format(move |out, message, record| {
+                    out.finish(format_args!(
+                        "{}[{}][{}] {}",
+                        Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                        record.target(),
+                        colors.color(record.level()),
+                        message
+                    ))
+                })
+        }
+    };
+
+    dispatch
+        .level(logging_level)
         .chain(std::io::stdout())
         .apply()?;
     Ok(())
 }
+
+/// Returns the log record as a single-line JSON object.
+fn format_json_record(timestamp: &str, level: &str, target: &str, message: &str) -> String {
+    let record = object!{
+        "timestamp" => timestamp,
+        "level" => level,
+        "target" => target,
+        "message" => message
+    };
+    record.dump()
+}
+
+#[cfg(test)]
+mod tests {
+    use json::parse as parse_json;
+
+    use crate::logging::format_json_record;
+
+    #[test]
+    fn test_format_json_record() {
+        let line = format_json_record(
+            "2019-01-01T00:00:00+00:00", "INFO", "pathfinder::proxy", "Listening on: \"127.0.0.1:9000\""
+        );
+
+        assert!(!line.contains('\n'));
+        let json = parse_json(&line).unwrap();
+        assert_eq!(json["timestamp"], "2019-01-01T00:00:00+00:00");
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["target"], "pathfinder::proxy");
+        assert_eq!(json["message"], "Listening on: \"127.0.0.1:9000\"");
+    }
+}
@@ -1,30 +1,61 @@
 //! WebSocket-over-RabbitMQ reverse proxy
 //!
 
-pub mod cli;
-pub mod config;
-#[macro_use]
-pub mod engine;
-pub mod error;
-pub mod logging;
-pub mod proxy;
-pub mod rabbitmq;
-
-use log::warn;
-use structopt::StructOpt;
-
-use crate::cli::CliOptions;
-use crate::logging::setup_logger;
-use crate::proxy::Proxy;
+use std::process;
+
+use log::{error, warn};
+
+use pathfinder::cli::CliOptions;
+use pathfinder::config::check_config;
+use pathfinder::logging::setup_logger;
+use pathfinder::proxy::Proxy;
 
 fn main() {
-    let cli = CliOptions::from_args();
+    let cli = CliOptions::from_args_and_env();
+    if cli.check_config {
+        process::exit(run_config_check(&cli.config));
+    }
+
     match setup_logger(&cli) {
         Ok(_) => {}
         Err(err) => warn!("Logger isn't instantiated: {}", err),
     };
 
-    let proxy = Box::new(Proxy::new(&cli));
-    let address = format!("{}:{}", cli.ip, cli.port).parse().unwrap();
-    proxy.run(address);
+    let proxy = match Proxy::new(&cli.get_proxy_config()) {
+        Ok(proxy) => Box::new(proxy),
+        Err(err) => {
+            error!("The reverse proxy can't be started: {}", err);
+            process::exit(1);
+        }
+    };
+    let mut addresses = vec![format!("{}:{}", cli.ip, cli.port).parse().unwrap()];
+    addresses.extend(cli.listen.iter().cloned());
+    proxy.run_on(&addresses).wait();
+}
+
+/// Prints the summary of the configuration file and returns the exit code.
+fn run_config_check(file_path: &str) -> i32 {
+    let report = match check_config(file_path) {
+        Ok(report) => report,
+        Err(error) => {
+            println!("Configuration file \"{}\" can't be loaded: {}", file_path, error);
+            return 1;
+        }
+    };
+
+    println!(
+        "Configuration file \"{}\": {} endpoints parsed, {} skipped.",
+        file_path, report.endpoints_count, report.errors.len()
+    );
+    for error in report.errors.iter() {
+        println!("  - {}", error);
+    }
+
+    match report.is_valid() {
+        true => 0,
+        false => {
+            println!("No valid endpoints were found.");
+            1
+        }
+    }
 }
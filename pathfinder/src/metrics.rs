@@ -0,0 +1,233 @@
+//! Metrics of the reverse proxy
+//!
+//! This module collects request counters and latencies, which are exposed
+//! in the Prometheus text format or as a JSON summary.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use json::{object, JsonValue};
+use strum::AsStaticRef;
+
+use crate::error::PathfinderError;
+
+/// The label value used for requests that weren't matched to any endpoint.
+pub const UNKNOWN_ROUTING_KEY: &str = "unknown";
+/// Upper bounds (in seconds) of the RPC latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A histogram with the fixed buckets for RPC latencies.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+
+        for (index, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                self.buckets[index] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Storage for all metrics collected by the proxy.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<String, u64>>,
+    errors: Mutex<BTreeMap<(String, String), u64>>,
+    in_flight: AtomicUsize,
+    connections: AtomicUsize,
+    latencies: Mutex<BTreeMap<String, Histogram>>
+}
+
+impl Metrics {
+    /// Returns a new instance of `Metrics`.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Increments the number of requests to the microservice.
+    pub fn inc_requests(&self, routing_key: &str) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.entry(routing_key.to_string()).or_insert(0) += 1;
+    }
+
+    /// Increments the number of errors of the certain type.
+    pub fn inc_errors(&self, routing_key: &str, error: &PathfinderError) {
+        let key = (routing_key.to_string(), error.as_static().to_string());
+        let mut errors = self.errors.lock().unwrap();
+        *errors.entry(key).or_insert(0) += 1;
+    }
+
+    /// Marks the beginning of the request processing.
+    pub fn inc_in_flight(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Marks the end of the request processing.
+    pub fn dec_in_flight(&self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Marks the opening of a client connection.
+    pub fn inc_connections(&self) {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Marks the closing of a client connection.
+    pub fn dec_connections(&self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Stores the time spent on the RPC request to the microservice.
+    pub fn observe_latency(&self, routing_key: &str, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.entry(routing_key.to_string()).or_default().observe(seconds);
+    }
+
+    /// Returns all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        writeln!(output, "# HELP pathfinder_requests_total Total number of requests to microservices.").unwrap();
+        writeln!(output, "# TYPE pathfinder_requests_total counter").unwrap();
+        for (routing_key, value) in self.requests.lock().unwrap().iter() {
+            writeln!(output, "pathfinder_requests_total{{routing_key=\"{}\"}} {}", routing_key, value).unwrap();
+        }
+
+        writeln!(output, "# HELP pathfinder_errors_total Total number of failed requests by the error type.").unwrap();
+        writeln!(output, "# TYPE pathfinder_errors_total counter").unwrap();
+        for ((routing_key, error), value) in self.errors.lock().unwrap().iter() {
+            writeln!(
+                output, "pathfinder_errors_total{{routing_key=\"{}\",error=\"{}\"}} {}",
+                routing_key, error, value
+            ).unwrap();
+        }
+
+        writeln!(output, "# HELP pathfinder_requests_in_flight Number of requests that are processed right now.").unwrap();
+        writeln!(output, "# TYPE pathfinder_requests_in_flight gauge").unwrap();
+        writeln!(output, "pathfinder_requests_in_flight {}", self.in_flight.load(Ordering::SeqCst)).unwrap();
+
+        writeln!(output, "# HELP pathfinder_connections Number of opened client connections.").unwrap();
+        writeln!(output, "# TYPE pathfinder_connections gauge").unwrap();
+        writeln!(output, "pathfinder_connections {}", self.connections.load(Ordering::SeqCst)).unwrap();
+
+        writeln!(output, "# HELP pathfinder_rpc_duration_seconds Round-trip time of RPC requests to microservices.").unwrap();
+        writeln!(output, "# TYPE pathfinder_rpc_duration_seconds histogram").unwrap();
+        for (routing_key, histogram) in self.latencies.lock().unwrap().iter() {
+            for (bound, value) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(
+                    output, "pathfinder_rpc_duration_seconds_bucket{{routing_key=\"{}\",le=\"{}\"}} {}",
+                    routing_key, bound, value
+                ).unwrap();
+            }
+            writeln!(
+                output, "pathfinder_rpc_duration_seconds_bucket{{routing_key=\"{}\",le=\"+Inf\"}} {}",
+                routing_key, histogram.count
+            ).unwrap();
+            writeln!(output, "pathfinder_rpc_duration_seconds_sum{{routing_key=\"{}\"}} {}", routing_key, histogram.sum).unwrap();
+            writeln!(output, "pathfinder_rpc_duration_seconds_count{{routing_key=\"{}\"}} {}", routing_key, histogram.count).unwrap();
+        }
+
+        output
+    }
+
+    /// Returns the summary of counters as JSON, with errors summed up by
+    /// their types across all routing keys.
+    pub fn get_stats(&self) -> JsonValue {
+        let requests: u64 = self.requests.lock().unwrap().values().sum();
+        let mut errors = BTreeMap::new();
+        for ((_, error), value) in self.errors.lock().unwrap().iter() {
+            *errors.entry(error.clone()).or_insert(0) += value;
+        }
+
+        let mut errors_json = JsonValue::new_object();
+        for (error, value) in errors {
+            errors_json[error] = value.into();
+        }
+
+        object!{
+            "connections" => self.connections.load(Ordering::SeqCst),
+            "requests" => requests,
+            "in_flight" => self.in_flight.load(Ordering::SeqCst),
+            "errors" => errors_json
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::error::PathfinderError;
+    use crate::metrics::{Metrics, UNKNOWN_ROUTING_KEY};
+
+    #[test]
+    fn test_render_counters() {
+        let metrics = Metrics::new();
+        metrics.inc_requests("microservice.search");
+        metrics.inc_requests("microservice.search");
+        metrics.inc_errors("microservice.search", &PathfinderError::MessageBrokerError(String::from("error")));
+        metrics.inc_in_flight();
+
+        let output = metrics.render();
+        assert!(output.contains("pathfinder_requests_total{routing_key=\"microservice.search\"} 2\n"));
+        assert!(output.contains(
+            "pathfinder_errors_total{routing_key=\"microservice.search\",error=\"MessageBrokerError\"} 1\n"
+        ));
+        assert!(output.contains("pathfinder_requests_in_flight 1\n"));
+
+        metrics.dec_in_flight();
+        assert!(metrics.render().contains("pathfinder_requests_in_flight 0\n"));
+    }
+
+    #[test]
+    fn test_get_stats_sums_counters_by_error_types() {
+        let metrics = Metrics::new();
+        metrics.inc_connections();
+        metrics.inc_connections();
+        metrics.dec_connections();
+        metrics.inc_requests("microservice.search");
+        metrics.inc_requests("microservice.leaderboard");
+        metrics.inc_errors("microservice.search", &PathfinderError::MessageBrokerError(String::from("error")));
+        metrics.inc_errors("microservice.leaderboard", &PathfinderError::MessageBrokerError(String::from("error")));
+        metrics.inc_errors(UNKNOWN_ROUTING_KEY, &PathfinderError::DecodingError(String::from("error")));
+
+        let stats = metrics.get_stats();
+        assert_eq!(stats["connections"], 1);
+        assert_eq!(stats["requests"], 2);
+        assert_eq!(stats["in_flight"], 0);
+        assert_eq!(stats["errors"]["MessageBrokerError"], 2);
+        assert_eq!(stats["errors"]["DecodingError"], 1);
+        assert!(metrics.render().contains("pathfinder_connections 1\n"));
+    }
+
+    #[test]
+    fn test_render_latency_histogram() {
+        let metrics = Metrics::new();
+        metrics.observe_latency("microservice.search", Duration::from_millis(20));
+        metrics.observe_latency("microservice.search", Duration::from_secs(3));
+
+        let output = metrics.render();
+        assert!(output.contains("pathfinder_rpc_duration_seconds_bucket{routing_key=\"microservice.search\",le=\"0.01\"} 0\n"));
+        assert!(output.contains("pathfinder_rpc_duration_seconds_bucket{routing_key=\"microservice.search\",le=\"0.025\"} 1\n"));
+        assert!(output.contains("pathfinder_rpc_duration_seconds_bucket{routing_key=\"microservice.search\",le=\"5\"} 2\n"));
+        assert!(output.contains("pathfinder_rpc_duration_seconds_bucket{routing_key=\"microservice.search\",le=\"+Inf\"} 2\n"));
+        assert!(output.contains("pathfinder_rpc_duration_seconds_count{routing_key=\"microservice.search\"} 2\n"));
+    }
+}
@@ -12,181 +12,1483 @@
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use amq_protocol::uri::AMQPUri;
-use futures::stream::Stream;
-use futures::sync::mpsc;
-use futures::{Future, Sink};
+use failure::{err_msg, Error as FailureError};
+use futures::future::{empty, join_all, lazy, ok, poll_fn, Either};
+use futures::stream::{self, Stream};
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Sink};
 use lapin_futures::error::{Error as LapinError};
-use log::{debug, info, error};
-use strum::AsStaticRef;
-use tokio::net::TcpListener;
-use tokio_tungstenite::accept_async;
+use lapin_futures_rustls::lapin::channel::{BasicConsumeOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin_futures_rustls::lapin::types::FieldTable;
+use log::{debug, info, error, log, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::runtime::Runtime;
+use tokio::timer::Interval;
+use tokio_tungstenite::accept_hdr_async;
+use tungstenite::protocol::frame::coding::CloseCode;
 use tungstenite::protocol::Message;
 
-use crate::cli::CliOptions;
-use crate::engine::{Engine, MessageSender, serialize_message, wrap_a_string_error};
-use crate::error::PathfinderError;
+use crate::config::ProxyConfig;
+use crate::engine::{
+    Broadcaster, Engine, ErrorTemplate, MessageSender, RpcOptions, Transformers, convert_message,
+    ping_request_future, wrap_a_microservice_error
+};
+use crate::engine::options::AmqpPrefix;
+use crate::error::{PathfinderError, RequestError};
+use crate::hooks::{ConnectionHooks, ConnectionInfo, EmptyConnectionHooks};
+use crate::http::{
+    get_handshake_callback, get_http_fallback_message, get_request_target, is_allowed_request, is_authorized_request,
+    is_health_check_request, is_http_fallback_request, peek_request_head, read_request, read_request_head,
+    send_forbidden, send_health_status, send_response, HealthStatus, ROUTES_PATH
+};
+use crate::limits::{ConnectionLimits, InFlightRequests, TokenBucket};
+use crate::metrics::UNKNOWN_ROUTING_KEY;
 use crate::rabbitmq::client::{RabbitMQContext, RabbitMQClient};
-use crate::rabbitmq::utils::get_uri;
+use crate::rabbitmq::utils::{get_tls_options, get_uri};
+use crate::rabbitmq::tls::TlsOptions;
+use crate::tracing::{get_export_future, Tracer};
+use crate::writer::OutboundWriter;
+
+/// Content type of metrics in the Prometheus text format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+/// How often the connections are checked for exceeding the idle timeout.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings for detecting and closing dead connections.
+#[derive(Clone, Copy, Debug)]
+struct KeepaliveOptions {
+    ping_interval: Duration,
+    max_missed_pongs: usize,
+    idle_timeout: Duration
+}
+
+/// Tracks whether the client is still responding.
+struct Liveness {
+    missed_pongs: AtomicUsize,
+    last_activity: Mutex<Instant>
+}
+
+impl Liveness {
+    /// Returns a new instance of `Liveness`.
+    fn new() -> Liveness {
+        Liveness {
+            missed_pongs: AtomicUsize::new(0),
+            last_activity: Mutex::new(Instant::now())
+        }
+    }
+
+    /// Marks the connection as active after receiving a frame from the client.
+    fn on_frame(&self, message: &Message) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+        if let Message::Pong(_) = message {
+            self.missed_pongs.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Type alias for the stream of accepted connections from all listeners.
+type IncomingStream = Box<dyn Stream<Item=TcpStream, Error=Error> + Send>;
+
+/// A handle to the running reverse proxy, returned by `Proxy::run`.
+pub struct ProxyHandle {
+    local_addrs: Vec<SocketAddr>,
+    runtime: Runtime,
+    shutdown: Option<oneshot::Sender<()>>,
+    completion: oneshot::Receiver<()>
+}
+
+impl ProxyHandle {
+    /// Returns the address the server is listening on. Useful when the proxy
+    /// was started on the port 0. For several addresses returns the first one.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Returns all addresses the server is listening on.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Stops accepting new connections, waits during the grace period until
+    /// the existing connections are closed and stops the runtime. Works the
+    /// same way as receiving SIGTERM.
+    pub fn shutdown(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).unwrap_or(());
+        }
+        self.wait();
+    }
+
+    /// Blocks the current thread until the server is stopped by a termination
+    /// signal or fails.
+    pub fn wait(self) {
+        let _shutdown = self.shutdown;
+        self.completion.wait().unwrap_or(());
+        self.runtime.shutdown_now().wait().unwrap_or(());
+    }
+}
 
 /// A reverse proxy application.
 pub struct Proxy {
     engine: Arc<Engine>,
+    config_poll_interval: Option<Duration>,
     amqp_uri: Arc<AMQPUri>,
-    connections: Arc<Mutex<HashMap<SocketAddr, MessageSender>>>
+    tls_options: Arc<TlsOptions>,
+    use_direct_reply_to: bool,
+    use_publisher_confirms: bool,
+    prefetch_count: u16,
+    queue_declare_rate: u32,
+    channel_pool_size: usize,
+    shutdown_grace_period: Duration,
+    shutdown_message: Option<Message>,
+    keepalive: KeepaliveOptions,
+    close_on_fatal_errors: bool,
+    ignore_bind_errors: bool,
+    listen_fd: Option<i32>,
+    max_pending_messages: usize,
+    max_message_size: usize,
+    http_fallback_path: Option<String>,
+    allowed_origins: Arc<Vec<String>>,
+    subprotocols: Arc<Vec<String>>,
+    connection_limits: Arc<ConnectionLimits>,
+    rate_limit: u32,
+    rate_limit_burst: u32,
+    max_in_flight_requests: usize,
+    metrics_address: Option<SocketAddr>,
+    metrics_path: String,
+    expose_routes: bool,
+    routes_token: String,
+    broadcast_exchange: String,
+    tracer: Option<Arc<Tracer>>,
+    hooks: Arc<dyn ConnectionHooks>,
+    connections: Arc<Mutex<HashMap<SocketAddr, MessageSender>>>,
+    contexts: Arc<Mutex<HashMap<SocketAddr, Arc<RabbitMQContext>>>>
 }
 
 impl Proxy {
-    /// Returns a new instance of a reverse proxy application.
-    pub fn new(cli: &CliOptions) -> Proxy {
-        let engine = Engine::new(cli);
-        let amqp_uri = get_uri(cli);
+    /// Returns a new instance of a reverse proxy application. Fails when the
+    /// secrets can't be loaded from the specified files or the configuration
+    /// contains invalid settings.
+    pub fn new(config: &ProxyConfig) -> Result<Proxy, FailureError> {
+        Proxy::new_with_transformers(config, Transformers::new())
+    }
+
+    /// Works the same way as `Proxy::new`, but registers the custom
+    /// transformers of payloads, that can be referenced by their names in
+    /// the configuration of endpoints.
+    pub fn new_with_transformers(config: &ProxyConfig, transformers: Transformers) -> Result<Proxy, FailureError> {
+        let tracer = match config.tracing_endpoint.is_empty() {
+            true => None,
+            false => Some(Arc::new(Tracer::new(&config.tracing_endpoint, config.tracing_sample_ratio)?))
+        };
+        let engine = Engine::new_with_transformers(config, transformers);
+        let engine = match tracer {
+            Some(ref tracer) => engine.with_tracer(tracer.clone()),
+            None => engine
+        };
+        engine.validate()?;
+        let amqp_uri = get_uri(config)?;
+        let shutdown_message = get_shutdown_message(&config.shutdown_message)?;
+        let connection_limits = ConnectionLimits::new(config.max_connections_per_ip, config.max_connections);
 
-        Proxy {
+        Ok(Proxy {
             engine: Arc::new(engine),
+            config_poll_interval: Some(config.config_poll_interval).filter(|interval| *interval > Duration::from_secs(0)),
             amqp_uri: Arc::new(amqp_uri),
-            connections: Arc::new(Mutex::new(HashMap::new()))
-        }
+            tls_options: Arc::new(get_tls_options(config)),
+            use_direct_reply_to: config.rabbitmq_direct_reply_to,
+            use_publisher_confirms: !config.rabbitmq_no_confirms,
+            prefetch_count: config.rabbitmq_prefetch,
+            queue_declare_rate: config.rabbitmq_declare_rate,
+            channel_pool_size: config.rabbitmq_channel_pool_size,
+            shutdown_grace_period: config.shutdown_grace_period,
+            shutdown_message,
+            keepalive: KeepaliveOptions {
+                ping_interval: config.ping_interval,
+                max_missed_pongs: config.max_missed_pongs,
+                idle_timeout: config.idle_timeout
+            },
+            close_on_fatal_errors: config.close_on_fatal_errors,
+            ignore_bind_errors: config.ignore_bind_errors,
+            listen_fd: config.listen_fd,
+            max_pending_messages: config.max_pending_messages,
+            max_message_size: config.max_message_size,
+            http_fallback_path: Some(config.http_fallback_path.clone()).filter(|path| !path.is_empty()),
+            allowed_origins: Arc::new(config.allowed_origins.clone()),
+            subprotocols: Arc::new(config.subprotocols.clone()),
+            connection_limits: Arc::new(connection_limits),
+            rate_limit: config.rate_limit,
+            rate_limit_burst: config.rate_limit_burst,
+            max_in_flight_requests: config.max_in_flight_requests,
+            metrics_address: config.metrics_address,
+            metrics_path: config.metrics_path.clone(),
+            expose_routes: config.expose_routes,
+            routes_token: config.routes_token.get_value().to_string(),
+            broadcast_exchange: AmqpPrefix::new(&config.amqp_prefix).apply(&config.broadcast_exchange),
+            tracer,
+            hooks: Arc::new(EmptyConnectionHooks),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            contexts: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    /// Sets the hooks that are called when a client connects and disconnects.
+    pub fn with_connection_hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Proxy {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Sends the message to each client subscribed onto the topic (e.g. a
+    /// room ID) and returns the number of clients that received it.
+    pub fn broadcast(&self, topic: &str, message: Message) -> usize {
+        self.engine.get_broadcaster().broadcast(topic, message)
+    }
+
+    /// Run the server on the specified address and the port in the background
+    /// and return a handle for controlling it. On receiving SIGTERM or SIGINT
+    /// or calling `ProxyHandle::shutdown` the server stops accepting new
+    /// connections and waits during the grace period until the existing
+    /// connections are closed.
+    pub fn run(&self, address: SocketAddr) -> ProxyHandle {
+        self.run_on(&[address])
     }
 
-    /// Run the server on the specified address and the port.
-    pub fn run(&self, address: SocketAddr) {
-        let listener = TcpListener::bind(&address).unwrap();
-        info!("Listening on: {}", address);
+    /// Works the same way as `Proxy::run`, but listens on each of the
+    /// specified addresses (e.g. IPv4 and IPv6 ones). All listeners share
+    /// the engine and the connection to RabbitMQ. When the listening socket
+    /// was passed by the descriptor, it's used instead of the first address.
+    pub fn run_on(&self, addresses: &[SocketAddr]) -> ProxyHandle {
+        let listeners = self.bind_listeners(addresses);
+        let local_addrs = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
 
         let engine = self.engine.clone();
         let connections = self.connections.clone();
+        let contexts = self.contexts.clone();
+        let keepalive = self.keepalive;
+        let close_on_fatal_errors = self.close_on_fatal_errors;
+        let max_pending_messages = self.max_pending_messages;
+        let max_message_size = self.max_message_size;
+        let http_fallback_path = self.http_fallback_path.clone();
+        let allowed_origins = self.allowed_origins.clone();
+        let subprotocols = self.subprotocols.clone();
+        let connection_limits = self.connection_limits.clone();
+        let rate_limit = self.rate_limit;
+        let rate_limit_burst = self.rate_limit_burst;
+        let max_in_flight_requests = self.max_in_flight_requests;
+        let hooks = self.hooks.clone();
 
-        let server = |rabbitmq: Arc<RabbitMQClient>| {
-            listener.incoming().for_each(move |stream| {
+        let server = move |rabbitmq: Arc<RabbitMQClient>| {
+            get_incoming_stream(listeners).for_each(move |stream| {
                 let addr = stream
                     .peer_addr()
                     .expect("Connected stream should have a peer address.");
 
                 let engine_local = engine.clone();
+                let error_template = engine.get_error_template();
+                let error_template_for_busy = engine.get_error_template();
                 let rabbimq_local = rabbitmq.clone();
+                let rabbitmq_for_clean = rabbitmq.clone();
                 let connections_local = connections.clone();
+                let contexts_local = contexts.clone();
+                let rabbitmq_for_health = rabbitmq.clone();
+                let engine_for_health = engine.clone();
+                let allowed_origins_local = allowed_origins.clone();
+                let subprotocols_local = subprotocols.clone();
+                let connection_limits_local = connection_limits.clone();
+                let http_fallback_path_local = http_fallback_path.clone();
+                let engine_for_fallback = engine.clone();
+                let rabbitmq_for_fallback = rabbitmq.clone();
+                let hooks_local = hooks.clone();
 
-                accept_async(stream)
-                    // Processing an unexpected error during creation a new connection
-                    .map_err(|error| {
-                        let io_error = Error::new(ErrorKind::Other, error);
-                        PathfinderError::Io(io_error)
-                    })
-                    // Prepare lapin client context for further communication with RabbitMQ.
-                    .and_then(move |ws_stream| {
-                        let rabbitmq_inner = rabbimq_local.clone();
-                        rabbitmq_inner
-                            .get_context()
-                            .map(move |rabbitmq_context: Arc<RabbitMQContext>| (ws_stream, rabbitmq_context))
-                            .map_err(|error: LapinError| PathfinderError::LapinChannelError(error))
-                    })
-                    // Process the messages
-                    .and_then(move |(ws_stream, rabbitmq_context)| {
-                        let connections_inner = connections_local.clone();
-                        let connection_for_insert = connections_local.clone();
-                        let connection_for_remove = connections_local.clone();
-
-                        let rabbitmq_context_inner = rabbitmq_context.clone();
-                        let rabbitmq_context_for_clean = rabbitmq_context.clone();
-
-                        // Create a channel for the stream, which other sockets will use to
-                        // send us messages. It could be used for broadcasting your data to
-                        // another users in the future.
-                        let (tx, rx) = mpsc::unbounded();
-                        connection_for_insert.lock().unwrap().insert(addr, Arc::new(tx));
-
-                        // Split the WebSocket stream so that it will be possible to work
-                        // with the reading and writing halves separately.
-                        let (sink, stream) = ws_stream.split();
-
-                        // Read and process each message
-                        let ws_reader = stream.for_each(move |message: Message| {
-                            // Get references to required components
-                            let addr_nested = addr.clone();
-                            let connections_nested = connections_inner.clone();
-                            let transmitter_nested = connections_nested.lock().unwrap()[&addr_nested].clone();
-                            let transmitter_for_errors = connections_nested.lock().unwrap()[&addr_nested].clone();
-                            let rabbitmq_context_nested = rabbitmq_context_inner.clone();
-
-                            let process_request_future = engine_local
-                                .process_request(message, transmitter_nested, rabbitmq_context_nested)
-                                .map_err(move |error: PathfinderError| {
-                                    let response = match error {
-                                        PathfinderError::MicroserviceError(json) => {
-                                            let message = Arc::new(Box::new(json));
-                                            serialize_message(message)
-                                        },
-                                        _ => {
-                                            let error_message = format!("{}", error);
-                                            let error_type = error.as_static();
-                                            wrap_a_string_error(&error_type, error_message.as_str())
+                peek_request_head(stream)
+                    .map_err(PathfinderError::Io)
+                    // Answer to health checks without upgrading the connection to WebSocket
+                    .and_then(move |(stream, head)| {
+                        if is_health_check_request(&head) {
+                            let ping_options = engine_for_health.get_health_check_options();
+                            let future = check_health(rabbitmq_for_health, ping_options)
+                                .then(move |health| send_health_status(stream, health.unwrap_or(HealthStatus::Degraded)))
+                                .map_err(PathfinderError::Io);
+                            return Either::A(Either::A(Either::A(future)));
+                        }
+
+                        // Reject handshakes from disallowed origins with the 403 status
+                        if !is_allowed_request(&head, &allowed_origins_local) {
+                            debug!("Handshake from {} was rejected: the origin isn't allowed.", addr);
+                            return Either::A(Either::A(Either::B(send_forbidden(stream).map_err(PathfinderError::Io))));
+                        }
+
+                        // Process requests of clients that can't use WebSockets
+                        if http_fallback_path_local.is_some_and(|path| is_http_fallback_request(&head, &path)) {
+                            let broadcaster = engine_for_fallback.get_broadcaster();
+                            let error_template = engine_for_fallback.get_error_template();
+                            let process = move |message: Message, transmitter: MessageSender| {
+                                let transmitter_for_remove = transmitter.clone();
+                                rabbitmq_for_fallback
+                                    .get_context()
+                                    .map_err(|error| RequestError::new(PathfinderError::LapinChannelError(error)))
+                                    .and_then(move |rabbitmq_context| {
+                                        engine_for_fallback
+                                            .process_request(message, None, transmitter, rabbitmq_context.clone(), None)
+                                            .then(move |result| {
+                                                // Subscriptions can't be used without the connection
+                                                broadcaster.remove(&transmitter_for_remove);
+                                                rabbitmq_for_fallback.release_context(rabbitmq_context).then(move |_| result)
+                                            })
+                                    })
+                            };
+                            return Either::A(Either::B(process_http_request(stream, max_message_size, error_template, process)));
+                        }
+
+                        // Reserve a place for the connection. The excess connections are
+                        // closed right after the handshake
+                        let slot = ConnectionLimits::acquire(&connection_limits_local, addr.ip());
+                        if let Err(ref error) = slot {
+                            warn!("Connection from {} was rejected: {}.", addr, error);
+                        }
+
+                        // The negotiated subprotocol is stored per connection and selects
+                        // the format of messages
+                        let subprotocol = Arc::new(Mutex::new(None));
+                        let subprotocol_inner = subprotocol.clone();
+                        let handshake_callback = get_handshake_callback(allowed_origins_local, subprotocols_local, subprotocol);
+
+                        Either::B(
+                            accept_hdr_async(stream, handshake_callback)
+                                // Processing an unexpected error during creation a new connection. The
+                                // handshake fails mostly because of malformed requests of clients
+                                .map_err(|error| {
+                                    let io_error = Error::new(ErrorKind::InvalidData, error);
+                                    PathfinderError::Io(io_error)
+                                })
+                                // Prepare lapin client context for further communication with RabbitMQ.
+                                .and_then(move |ws_stream| {
+                                    let slot = match slot {
+                                        Ok(slot) => slot,
+                                        Err(error) => {
+                                            // Let the client know that it can reconnect later
+                                            let reason = format!("Connection from {} was closed: {}.", addr, error);
+                                            let error = PathfinderError::ServerBusy(error.to_string());
+                                            let response = error_template_for_busy.wrap_a_fatal_error(&error, None, &CloseCode::Again);
+                                            return Either::B(
+                                                ws_stream
+                                                    .send(response)
+                                                    .and_then(|mut ws_stream| poll_fn(move || ws_stream.close()))
+                                                    .then(move |_| Err(PathfinderError::Io(Error::new(ErrorKind::ConnectionRefused, reason))))
+                                            )
                                         }
                                     };
 
-                                    transmitter_for_errors.unbounded_send(response).unwrap_or(())
-                                });
-
-                            tokio::spawn(process_request_future);
-                            Ok(())
-                        });
-
-                        // Write back prepared responses
-                        let ws_writer = rx.fold(sink, |mut sink, msg| {
-                            sink.start_send(msg).unwrap();
-                            Ok(sink)
-                        });
-
-                        // Wait for either half to be done to tear down the other
-                        let connection = ws_reader
-                            .map(|_| ())
-                            .map_err(|_| ())
-                            .select(ws_writer.map(|_| ()).map_err(|_| ()));
-
-                        // Then clean up RabbitMQ context and close the connection after the usage
-                        let handler = connection
-                            .then(move |_| {
-                                debug!("Clean up RabbitMQ context.");
-                                rabbitmq_context_for_clean.close_channels()
-                            })
-                            .then(move |_| {
-                                connection_for_remove.lock().unwrap().remove(&addr);
-                                debug!("Connection {} closed.", addr);
-                                Ok(())
-                            });
-
-                        tokio::spawn(handler);
-                        Ok(())
+                                    let rabbitmq_inner = rabbimq_local.clone();
+                                    Either::A(
+                                        rabbitmq_inner
+                                            .get_context()
+                                            .map(move |rabbitmq_context: Arc<RabbitMQContext>| (ws_stream, rabbitmq_context, slot))
+                                            .map_err(|error: LapinError| PathfinderError::LapinChannelError(error))
+                                    )
+                                })
+                                // Process the messages
+                                .and_then(move |(ws_stream, rabbitmq_context, slot)| {
+                                    let subprotocol: Option<String> = subprotocol_inner.lock().unwrap().take();
+                                    let connections_inner = connections_local.clone();
+                                    let connection_for_insert = connections_local.clone();
+                                    let connection_for_remove = connections_local.clone();
+                                    let metrics_for_connection = engine_local.get_metrics();
+                                    let contexts_for_remove = contexts_local.clone();
+                                    let broadcaster_for_remove = engine_local.get_broadcaster();
+                                    contexts_local.lock().unwrap().insert(addr, rabbitmq_context.clone());
+
+                                    let rabbitmq_context_inner = rabbitmq_context.clone();
+                                    let rabbitmq_context_for_clean = rabbitmq_context.clone();
+
+                                    // Create a channel for the stream, which other sockets will use to
+                                    // send us messages. The subscriptions onto broadcasted messages
+                                    // are cancelled after closing the connection.
+                                    let (tx, rx) = mpsc::unbounded();
+                                    let transmitter = Arc::new(tx);
+                                    let transmitter_for_remove = transmitter.clone();
+                                    let connection_info = ConnectionInfo::new(addr).with_subprotocol(subprotocol.clone());
+                                    add_connection(&connection_for_insert, hooks_local.as_ref(), &connection_info, transmitter.clone());
+                                    metrics_for_connection.inc_connections();
+
+                                    // Send pings and watch for the client activity
+                                    let liveness = Arc::new(Liveness::new());
+                                    let liveness_inner = liveness.clone();
+                                    let keepalive_future = get_keepalive_future(addr, transmitter, liveness, keepalive);
+
+                                    // Split the WebSocket stream so that it will be possible to work
+                                    // with the reading and writing halves separately.
+                                    let (sink, stream) = ws_stream.split();
+
+                                    // The last message that must be sent before closing the connection
+                                    let closing_message: Arc<Mutex<Option<Message>>> = Arc::new(Mutex::new(None));
+                                    let closing_message_inner = closing_message.clone();
+
+                                    // Limit the rate of requests. The state is kept per connection, so
+                                    // that it's reset after reconnecting
+                                    let mut rate_limiter = match rate_limit {
+                                        0 => None,
+                                        rate => Some(TokenBucket::new(rate, rate_limit_burst))
+                                    };
+
+                                    // Limit the number of concurrent requests, so that the client
+                                    // can't hold broker resources without waiting for responses
+                                    let in_flight_requests = match max_in_flight_requests {
+                                        0 => None,
+                                        max_count => Some(Arc::new(InFlightRequests::new(max_count)))
+                                    };
+
+                                    // The user headers are kept per connection, when clients are
+                                    // authenticated by their first message
+                                    let session = engine_local.new_session();
+                                    let session_for_remove = session.clone();
+
+                                    // Read and process each message
+                                    let ws_reader = stream.for_each(move |message: Message| {
+                                        // Ignore messages after the connection started closing
+                                        if closing_message_inner.lock().unwrap().is_some() {
+                                            return Ok(());
+                                        }
+
+                                        // Control frames only confirm that the client is alive
+                                        liveness_inner.on_frame(&message);
+                                        if message.is_ping() || message.is_pong() {
+                                            return Ok(());
+                                        }
+
+                                        // Get references to required components
+                                        let addr_nested = addr;
+                                        let connections_nested = connections_inner.clone();
+                                        let transmitter_for_errors = connections_nested.lock().unwrap()[&addr_nested].clone();
+                                        let content_type = engine_local.get_content_type(&message, subprotocol.as_deref());
+
+                                        // Reject too large messages and close the connection
+                                        if let Err(error) = engine_local.check_message_size(&message) {
+                                            let response = error_template.wrap_a_fatal_error(&error, None, &CloseCode::Size);
+                                            let response = convert_message(response, content_type);
+                                            *closing_message_inner.lock().unwrap() = Some(response.clone());
+                                            transmitter_for_errors.unbounded_send(response).unwrap_or(());
+                                            return Ok(());
+                                        }
+
+                                        // The batch of requests is split into separate requests, that are
+                                        // limited and processed independently of each other
+                                        let messages = match engine_local.split_batch(message, subprotocol.as_deref()) {
+                                            Ok(messages) => messages,
+                                            Err(error) => {
+                                                engine_local.get_metrics().inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                                                let response = convert_message(error_template.wrap_an_error(&error, None), content_type);
+                                                transmitter_for_errors.unbounded_send(response).unwrap_or(());
+                                                return Ok(());
+                                            }
+                                        };
+
+                                        for message in messages {
+                                            let transmitter_nested = connections_nested.lock().unwrap()[&addr_nested].clone();
+                                            let transmitter_for_errors = transmitter_for_errors.clone();
+                                            let rabbitmq_context_nested = rabbitmq_context_inner.clone();
+                                            let closing_message_nested = closing_message_inner.clone();
+                                            let session_nested = session.clone();
+
+                                            // Reject requests over the rate limit without forwarding them
+                                            if let Some(Err(retry_after)) = rate_limiter.as_mut().map(|bucket| bucket.try_acquire()) {
+                                                let error = PathfinderError::RateLimited(retry_after);
+                                                engine_local.get_metrics().inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                                                let response = convert_message(error_template.wrap_an_error(&error, None), content_type);
+                                                transmitter_for_errors.unbounded_send(response).unwrap_or(());
+                                                continue;
+                                            }
+
+                                            // Reject requests over the limit of in-flight requests. The place
+                                            // is released when the request is processed
+                                            let in_flight_slot = match in_flight_requests.as_ref().map(InFlightRequests::acquire) {
+                                                Some(Err(error)) => {
+                                                    let error = PathfinderError::TooManyRequests(error.to_string());
+                                                    engine_local.get_metrics().inc_errors(UNKNOWN_ROUTING_KEY, &error);
+                                                    let response = convert_message(error_template.wrap_an_error(&error, None), content_type);
+                                                    transmitter_for_errors.unbounded_send(response).unwrap_or(());
+                                                    continue;
+                                                },
+                                                Some(Ok(slot)) => Some(slot),
+                                                None => None
+                                            };
+
+                                            let error_template_nested = error_template.clone();
+                                            let process_request_future = engine_local
+                                                .process_request(message, subprotocol.as_deref(), transmitter_nested, rabbitmq_context_nested, session_nested)
+                                                .map_err(move |request_error: RequestError| {
+                                                    let correlation_id = request_error.get_correlation_id();
+                                                    let error = request_error.get_error();
+                                                    log!(error.log_level(), "[{}] Request failed: {}", correlation_id.as_deref().unwrap_or("-"), error);
+                                                    let close_code = match close_on_fatal_errors || request_error.is_closing() {
+                                                        true => request_error.get_error().close_code(),
+                                                        false => None
+                                                    };
+                                                    let response = match (request_error.into_error(), close_code) {
+                                                        (PathfinderError::MicroserviceError(json), _) => {
+                                                            wrap_a_microservice_error(&json, correlation_id.as_deref())
+                                                        },
+                                                        (error, Some(close_code)) => {
+                                                            error_template_nested.wrap_a_fatal_error(&error, correlation_id.as_deref(), &close_code)
+                                                        },
+                                                        (error, None) => error_template_nested.wrap_an_error(&error, correlation_id.as_deref())
+                                                    };
+
+                                                    // The connection is closed right after sending the fatal error
+                                                    let response = convert_message(response, content_type);
+                                                    if close_code.is_some() {
+                                                        *closing_message_nested.lock().unwrap() = Some(response.clone());
+                                                    }
+                                                    transmitter_for_errors.unbounded_send(response).unwrap_or(())
+                                                })
+                                                .then(move |result| {
+                                                    drop(in_flight_slot);
+                                                    result
+                                                });
+
+                                            tokio::spawn(process_request_future);
+                                        }
+                                        Ok(())
+                                    });
+
+                                    // Write back prepared responses when the client is ready to read
+                                    // them. The closing handshake is started after sending the last message
+                                    let ws_writer = OutboundWriter::new(sink, rx, max_pending_messages, closing_message);
+
+                                    // Wait for either half to be done (or for the client to stop
+                                    // responding) to tear down the other
+                                    let connection = ws_reader
+                                        .map(|_| ())
+                                        .map_err(|_| ())
+                                        .select(ws_writer)
+                                        .map(|_| ())
+                                        .map_err(|_| ())
+                                        .select(keepalive_future);
+
+                                    // Then clean up RabbitMQ context and close the connection after the usage
+                                    let handler = connection
+                                        .then(move |_| {
+                                            debug!("Clean up RabbitMQ context.");
+                                            rabbitmq_for_clean.release_context(rabbitmq_context_for_clean)
+                                        })
+                                        .then(move |_| {
+                                            let user_headers = session_for_remove.and_then(|session| session.get_headers().ok());
+                                            let connection_info = connection_info.with_user_headers(user_headers);
+                                            remove_connection(&connection_for_remove, hooks_local.as_ref(), &connection_info);
+                                            metrics_for_connection.dec_connections();
+                                            broadcaster_for_remove.remove(&transmitter_for_remove);
+                                            contexts_for_remove.lock().unwrap().remove(&addr);
+                                            drop(slot);
+                                            debug!("Connection {} closed.", addr);
+                                            Ok(())
+                                        });
+
+                                    tokio::spawn(handler);
+                                    Ok(())
+                                })
+                        )
                     })
-                    // An unexpected error occurred during processing or the WebSocket handshake
+                    // An unexpected error occurred during processing or the WebSocket handshake.
+                    // Only failures of the server side are visible at the default verbosity
                     .or_else(|error| {
-                        debug!("{}", error);
+                        log!(error.log_level(), "{}", error);
                         Ok(())
                     })
             })
         };
 
         // Run the server
+        let broadcaster = self.engine.get_broadcaster();
+        let broadcast_exchange = self.broadcast_exchange.clone();
         let server_future = self
             .get_rabbitmq_client()
             .map_err(|error| error!("{}", error))
-            .and_then(|rabbitmq: Arc<RabbitMQClient>| {
+            .and_then(move |rabbitmq: Arc<RabbitMQClient>| {
+                if !broadcast_exchange.is_empty() {
+                    tokio::spawn(get_broadcast_future(rabbitmq.clone(), broadcast_exchange, broadcaster));
+                }
+
                 server(rabbitmq)
                     .map_err(|_error| ())
             });
 
-        tokio::runtime::run(server_future);
+        // Stop accepting new connections right after getting a signal, so
+        // that the listeners will be dropped and the clients will be rejected
+        // while the existing connections are drained
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let (completion_sender, completion_receiver) = oneshot::channel();
+        let shutdown_signal = self.get_shutdown_signal(shutdown_receiver);
+        let drain_future = self.get_drain_future();
+        let mut runtime = Runtime::new().unwrap();
+        if let Some(metrics_server) = self.metrics_address.and_then(|address| self.get_metrics_server(address)) {
+            runtime.spawn(metrics_server);
+        }
+        runtime.spawn(get_reload_future(self.engine.clone()));
+        if let Some(interval) = self.config_poll_interval {
+            runtime.spawn(get_poll_future(self.engine.clone(), interval));
+        }
+        if let Some(ref tracer) = self.tracer {
+            runtime.spawn(get_export_future(tracer.clone()));
+        }
+        runtime.spawn(
+            serve_until_shutdown(server_future, shutdown_signal, drain_future)
+                .then(move |_| completion_sender.send(()))
+                .map_err(|_| ())
+        );
+
+        ProxyHandle {
+            local_addrs,
+            runtime,
+            shutdown: Some(shutdown_sender),
+            completion: completion_receiver
+        }
+    }
+
+    /// Binds a listener for each address. When the address can't be used,
+    /// the error is logged and the other addresses are still listened, if
+    /// it's allowed by the settings. Panics when no listeners were bound.
+    fn bind_listeners(&self, addresses: &[SocketAddr]) -> Vec<TcpListener> {
+        let mut listeners = Vec::new();
+        let addresses = match self.listen_fd {
+            Some(fd) => {
+                match adopt_listener(fd) {
+                    Ok(listener) => {
+                        info!("Listening on: {} (file descriptor {})", listener.local_addr().unwrap(), fd);
+                        listeners.push(listener);
+                    },
+                    Err(error) if self.ignore_bind_errors => error!("Couldn't use the file descriptor {}: {}", fd, error),
+                    Err(error) => panic!("Couldn't use the file descriptor {}: {}", fd, error)
+                }
+                addresses.get(1..).unwrap_or(&[])
+            },
+            None => addresses
+        };
+
+        for address in addresses {
+            match TcpListener::bind(address) {
+                Ok(listener) => {
+                    info!("Listening on: {}", listener.local_addr().unwrap());
+                    listeners.push(listener);
+                },
+                Err(error) if self.ignore_bind_errors => error!("Couldn't listen on \"{}\": {}", address, error),
+                Err(error) => panic!("Couldn't listen on \"{}\": {}", address, error)
+            }
+        }
+
+        match listeners.is_empty() {
+            true => panic!("Couldn't listen on any of the addresses: {:?}", addresses),
+            false => listeners
+        }
+    }
+
+    /// Returns a future that exposes the collected metrics in the Prometheus
+    /// text format on the specified address. When it's enabled, the list of
+    /// the loaded routes is available on the same address. When the address
+    /// can't be used, the error is logged and the metrics server is skipped.
+    fn get_metrics_server(&self, address: SocketAddr) -> Option<impl Future<Item=(), Error=()> + Send + 'static> {
+        let listener = match TcpListener::bind(&address) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!("Couldn't expose metrics on \"{}\": {}", address, error);
+                return None;
+            }
+        };
+        info!("Metrics are available on: {}{}", address, self.metrics_path);
+        if self.expose_routes {
+            info!("Routes are available on: {}{}", address, ROUTES_PATH);
+        }
+
+        let engine = self.engine.clone();
+        let metrics = self.engine.get_metrics();
+        let metrics_path = self.metrics_path.clone();
+        let expose_routes = self.expose_routes;
+        let routes_token = self.routes_token.clone();
+        let server = listener.incoming()
+            .for_each(move |stream| {
+                let engine_local = engine.clone();
+                let metrics_local = metrics.clone();
+                let metrics_path_local = metrics_path.clone();
+                let routes_token_local = routes_token.clone();
+                let response_future = read_request_head(stream)
+                    .and_then(move |(stream, head)| {
+                        match get_request_target(&head) {
+                            Some(("GET", path)) if path == metrics_path_local => {
+                                send_response(stream, "200 OK", METRICS_CONTENT_TYPE, metrics_local.render())
+                            },
+                            Some(("GET", ROUTES_PATH)) if expose_routes => {
+                                match is_authorized_request(&head, &routes_token_local) {
+                                    true => send_response(stream, "200 OK", "application/json", engine_local.get_routes().dump()),
+                                    false => send_response(stream, "401 Unauthorized", "text/plain", String::from("Unauthorized"))
+                                }
+                            },
+                            _ => send_response(stream, "404 Not Found", "text/plain", String::from("Not Found"))
+                        }
+                    })
+                    .map_err(|error| debug!("Can't send metrics: {}", error));
+
+                tokio::spawn(response_future);
+                Ok(())
+            })
+            .map_err(|error| error!("Metrics server error: {}", error));
+        Some(server)
+    }
+
+    /// Returns a future that will be resolved after receiving a termination
+    /// signal or a shutdown request from the handle.
+    fn get_shutdown_signal(&self, trigger: oneshot::Receiver<()>) -> impl Future<Item=(), Error=()> + Send + 'static {
+        // The dropped handle doesn't stop the server
+        let trigger = trigger.or_else(|_| empty());
+        get_termination_signal()
+            .map_err(|error| error!("Can't handle termination signals: {}", error))
+            .select(trigger)
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Returns a future that will be resolved after closing the existing
+    /// connections or when the grace period is over.
+    fn get_drain_future(&self) -> impl Future<Item=(), Error=()> + Send + 'static {
+        let connections = self.connections.clone();
+        let contexts = self.contexts.clone();
+        let grace_period = self.shutdown_grace_period;
+        let shutdown_message = self.shutdown_message.clone();
+
+        lazy(move || {
+            info!("Shutting down. Waiting {} seconds for active connections.", grace_period.as_secs());
+            if let Some(ref message) = shutdown_message {
+                let count = notify_connections(&connections, message);
+                debug!("The shutdown notice was sent to {} clients.", count);
+            }
+            let deadline = Instant::now() + grace_period;
+            Interval::new_interval(Duration::from_millis(100))
+                .map_err(|error| error!("Timer error: {}", error))
+                .take_while(move |_| {
+                    let has_connections = !connections.lock().unwrap().is_empty();
+                    Ok(has_connections && Instant::now() < deadline)
+                })
+                .for_each(|_| Ok(()))
+        })
+        // Clean up RabbitMQ contexts of the connections, that are still opened
+        .and_then(move |_| {
+            let contexts: Vec<Arc<RabbitMQContext>> = contexts.lock().unwrap().values().cloned().collect();
+            if !contexts.is_empty() {
+                warn!("{} connection(s) were not closed during the grace period.", contexts.len());
+            }
+            join_all(contexts.into_iter().map(|context| context.close_channels().then(|_| Ok(()))))
+        })
+        .map(|_: Vec<()>| info!("Shutdown completed."))
     }
 
-    fn get_rabbitmq_client(&self) -> impl Future<Item=Arc<RabbitMQClient>, Error=PathfinderError> + Sync + Send + 'static {
+    fn get_rabbitmq_client(&self) -> impl Future<Item=Arc<RabbitMQClient>, Error=PathfinderError> + Send + 'static {
         let amqp_uri = self.amqp_uri.clone();
-        RabbitMQClient::connect(amqp_uri.as_ref())
-            .map(|client| Arc::new(client))
+        let tls_options = self.tls_options.clone();
+        let use_direct_reply_to = self.use_direct_reply_to;
+        let use_publisher_confirms = self.use_publisher_confirms;
+        let prefetch_count = self.prefetch_count;
+        let queue_declare_rate = self.queue_declare_rate;
+        let channel_pool_size = self.channel_pool_size;
+        RabbitMQClient::connect(amqp_uri.as_ref(), tls_options.as_ref())
+            .map(move |client| {
+                let client = client
+                    .with_direct_reply_to(use_direct_reply_to)
+                    .with_publisher_confirms(use_publisher_confirms)
+                    .with_prefetch_count(prefetch_count)
+                    .with_queue_declare_rate(queue_declare_rate)
+                    .with_pool_size(channel_pool_size);
+                Arc::new(client)
+            })
             .map_err(|error| {
                 let failure_error = error.compat().into_inner();
                 PathfinderError::LapinError(failure_error)
             })
     }
 }
+
+/// Returns a listener for the already open socket, e.g. the one passed by
+/// systemd or by the previous process on a restart.
+#[cfg(unix)]
+fn adopt_listener(fd: i32) -> Result<TcpListener, Error> {
+    use std::os::unix::io::FromRawFd;
+
+    // The socket is owned by the listener since here and closed together with it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // Fails for descriptors that aren't sockets
+    listener.local_addr()?;
+    TcpListener::from_std(listener, &Handle::default())
+}
+
+#[cfg(not(unix))]
+fn adopt_listener(_fd: i32) -> Result<TcpListener, Error> {
+    Err(Error::new(ErrorKind::Other, "listening on file descriptors is supported on Unix only"))
+}
+
+/// Returns the health status of the proxy. When the ping options are
+/// specified, the alive connection is checked by a round-trip to the
+/// microservice, because the broker can be stuck with the opened connection.
+fn check_health(rabbitmq: Arc<RabbitMQClient>, ping_options: Option<Arc<RpcOptions>>) -> impl Future<Item=HealthStatus, Error=()> {
+    let ping_options = match (rabbitmq.is_connected(), ping_options) {
+        (false, _) => return Either::A(ok(HealthStatus::Unavailable)),
+        (true, None) => return Either::A(ok(HealthStatus::Healthy)),
+        (true, Some(ping_options)) => ping_options
+    };
+
+    let rabbitmq_for_release = rabbitmq.clone();
+    Either::B(
+        rabbitmq
+            .get_context()
+            .map_err(|err| warn!("The health check ping wasn't sent: {}", err))
+            .and_then(move |rabbitmq_context| {
+                ping_request_future(rabbitmq_context.clone(), ping_options)
+                    .then(move |result| rabbitmq_for_release.release_context(rabbitmq_context).then(move |_| result))
+            })
+            .then(|result| match result {
+                Ok(true) => Ok(HealthStatus::Healthy),
+                _ => Ok(HealthStatus::Degraded)
+            })
+    )
+}
+
+/// Reads the request sent via the HTTP fallback and passes its body to the
+/// engine as a WebSocket message. The messages sent by the engine to the
+/// client are returned in the body of the response: a single response as
+/// is, and several ones (e.g. the acknowledgement and the response, or the
+/// parts of a streamed response) as a JSON array. Errors are returned in
+/// the same format with the HTTP status depending on the error.
+fn process_http_request<F, R>(
+    stream: TcpStream,
+    max_body_size: usize,
+    error_template: Arc<ErrorTemplate>,
+    process: F
+) -> impl Future<Item=(), Error=PathfinderError>
+    where F: FnOnce(Message, MessageSender) -> R + Send + 'static,
+          R: Future<Item=(), Error=RequestError> + Send + 'static
+{
+    read_request(stream, max_body_size)
+        .map_err(PathfinderError::Io)
+        .and_then(move |(stream, head, body)| {
+            let body = match body {
+                Some(body) => body,
+                None => {
+                    let error = PathfinderError::DecodingError(format!("The message size exceeds the limit of {} bytes", max_body_size));
+                    let response = get_message_text(error_template.wrap_an_error(&error, None));
+                    return Either::A(send_response(stream, "413 Payload Too Large", "application/json", response).map_err(PathfinderError::Io));
+                }
+            };
+
+            let (transmitter, mut receiver) = mpsc::unbounded();
+            let transmitter = Arc::new(transmitter);
+            let transmitter_for_errors = transmitter.clone();
+            let future = process(get_http_fallback_message(&head, &body), transmitter)
+                .then(move |result| {
+                    let status = match result {
+                        Ok(_) => "200 OK",
+                        Err(request_error) => {
+                            let correlation_id = request_error.get_correlation_id();
+                            let error = request_error.into_error();
+                            log!(error.log_level(), "[{}] Request failed: {}", correlation_id.as_deref().unwrap_or("-"), error);
+                            let response = match error {
+                                PathfinderError::MicroserviceError(ref json) => wrap_a_microservice_error(json, correlation_id.as_deref()),
+                                ref error => error_template.wrap_an_error(error, correlation_id.as_deref())
+                            };
+                            transmitter_for_errors.unbounded_send(response).unwrap_or(());
+                            error.http_status()
+                        }
+                    };
+
+                    // The request is completed, so all messages were already sent
+                    let mut messages = Vec::new();
+                    while let Ok(Async::Ready(Some(message))) = receiver.poll() {
+                        messages.push(get_message_text(message));
+                    }
+                    let body = match messages.len() {
+                        1 => messages.remove(0),
+                        _ => format!("[{}]", messages.join(","))
+                    };
+                    Ok((stream, status, body))
+                })
+                .and_then(|(stream, status, body)| send_response(stream, status, "application/json", body).map_err(PathfinderError::Io));
+            Either::B(future)
+        })
+}
+
+/// Returns the text of the message, that is sent to the client via HTTP.
+fn get_message_text(message: Message) -> String {
+    match message {
+        Message::Text(text) => text,
+        message => String::from_utf8_lossy(&message.into_data()).to_string()
+    }
+}
+
+/// Returns the notice about the shutdown for clients. Fails when the body
+/// isn't a JSON object, and returns `None` when the notice is disabled.
+fn get_shutdown_message(body: &str) -> Result<Option<Message>, FailureError> {
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match json::parse(body) {
+        Ok(ref json) if json.is_object() => Ok(Some(Message::Text(json.dump()))),
+        _ => Err(err_msg(format!("The shutdown message must be a JSON object, but got: {}", body)))
+    }
+}
+
+/// Sends the message to each connected client and returns the number of
+/// clients that received it.
+fn notify_connections(connections: &Mutex<HashMap<SocketAddr, MessageSender>>, message: &Message) -> usize {
+    connections
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|transmitter| transmitter.unbounded_send(message.clone()).is_ok())
+        .count()
+}
+
+/// Registers the accepted connection and calls the hook.
+fn add_connection(
+    connections: &Mutex<HashMap<SocketAddr, MessageSender>>,
+    hooks: &dyn ConnectionHooks,
+    info: &ConnectionInfo,
+    transmitter: MessageSender
+) {
+    connections.lock().unwrap().insert(info.get_addr(), transmitter);
+    hooks.on_connect(info);
+}
+
+/// Removes the closed connection and calls the hook, unless the connection
+/// was removed already.
+fn remove_connection(connections: &Mutex<HashMap<SocketAddr, MessageSender>>, hooks: &dyn ConnectionHooks, info: &ConnectionInfo) {
+    let is_removed = connections.lock().unwrap().remove(&info.get_addr()).is_some();
+    if is_removed {
+        hooks.on_disconnect(info);
+    }
+}
+
+/// Merges the connections accepted by all listeners into a single stream.
+fn get_incoming_stream(listeners: Vec<TcpListener>) -> IncomingStream {
+    listeners
+        .into_iter()
+        .map(|listener| Box::new(listener.incoming()) as IncomingStream)
+        .fold(Box::new(stream::empty()), |incoming, listener_incoming| Box::new(incoming.select(listener_incoming)))
+}
+
+/// Runs the server until the shutdown signal is received. The server (with
+/// its listeners) is dropped right away, so that new clients are refused
+/// while the existing connections are drained. When the server stops by
+/// itself (e.g. it couldn't connect to RabbitMQ), nothing is drained.
+fn serve_until_shutdown<S, T, D>(server: S, shutdown_signal: T, drain: D) -> impl Future<Item=(), Error=()> + Send + 'static
+    where S: Future<Item=(), Error=()> + Send + 'static,
+          T: Future<Item=(), Error=()> + Send + 'static,
+          D: Future<Item=(), Error=()> + Send + 'static
+{
+    server.select2(shutdown_signal).then(move |result| match result {
+        Ok(Either::B(_)) => Either::A(drain),
+        _ => Either::B(ok(()))
+    })
+}
+
+/// Returns a future that periodically sends pings to the client. The future
+/// fails when the client missed too many pongs or didn't send any frames
+/// during the idle timeout, so that the connection must be closed.
+fn get_keepalive_future(
+    addr: SocketAddr,
+    transmitter: MessageSender,
+    liveness: Arc<Liveness>,
+    options: KeepaliveOptions
+) -> impl Future<Item=(), Error=()> + Send + 'static {
+    let liveness_inner = liveness.clone();
+
+    let pings = match options.ping_interval.as_secs() {
+        0 => Either::A(empty()),
+        _ => Either::B(
+            Interval::new(Instant::now() + options.ping_interval, options.ping_interval)
+                .map_err(|error| error!("Timer error: {}", error))
+                .for_each(move |_| {
+                    if liveness.missed_pongs.fetch_add(1, Ordering::SeqCst) >= options.max_missed_pongs {
+                        info!("Connection {} didn't respond to {} pings.", addr, options.max_missed_pongs);
+                        return Err(());
+                    }
+                    transmitter.unbounded_send(Message::Ping(Vec::new())).map_err(|_| ())
+                })
+        )
+    };
+
+    let idle_timeout = match options.idle_timeout.as_secs() {
+        0 => Either::A(empty()),
+        _ => Either::B(
+            Interval::new(Instant::now() + IDLE_CHECK_INTERVAL, IDLE_CHECK_INTERVAL)
+                .map_err(|error| error!("Timer error: {}", error))
+                .for_each(move |_| {
+                    if liveness_inner.last_activity.lock().unwrap().elapsed() >= options.idle_timeout {
+                        info!("Connection {} was idle for {} seconds.", addr, options.idle_timeout.as_secs());
+                        return Err(());
+                    }
+                    Ok(())
+                })
+        )
+    };
+
+    pings.select(idle_timeout).map(|_| ()).map_err(|_| ())
+}
+
+/// Returns a future that consumes messages published into the topic exchange
+/// and sends them to the clients subscribed onto the routing key of each message.
+fn get_broadcast_future(
+    rabbitmq: Arc<RabbitMQClient>,
+    exchange: String,
+    broadcaster: Arc<Broadcaster>
+) -> impl Future<Item=(), Error=()> + Send + 'static {
+    let exchange_declare_options = ExchangeDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+    let queue_declare_options = QueueDeclareOptions {
+        exclusive: true,
+        auto_delete: true,
+        ..Default::default()
+    };
+    let consume_options = BasicConsumeOptions {
+        no_ack: true,
+        ..Default::default()
+    };
+
+    rabbitmq
+        .get_context()
+        .map(|rabbitmq_context: Arc<RabbitMQContext>| rabbitmq_context.get_consume_channel())
+        // 1. Declare the exchange and a server-named queue for this instance
+        .and_then(move |channel| {
+            channel
+                .exchange_declare(&exchange, "topic", exchange_declare_options, FieldTable::new())
+                .map(move |_| (channel, exchange))
+        })
+        .and_then(move |(channel, exchange)| {
+            channel
+                .queue_declare("", queue_declare_options, FieldTable::new())
+                .map(move |queue| (channel, exchange, queue))
+        })
+        // 2. Receive messages for all topics
+        .and_then(|(channel, exchange, queue)| {
+            channel
+                .queue_bind(&queue.name(), &exchange, "#", QueueBindOptions::default(), FieldTable::new())
+                .map(move |_| (channel, queue))
+        })
+        .and_then(|(channel, queue)| {
+            channel.basic_consume(&queue, "broadcast_consumer", consume_options, FieldTable::new())
+        })
+        // 3. Pass each message to the subscribers
+        .and_then(move |stream| {
+            stream.for_each(move |delivery| {
+                match String::from_utf8(delivery.data) {
+                    Ok(text) => {
+                        let count = broadcaster.broadcast(&delivery.routing_key, Message::Text(text));
+                        debug!("Message for \"{}\" was sent to {} clients.", delivery.routing_key, count);
+                    },
+                    Err(_) => warn!("Message for \"{}\" wasn't broadcasted: it isn't a valid UTF-8 string.", delivery.routing_key)
+                };
+                Ok(())
+            })
+        })
+        .map_err(|error: LapinError| error!("Broadcast consumer error: {}", error))
+}
+
+/// Returns a future that reloads the endpoints configuration on receiving SIGHUP.
+#[cfg(unix)]
+fn get_reload_future(engine: Arc<Engine>) -> impl Future<Item=(), Error=()> + Send + 'static {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    lazy(move || {
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .map_err(|error| error!("Can't handle the SIGHUP signal: {}", error))
+            .for_each(move |_| {
+                info!("Reloading configuration.");
+                reload_config(engine.clone()).map(|result| {
+                    if let Err(error) = result {
+                        error!("Configuration wasn't reloaded: {}", error);
+                    }
+                })
+            })
+    })
+}
+
+/// Reloading the configuration by a signal is supported only on Unix.
+#[cfg(not(unix))]
+fn get_reload_future(_engine: Arc<Engine>) -> impl Future<Item=(), Error=()> + Send + 'static {
+    empty()
+}
+
+/// Returns a future that reloads the endpoints configuration periodically.
+/// When the configuration can't be loaded, the last valid one is kept.
+fn get_poll_future(engine: Arc<Engine>, interval: Duration) -> impl Future<Item=(), Error=()> + Send + 'static {
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|error| error!("Configuration polling error: {}", error))
+        .for_each(move |_| {
+            reload_config(engine.clone()).map(|result| {
+                if let Err(error) = result {
+                    warn!("Configuration wasn't reloaded, the last valid one is kept: {}", error);
+                }
+            })
+        })
+}
+
+/// Reloads the configuration on a separate thread, so that reading the files
+/// or fetching the remote configuration doesn't block the event loop.
+fn reload_config(engine: Arc<Engine>) -> impl Future<Item=Result<(), PathfinderError>, Error=()> + Send + 'static {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || sender.send(engine.reload()).unwrap_or(()));
+    receiver.map_err(|_| error!("Configuration reloading was interrupted."))
+}
+
+/// Returns a future that will be resolved after receiving SIGINT or SIGTERM.
+#[cfg(unix)]
+fn get_termination_signal() -> impl Future<Item=(), Error=Error> + Send + 'static {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    lazy(|| {
+        let sigint = Signal::new(SIGINT).flatten_stream();
+        let sigterm = Signal::new(SIGTERM).flatten_stream();
+        sigint.select(sigterm)
+            .into_future()
+            .map(|_| ())
+            .map_err(|(error, _)| error)
+    })
+}
+
+/// Returns a future that will be resolved after receiving Ctrl-C.
+#[cfg(not(unix))]
+fn get_termination_signal() -> impl Future<Item=(), Error=Error> + Send + 'static {
+    lazy(|| {
+        tokio_signal::ctrl_c()
+            .flatten_stream()
+            .into_future()
+            .map(|_| ())
+            .map_err(|(error, _)| error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use futures::future::lazy;
+    use futures::sync::{mpsc, oneshot};
+    use futures::{Future, Stream};
+    use json::parse as parse_json;
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+    use tungstenite::protocol::Message;
+
+    use crate::config::ProxyConfig;
+    use crate::engine::{ErrorTemplate, MessageSender};
+    use crate::error::{PathfinderError, RequestError};
+    use crate::hooks::{ConnectionHooks, ConnectionInfo};
+    use crate::proxy::{
+        add_connection, get_incoming_stream, get_shutdown_message, notify_connections, process_http_request,
+        remove_connection, serve_until_shutdown, Proxy
+    };
+
+    #[derive(Default)]
+    struct RecordingHooks {
+        connected: Mutex<Vec<ConnectionInfo>>,
+        disconnected: Mutex<Vec<ConnectionInfo>>
+    }
+
+    impl ConnectionHooks for RecordingHooks {
+        fn on_connect(&self, info: &ConnectionInfo) {
+            self.connected.lock().unwrap().push(info.clone());
+        }
+
+        fn on_disconnect(&self, info: &ConnectionInfo) {
+            self.disconnected.lock().unwrap().push(info.clone());
+        }
+    }
+
+    fn get_proxy(ignore_bind_errors: bool) -> Proxy {
+        let config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            ignore_bind_errors,
+            ..ProxyConfig::default()
+        };
+        Proxy::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_run_returns_a_handle_that_stops_the_proxy() {
+        let config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            ..ProxyConfig::default()
+        };
+        let proxy = Proxy::new(&config).unwrap();
+
+        let handle = proxy.run("127.0.0.1:0".parse().unwrap());
+        assert_ne!(handle.local_addr().port(), 0);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_run_on_listens_on_each_address() {
+        let addresses: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+        let handle = get_proxy(false).run_on(&addresses);
+        assert_eq!(handle.local_addrs().len(), 2);
+        assert_ne!(handle.local_addrs()[0], handle.local_addrs()[1]);
+        assert_eq!(handle.local_addr(), handle.local_addrs()[0]);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_run_on_skips_busy_addresses_when_bind_errors_are_ignored() {
+        let busy_listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addresses = vec![busy_listener.local_addr().unwrap(), "127.0.0.1:0".parse().unwrap()];
+        let handle = get_proxy(true).run_on(&addresses);
+        assert_eq!(handle.local_addrs().len(), 1);
+        assert_ne!(handle.local_addr(), busy_listener.local_addr().unwrap());
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_run_on_skips_the_metrics_server_on_a_busy_address() {
+        let busy_listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            metrics_address: Some(busy_listener.local_addr().unwrap()),
+            ..ProxyConfig::default()
+        };
+        let addresses: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap()];
+
+        let handle = Proxy::new(&config).unwrap().run_on(&addresses);
+        assert_eq!(handle.local_addrs().len(), 1);
+        handle.shutdown();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_on_uses_the_listener_passed_by_the_descriptor() {
+        use std::os::unix::io::IntoRawFd;
+
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let passed_addr = listener.local_addr().unwrap();
+        let config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            listen_fd: Some(listener.into_raw_fd()),
+            ..ProxyConfig::default()
+        };
+        let addresses: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+
+        let handle = Proxy::new(&config).unwrap().run_on(&addresses);
+        assert_eq!(handle.local_addrs().len(), 2);
+        assert_eq!(handle.local_addr(), passed_addr);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_incoming_stream_accepts_connections_from_all_listeners() {
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listeners = vec![TcpListener::bind(&address).unwrap(), TcpListener::bind(&address).unwrap()];
+        let local_addrs: Vec<SocketAddr> = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
+
+        let _clients: Vec<StdTcpStream> = local_addrs.iter().map(|addr| StdTcpStream::connect(addr).unwrap()).collect();
+        let mut runtime = Runtime::new().unwrap();
+        let accepted = runtime.block_on(get_incoming_stream(listeners).take(2).collect()).unwrap();
+        let mut accepted_addrs: Vec<SocketAddr> = accepted.iter().map(|stream| stream.local_addr().unwrap()).collect();
+        accepted_addrs.sort();
+
+        let mut expected_addrs = local_addrs.clone();
+        expected_addrs.sort();
+        assert_eq!(accepted_addrs, expected_addrs);
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    #[test]
+    fn test_serve_until_shutdown_refuses_connections_during_draining() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = get_incoming_stream(vec![listener]).for_each(|_| Ok(())).map_err(|_| ());
+        let (signal_sender, signal_receiver) = oneshot::channel::<()>();
+        let (draining_sender, draining_receiver) = oneshot::channel::<()>();
+        let (drained_sender, drained_receiver) = oneshot::channel::<()>();
+        let drain = lazy(move || {
+            draining_sender.send(()).unwrap();
+            drained_receiver.map_err(|_| ())
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let (completion_sender, completion_receiver) = oneshot::channel();
+        runtime.spawn(
+            serve_until_shutdown(server, signal_receiver.map_err(|_| ()), drain)
+                .then(move |_| completion_sender.send(()))
+                .map_err(|_| ())
+        );
+        assert!(StdTcpStream::connect(addr).is_ok());
+
+        signal_sender.send(()).unwrap();
+        draining_receiver.wait().unwrap();
+        assert!(StdTcpStream::connect(addr).is_err());
+
+        drained_sender.send(()).unwrap();
+        completion_receiver.wait().unwrap();
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    #[test]
+    fn test_connection_hooks_are_called_once_per_connection() {
+        let connections = Mutex::new(HashMap::new());
+        let hooks = RecordingHooks::default();
+        let first = ConnectionInfo::new("127.0.0.1:1".parse().unwrap()).with_subprotocol(Some(String::from("msgpack")));
+        let second = ConnectionInfo::new("127.0.0.1:2".parse().unwrap());
+        for info in &[&first, &second] {
+            let (transmitter, _) = mpsc::unbounded();
+            add_connection(&connections, &hooks, info, Arc::new(transmitter));
+        }
+        assert_eq!(*hooks.connected.lock().unwrap(), vec![first.clone(), second.clone()]);
+        assert!(hooks.disconnected.lock().unwrap().is_empty());
+
+        let mut user_headers = HashMap::new();
+        user_headers.insert(String::from("user_id"), String::from("1"));
+        let first = first.with_user_headers(Some(user_headers));
+        remove_connection(&connections, &hooks, &first);
+        remove_connection(&connections, &hooks, &first);
+        remove_connection(&connections, &hooks, &second);
+
+        assert!(connections.lock().unwrap().is_empty());
+        assert_eq!(hooks.connected.lock().unwrap().len(), 2);
+        assert_eq!(*hooks.disconnected.lock().unwrap(), vec![first, second]);
+    }
+
+    #[test]
+    fn test_notify_connections_sends_the_shutdown_notice_to_each_client() {
+        let connections = Mutex::new(HashMap::new());
+        let mut receivers = Vec::new();
+        for port in 1..=3 {
+            let (transmitter, receiver) = mpsc::unbounded();
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            connections.lock().unwrap().insert(addr, Arc::new(transmitter));
+            receivers.push(receiver);
+        }
+
+        let message = get_shutdown_message(&ProxyConfig::default().shutdown_message).unwrap().unwrap();
+        assert_eq!(notify_connections(&connections, &message), 3);
+
+        let expected = Message::Text(String::from(r#"{"type":"server_shutdown","reconnect_after_ms":1000}"#));
+        for receiver in receivers {
+            let (received, _) = receiver.into_future().wait().map_err(|_| ()).unwrap();
+            assert_eq!(received, Some(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn test_broadcast_exchange_has_the_amqp_prefix() {
+        let get_broadcast_exchange = |amqp_prefix: &str, broadcast_exchange: &str| {
+            let config = ProxyConfig {
+                config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+                amqp_prefix: String::from(amqp_prefix),
+                broadcast_exchange: String::from(broadcast_exchange),
+                ..ProxyConfig::default()
+            };
+            Proxy::new(&config).unwrap().broadcast_exchange
+        };
+        assert_eq!(get_broadcast_exchange("staging", "open-matchmaking.events"), "staging.open-matchmaking.events");
+        assert_eq!(get_broadcast_exchange("", "open-matchmaking.events"), "open-matchmaking.events");
+        assert_eq!(get_broadcast_exchange("staging", ""), "");
+    }
+
+    #[test]
+    fn test_get_shutdown_message_requires_a_json_object() {
+        assert_eq!(get_shutdown_message("").unwrap(), None);
+        assert!(get_shutdown_message("[1, 2]").is_err());
+        assert!(get_shutdown_message("{\"type\": ").is_err());
+
+        let config = ProxyConfig {
+            config: String::from("./tests/files/config_with_valid_endpoints.yaml"),
+            shutdown_message: String::from("server_shutdown"),
+            ..ProxyConfig::default()
+        };
+        assert!(Proxy::new(&config).is_err());
+    }
+
+    fn send_http_request<F, R>(request: &str, process: F) -> String
+        where F: FnOnce(Message, MessageSender) -> R + Send + 'static,
+              R: Future<Item=(), Error=RequestError> + Send + 'static
+    {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = request.to_string();
+        let client = thread::spawn(move || {
+            let mut stream = StdTcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let listener = TcpListener::from_std(listener, &tokio::reactor::Handle::default()).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        let future = listener
+            .incoming()
+            .into_future()
+            .map_err(|(error, _)| PathfinderError::Io(error))
+            .and_then(move |(stream, _)| process_http_request(stream.unwrap(), 1024, Arc::new(ErrorTemplate::default()), process));
+        runtime.block_on(future).unwrap();
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn test_process_http_request_returns_the_response_in_the_body() {
+        let body = r#"{"url": "/api/matchmaking/search", "correlation-id": "id"}"#;
+        let request = format!(
+            "POST /rpc HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(), body
+        );
+        let received = Arc::new(Mutex::new(None));
+        let received_inner = received.clone();
+        let response = send_http_request(&request, move |message, transmitter| {
+            *received_inner.lock().unwrap() = Some(message);
+            lazy(move || {
+                transmitter.unbounded_send(Message::Text(String::from(r#"{"content":[1,2]}"#))).unwrap();
+                Ok(())
+            })
+        });
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"content\":[1,2]}"));
+        let message = received.lock().unwrap().take().unwrap();
+        let json = parse_json(message.to_text().unwrap()).unwrap();
+        assert_eq!(json["url"], "/api/matchmaking/search");
+        assert_eq!(json["token"], "secret");
+    }
+
+    #[test]
+    fn test_process_http_request_returns_errors_with_the_http_status() {
+        let request = "POST /rpc HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}";
+        let response = send_http_request(request, |_message, transmitter| {
+            lazy(move || {
+                transmitter.unbounded_send(Message::Text(String::from(r#"{"type":"ack"}"#))).unwrap();
+                let error = PathfinderError::EndpointNotFound(String::from("/api/unknown"));
+                Err(RequestError::new(error).with_correlation_id("id"))
+            })
+        });
+
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let body = parse_json(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[0]["type"], "ack");
+        assert_eq!(body[1]["code"], "ENDPOINT_NOT_FOUND");
+        assert_eq!(body[1]["correlation_id"], "id");
+    }
+
+    #[test]
+    fn test_process_http_request_rejects_too_large_bodies() {
+        let request = "POST /rpc HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4096\r\n\r\n{}";
+        let response = send_http_request(request, |_message, _transmitter| lazy(|| Ok(())));
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+}
@@ -2,40 +2,76 @@
 //!
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
-use amq_protocol::uri::AMQPUri;
+use amq_protocol::uri::{AMQPScheme, AMQPUri};
 use failure::{err_msg, Error};
-use futures::future::Future;
-use futures::IntoFuture;
+use futures::future::{ok, result, Either, Future};
+use futures::{IntoFuture, Stream};
 use lapin_futures::error::{Error as LapinError};
-use lapin_futures_rustls::lapin::channel::{Channel, ConfirmSelectOptions};
-use lapin_futures_rustls::lapin::client::{Client, ConnectionOptions};
-use log::error;
+use lapin_futures::queue::Queue;
+use lapin_futures_rustls::lapin::channel::{BasicConsumeOptions, BasicQosOptions, Channel, ConfirmSelectOptions};
+use lapin_futures_rustls::lapin::client::Client;
+use lapin_futures_rustls::lapin::types::FieldTable;
+use lapin_futures_rustls::AMQPStream;
+use log::{error, warn};
 use tokio::executor::spawn;
 use tokio::net::TcpStream;
+use tokio::timer::Delay;
 
-use crate::rabbitmq::utils::get_address_to_rabbitmq;
+use crate::limits::Throttle;
+use crate::rabbitmq::pool::Pool;
+use crate::rabbitmq::reply_to::{DirectReplyTo, DIRECT_REPLY_TO_QUEUE};
+use crate::rabbitmq::tls::{connect_tls, TlsOptions};
+use crate::rabbitmq::utils::{get_address_to_rabbitmq, get_connection_options};
 
 /// Alias for the lapin client with TLS.
-pub type LapinClient = Client<TcpStream>;
+pub type LapinClient = Client<AMQPStream>;
 /// Alias for the lapin channel.
-pub type LapinChannel = Channel<TcpStream>;
+pub type LapinChannel = Channel<AMQPStream>;
 
 /// Custom client context, stores data, channels and everything else
 /// that can be used for communicating with AMQP.
 pub struct RabbitMQContext {
     publish_channel: LapinChannel,
-    consume_channel: LapinChannel
+    consume_channel: LapinChannel,
+    direct_reply_to: Option<Arc<DirectReplyTo>>,
+    confirm_mode: bool,
+    declare_throttle: Option<Arc<Throttle>>
 }
 
 impl RabbitMQContext {
     pub fn new(publish_channel: LapinChannel, consume_channel: LapinChannel) -> RabbitMQContext {
         RabbitMQContext {
             publish_channel,
-            consume_channel
+            consume_channel,
+            direct_reply_to: None,
+            confirm_mode: true,
+            declare_throttle: None
         }
     }
 
+    /// Marks whether the broker confirms the messages published via the
+    /// publish channel.
+    pub fn with_confirm_mode(mut self, value: bool) -> RabbitMQContext {
+        self.confirm_mode = value;
+        self
+    }
+
+    /// Sets the throttle for declaring reply queues, that is shared by the
+    /// contexts of all connections.
+    pub fn with_declare_throttle(mut self, throttle: Option<Arc<Throttle>>) -> RabbitMQContext {
+        self.declare_throttle = throttle;
+        self
+    }
+
+    /// Enables consuming responses via the `amq.rabbitmq.reply-to` pseudo-queue.
+    pub fn with_direct_reply_to(mut self, direct_reply_to: Arc<DirectReplyTo>) -> RabbitMQContext {
+        self.direct_reply_to = Some(direct_reply_to);
+        self
+    }
+
     pub fn get_publish_channel(&self) -> LapinChannel {
         self.publish_channel.clone()
     }
@@ -44,6 +80,27 @@ impl RabbitMQContext {
         self.consume_channel.clone()
     }
 
+    pub fn get_direct_reply_to(&self) -> Option<Arc<DirectReplyTo>> {
+        self.direct_reply_to.clone()
+    }
+
+    pub fn is_confirm_mode(&self) -> bool {
+        self.confirm_mode
+    }
+
+    /// Returns a future that is resolved when the reply queue can be declared
+    /// without exceeding the configured rate of declarations.
+    pub fn wait_for_queue_declare(&self) -> impl Future<Item=(), Error=LapinError> + Sync + Send + 'static {
+        let delay = self.declare_throttle.as_ref().map(|throttle| throttle.reserve());
+        match delay {
+            Some(delay) if delay.as_nanos() > 0 => Either::A(
+                // The timer fails only on shutdown, so the queue is declared as usual
+                Delay::new(Instant::now() + delay).then(|_| Ok(()))
+            ),
+            _ => Either::B(ok(()))
+        }
+    }
+
     pub fn close_channels(&self) -> impl Future<Item=(), Error=LapinError> + Sync + Send + 'static {
         let publish_channel = self.publish_channel.clone();
         let consume_channel = self.consume_channel.clone();
@@ -55,45 +112,253 @@ impl RabbitMQContext {
 
 /// A future-based asynchronous RabbitMQ client.
 pub struct RabbitMQClient {
-    client: Arc<LapinClient>
+    client: Arc<LapinClient>,
+    is_connected: Arc<AtomicBool>,
+    use_direct_reply_to: bool,
+    use_publisher_confirms: bool,
+    prefetch_count: u16,
+    declare_throttle: Option<Arc<Throttle>>,
+    pool: Arc<Pool<Arc<RabbitMQContext>>>
 }
 
 impl RabbitMQClient {
-    /// Initializes the inner fields of RabbitMQ client for future usage.
-    pub fn connect(uri: &AMQPUri) -> impl Future<Item=Self, Error=Error> + Sync + Send + 'static {
-        let address = get_address_to_rabbitmq(uri);
+    /// Initializes the inner fields of RabbitMQ client for future usage. For
+    /// the `amqps` scheme the connection is wrapped into the TLS session.
+    pub fn connect(uri: &AMQPUri, tls_options: &TlsOptions) -> impl Future<Item=Self, Error=Error> + Send + 'static {
+        let host = uri.authority.host.clone();
         let uri_inner = uri.clone();
+        let tls_config = match uri.scheme {
+            AMQPScheme::AMQPS => tls_options.get_client_config().map(|config| Some(Arc::new(config))),
+            AMQPScheme::AMQP => Ok(None)
+        };
 
-        TcpStream::connect(&address)
-            .map_err(Error::from)
+        result(get_address_to_rabbitmq(uri).and_then(|address| tls_config.map(|tls_config| (address, tls_config))))
+            .and_then(move |(address, tls_config)| {
+                TcpStream::connect(&address)
+                    .map_err(Error::from)
+                    .map(|stream| (stream, tls_config))
+            })
+            .and_then(move |(stream, tls_config)| match tls_config {
+                Some(config) => Either::A(
+                    connect_tls(config, &host, stream)
+                        .map(|stream| AMQPStream::Tls(Box::new(stream)))
+                ),
+                None => Either::B(ok(AMQPStream::Raw(stream)))
+            })
             .and_then(|stream| {
-                Client::connect(stream, ConnectionOptions::from_uri(uri_inner))
+                Client::connect(stream, get_connection_options(uri_inner))
                     .map_err(Error::from)
             })
             .and_then(|(client, heartbeat)| {
-                spawn(heartbeat.map_err(|err| error!("Heartbeat error: {}", err)))
+                let is_connected = Arc::new(AtomicBool::new(true));
+                let is_connected_inner = is_connected.clone();
+                let heartbeat = heartbeat.then(move |result| {
+                    // The heartbeat is stopped only when the connection was lost
+                    is_connected_inner.store(false, Ordering::SeqCst);
+                    match result {
+                        Ok(_) => error!("Connection to RabbitMQ was closed."),
+                        Err(err) => error!("Heartbeat error: {}", err)
+                    };
+                    Ok(())
+                });
+
+                spawn(heartbeat)
                     .into_future()
-                    .map(|_| RabbitMQClient { client: Arc::new(client) })
+                    .map(|_| RabbitMQClient {
+                        client: Arc::new(client),
+                        is_connected,
+                        use_direct_reply_to: false,
+                        use_publisher_confirms: true,
+                        prefetch_count: 0,
+                        declare_throttle: None,
+                        pool: Arc::new(Pool::new(0))
+                    })
                     .map_err(|_| err_msg("Couldn't spawn the heartbeat task."))
             })
     }
 
-    /// Returns client context as future, based on the lapin client instance.
+    /// Enables usage of the direct reply-to instead of the temporary response queues.
+    pub fn with_direct_reply_to(mut self, value: bool) -> RabbitMQClient {
+        self.use_direct_reply_to = value;
+        self
+    }
+
+    /// Opens the channels in the confirm mode, so that the broker acknowledges
+    /// each published message. Disabling it increases the throughput, but
+    /// the messages lost by the broker are no longer detected.
+    pub fn with_publisher_confirms(mut self, value: bool) -> RabbitMQClient {
+        self.use_publisher_confirms = value;
+        self
+    }
+
+    /// Sets the maximum number of unacknowledged messages for each consumer
+    /// on the consume channel. Zero means no limit.
+    pub fn with_prefetch_count(mut self, value: u16) -> RabbitMQClient {
+        self.prefetch_count = value;
+        self
+    }
+
+    /// Limits the number of reply queues declared per second by all
+    /// connections. Requests over the limit wait for their turn. Zero means
+    /// no limit.
+    pub fn with_queue_declare_rate(mut self, value: u32) -> RabbitMQClient {
+        self.declare_throttle = match value {
+            0 => None,
+            rate => Some(Arc::new(Throttle::new(rate)))
+        };
+        self
+    }
+
+    /// Sets the maximum number of idle contexts that are kept for reuse after
+    /// closing the WebSocket connections. Zero disables pooling.
+    pub fn with_pool_size(mut self, value: usize) -> RabbitMQClient {
+        self.pool = Arc::new(Pool::new(value));
+        self
+    }
+
+    /// Checks that the connection to RabbitMQ is still alive.
+    pub fn is_connected(&self) -> bool {
+        self.is_connected.load(Ordering::SeqCst)
+    }
+
+    /// Returns client context as future. The idle context from the pool is
+    /// reused when its channels are still alive, otherwise a new one is created.
     pub fn get_context(&self) -> impl Future<Item=Arc<RabbitMQContext>, Error=LapinError> + Sync + Send + 'static {
         let client = self.client.clone();
+        let use_direct_reply_to = self.use_direct_reply_to;
+        let use_publisher_confirms = self.use_publisher_confirms;
+        let prefetch_count = self.prefetch_count;
+        let declare_throttle = self.declare_throttle.clone();
+
+        match self.pool.take() {
+            Some(context) => Either::A(
+                RabbitMQClient::check_context(context, prefetch_count)
+                    .or_else(move |err| {
+                        warn!("The pooled channels are broken and will be replaced: {}", err);
+                        RabbitMQClient::create_context(client, use_direct_reply_to, use_publisher_confirms, prefetch_count, declare_throttle)
+                    })
+            ),
+            None => Either::B(RabbitMQClient::create_context(client, use_direct_reply_to, use_publisher_confirms, prefetch_count, declare_throttle))
+        }
+    }
+
+    /// Returns the context of the closed connection into the pool. When the
+    /// pool is full (or disabled) or the connection was lost, the channels are
+    /// closed. The requests awaiting responses via the direct reply-to are
+    /// forgotten, so that the next connection will not receive them.
+    pub fn release_context(&self, context: Arc<RabbitMQContext>) -> impl Future<Item=(), Error=LapinError> + Sync + Send + 'static {
+        if let Some(direct_reply_to) = context.get_direct_reply_to() {
+            direct_reply_to.clear();
+        }
+        let context = match self.is_connected() {
+            true => self.pool.put(context),
+            false => Some(context)
+        };
+
+        match context {
+            Some(context) => Either::A(context.close_channels()),
+            None => Either::B(ok(()))
+        }
+    }
 
+    /// Checks that the channels of the pooled context are still opened by
+    /// doing a round-trip to the broker on each channel.
+    fn check_context(context: Arc<RabbitMQContext>, prefetch_count: u16)
+        -> impl Future<Item=Arc<RabbitMQContext>, Error=LapinError> + Sync + Send + 'static
+    {
+        let consume_channel = context.get_consume_channel();
+        let qos_options = BasicQosOptions {
+            prefetch_count,
+            ..Default::default()
+        };
+
+        context.get_publish_channel()
+            .basic_qos(BasicQosOptions::default())
+            .and_then(move |_| consume_channel.basic_qos(qos_options))
+            .map(move |_| context)
+    }
+
+    /// Opens new channels and returns a new client context based on them.
+    fn create_context(
+        client: Arc<LapinClient>,
+        use_direct_reply_to: bool,
+        use_publisher_confirms: bool,
+        prefetch_count: u16,
+        declare_throttle: Option<Arc<Throttle>>
+    ) -> impl Future<Item=Arc<RabbitMQContext>, Error=LapinError> + Sync + Send + 'static
+    {
         // Request channel for publishing messages
-        client.create_confirm_channel(ConfirmSelectOptions::default())
+        RabbitMQClient::create_channel(&client, use_publisher_confirms)
             .map(|publish_channel| (client, publish_channel))
-            .map(|(client, publish_channel)|
+            .map(move |(client, publish_channel)|
                 // Request channel for consuming messages
-                client.create_confirm_channel(ConfirmSelectOptions::default())
+                RabbitMQClient::create_channel(&client, use_publisher_confirms)
                     .map(|consume_channel| (publish_channel, consume_channel))
             )
             .flatten()
+            // Limit the number of unacknowledged responses if necessary
+            .and_then(move |(publish_channel, consume_channel)| match prefetch_count {
+                0 => Either::A(Ok((publish_channel, consume_channel)).into_future()),
+                _ => {
+                    let qos_options = BasicQosOptions {
+                        prefetch_count,
+                        ..Default::default()
+                    };
+                    Either::B(
+                        consume_channel
+                            .basic_qos(qos_options)
+                            .map(move |_| (publish_channel, consume_channel))
+                    )
+                }
+            })
             // Initialize the client context
-            .map(|(publish_channel, consume_channel)| 
-                Arc::new(RabbitMQContext::new(publish_channel, consume_channel))
+            .map(move |(publish_channel, consume_channel)| 
+                RabbitMQContext::new(publish_channel, consume_channel)
+                    .with_confirm_mode(use_publisher_confirms)
+                    .with_declare_throttle(declare_throttle)
             )
+            // Start consuming responses from the direct reply-to pseudo-queue if necessary
+            .and_then(move |context| match use_direct_reply_to {
+                true => Either::A(RabbitMQClient::start_direct_reply_to(context)),
+                false => Either::B(Ok(context).into_future())
+            })
+            .map(Arc::new)
+    }
+
+    /// Opens a new channel, in the confirm mode when it's required.
+    fn create_channel(client: &LapinClient, confirm_mode: bool)
+        -> impl Future<Item=LapinChannel, Error=LapinError> + Sync + Send + 'static
+    {
+        match confirm_mode {
+            true => Either::A(client.create_confirm_channel(ConfirmSelectOptions::default())),
+            false => Either::B(client.create_channel())
+        }
+    }
+
+    /// Subscribes the publish channel onto the `amq.rabbitmq.reply-to`
+    /// pseudo-queue and routes the consumed responses to the awaiting requests.
+    /// The consumer must be started before publishing any requests via the channel.
+    fn start_direct_reply_to(context: RabbitMQContext) -> impl Future<Item=RabbitMQContext, Error=LapinError> + Sync + Send + 'static {
+        let direct_reply_to = Arc::new(DirectReplyTo::new());
+        let direct_reply_to_inner = direct_reply_to.clone();
+        let queue = Queue::new(DIRECT_REPLY_TO_QUEUE.to_string(), 0, 0);
+        let consume_options = BasicConsumeOptions {
+            no_ack: true,
+            ..Default::default()
+        };
+
+        context.get_publish_channel()
+            .basic_consume(&queue, "direct_reply_to_consumer", consume_options, FieldTable::new())
+            .map(move |stream| {
+                spawn(
+                    stream
+                        .for_each(move |delivery| {
+                            direct_reply_to_inner.dispatch(delivery);
+                            Ok(())
+                        })
+                        .map_err(|err| error!("Direct reply-to consumer error: {}", err))
+                );
+                context.with_direct_reply_to(direct_reply_to)
+            })
     }
 }
@@ -2,7 +2,13 @@
 //!
 
 pub mod client;
+pub mod pool;
+pub mod reply_to;
+pub mod tls;
 pub mod utils;
 
 pub use self::client::{LapinChannel, LapinClient, RabbitMQContext, RabbitMQClient};
-pub use self::utils::{get_address_to_rabbitmq, get_uri};
+pub use self::pool::Pool;
+pub use self::reply_to::{DirectReplyTo, DIRECT_REPLY_TO_QUEUE};
+pub use self::tls::TlsOptions;
+pub use self::utils::{get_address_to_rabbitmq, get_tls_options, get_uri};
@@ -0,0 +1,83 @@
+//! A pool of idle RabbitMQ client contexts
+//!
+//! Opening channels for each WebSocket connection and closing them on
+//! disconnect is expensive when clients reconnect often, so the contexts
+//! of closed connections are kept for reuse.
+//!
+
+use std::sync::Mutex;
+
+/// Storage for idle items with the limited size.
+pub struct Pool<T> {
+    max_size: usize,
+    items: Mutex<Vec<T>>
+}
+
+impl<T> Pool<T> {
+    /// Returns a new instance of `Pool`. A zero size disables pooling.
+    pub fn new(max_size: usize) -> Pool<T> {
+        Pool {
+            max_size,
+            items: Mutex::new(Vec::with_capacity(max_size))
+        }
+    }
+
+    /// Takes an idle item from the pool if there is any.
+    pub fn take(&self) -> Option<T> {
+        self.items.lock().unwrap().pop()
+    }
+
+    /// Returns the item into the pool. When the pool is full, the item is
+    /// given back to the caller, so that it can be released.
+    pub fn put(&self, item: T) -> Option<T> {
+        let mut items = self.items.lock().unwrap();
+        match items.len() < self.max_size {
+            true => {
+                items.push(item);
+                None
+            },
+            false => Some(item)
+        }
+    }
+
+    /// Returns the number of idle items.
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    /// Checks that there are no idle items.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rabbitmq::pool::Pool;
+
+    #[test]
+    fn test_put_keeps_items_until_the_pool_is_full() {
+        let pool = Pool::new(2);
+        assert_eq!(pool.put(1), None);
+        assert_eq!(pool.put(2), None);
+        assert_eq!(pool.put(3), Some(3));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn test_take_returns_idle_items() {
+        let pool = Pool::new(2);
+        assert_eq!(pool.take(), None);
+
+        pool.put(1);
+        assert_eq!(pool.take(), Some(1));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_pool_with_zero_size_keeps_nothing() {
+        let pool = Pool::new(0);
+        assert_eq!(pool.put(1), Some(1));
+        assert_eq!(pool.take(), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! Support of the RabbitMQ direct reply-to feature
+//!
+//! For more details about the feature you can find [here](https://www.rabbitmq.com/direct-reply-to.html).
+//!
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::sync::{mpsc, oneshot};
+use lapin_futures::message::Delivery;
+use log::warn;
+use uuid::Uuid;
+
+/// The name of the pseudo-queue used for consuming responses directly.
+pub const DIRECT_REPLY_TO_QUEUE: &str = "amq.rabbitmq.reply-to";
+
+/// The receiving side of the request that awaits responses.
+enum PendingRequest {
+    /// The request awaits a single response.
+    Single(oneshot::Sender<Delivery>),
+    /// The request awaits several parts of the response until it's cancelled.
+    Stream(mpsc::UnboundedSender<Delivery>)
+}
+
+/// Routes responses, that were consumed from the `amq.rabbitmq.reply-to`
+/// pseudo-queue, to the awaiting requests by the correlation ID. Each request
+/// is published with a unique ID generated by reverse proxy, because the IDs
+/// passed by clients can repeat, and the ID is mapped to the ID of the client.
+#[derive(Default)]
+pub struct DirectReplyTo {
+    pending: Mutex<HashMap<String, (String, PendingRequest)>>
+}
+
+impl DirectReplyTo {
+    /// Returns a new instance of `DirectReplyTo`.
+    pub fn new() -> DirectReplyTo {
+        DirectReplyTo {
+            pending: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Registers a request of the client with the given correlation ID.
+    /// Returns the unique ID, that must be used as the correlation ID of the
+    /// published request, and a receiver that will be resolved with the response.
+    pub fn register(&self, correlation_id: &str) -> (String, oneshot::Receiver<Delivery>) {
+        let (transmitter, receiver) = oneshot::channel();
+        let request_id = self.insert(correlation_id, PendingRequest::Single(transmitter));
+        (request_id, receiver)
+    }
+
+    /// Registers a request of the client with the given correlation ID.
+    /// Returns the unique ID of the published request and a stream of the
+    /// received parts of the response. The request must be cancelled after
+    /// receiving the last part.
+    pub fn register_stream(&self, correlation_id: &str) -> (String, mpsc::UnboundedReceiver<Delivery>) {
+        let (transmitter, receiver) = mpsc::unbounded();
+        let request_id = self.insert(correlation_id, PendingRequest::Stream(transmitter));
+        (request_id, receiver)
+    }
+
+    /// Stores the awaiting request under a newly generated unique ID.
+    fn insert(&self, correlation_id: &str, request: PendingRequest) -> String {
+        let request_id = Uuid::new_v4().to_string();
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert(request_id.clone(), (correlation_id.to_string(), request));
+        request_id
+    }
+
+    /// Forgets about the request, e.g. when the response wasn't received in time.
+    pub fn cancel(&self, request_id: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.remove(request_id);
+    }
+
+    /// Forgets about all awaiting requests, so that the late responses will
+    /// not be passed to the next user of the channels.
+    pub fn clear(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.clear();
+    }
+
+    /// Passes the response to the request with the same unique ID.
+    pub fn dispatch(&self, delivery: Delivery) {
+        let request_id = delivery.properties.correlation_id().clone().unwrap_or_default();
+        let mut pending = self.pending.lock().unwrap();
+
+        match pending.remove(&request_id) {
+            Some((correlation_id, PendingRequest::Single(transmitter))) => {
+                if transmitter.send(delivery).is_err() {
+                    warn!("[{}] The response was received after the request was finished.", correlation_id);
+                }
+            },
+            Some((correlation_id, PendingRequest::Stream(transmitter))) => {
+                if transmitter.unbounded_send(delivery).is_ok() {
+                    pending.insert(request_id, (correlation_id, PendingRequest::Stream(transmitter)));
+                }
+            },
+            None => warn!("Received a response for the unknown request \"{}\".", request_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::Future;
+    use futures::Stream;
+    use lapin_futures::channel::BasicProperties;
+    use lapin_futures::message::Delivery;
+
+    use crate::rabbitmq::reply_to::DirectReplyTo;
+
+    fn get_delivery(correlation_id: &str) -> Delivery {
+        let mut delivery = Delivery::new(1, String::from(""), String::from("amq.rabbitmq.reply-to.test"), false);
+        delivery.properties = BasicProperties::default().with_correlation_id(correlation_id.to_string());
+        delivery.receive_content(b"{}".to_vec());
+        delivery
+    }
+
+    #[test]
+    fn test_dispatch_passes_response_to_the_registered_request() {
+        let direct_reply_to = DirectReplyTo::new();
+        let (request_id, receiver) = direct_reply_to.register("client-id");
+        direct_reply_to.dispatch(get_delivery(&request_id));
+
+        let delivery = receiver.wait().unwrap();
+        assert_eq!(delivery.data, b"{}".to_vec());
+    }
+
+    #[test]
+    fn test_dispatch_ignores_response_for_unknown_request() {
+        let direct_reply_to = DirectReplyTo::new();
+        let (request_id, receiver) = direct_reply_to.register("client-id");
+        direct_reply_to.dispatch(get_delivery("client-id"));
+        direct_reply_to.cancel(&request_id);
+
+        assert!(receiver.wait().is_err());
+    }
+
+    #[test]
+    fn test_dispatch_passes_parts_of_response_until_cancelled() {
+        let direct_reply_to = DirectReplyTo::new();
+        let (request_id, receiver) = direct_reply_to.register_stream("client-id");
+        direct_reply_to.dispatch(get_delivery(&request_id));
+        direct_reply_to.dispatch(get_delivery(&request_id));
+        direct_reply_to.cancel(&request_id);
+        direct_reply_to.dispatch(get_delivery(&request_id));
+
+        let parts: Vec<_> = receiver.collect().wait().unwrap();
+        assert_eq!(parts.len(), 2);
+    }
+
+    #[test]
+    fn test_register_keeps_requests_with_the_same_client_id_apart() {
+        let direct_reply_to = DirectReplyTo::new();
+        let (first_id, first_receiver) = direct_reply_to.register("client-id");
+        let (second_id, second_receiver) = direct_reply_to.register("client-id");
+        assert_ne!(first_id, second_id);
+
+        direct_reply_to.dispatch(get_delivery(&second_id));
+        direct_reply_to.dispatch(get_delivery(&first_id));
+        assert!(first_receiver.wait().is_ok());
+        assert!(second_receiver.wait().is_ok());
+    }
+
+    #[test]
+    fn test_clear_forgets_all_awaiting_requests() {
+        let direct_reply_to = DirectReplyTo::new();
+        let (request_id, receiver) = direct_reply_to.register("client-id");
+        let (stream_id, parts) = direct_reply_to.register_stream("client-id");
+        direct_reply_to.clear();
+        direct_reply_to.dispatch(get_delivery(&request_id));
+        direct_reply_to.dispatch(get_delivery(&stream_id));
+
+        assert!(receiver.wait().is_err());
+        assert!(parts.collect().wait().unwrap().is_empty());
+    }
+}
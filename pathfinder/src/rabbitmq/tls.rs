@@ -0,0 +1,185 @@
+//! TLS settings for connections to RabbitMQ
+//!
+//! The server certificate is verified against the CA bundle (or the
+//! Mozilla root certificates, if the bundle isn't specified). When the
+//! broker requires mutual TLS, the client certificate and the private
+//! key are presented during the handshake.
+//!
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use failure::{err_msg, Error};
+use futures::future::{result, Future};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{sign, Certificate, ClientConfig, ClientSession, PrivateKey};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsConnector, TlsStream};
+
+/// Paths to the files used for TLS connections. Empty paths mean that
+/// the files aren't specified.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    pub ca_certificate: String,
+    pub client_certificate: String,
+    pub client_key: String
+}
+
+impl TlsOptions {
+    /// Returns the rustls configuration with the loaded certificates.
+    pub fn get_client_config(&self) -> Result<ClientConfig, Error> {
+        let mut config = ClientConfig::new();
+
+        match self.ca_certificate.is_empty() {
+            true => config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+            false => {
+                let mut reader = open_file(&self.ca_certificate, "CA bundle")?;
+                let (valid_count, _) = config.root_store.add_pem_file(&mut reader)
+                    .map_err(|_| err_msg(format!("The CA bundle \"{}\" isn't a valid PEM file.", self.ca_certificate)))?;
+                if valid_count == 0 {
+                    let message = format!("The CA bundle \"{}\" doesn't contain any valid certificates.", self.ca_certificate);
+                    return Err(err_msg(message));
+                }
+            }
+        }
+
+        match (self.client_certificate.is_empty(), self.client_key.is_empty()) {
+            (true, true) => {},
+            (false, false) => {
+                let cert_chain = load_certificates(&self.client_certificate)?;
+                let key = load_private_key(&self.client_key)?;
+                config.set_single_client_cert(cert_chain, key);
+            },
+            _ => return Err(err_msg("Both the client certificate and the private key must be specified."))
+        }
+
+        Ok(config)
+    }
+}
+
+/// Establishes the TLS session over the TCP stream with the RabbitMQ node.
+pub fn connect_tls(config: Arc<ClientConfig>, host: &str, stream: TcpStream)
+    -> impl Future<Item=TlsStream<TcpStream, ClientSession>, Error=Error> + Send + 'static
+{
+    let domain = DNSNameRef::try_from_ascii_str(host)
+        .map(|domain| domain.to_owned())
+        .map_err(|_| err_msg(format!("\"{}\" isn't a valid DNS name for the TLS connection.", host)));
+
+    result(domain).and_then(move |domain| {
+        TlsConnector::from(config)
+            .connect(domain.as_ref(), stream)
+            .map_err(Error::from)
+    })
+}
+
+fn open_file(file_path: &str, description: &str) -> Result<BufReader<File>, Error> {
+    File::open(file_path)
+        .map(BufReader::new)
+        .map_err(|err| err_msg(format!("Couldn't read the {} \"{}\": {}", description, file_path, err)))
+}
+
+fn load_certificates(file_path: &str) -> Result<Vec<Certificate>, Error> {
+    let mut reader = open_file(file_path, "client certificate")?;
+    match certs(&mut reader) {
+        Ok(ref cert_chain) if cert_chain.is_empty() => {
+            Err(err_msg(format!("The client certificate \"{}\" doesn't contain any PEM-encoded certificates.", file_path)))
+        },
+        Ok(cert_chain) => Ok(cert_chain),
+        Err(_) => Err(err_msg(format!("The client certificate \"{}\" isn't a valid PEM file.", file_path)))
+    }
+}
+
+fn load_private_key(file_path: &str) -> Result<PrivateKey, Error> {
+    let read_keys = |parse: fn(&mut dyn io::BufRead) -> Result<Vec<PrivateKey>, ()>| -> Result<Vec<PrivateKey>, Error> {
+        let mut reader = open_file(file_path, "private key")?;
+        parse(&mut reader)
+            .map_err(|_| err_msg(format!("The private key \"{}\" isn't a valid PEM file.", file_path)))
+    };
+
+    let mut keys = read_keys(pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(rsa_private_keys)?;
+    }
+
+    match keys.into_iter().next() {
+        Some(ref key) if sign::any_supported_type(key).is_err() => {
+            Err(err_msg(format!("The private key \"{}\" has an unsupported type.", file_path)))
+        },
+        Some(key) => Ok(key),
+        None => Err(err_msg(format!("The private key \"{}\" doesn't contain any PKCS8 or RSA keys.", file_path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rabbitmq::tls::TlsOptions;
+
+    fn get_options(ca_certificate: &str, client_certificate: &str, client_key: &str) -> TlsOptions {
+        TlsOptions {
+            ca_certificate: String::from(ca_certificate),
+            client_certificate: String::from(client_certificate),
+            client_key: String::from(client_key)
+        }
+    }
+
+    fn get_error(options: &TlsOptions) -> String {
+        format!("{}", options.get_client_config().err().unwrap())
+    }
+
+    #[test]
+    fn test_get_client_config_without_files() {
+        let options = TlsOptions::default();
+        let config = options.get_client_config().unwrap();
+        assert!(!config.root_store.is_empty());
+        assert!(!config.client_auth_cert_resolver.has_certs());
+    }
+
+    #[test]
+    fn test_get_client_config_with_client_certificate() {
+        let options = get_options(
+            "./tests/files/tls/ca_certificate.pem",
+            "./tests/files/tls/client_certificate.pem",
+            "./tests/files/tls/client_key.pem"
+        );
+        let config = options.get_client_config().unwrap();
+        assert_eq!(config.root_store.len(), 1);
+        assert!(config.client_auth_cert_resolver.has_certs());
+    }
+
+    #[test]
+    fn test_get_client_config_requires_both_certificate_and_key() {
+        let options = get_options("", "./tests/files/tls/client_certificate.pem", "");
+        assert_eq!(get_error(&options), "Both the client certificate and the private key must be specified.");
+    }
+
+    #[test]
+    fn test_get_client_config_with_missing_files() {
+        let options = get_options("", "./tests/files/tls/unknown.pem", "./tests/files/tls/client_key.pem");
+        assert!(get_error(&options).starts_with("Couldn't read the client certificate \"./tests/files/tls/unknown.pem\""));
+
+        let options = get_options("./tests/files/tls/unknown.pem", "", "");
+        assert!(get_error(&options).starts_with("Couldn't read the CA bundle \"./tests/files/tls/unknown.pem\""));
+    }
+
+    #[test]
+    fn test_get_client_config_with_invalid_files() {
+        let options = get_options(
+            "",
+            "./tests/files/tls/client_key.pem",
+            "./tests/files/tls/client_key.pem"
+        );
+        assert!(get_error(&options).contains("doesn't contain any PEM-encoded certificates"));
+
+        let options = get_options(
+            "",
+            "./tests/files/tls/client_certificate.pem",
+            "./tests/files/tls/client_certificate.pem"
+        );
+        assert!(get_error(&options).contains("doesn't contain any PKCS8 or RSA keys"));
+
+        let options = get_options("./tests/files/tls/client_key.pem", "", "");
+        assert!(get_error(&options).contains("doesn't contain any valid certificates"));
+    }
+}
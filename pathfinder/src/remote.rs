@@ -0,0 +1,433 @@
+//! Fetching the configuration from a remote source
+//!
+//! Instead of a local file, the configuration can be served over HTTP(S)
+//! (e.g. by a configuration service). The document is fetched with a plain
+//! GET request and parsed in the format defined by the extension of the
+//! path or, when the path doesn't have one, by the `Content-Type` header.
+//!
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use config::{Config, ConfigError, File, FileFormat};
+use tokio_rustls::rustls::{ClientSession, StreamOwned};
+use tokio_rustls::webpki::DNSNameRef;
+
+use crate::config::get_file_format;
+use crate::error::Result;
+use crate::http::get_header;
+use crate::rabbitmq::tls::TlsOptions;
+
+/// The time to wait for the whole response of the remote source, so that
+/// a slow or stuck source can't block reloading forever.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum size of the response of the remote source in bytes.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Returns `true` when the configuration must be fetched over HTTP(S)
+/// instead of being read from the file system.
+pub fn is_remote_config(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// The parsed URL of the remote configuration.
+#[derive(Debug, PartialEq)]
+struct RemoteUrl {
+    secured: bool,
+    host: String,
+    port: u16,
+    path: String
+}
+
+impl RemoteUrl {
+    /// Parses the URL with the `http` or `https` scheme.
+    fn parse(url: &str) -> Option<RemoteUrl> {
+        let (secured, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => return None
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(position) => (&rest[..position], &rest[position..]),
+            None => (rest, "/")
+        };
+        let default_port = match secured {
+            true => 443,
+            false => 80
+        };
+        // IPv6 addresses are enclosed in brackets, because they contain colons
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let end = rest.find(']')?;
+                match &rest[end + 1..] {
+                    "" => (&rest[..end], default_port),
+                    port => (&rest[..end], port.strip_prefix(':')?.parse::<u16>().ok()?)
+                }
+            },
+            None => match authority.rfind(':') {
+                Some(position) => (&authority[..position], authority[position + 1..].parse::<u16>().ok()?),
+                None => (authority, default_port)
+            }
+        };
+
+        match host.is_empty() {
+            true => None,
+            false => Some(RemoteUrl {
+                secured,
+                host: host.to_string(),
+                port,
+                path: path.to_string()
+            })
+        }
+    }
+
+    /// Returns the value of the `Host` header. The port is omitted when it's
+    /// the default one for the scheme.
+    fn get_host_header(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone()
+        };
+        match (self.secured, self.port) {
+            (false, 80) | (true, 443) => host,
+            (_, port) => format!("{}:{}", host, port)
+        }
+    }
+
+    /// Returns the format of the document by the extension of the path.
+    fn get_file_format(&self) -> Option<FileFormat> {
+        let path = self.path.split(['?', '#']).next().unwrap_or("");
+        get_file_format(Path::new(path))
+    }
+}
+
+/// Fetches the configuration from the URL. Fails when the source can't be
+/// reached, doesn't respond with `200 OK` or returns an invalid document.
+pub fn fetch_config(url: &str) -> Result<Config> {
+    let remote_url = RemoteUrl::parse(url).ok_or_else(|| {
+        ConfigError::Message(format!("\"{}\" isn't a valid URL of the configuration", url))
+    })?;
+    let (head, body) = fetch(&remote_url, FETCH_TIMEOUT).map_err(|err| {
+        ConfigError::Message(format!("the configuration can't be fetched from \"{}\": {}", url, err))
+    })?;
+
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        let message = format!("the configuration can't be fetched from \"{}\": {}", url, status);
+        return Err(ConfigError::Message(message).into());
+    }
+
+    let format = match remote_url.get_file_format().or_else(|| get_content_format(&head)) {
+        Some(format) => format,
+        None => {
+            let message = format!(
+                "the format of \"{}\" isn't supported, the path must have a known extension \
+                 or the response must have the JSON, YAML or TOML content type",
+                url
+            );
+            return Err(ConfigError::Message(message).into());
+        }
+    };
+
+    let mut conf = Config::default();
+    conf.merge(File::from_str(&body, format)).map_err(|err| {
+        let message = format!("the configuration from \"{}\" can't be loaded as {:?}: {}", url, format, err);
+        ConfigError::Message(message)
+    })?;
+    Ok(conf)
+}
+
+/// Returns the format of the document by the `Content-Type` header.
+fn get_content_format(head: &str) -> Option<FileFormat> {
+    let content_type = get_header(head, "Content-Type")?.to_lowercase();
+    match content_type.split(';').next().unwrap_or("").trim() {
+        "application/json" => Some(FileFormat::Json),
+        "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => Some(FileFormat::Yaml),
+        "application/toml" => Some(FileFormat::Toml),
+        _ => None
+    }
+}
+
+/// The TCP stream that fails all reads and writes after the deadline.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant
+}
+
+impl DeadlineStream {
+    /// Limits the next read or write by the time left until the deadline.
+    fn get_timeout(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(timeout) if timeout > Duration::from_millis(0) => Ok(timeout),
+            _ => Err(get_timed_out_error())
+        }
+    }
+
+    fn map_timed_out_error(err: io::Error) -> io::Error {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => get_timed_out_error(),
+            _ => err
+        }
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.get_timeout()?))?;
+        self.stream.read(buf).map_err(DeadlineStream::map_timed_out_error)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.get_timeout()?))?;
+        self.stream.write(buf).map_err(DeadlineStream::map_timed_out_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn get_timed_out_error() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "the response wasn't received in time")
+}
+
+/// Sends the GET request and returns the head and the body of the response.
+/// Fails when the whole response isn't received before the timeout.
+// The errors are built via io::Error::new, as in the rest of the crate
+#[allow(clippy::io_other_error)]
+fn fetch(url: &RemoteUrl, timeout: Duration) -> io::Result<(String, String)> {
+    let deadline = Instant::now() + timeout;
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host can't be resolved"))?;
+    let stream = DeadlineStream {
+        stream: TcpStream::connect_timeout(&address, timeout)?,
+        deadline
+    };
+
+    // HTTP/1.0 guarantees that the body isn't chunked and the connection
+    // is closed after the response
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json, application/yaml, application/toml\r\n\r\n",
+        url.path, url.get_host_header()
+    );
+    let response = match url.secured {
+        true => {
+            let config = TlsOptions::default()
+                .get_client_config()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            let domain = DNSNameRef::try_from_ascii_str(&url.host)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the host isn't a valid DNS name"))?;
+            let session = ClientSession::new(&Arc::new(config), domain);
+            send_request(StreamOwned::new(session, stream), &request)?
+        },
+        false => send_request(stream, &request)?
+    };
+
+    let head_size = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the response is incomplete"))?;
+    let head = String::from_utf8_lossy(&response[..head_size]).to_string();
+    let body_size = get_header(&head, "Content-Length")
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(response.len() - head_size);
+    if response.len() - head_size < body_size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the response is incomplete"));
+    }
+
+    let body = String::from_utf8_lossy(&response[head_size..head_size + body_size]).to_string();
+    Ok((head, body))
+}
+
+/// Writes the request and reads the response until the connection is closed.
+/// Fails when the response exceeds `MAX_RESPONSE_SIZE`.
+// The errors are built via io::Error::new, as in the rest of the crate
+#[allow(clippy::io_other_error)]
+fn send_request<S: Read + Write>(mut stream: S, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    match stream.take(MAX_RESPONSE_SIZE as u64 + 1).read_to_end(&mut response) {
+        Ok(_) => (),
+        // rustls reports the `close_notify` alert of the server as an error,
+        // though the response was read completely
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted => (),
+        Err(err) => return Err(err)
+    };
+
+    match response.len() > MAX_RESPONSE_SIZE {
+        true => {
+            let message = format!("the response exceeds {} bytes", MAX_RESPONSE_SIZE);
+            Err(io::Error::new(io::ErrorKind::InvalidData, message))
+        },
+        false => Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use config::FileFormat;
+
+    use crate::engine::router::parse_endpoints;
+    use crate::remote::{fetch, fetch_config, get_content_format, is_remote_config, RemoteUrl, MAX_RESPONSE_SIZE};
+
+    /// Serves a single request with the response and returns the URL of the server.
+    fn serve_once(response: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}", address)
+    }
+
+    fn get_response(status: &str, content_type: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            status, content_type, body.len(), body
+        )
+    }
+
+    #[test]
+    fn test_is_remote_config() {
+        assert!(is_remote_config("http://config.local/pathfinder.yaml"));
+        assert!(is_remote_config("https://config.local/pathfinder.yaml"));
+        assert!(!is_remote_config("./tests/files/config_with_valid_endpoints.yaml"));
+        assert!(!is_remote_config(""));
+    }
+
+    #[test]
+    fn test_remote_url_parse() {
+        let url = RemoteUrl::parse("https://config.local/api/pathfinder.yaml?version=2").unwrap();
+        assert_eq!(url, RemoteUrl {
+            secured: true,
+            host: String::from("config.local"),
+            port: 443,
+            path: String::from("/api/pathfinder.yaml?version=2")
+        });
+        assert_eq!(url.get_file_format(), Some(FileFormat::Yaml));
+
+        let url = RemoteUrl::parse("http://127.0.0.1:8080").unwrap();
+        assert_eq!((url.port, url.path.as_str()), (8080, "/"));
+        assert_eq!(url.get_file_format(), None);
+
+        let url = RemoteUrl::parse("http://[::1]:8080/pathfinder.yaml").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 8080));
+        let url = RemoteUrl::parse("https://[::1]/pathfinder.yaml").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 443));
+
+        assert_eq!(RemoteUrl::parse("http://127.0.0.1:port/config.yaml"), None);
+        assert_eq!(RemoteUrl::parse("http://[::1/config.yaml"), None);
+        assert_eq!(RemoteUrl::parse("ftp://config.local/config.yaml"), None);
+    }
+
+    #[test]
+    fn test_get_host_header_keeps_non_default_ports() {
+        let get_host_header = |url: &str| RemoteUrl::parse(url).unwrap().get_host_header();
+        assert_eq!(get_host_header("http://config.local/pathfinder.yaml"), "config.local");
+        assert_eq!(get_host_header("https://config.local:443/pathfinder.yaml"), "config.local");
+        assert_eq!(get_host_header("http://config.local:8080/pathfinder.yaml"), "config.local:8080");
+        assert_eq!(get_host_header("https://config.local:80/pathfinder.yaml"), "config.local:80");
+        assert_eq!(get_host_header("http://[::1]:8080/pathfinder.yaml"), "[::1]:8080");
+    }
+
+    #[test]
+    fn test_get_content_format() {
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\n\r\n";
+        assert_eq!(get_content_format(head), Some(FileFormat::Json));
+        assert_eq!(get_content_format("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n"), None);
+        assert_eq!(get_content_format("HTTP/1.1 200 OK\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_fetch_config_returns_endpoints() {
+        let body = "endpoints:\n  - search:\n      url: \"/api/matchmaking/search\"\n      routing_key: \"microservice.search\"\n";
+        let url = serve_once(get_response("200 OK", "text/plain", body));
+        let conf = fetch_config(&format!("{}/pathfinder.yaml", url)).unwrap();
+
+        let (endpoints, errors) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 1);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_fetch_config_uses_the_content_type() {
+        let body = r#"{"endpoints": [{"search": {"url": "/api/matchmaking/search", "routing_key": "microservice.search"}}]}"#;
+        let url = serve_once(get_response("200 OK", "application/json", body));
+        let conf = fetch_config(&format!("{}/config", url)).unwrap();
+
+        let (endpoints, _) = parse_endpoints(&conf);
+        assert_eq!(endpoints.len(), 1);
+    }
+
+    #[test]
+    fn test_fetch_config_fails_for_unsuccessful_responses() {
+        let url = serve_once(get_response("404 Not Found", "text/plain", "Not found"));
+        let error = fetch_config(&format!("{}/pathfinder.yaml", url)).unwrap_err();
+        assert!(format!("{}", error).contains("404 Not Found"));
+    }
+
+    #[test]
+    fn test_fetch_config_fails_for_unknown_formats() {
+        let url = serve_once(get_response("200 OK", "text/plain", "endpoints: []"));
+        let error = fetch_config(&format!("{}/config", url)).unwrap_err();
+        assert!(format!("{}", error).contains("isn't supported"));
+    }
+
+    #[test]
+    fn test_fetch_config_fails_for_unreachable_sources() {
+        // The port of the closed listener isn't used by anyone
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let error = fetch_config(&format!("http://{}/pathfinder.yaml", address)).unwrap_err();
+        assert!(format!("{}", error).contains("can't be fetched"));
+    }
+
+    #[test]
+    fn test_fetch_fails_for_slow_sources() {
+        // Each byte arrives before the timeout, but the response never ends
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).unwrap();
+            while stream.write_all(b" ").is_ok() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        let started_at = Instant::now();
+        let url = RemoteUrl::parse(&format!("http://{}/pathfinder.yaml", address)).unwrap();
+        let error = fetch(&url, Duration::from_millis(500)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_fetch_config_fails_for_too_large_responses() {
+        let body = format!("endpoints: []\n#{}", "#".repeat(MAX_RESPONSE_SIZE));
+        let url = serve_once(get_response("200 OK", "text/plain", &body));
+        let error = fetch_config(&format!("{}/pathfinder.yaml", url)).unwrap_err();
+        assert!(format!("{}", error).contains("exceeds"));
+    }
+}
@@ -0,0 +1,434 @@
+//! Distributed tracing of requests
+//!
+//! This module records spans for the processed requests and periodically
+//! exports them to an OpenTelemetry collector in the OTLP/HTTP JSON format.
+//! The trace context is passed to microservices in the W3C `traceparent`
+//! header, so that their spans are attached to the same trace.
+//!
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{err_msg, Error};
+use futures::{Future, Stream};
+use json::{object, JsonValue};
+use log::{debug, error, warn};
+use tokio::timer::Interval;
+use uuid::Uuid;
+
+use crate::http::send_json_request;
+
+/// The header (and the field of incoming messages) with the trace context.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// The name of the service in the exported spans.
+const SERVICE_NAME: &str = "pathfinder";
+/// The path of the OTLP/HTTP endpoint, when it isn't specified explicitly.
+const DEFAULT_TRACES_PATH: &str = "/v1/traces";
+/// How often the finished spans are sent to the collector.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// The maximum number of finished spans waiting for the export. The newer
+/// spans are dropped when the collector isn't available for a long time.
+const MAX_BUFFERED_SPANS: usize = 2048;
+
+/// Identifiers of the span that are propagated between services.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpanContext {
+    trace_id: String,
+    span_id: String,
+    is_sampled: bool
+}
+
+impl SpanContext {
+    /// Parses the value of the `traceparent` header (e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`).
+    pub fn from_traceparent(value: &str) -> Option<SpanContext> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        match parts.as_slice() {
+            [version, trace_id, span_id, flags]
+                if version.len() == 2 && *version != "ff" && is_valid_id(trace_id, 32) && is_valid_id(span_id, 16) =>
+            {
+                let flags = u8::from_str_radix(flags, 16).ok()?;
+                Some(SpanContext {
+                    trace_id: trace_id.to_lowercase(),
+                    span_id: span_id.to_lowercase(),
+                    is_sampled: flags & 1 == 1
+                })
+            },
+            _ => None
+        }
+    }
+
+    /// Returns the value of the `traceparent` header for the span.
+    pub fn to_traceparent(&self) -> String {
+        let flags = match self.is_sampled {
+            true => "01",
+            false => "00"
+        };
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, flags)
+    }
+
+    /// Returns the identifier of the trace.
+    pub fn get_trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// Returns the identifier of the span.
+    pub fn get_span_id(&self) -> &str {
+        &self.span_id
+    }
+
+    /// Determines whether the span will be exported.
+    pub fn is_sampled(&self) -> bool {
+        self.is_sampled
+    }
+}
+
+/// The role of the span in the communication between services.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Server = 2,
+    Client = 3,
+    Producer = 4,
+    Consumer = 5
+}
+
+/// Records spans and keeps the finished ones until the export.
+#[derive(Debug)]
+pub struct Tracer {
+    address: SocketAddr,
+    host: String,
+    path: String,
+    sample_ratio: f64,
+    spans: Mutex<Vec<JsonValue>>
+}
+
+impl Tracer {
+    /// Returns a new instance of `Tracer` for the collector endpoint (e.g.
+    /// `http://127.0.0.1:4318/v1/traces`). The sample ratio defines the share
+    /// of new traces, that are exported. Fails when the endpoint is invalid
+    /// or its host can't be resolved.
+    pub fn new(endpoint: &str, sample_ratio: f64) -> Result<Tracer, Error> {
+        let url = match endpoint.starts_with("http://") {
+            true => &endpoint["http://".len()..],
+            false => {
+                let message = format!("The tracing endpoint \"{}\" must use the http scheme.", endpoint);
+                return Err(err_msg(message));
+            }
+        };
+        let (host, path) = match url.find('/') {
+            Some(position) => (&url[..position], &url[position..]),
+            None => (url, DEFAULT_TRACES_PATH)
+        };
+        let authority = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:80", host)
+        };
+        let address = authority
+            .to_socket_addrs()
+            .map_err(|error| err_msg(format!("Couldn't resolve the tracing endpoint \"{}\": {}", endpoint, error)))?
+            .next()
+            .ok_or_else(|| err_msg(format!("Couldn't resolve the tracing endpoint \"{}\".", endpoint)))?;
+
+        Ok(Tracer {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+            sample_ratio: sample_ratio.clamp(0.0, 1.0),
+            spans: Mutex::new(Vec::new())
+        })
+    }
+
+    /// Starts a new span. The span continues the trace of the parent when
+    /// it's specified and keeps its sampling decision, otherwise a new trace
+    /// is started. The span is finished when it's dropped.
+    pub fn start_span(tracer: &Arc<Tracer>, name: &str, kind: SpanKind, parent: Option<&SpanContext>) -> ActiveSpan {
+        let context = match parent {
+            Some(parent) => SpanContext {
+                trace_id: parent.trace_id.clone(),
+                span_id: generate_id(16),
+                is_sampled: parent.is_sampled
+            },
+            None => SpanContext {
+                trace_id: generate_id(32),
+                span_id: generate_id(16),
+                is_sampled: tracer.should_sample()
+            }
+        };
+
+        ActiveSpan {
+            tracer: tracer.clone(),
+            context,
+            parent_span_id: parent.map(|parent| parent.span_id.clone()),
+            name: name.to_string(),
+            kind,
+            started_at: SystemTime::now(),
+            attributes: Vec::new(),
+            is_error: false
+        }
+    }
+
+    /// Returns the number of finished spans waiting for the export.
+    pub fn get_buffered_count(&self) -> usize {
+        self.spans.lock().unwrap().len()
+    }
+
+    /// Makes the sampling decision for a new trace.
+    fn should_sample(&self) -> bool {
+        let bytes = *Uuid::new_v4().as_bytes();
+        let random = bytes[..6].iter().fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+        (random as f64) < self.sample_ratio * (1u64 << 48) as f64
+    }
+
+    /// Keeps the finished span until the next export.
+    fn record(&self, span: JsonValue) {
+        let mut spans = self.spans.lock().unwrap();
+        match spans.len() < MAX_BUFFERED_SPANS {
+            true => spans.push(span),
+            false => debug!("The span was dropped: too many spans are waiting for the export.")
+        }
+    }
+
+    /// Returns the request body with the finished spans in the OTLP/HTTP
+    /// JSON format, or `None` when there is nothing to export.
+    fn take_export_body(&self) -> Option<String> {
+        let spans: Vec<JsonValue> = self.spans.lock().unwrap().drain(..).collect();
+        if spans.is_empty() {
+            return None;
+        }
+
+        let body = object!{
+            "resourceSpans" => vec![object!{
+                "resource" => object!{
+                    "attributes" => vec![get_attribute_json("service.name", SERVICE_NAME)]
+                },
+                "scopeSpans" => vec![object!{
+                    "scope" => object!{"name" => SERVICE_NAME},
+                    "spans" => JsonValue::Array(spans)
+                }]
+            }]
+        };
+        Some(body.dump())
+    }
+}
+
+/// A span in progress. It's recorded when dropped, if the trace is sampled.
+#[derive(Debug)]
+pub struct ActiveSpan {
+    tracer: Arc<Tracer>,
+    context: SpanContext,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: SpanKind,
+    started_at: SystemTime,
+    attributes: Vec<(String, String)>,
+    is_error: bool
+}
+
+impl ActiveSpan {
+    /// Returns the identifiers of the span.
+    pub fn get_context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    /// Returns the context for starting child spans in other futures.
+    pub fn get_trace_context(&self) -> TraceContext {
+        TraceContext {
+            tracer: self.tracer.clone(),
+            context: self.context.clone()
+        }
+    }
+
+    /// Adds an attribute to the span.
+    pub fn set_attribute(&mut self, key: &str, value: &str) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    /// Marks the span as failed.
+    pub fn set_error(&mut self) {
+        self.is_error = true;
+    }
+
+    /// Returns the span in the OTLP/HTTP JSON format.
+    fn to_json(&self, finished_at: SystemTime) -> JsonValue {
+        let attributes: Vec<JsonValue> = self.attributes
+            .iter()
+            .map(|(key, value)| get_attribute_json(key, value))
+            .collect();
+        let status_code = match self.is_error {
+            true => 2,
+            false => 0
+        };
+
+        let mut span = object!{
+            "traceId" => self.context.trace_id.as_str(),
+            "spanId" => self.context.span_id.as_str(),
+            "name" => self.name.as_str(),
+            "kind" => self.kind as u8,
+            "startTimeUnixNano" => get_unix_nanos(self.started_at),
+            "endTimeUnixNano" => get_unix_nanos(finished_at),
+            "attributes" => attributes,
+            "status" => object!{"code" => status_code}
+        };
+        if let Some(ref parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = parent_span_id.as_str().into();
+        }
+        span
+    }
+}
+
+impl Drop for ActiveSpan {
+    fn drop(&mut self) {
+        if self.context.is_sampled {
+            self.tracer.record(self.to_json(SystemTime::now()));
+        }
+    }
+}
+
+/// The tracer with the context of the parent span, that is passed into
+/// futures for recording child spans.
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    tracer: Arc<Tracer>,
+    context: SpanContext
+}
+
+impl TraceContext {
+    /// Starts a child span of the parent one.
+    pub fn start_span(&self, name: &str, kind: SpanKind) -> ActiveSpan {
+        Tracer::start_span(&self.tracer, name, kind, Some(&self.context))
+    }
+}
+
+/// Returns a future that periodically sends the finished spans to the collector.
+pub fn get_export_future(tracer: Arc<Tracer>) -> impl Future<Item=(), Error=()> + Send + 'static {
+    Interval::new_interval(EXPORT_INTERVAL)
+        .map_err(|error| error!("Timer error: {}", error))
+        .for_each(move |_| {
+            if let Some(body) = tracer.take_export_body() {
+                let export_future = send_json_request(tracer.address, &tracer.host, &tracer.path, body)
+                    .map(|status| {
+                        if !status.contains(" 200 ") {
+                            warn!("Spans weren't accepted by the collector: {}", status);
+                        }
+                    })
+                    .map_err(|error| warn!("Spans weren't exported: {}", error));
+                tokio::spawn(export_future);
+            }
+            Ok(())
+        })
+}
+
+/// Checks that the identifier is a non-zero hex string of the given length.
+fn is_valid_id(value: &str, length: usize) -> bool {
+    value.len() == length
+        && value.chars().all(|symbol| symbol.is_ascii_hexdigit())
+        && value.chars().any(|symbol| symbol != '0')
+}
+
+/// Returns a random hex identifier of the given length (up to 32 symbols).
+fn generate_id(length: usize) -> String {
+    let mut id = Uuid::new_v4().to_simple().to_string();
+    id.truncate(length);
+    id
+}
+
+/// Returns the number of nanoseconds since the Unix epoch as a string,
+/// because 64-bit integers are encoded as strings in OTLP JSON.
+fn get_unix_nanos(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    duration.as_nanos().to_string()
+}
+
+/// Returns a string attribute in the OTLP JSON format.
+fn get_attribute_json(key: &str, value: &str) -> JsonValue {
+    object!{"key" => key, "value" => object!{"stringValue" => value}}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use json::parse as parse_json;
+
+    use crate::tracing::{SpanContext, SpanKind, Tracer};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn get_tracer(sample_ratio: f64) -> Arc<Tracer> {
+        Arc::new(Tracer::new("http://127.0.0.1:4318", sample_ratio).unwrap())
+    }
+
+    #[test]
+    fn test_span_context_from_traceparent() {
+        let context = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(context.get_trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.get_span_id(), "00f067aa0ba902b7");
+        assert!(context.is_sampled());
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+    }
+
+    #[test]
+    fn test_span_context_from_invalid_traceparent() {
+        assert_eq!(SpanContext::from_traceparent(""), None);
+        assert_eq!(SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"), None);
+        assert_eq!(SpanContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-xxf067aa0ba902b7-01"), None);
+        assert_eq!(SpanContext::from_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+    }
+
+    #[test]
+    fn test_new_returns_an_error_for_unsupported_schemes() {
+        assert!(Tracer::new("https://127.0.0.1:4318", 1.0).is_err());
+        assert!(Tracer::new("127.0.0.1:4318", 1.0).is_err());
+    }
+
+    #[test]
+    fn test_start_span_continues_the_parent_trace() {
+        let tracer = get_tracer(0.0);
+        let parent = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        let span = Tracer::start_span(&tracer, "request", SpanKind::Server, Some(&parent));
+
+        assert_eq!(span.get_context().get_trace_id(), parent.get_trace_id());
+        assert_ne!(span.get_context().get_span_id(), parent.get_span_id());
+        assert!(span.get_context().is_sampled());
+    }
+
+    #[test]
+    fn test_finished_spans_are_exported_in_the_otlp_format() {
+        let tracer = get_tracer(1.0);
+        let mut span = Tracer::start_span(&tracer, "request", SpanKind::Server, None);
+        span.set_attribute("routing_key", "microservice.search");
+        let child = span.get_trace_context().start_span("publish", SpanKind::Producer);
+        let trace_id = span.get_context().get_trace_id().to_string();
+        let span_id = span.get_context().get_span_id().to_string();
+        drop(child);
+        span.set_error();
+        drop(span);
+        assert_eq!(tracer.get_buffered_count(), 2);
+
+        let body = parse_json(&tracer.take_export_body().unwrap()).unwrap();
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["name"], "publish");
+        assert_eq!(spans[0]["kind"], 4);
+        assert_eq!(spans[0]["traceId"], trace_id.as_str());
+        assert_eq!(spans[0]["parentSpanId"], span_id.as_str());
+        assert_eq!(spans[1]["name"], "request");
+        assert_eq!(spans[1]["spanId"], span_id.as_str());
+        assert_eq!(spans[1]["status"]["code"], 2);
+        assert_eq!(spans[1]["attributes"][0]["value"]["stringValue"], "microservice.search");
+        assert!(spans[1]["parentSpanId"].is_null());
+        assert_eq!(tracer.take_export_body(), None);
+    }
+
+    #[test]
+    fn test_spans_of_not_sampled_traces_are_not_exported() {
+        let tracer = get_tracer(0.0);
+        let span = Tracer::start_span(&tracer, "request", SpanKind::Server, None);
+        assert!(!span.get_context().is_sampled());
+        assert!(span.get_context().to_traceparent().ends_with("-00"));
+        drop(span);
+
+        assert_eq!(tracer.get_buffered_count(), 0);
+    }
+}
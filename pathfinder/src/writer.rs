@@ -0,0 +1,237 @@
+//! Writing responses to the client
+//!
+//! This module provides a future that moves the prepared responses from the
+//! channel of the connection into the WebSocket sink. The sink is written only
+//! when it's ready, and the responses are kept in a bounded buffer meanwhile,
+//! so that a slow client can't make the proxy panic or exhaust the memory.
+//!
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use log::{debug, warn};
+use tungstenite::protocol::Message;
+
+/// Writes each received message into the sink in the order of receiving.
+/// Resolves after writing all messages when the channel is closed, or after
+/// closing the sink when the closing message was sent. Fails when the sink
+/// fails or more than `max_pending` messages are waiting to be written while
+/// the sink isn't ready.
+pub struct OutboundWriter<S, R> {
+    sink: S,
+    receiver: R,
+    buffer: VecDeque<Message>,
+    max_pending: usize,
+    closing_message: Arc<Mutex<Option<Message>>>,
+    is_receiver_done: bool,
+    is_closing: bool
+}
+
+impl<S, R> OutboundWriter<S, R>
+where
+    S: Sink<SinkItem=Message>,
+    S::SinkError: fmt::Debug,
+    R: Stream<Item=Message>
+{
+    /// Returns a new instance of `OutboundWriter`. The connection is closed
+    /// after sending the message stored in the `closing_message`.
+    pub fn new(sink: S, receiver: R, max_pending: usize, closing_message: Arc<Mutex<Option<Message>>>) -> OutboundWriter<S, R> {
+        OutboundWriter {
+            sink,
+            receiver,
+            buffer: VecDeque::new(),
+            max_pending,
+            closing_message,
+            is_receiver_done: false,
+            is_closing: false
+        }
+    }
+
+    /// Moves the available messages from the channel into the buffer, until
+    /// more than `max_pending` messages are buffered. Returns `true` when the
+    /// channel can still have messages.
+    fn receive_messages(&mut self) -> Result<bool, ()> {
+        while !self.is_receiver_done {
+            if self.buffer.len() > self.max_pending {
+                return Ok(true);
+            }
+
+            match self.receiver.poll().map_err(|_| ())? {
+                Async::Ready(Some(message)) => self.buffer.push_back(message),
+                Async::Ready(None) => self.is_receiver_done = true,
+                Async::NotReady => break
+            }
+        }
+        Ok(false)
+    }
+
+    /// Starts sending the buffered messages until the sink is not ready.
+    /// Returns `true` when the sink isn't ready.
+    fn send_messages(&mut self) -> Result<bool, ()> {
+        while let Some(message) = self.buffer.pop_front() {
+            let is_closing_message = self.closing_message.lock().unwrap().as_ref() == Some(&message);
+            match self.sink.start_send(message).map_err(|error| debug!("Couldn't write the message: {:?}", error))? {
+                AsyncSink::Ready if is_closing_message => {
+                    self.is_closing = true;
+                    self.buffer.clear();
+                    break;
+                },
+                AsyncSink::Ready => {},
+                AsyncSink::NotReady(message) => {
+                    self.buffer.push_front(message);
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Moves the messages into the sink while it accepts them. Fails when
+    /// the sink isn't ready and too many messages are waiting for it.
+    fn write_messages(&mut self) -> Result<(), ()> {
+        loop {
+            let has_more_messages = self.receive_messages()?;
+            let is_sink_busy = self.send_messages()?;
+            if self.is_closing {
+                return Ok(());
+            }
+
+            if is_sink_busy {
+                self.receive_messages()?;
+                return match self.buffer.len() > self.max_pending {
+                    true => {
+                        warn!("The client doesn't read {} pending messages, the connection is dropped.", self.buffer.len());
+                        Err(())
+                    },
+                    false => Ok(())
+                };
+            }
+
+            if !has_more_messages {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S, R> Future for OutboundWriter<S, R>
+where
+    S: Sink<SinkItem=Message>,
+    S::SinkError: fmt::Debug,
+    R: Stream<Item=Message>
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if !self.is_closing {
+            self.write_messages()?;
+        }
+
+        // The closing handshake is started after flushing the last message
+        if self.is_closing {
+            return self.sink.close().map_err(|error| debug!("Couldn't close the sink: {:?}", error));
+        }
+
+        let is_flushed = self.sink.poll_complete().map_err(|error| debug!("Couldn't flush the sink: {:?}", error))?.is_ready();
+        match is_flushed && self.is_receiver_done && self.buffer.is_empty() {
+            true => Ok(Async::Ready(())),
+            false => Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::future::lazy;
+    use futures::sync::mpsc;
+    use futures::{Async, Future, Stream};
+    use tungstenite::protocol::Message;
+
+    use crate::writer::OutboundWriter;
+
+    #[test]
+    fn test_writer_sends_messages_in_order() {
+        let (tx, rx) = mpsc::unbounded();
+        let (sink, sink_rx) = mpsc::unbounded();
+        for index in 0..3 {
+            tx.unbounded_send(Message::Text(index.to_string())).unwrap();
+        }
+        drop(tx);
+
+        let writer = OutboundWriter::new(sink, rx, 10, Arc::new(Mutex::new(None)));
+        assert!(writer.wait().is_ok());
+
+        let messages = sink_rx.collect().wait().unwrap();
+        assert_eq!(messages, vec![
+            Message::Text(String::from("0")),
+            Message::Text(String::from("1")),
+            Message::Text(String::from("2")),
+        ]);
+    }
+
+    #[test]
+    fn test_writer_keeps_messages_while_the_sink_is_not_ready() {
+        let (tx, rx) = mpsc::unbounded();
+        let (sink, sink_rx) = mpsc::channel(0);
+        for index in 0..3 {
+            tx.unbounded_send(Message::Text(index.to_string())).unwrap();
+        }
+
+        let mut writer = OutboundWriter::new(sink, rx, 10, Arc::new(Mutex::new(None)));
+        let result = lazy(move || Ok::<_, ()>((writer.poll(), writer))).wait().unwrap();
+        assert_eq!(result.0, Ok(Async::NotReady));
+        assert_eq!(result.1.buffer.len(), 2);
+        drop(sink_rx);
+    }
+
+    #[test]
+    fn test_writer_fails_when_too_many_messages_are_pending() {
+        let (tx, rx) = mpsc::unbounded();
+        let (sink, _sink_rx) = mpsc::channel(0);
+        for index in 0..5 {
+            tx.unbounded_send(Message::Text(index.to_string())).unwrap();
+        }
+
+        let mut writer = OutboundWriter::new(sink, rx, 2, Arc::new(Mutex::new(None)));
+        let result = lazy(move || Ok::<_, ()>(writer.poll())).wait().unwrap();
+        assert_eq!(result, Err(()));
+    }
+
+    #[test]
+    fn test_writer_sends_more_than_max_pending_messages_to_the_ready_sink() {
+        let (tx, rx) = mpsc::unbounded();
+        let (sink, sink_rx) = mpsc::unbounded();
+        for index in 0..10 {
+            tx.unbounded_send(Message::Text(index.to_string())).unwrap();
+        }
+        drop(tx);
+
+        let mut writer = OutboundWriter::new(sink, rx, 2, Arc::new(Mutex::new(None)));
+        let result = lazy(move || Ok::<_, ()>(writer.poll())).wait().unwrap();
+        assert_eq!(result, Ok(Async::Ready(())));
+
+        let messages: Vec<Message> = sink_rx.collect().wait().unwrap();
+        assert_eq!(messages, (0..10).map(|index| Message::Text(index.to_string())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_writer_stops_after_the_closing_message() {
+        let closing_message = Message::Text(String::from("fatal error"));
+        let (tx, rx) = mpsc::unbounded();
+        let (sink, sink_rx) = mpsc::unbounded();
+        tx.unbounded_send(Message::Text(String::from("response"))).unwrap();
+        tx.unbounded_send(closing_message.clone()).unwrap();
+        tx.unbounded_send(Message::Text(String::from("ignored"))).unwrap();
+
+        let writer = OutboundWriter::new(sink, rx, 10, Arc::new(Mutex::new(Some(closing_message.clone()))));
+        assert!(writer.wait().is_ok());
+
+        let messages = sink_rx.collect().wait().unwrap();
+        assert_eq!(messages, vec![Message::Text(String::from("response")), closing_message]);
+    }
+}